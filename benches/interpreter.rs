@@ -0,0 +1,113 @@
+//! Interpreter throughput on small compute-heavy functions.
+//!
+//! Run with `cargo bench`; the modules are built by hand so the benchmark
+//! doesn't need any `.wasm` files.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use wasm::engine::{Engine, Instance};
+use wasm::instance::Externals;
+use wasm::repr::{
+    BlockType, Export, ExportDesc, Func, FuncIdx, FuncType, Inst, LabelIdx, LocalIdx, Locals,
+    Module, ResultType, TypeIdx, ValType,
+};
+use wasm::rt::Val;
+
+fn i32_to_i32() -> FuncType {
+    FuncType {
+        from: ResultType {
+            types: vec![ValType::I32],
+        },
+        to: ResultType {
+            types: vec![ValType::I32],
+        },
+    }
+}
+
+fn export(name: &str, func: u32) -> Export {
+    Export {
+        name: name.into(),
+        desc: ExportDesc::Func(FuncIdx(func)),
+    }
+}
+
+/// `fib(n)`, computed recursively, exercises calls.
+/// `count(n)` counts up to `n` in a loop, exercises plain instructions.
+fn module() -> Module {
+    let n = || Inst::LocalGet(LocalIdx(0));
+    let fib = Func {
+        typ: TypeIdx(0),
+        locals: vec![],
+        body: vec![
+            n(),
+            Inst::I32Const(2),
+            Inst::I32LtS,
+            Inst::IfElse(
+                BlockType::Inline(ValType::I32),
+                vec![n()].into(),
+                vec![
+                    n(),
+                    Inst::I32Const(1),
+                    Inst::I32Sub,
+                    Inst::Call(FuncIdx(0)),
+                    n(),
+                    Inst::I32Const(2),
+                    Inst::I32Sub,
+                    Inst::Call(FuncIdx(0)),
+                    Inst::I32Add,
+                ]
+                .into(),
+            ),
+        ],
+    };
+    let i = LocalIdx(1);
+    let count = Func {
+        typ: TypeIdx(0),
+        locals: vec![Locals {
+            n: 1,
+            t: ValType::I32,
+        }],
+        body: vec![
+            Inst::Loop(
+                BlockType::Empty,
+                vec![
+                    Inst::LocalGet(i),
+                    Inst::I32Const(1),
+                    Inst::I32Add,
+                    Inst::LocalSet(i),
+                    Inst::LocalGet(i),
+                    n(),
+                    Inst::I32LtS,
+                    Inst::BreakIf(LabelIdx(0)),
+                ]
+                .into(),
+            ),
+            Inst::LocalGet(i),
+        ],
+    };
+    Module {
+        types: vec![i32_to_i32()],
+        funcs: vec![fib, count],
+        exports: vec![export("fib", 0), export("count", 1)],
+        ..Default::default()
+    }
+}
+
+fn interpreter(c: &mut Criterion) {
+    let engine = Engine::new();
+    let module = module();
+    let mut instance = Instance::new(&engine, &module, Externals::default()).unwrap();
+
+    c.bench_function("fib 20", |b| {
+        b.iter(|| instance.call("fib", &[black_box(Val::I32(20))]).unwrap())
+    });
+    c.bench_function("count 100000", |b| {
+        b.iter(|| {
+            instance
+                .call("count", &[black_box(Val::I32(100_000))])
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, interpreter);
+criterion_main!(benches);
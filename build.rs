@@ -1,5 +1,104 @@
 use std::{fs, path::PathBuf};
 
+/// Spec scripts the runner can't pass yet. Their tests are still generated,
+/// but marked `#[ignore]`; remove a name once `cargo test -- --ignored` passes
+/// for it.
+const KNOWN_FAILING: &[&str] = &[
+    "address",
+    "align",
+    "binary",
+    "binary-leb128",
+    "block",
+    "br",
+    "br_if",
+    "br_table",
+    "bulk",
+    "call",
+    "call_indirect",
+    "comments",
+    "const",
+    "conversions",
+    "custom",
+    "data",
+    "elem",
+    "endianness",
+    "exports",
+    "f32",
+    "f32_bitwise",
+    "f32_cmp",
+    "f64",
+    "f64_bitwise",
+    "f64_cmp",
+    "fac",
+    "float_exprs",
+    "float_literals",
+    "float_memory",
+    "float_misc",
+    "forward",
+    "func",
+    "func_ptrs",
+    "global",
+    "i32",
+    "i64",
+    "if",
+    "imports",
+    "inline-module",
+    "int_exprs",
+    "int_literals",
+    "labels",
+    "left-to-right",
+    "linking",
+    "load",
+    "local_get",
+    "local_set",
+    "local_tee",
+    "loop",
+    "memory",
+    "memory_copy",
+    "memory_fill",
+    "memory_grow",
+    "memory_init",
+    "memory_redundancy",
+    "memory_size",
+    "memory_trap",
+    "names",
+    "nop",
+    "ref_func",
+    "ref_is_null",
+    "ref_null",
+    "return",
+    "select",
+    "skip-stack-guard-page",
+    "stack",
+    "start",
+    "store",
+    "switch",
+    "table",
+    "table-sub",
+    "table_copy",
+    "table_fill",
+    "table_get",
+    "table_grow",
+    "table_init",
+    "table_set",
+    "table_size",
+    "token",
+    "traps",
+    "type",
+    "unreachable",
+    "unreached-invalid",
+    "unreached-valid",
+    "unwind",
+    "utf8-custom-section-id",
+    "utf8-import-field",
+    "utf8-import-module",
+    "utf8-invalid-encoding",
+];
+
+/// If not empty, tests are only generated for these spec scripts, e.g. while
+/// working on one of them.
+const ONLY: &[&str] = &[];
+
 #[derive(Debug)]
 struct WastFile {
     name: String,
@@ -10,7 +109,8 @@ fn wast_files() -> Vec<WastFile> {
     let root = std::env::var("CARGO_MANIFEST_DIR").unwrap();
     let mut wast_dir = PathBuf::from(root);
     wast_dir.push("spec/test/core");
-    let entries = fs::read_dir(wast_dir).unwrap();
+    // without the spec submodule there are no tests to generate
+    let Ok(entries) = fs::read_dir(wast_dir) else { return vec![] };
 
     let mut wast_files = vec![];
     for entry in entries {
@@ -47,12 +147,17 @@ fn tokenize_wast_{test_name}() {{
     .unwrap();
 }
 
-#[allow(dead_code)]
 fn write_wast_script_test(writer: &mut dyn std::io::Write, wast: &WastFile) {
+    let ignore = if KNOWN_FAILING.contains(&wast.name.as_str()) {
+        "#[ignore = \"known failing, see KNOWN_FAILING in build.rs\"]"
+    } else {
+        ""
+    };
     write!(
         writer,
         "
     #[test]
+    {ignore}
     fn wast_script_{test_name}() {{
         let path = std::path::PathBuf::from(\"{filename}\");
         let content = std::fs::read_to_string(&path).unwrap();
@@ -67,16 +172,27 @@ fn write_wast_script_test(writer: &mut dyn std::io::Write, wast: &WastFile) {
 
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=spec/test/core");
     let out_dir = std::env::var("OUT_DIR").unwrap();
     let destination = std::path::Path::new(&out_dir).join("wast_tests.rs");
     let mut f = std::fs::File::create(&destination).unwrap();
-    let wast_files = wast_files();
+    let mut wast_files = wast_files();
+    // no scripts at all means the spec submodule isn't checked out, rather
+    // than that every entry is stale
+    if !wast_files.is_empty() {
+        for name in KNOWN_FAILING {
+            if !wast_files.iter().any(|wast| wast.name == *name) {
+                println!("cargo:warning=KNOWN_FAILING lists {name}.wast, which doesn't exist");
+            }
+        }
+    }
+    if !ONLY.is_empty() {
+        wast_files.retain(|wast| ONLY.contains(&wast.name.as_str()));
+    }
     for wast in &wast_files {
-        write_wast_tokenization_test(&mut f, &wast);
+        write_wast_tokenization_test(&mut f, wast);
     }
-    /*
     for wast in &wast_files {
-        write_wast_script_test(&mut f, &wast);
+        write_wast_script_test(&mut f, wast);
     }
-    */
 }
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use wasm::binary::parser::parse_module_checked;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_module_checked(data);
+});
@@ -0,0 +1,35 @@
+//! Writing the binary format, so far only the LEB128 integers that
+//! [`super::parser`] reads.
+
+/// Appends `value` as an unsigned LEB128, in as few bytes as possible.
+pub fn encode_u32(mut value: u32, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Appends `value` as a signed LEB128, in as few bytes as possible.
+pub fn encode_i32(value: i32, out: &mut Vec<u8>) {
+    encode_i64(value.into(), out)
+}
+
+/// Appends `value` as a signed LEB128, in as few bytes as possible.
+pub fn encode_i64(mut value: i64, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        // shifting is arithmetic, so this ends at 0 or -1
+        value >>= 7;
+        let sign = byte & 0x40 != 0;
+        if (value == 0 && !sign) || (value == -1 && sign) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
@@ -1,15 +1,116 @@
-use std::{
-    io::{self, BufRead, BufReader, ErrorKind},
-    sync::atomic::AtomicU32,
-};
+use std::io::{self, BufReader, Cursor, ErrorKind, Read};
 
 use crate::repr::*;
 
-pub struct Parser {
-    pub stream: Box<dyn BufRead>,
+/// A reader that can look at the next byte without consuming it.
+struct PeekReader<R> {
+    inner: R,
+    peeked: Option<u8>,
 }
 
-impl Parser {
+impl<R: Read> PeekReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            peeked: None,
+        }
+    }
+
+    /// Returns the next byte, or `None` at the end of the stream.
+    fn peek(&mut self) -> Result<Option<u8>, io::Error> {
+        if self.peeked.is_none() {
+            let mut byte = [0];
+            loop {
+                match self.inner.read(&mut byte) {
+                    Ok(0) => return Ok(None),
+                    Ok(_) => break,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            self.peeked = Some(byte[0]);
+        }
+        Ok(self.peeked)
+    }
+
+    /// Drops the byte returned by the last [`PeekReader::peek`].
+    fn consume(&mut self) {
+        self.peeked = None;
+    }
+}
+
+impl<R: Read> Read for PeekReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some(byte) = self.peeked.take() {
+            buf[0] = byte;
+            return Ok(1);
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Parses the binary format from any [`Read`]er.
+///
+/// The parser reads a byte at a time, so an unbuffered source like a `File`
+/// should be wrapped in a [`BufReader`] first.
+pub struct Parser<R> {
+    stream: PeekReader<R>,
+    /// how many blocks the parser is inside of
+    depth: usize,
+    /// how many bytes the stream holds at most
+    limit: usize,
+}
+
+/// Blocks nested deeper than this are rejected instead of overflowing the
+/// stack of the recursive parser. Debug builds need a few KB per level, so
+/// this stays well within a 2MB thread stack.
+const MAX_NESTING: usize = 256;
+
+fn malformed(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn unsupported(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::Unsupported, msg)
+}
+
+impl Parser<Cursor<Vec<u8>>> {
+    /// Creates a parser for a payload that was read in full.
+    fn bounded(payload: Vec<u8>) -> Self {
+        Self {
+            limit: payload.len(),
+            ..Self::new(Cursor::new(payload))
+        }
+    }
+}
+
+impl<R: Read> Parser<R> {
+    pub fn new(stream: R) -> Self {
+        Self {
+            stream: PeekReader::new(stream),
+            depth: 0,
+            limit: usize::MAX,
+        }
+    }
+
+    /// Reads the next `size` bytes and returns a parser for just those.
+    fn sub_parser(&mut self, size: u32) -> Result<Parser<Cursor<Vec<u8>>>, io::Error> {
+        let payload = self.read_bytes(size as usize)?;
+        Ok(Parser::bounded(payload))
+    }
+
+    /// Checks that everything in the stream was parsed, `what` names the
+    /// thing it holds in the error.
+    fn finish(&mut self, what: &str) -> Result<(), io::Error> {
+        if self.stream.peek()?.is_some() {
+            return Err(malformed(&format!("{what} size mismatch")));
+        }
+        Ok(())
+    }
+
     fn parse_magic(&mut self) -> Result<(), io::Error> {
         let mut magic = [0u8; 4];
         self.stream.read_exact(&mut magic)?;
@@ -35,8 +136,14 @@ impl Parser {
     }
 
     fn read_bytes(&mut self, bytes: usize) -> Result<Vec<u8>, io::Error> {
-        let mut buf = vec![0; bytes];
-        self.stream.read_exact(&mut buf)?;
+        // the length is untrusted, so only allocate for what is actually there
+        let mut buf = vec![];
+        (&mut self.stream)
+            .take(bytes as u64)
+            .read_to_end(&mut buf)?;
+        if buf.len() != bytes {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
         Ok(buf)
     }
 
@@ -47,13 +154,48 @@ impl Parser {
         for _ in 0..5 {
             let byte = self.parse_byte()?;
             const HIGHMASK: u8 = 0b1000_0000;
+            // the last byte only has room for the top 4 bits
+            if shift == 28 && byte & 0x70 != 0 && byte & HIGHMASK == 0 {
+                return Err(malformed("integer too large"));
+            }
             result |= ((byte & !HIGHMASK) as u32) << shift;
             if byte & HIGHMASK == 0 {
-                break;
+                return Ok(result);
             }
             shift += 7;
         }
-        Ok(result)
+        Err(malformed("integer too long"))
+    }
+
+    fn parse_u64(&mut self) -> Result<u64, io::Error> {
+        let mut result: u64 = 0;
+        let mut shift: u32 = 0;
+        // 10 = 64/7 rounded up
+        for _ in 0..10 {
+            let byte = self.parse_byte()?;
+            const HIGHMASK: u8 = 0b1000_0000;
+            // the last byte only has room for the top bit
+            if shift == 63 && byte & 0x7e != 0 && byte & HIGHMASK == 0 {
+                return Err(malformed("integer too large"));
+            }
+            result |= ((byte & !HIGHMASK) as u64) << shift;
+            if byte & HIGHMASK == 0 {
+                return Ok(result);
+            }
+            shift += 7;
+        }
+        Err(malformed("integer too long"))
+    }
+
+    /// Parses the length of a vector or byte string. Every element takes at
+    /// least one byte, so lengths larger than the stream are rejected before
+    /// anything is allocated for them.
+    fn parse_len(&mut self) -> Result<u32, io::Error> {
+        let len = self.parse_u32()?;
+        if len as usize > self.limit {
+            return Err(malformed("length out of bounds"));
+        }
+        Ok(len)
     }
 
     fn parse_section_header(&mut self) -> Result<(SectionId, u32), io::Error> {
@@ -66,13 +208,16 @@ impl Parser {
 
     fn parse_valtype(&mut self) -> Result<ValType, io::Error> {
         let typ = self.parse_byte()?;
+        if typ == 0x7b {
+            return Err(unsupported("simd value type v128"));
+        }
         let typ = ValType::try_from(typ)
             .map_err(|_e| io::Error::new(ErrorKind::InvalidInput, "unknown value type"))?;
         Ok(typ)
     }
 
     fn parse_resulttype(&mut self) -> Result<ResultType, io::Error> {
-        let elems = self.parse_u32()?;
+        let elems = self.parse_len()?;
         let mut vals = vec![];
         for _ in 0..elems {
             let val = self.parse_valtype()?;
@@ -83,7 +228,9 @@ impl Parser {
 
     fn parse_functype(&mut self) -> Result<FuncType, io::Error> {
         let header = self.parse_byte()?;
-        assert_eq!(header, 0x60);
+        if header != 0x60 {
+            return Err(malformed("invalid functype"));
+        }
         let from = self.parse_resulttype()?;
         let to = self.parse_resulttype()?;
         Ok(FuncType { from, to })
@@ -100,9 +247,9 @@ impl Parser {
     }
 
     fn parse_name(&mut self) -> Result<String, io::Error> {
-        let size = self.parse_u32()?;
+        let size = self.parse_len()?;
         let bytes = self.read_bytes(size as usize)?;
-        let name = String::from_utf8(bytes).expect("invalid utf8");
+        let name = String::from_utf8(bytes).map_err(|_| malformed("invalid utf8"))?;
         Ok(name)
     }
 
@@ -114,7 +261,7 @@ impl Parser {
             1 => ExportDesc::Table(TableIdx(idx)),
             2 => ExportDesc::Mem(MemIdx(idx)),
             3 => ExportDesc::Global(GlobalIdx(idx)),
-            _ => panic!("invalid export desc"),
+            _ => return Err(malformed("invalid export desc")),
         };
         Ok(desc)
     }
@@ -132,18 +279,27 @@ impl Parser {
     }
 
     fn parse_code(&mut self, func_types: &[TypeIdx]) -> Result<Vec<Func>, io::Error> {
-        let elems = self.parse_u32()?;
+        let elems = self.parse_len()?;
         let mut funcs = vec![];
         for func in 0..elems {
-            let typidx = func_types[func as usize];
-            // TODO: figure out how to use this field effectively
-            let _size = self.parse_u32()?;
+            let Some(&typidx) = func_types.get(func as usize) else { return Err(malformed("more code entries than functions")) };
+            let size = self.parse_u32()?;
+            let mut body = self.sub_parser(size)?;
             let mut locals = vec![];
-            let local_count = self.parse_u32()?;
+            let local_count = body.parse_len()?;
+            let mut total = 0u64;
             for _ in 0..local_count {
-                locals.push(self.parse_local()?);
+                let local = body.parse_local()?;
+                total += u64::from(local.n);
+                if total > u64::from(u32::MAX) {
+                    return Err(malformed("too many locals"));
+                }
+                locals.push(local);
             }
-            let expr = self.parse_expr()?;
+            // the body has to end exactly at its `end`, and can't run into
+            // the next one
+            let expr = body.parse_expr()?;
+            body.finish("function body")?;
 
             funcs.push(Func {
                 typ: typidx,
@@ -161,10 +317,10 @@ impl Parser {
                 let idx = self.parse_typeidx()?;
                 Ok(ImportDesc::Func(idx))
             }
-            0x01 => todo!(),
-            0x02 => todo!(),
-            0x03 => todo!(),
-            _ => panic!("invalid import desc"),
+            0x01 => Ok(ImportDesc::Table(self.parse_tabletype()?)),
+            0x02 => Ok(ImportDesc::Mem(self.parse_memtype()?)),
+            0x03 => Ok(ImportDesc::Global(self.parse_globaltype()?)),
+            _ => Err(malformed("invalid import desc")),
         }
     }
 
@@ -173,29 +329,60 @@ impl Parser {
         let typ = match byte {
             0x70 => Reftype::Funcref,
             0x6F => Reftype::Externref,
-            _ => panic!("invalid reftype"),
+            _ => return Err(malformed("invalid reftype")),
         };
         Ok(typ)
     }
 
-    fn parse_limits(&mut self) -> Result<Limits, io::Error> {
+    // the only elemkind is funcref
+    fn parse_elemkind(&mut self) -> Result<Reftype, io::Error> {
         let byte = self.parse_byte()?;
-        let limits = match byte {
-            0x00 => {
-                let min = self.parse_u32()?;
-                Limits { min, max: None }
-            }
-            0x01 => {
-                let min = self.parse_u32()?;
-                let max = self.parse_u32()?;
-                Limits {
-                    min,
-                    max: Some(max),
-                }
+        let typ = match byte {
+            0x00 => Reftype::Funcref,
+            _ => return Err(malformed("invalid elemkind")),
+        };
+        Ok(typ)
+    }
+
+    fn parse_globaltype(&mut self) -> Result<GlobalType, io::Error> {
+        let valtype = self.parse_valtype()?;
+        let mutable = match self.parse_byte()? {
+            0x00 => false,
+            0x01 => true,
+            _ => return Err(malformed("invalid mutability")),
+        };
+        Ok(GlobalType { valtype, mutable })
+    }
+
+    fn parse_limits(&mut self) -> Result<Limits, io::Error> {
+        const HAS_MAX: u8 = 0x01;
+        const SHARED: u8 = 0x02;
+        const IS_64: u8 = 0x04;
+        let flags = self.parse_byte()?;
+        if flags & !(HAS_MAX | SHARED | IS_64) != 0 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "invalid limits"));
+        }
+        let is_64 = flags & IS_64 != 0;
+        let shared = flags & SHARED != 0;
+        let bound = |parser: &mut Self| {
+            if is_64 {
+                parser.parse_u64()
+            } else {
+                parser.parse_u32().map(u64::from)
             }
-            _ => panic!("invalid limits"),
         };
-        Ok(limits)
+        let min = bound(self)?;
+        let max = if flags & HAS_MAX != 0 {
+            Some(bound(self)?)
+        } else {
+            None
+        };
+        Ok(Limits {
+            min,
+            max,
+            is_64,
+            shared,
+        })
     }
 
     fn parse_tabletype(&mut self) -> Result<TableType, io::Error> {
@@ -212,19 +399,24 @@ impl Parser {
     fn parse_blocktype(&mut self) -> Result<BlockType, io::Error> {
         let typ = match self.peek_byte()? {
             0x40 => {
-                self.stream.consume(1);
+                self.stream.consume();
                 BlockType::Empty
             }
-            0x7F | 0x7E | 0x7D | 0x7C | 0x7B | 0x70 | 0x67 => {
+            0x7F | 0x7E | 0x7D | 0x7C | 0x7B | 0x70 | 0x6F => {
                 BlockType::Inline(self.parse_valtype()?)
             }
-            _ => todo!(),
+            // a type index, encoded as a positive s33
+            _ => {
+                let idx = self.parse_i64()?;
+                let idx = u32::try_from(idx).map_err(|_| malformed("invalid blocktype"))?;
+                BlockType::Type(TypeIdx(idx))
+            }
         };
         Ok(typ)
     }
 
     fn peek_byte(&mut self) -> Result<u8, io::Error> {
-        Ok(self.stream.fill_buf()?[0])
+        self.stream.peek()?.ok_or(ErrorKind::UnexpectedEof.into())
     }
 
     fn parse_block(&mut self) -> Result<(BlockType, Vec<Inst>), io::Error> {
@@ -234,15 +426,28 @@ impl Parser {
     }
 
     fn parse_if(&mut self) -> Result<(BlockType, Vec<Inst>, Vec<Inst>), io::Error> {
-        todo!()
+        let bt = self.parse_blocktype()?;
+        let (then, end) = self.parse_instrs_until_else()?;
+        let els = if end == 0x05 {
+            self.parse_expr()?
+        } else {
+            vec![]
+        };
+        Ok((bt, then, els))
     }
 
     // TODO: check if correct
     fn parse_i32(&mut self) -> Result<i32, io::Error> {
         let mut result: i32 = 0;
         let mut shift = 0;
-        loop {
+        // 5 = 32/7 rounded up
+        for _ in 0..5 {
             let byte = self.parse_byte()?;
+            // the last byte holds the top 4 bits, the unused ones have to
+            // repeat the sign
+            if shift == 28 && byte & 0x80 == 0 && !matches!(byte & 0x78, 0 | 0x78) {
+                return Err(malformed("integer too large"));
+            }
             result |= ((byte & 0x7f) as i32) << shift;
             shift += 7;
             if (0x80 & byte) == 0 {
@@ -252,34 +457,59 @@ impl Parser {
                 return Ok(result);
             }
         }
+        Err(malformed("integer too long"))
     }
 
     fn parse_i64(&mut self) -> Result<i64, io::Error> {
         let mut result: i64 = 0;
         let mut shift = 0;
-        loop {
+        // 10 = 64/7 rounded up
+        for _ in 0..10 {
             let byte = self.parse_byte()?;
+            // the last byte holds the top bit, the unused ones have to repeat
+            // the sign
+            if shift == 63 && byte & 0x80 == 0 && !matches!(byte & 0x7f, 0 | 0x7f) {
+                return Err(malformed("integer too large"));
+            }
             result |= ((byte & 0x7f) as i64) << shift;
             shift += 7;
             if (0x80 & byte) == 0 {
-                if shift < 32 && (byte & 0x40) != 0 {
+                if shift < 64 && (byte & 0x40) != 0 {
                     return Ok(result | (!0 << shift));
                 }
                 return Ok(result);
             }
         }
+        Err(malformed("integer too long"))
     }
 
     fn parse_memarg(&mut self) -> Result<MemArg, io::Error> {
+        // multi-memory: bit 6 of the alignment signals an explicit memory index
+        const HAS_MEMIDX: u32 = 0x40;
         let align = self.parse_u32()?;
+        let memory = if align & HAS_MEMIDX != 0 {
+            self.parse_memidx()?
+        } else {
+            MemIdx(0)
+        };
         let offset = self.parse_u32()?;
-        Ok(MemArg { align, offset })
+        Ok(MemArg {
+            align: align & !HAS_MEMIDX,
+            offset,
+            memory,
+        })
     }
 
     fn parse_labelidx(&mut self) -> Result<LabelIdx, io::Error> {
         Ok(LabelIdx(self.parse_u32()?))
     }
 
+    fn parse_f32(&mut self) -> Result<f32, io::Error> {
+        let mut bytes = [0u8; 4];
+        self.stream.read_exact(&mut bytes)?;
+        Ok(f32::from_le_bytes(bytes))
+    }
+
     fn parse_f64(&mut self) -> Result<f64, io::Error> {
         let mut bytes = [0u8; 8];
         self.stream.read_exact(&mut bytes)?;
@@ -291,31 +521,34 @@ impl Parser {
         Ok(TableIdx(idx))
     }
 
+    fn parse_elemidx(&mut self) -> Result<ElemIdx, io::Error> {
+        let idx = self.parse_u32()?;
+        Ok(ElemIdx(idx))
+    }
+
     fn parse_instr(&mut self) -> Result<Inst, io::Error> {
-        static COUNT: AtomicU32 = AtomicU32::new(0);
         let byte = self.parse_byte()?;
-        let i = COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-        println!("{i}: 0x{byte:x}");
+        trace!("opcode 0x{byte:x}");
         let inst = match byte {
             0x00 => Inst::Unreachable,
             0x01 => Inst::Nop,
             0x02 => {
                 let (bt, i) = self.parse_block()?;
-                Inst::Block(i.into())
+                Inst::Block(bt, i.into())
             }
             0x03 => {
                 let (bt, i) = self.parse_block()?;
-                Inst::Loop(i.into())
+                Inst::Loop(bt, i.into())
             }
             0x04 => {
                 let (bt, then, els) = self.parse_if()?;
-                todo!()
+                Inst::IfElse(bt, then.into(), els.into())
             }
             0x0C => Inst::Break(self.parse_labelidx()?),
             0x0d => Inst::BreakIf(self.parse_labelidx()?),
             0x0e => {
                 let mut labels = vec![];
-                let count = self.parse_u32()?;
+                let count = self.parse_len()?;
                 for _ in 0..count {
                     labels.push(self.parse_labelidx()?);
                 }
@@ -326,12 +559,38 @@ impl Parser {
             0x10 => Inst::Call(self.parse_funcidx()?),
             0x11 => Inst::CallIndirect(self.parse_typeidx()?, self.parse_tableidx()?),
 
+            0xd0 => Inst::RefNull(self.parse_reftype()?),
+            0xd1 => Inst::RefIsNull,
+            0xd2 => Inst::RefFunc(self.parse_funcidx()?),
+
+            0xfc => match self.parse_u32()? {
+                12 => {
+                    let elem = self.parse_elemidx()?;
+                    Inst::TableInit(elem, self.parse_tableidx()?)
+                }
+                13 => Inst::ElemDrop(self.parse_elemidx()?),
+                14 => Inst::TableCopy(self.parse_tableidx()?, self.parse_tableidx()?),
+                _ => return Err(malformed("unknown instruction")),
+            },
+            0xfd => {
+                let op = self.parse_u32()?;
+                return Err(unsupported(&format!("simd instruction 0xfd {op}")));
+            }
+
             0x1a => Inst::Drop,
             0x1b => Inst::Select,
+            0x1c => {
+                if self.parse_len()? != 1 {
+                    return Err(malformed("invalid result arity"));
+                }
+                Inst::SelectTyped(self.parse_valtype()?)
+            }
 
             0x20 => Inst::LocalGet(self.parse_localidx()?),
             0x21 => Inst::LocalSet(self.parse_localidx()?),
             0x22 => Inst::LocalTee(self.parse_localidx()?),
+            0x23 => Inst::GlobalGet(self.parse_globalidx()?),
+            0x24 => Inst::GlobalSet(self.parse_globalidx()?),
 
             0x28 => Inst::I32Load(self.parse_memarg()?),
             0x29 => Inst::I64Load(self.parse_memarg()?),
@@ -341,26 +600,26 @@ impl Parser {
             0x2d => Inst::I32Load8U(self.parse_memarg()?),
             0x2e => Inst::I32Load16S(self.parse_memarg()?),
             0x2f => Inst::I32Load16U(self.parse_memarg()?),
-            /* todo */
+            0x30 => Inst::I64Load8S(self.parse_memarg()?),
+            0x31 => Inst::I64Load8U(self.parse_memarg()?),
+            0x32 => Inst::I64Load16S(self.parse_memarg()?),
+            0x33 => Inst::I64Load16U(self.parse_memarg()?),
+            0x34 => Inst::I64Load32S(self.parse_memarg()?),
             0x35 => Inst::I64Load32U(self.parse_memarg()?),
             0x36 => Inst::I32Store(self.parse_memarg()?),
             0x37 => Inst::I64Store(self.parse_memarg()?),
+            0x38 => Inst::F32Store(self.parse_memarg()?),
             0x39 => Inst::F64Store(self.parse_memarg()?),
             0x3a => Inst::I32Store8(self.parse_memarg()?),
             0x3b => Inst::I32Store16(self.parse_memarg()?),
             0x3c => Inst::I64Store8(self.parse_memarg()?),
             0x3d => Inst::I64Store16(self.parse_memarg()?),
             0x3e => Inst::I64Store32(self.parse_memarg()?),
-            0x3f => {
-                self.parse_byte()?;
-                Inst::MemorySize
-            }
-            0x40 => {
-                self.parse_byte()?;
-                Inst::MemoryGrow
-            }
+            0x3f => Inst::MemorySize(self.parse_memidx()?),
+            0x40 => Inst::MemoryGrow(self.parse_memidx()?),
             0x41 => Inst::I32Const(self.parse_i32()?),
             0x42 => Inst::I64Const(self.parse_i64()?),
+            0x43 => Inst::F32Const(self.parse_f32()?),
             0x44 => Inst::F64Const(self.parse_f64()?),
 
             0x45 => Inst::I32Eqz,
@@ -373,7 +632,7 @@ impl Parser {
             0x4c => Inst::I32LeS,
             0x4d => Inst::I32LeU,
             0x4e => Inst::I32GeS,
-            0x4F => Inst::I32GeU,
+            0x4f => Inst::I32GeU,
 
             0x50 => Inst::I64Eqz,
             0x51 => Inst::I64Eq,
@@ -382,6 +641,17 @@ impl Parser {
             0x54 => Inst::I64LtU,
             0x55 => Inst::I64GtS,
             0x56 => Inst::I64GtU,
+            0x57 => Inst::I64LeS,
+            0x58 => Inst::I64LeU,
+            0x59 => Inst::I64GeS,
+            0x5a => Inst::I64GeU,
+
+            0x5b => Inst::F32Eq,
+            0x5c => Inst::F32Ne,
+            0x5d => Inst::F32Lt,
+            0x5e => Inst::F32Gt,
+            0x5f => Inst::F32Le,
+            0x60 => Inst::F32Ge,
 
             0x61 => Inst::F64Eq,
             0x62 => Inst::F64Ne,
@@ -409,13 +679,39 @@ impl Parser {
             0x77 => Inst::I32Rotl,
             0x78 => Inst::I32Rotr,
 
+            0x79 => Inst::I64Clz,
+            0x7a => Inst::I64Ctz,
+            0x7b => Inst::I64Popcnt,
             0x7c => Inst::I64Add,
+            0x7d => Inst::I64Sub,
             0x7e => Inst::I64Mul,
+            0x7f => Inst::I64DivS,
+            0x80 => Inst::I64DivU,
+            0x81 => Inst::I64RemS,
+            0x82 => Inst::I64RemU,
             0x83 => Inst::I64And,
             0x84 => Inst::I64Or,
             0x85 => Inst::I64Xor,
             0x86 => Inst::I64Shl,
+            0x87 => Inst::I64ShrS,
             0x88 => Inst::I64ShrU,
+            0x89 => Inst::I64Rotl,
+            0x8a => Inst::I64Rotr,
+
+            0x8b => Inst::F32Abs,
+            0x8c => Inst::F32Neg,
+            0x8d => Inst::F32Ceil,
+            0x8e => Inst::F32Floor,
+            0x8f => Inst::F32Trunc,
+            0x90 => Inst::F32Nearest,
+            0x91 => Inst::F32Sqrt,
+            0x92 => Inst::F32Add,
+            0x93 => Inst::F32Sub,
+            0x94 => Inst::F32Mul,
+            0x95 => Inst::F32Div,
+            0x96 => Inst::F32Min,
+            0x97 => Inst::F32Max,
+            0x98 => Inst::F32Copysign,
 
             0x99 => Inst::F64Abs,
             0x9a => Inst::F64Neg,
@@ -430,29 +726,71 @@ impl Parser {
             0xa3 => Inst::F64Div,
             0xa4 => Inst::F64Min,
             0xa5 => Inst::F64Max,
+            0xa6 => Inst::F64Copysign,
 
             0xa7 => Inst::I32WrapI64,
+            0xa8 => Inst::I32TruncF32S,
+            0xa9 => Inst::I32TruncF32U,
+            0xaa => Inst::I32TruncF64S,
+            0xab => Inst::I32TruncF64U,
+            0xac => Inst::I64ExtendI32S,
             0xad => Inst::I64ExtendI32U,
-            0xb8 => Inst::F64ConvertI64U,
+            0xae => Inst::I64TruncF32S,
+            0xaf => Inst::I64TruncF32U,
+            0xb0 => Inst::I64TruncF64S,
+            0xb1 => Inst::I64TruncF64U,
+            0xb2 => Inst::F32ConvertI32S,
+            0xb3 => Inst::F32ConvertI32U,
+            0xb4 => Inst::F32ConvertI64S,
+            0xb5 => Inst::F32ConvertI64U,
+            0xb6 => Inst::F32DemoteF64,
+            0xb7 => Inst::F64ConvertI32S,
+            0xb8 => Inst::F64ConvertI32U,
+            0xb9 => Inst::F64ConvertI64S,
+            0xba => Inst::F64ConvertI64U,
+            0xbb => Inst::F64PromoteF32,
+            0xbc => Inst::I32ReinterpretF32,
+            0xbd => Inst::I64ReinterpretF64,
+            0xbe => Inst::F32ReinterpretI32,
             0xbf => Inst::F64ReinterpretI64,
-            x => panic!("unknown op: 0x{x:x?}"),
+            0xc0 => Inst::I32Extend8S,
+            0xc1 => Inst::I32Extend16S,
+            0xc2 => Inst::I64Extend8S,
+            0xc3 => Inst::I64Extend16S,
+            0xc4 => Inst::I64Extend32S,
+            _ => return Err(malformed("unknown instruction")),
         };
         Ok(inst)
     }
 
     fn parse_expr(&mut self) -> Result<Vec<Inst>, io::Error> {
+        let (is, end) = self.parse_instrs_until_else()?;
+        if end != 0x0B {
+            return Err(malformed("else outside of if"));
+        }
+        Ok(is)
+    }
+
+    /// Parses instructions up to an `end` or an `else`, and returns which of
+    /// the two it stopped at.
+    fn parse_instrs_until_else(&mut self) -> Result<(Vec<Inst>, u8), io::Error> {
+        if self.depth >= MAX_NESTING {
+            return Err(unsupported("blocks nested too deep"));
+        }
+        self.depth += 1;
         let mut is = vec![];
-        loop {
+        let end = loop {
             match self.peek_byte()? {
-                0x0B => {
-                    self.stream.consume(1);
-                    break;
+                byte @ (0x0B | 0x05) => {
+                    self.stream.consume();
+                    break byte;
                 }
                 _ => {}
             };
             is.push(self.parse_instr()?);
-        }
-        Ok(is)
+        };
+        self.depth -= 1;
+        Ok((is, end))
     }
 
     fn parse_data(&mut self) -> Result<Data, io::Error> {
@@ -460,7 +798,7 @@ impl Parser {
         let data = match kind {
             0 => {
                 let expr = self.parse_expr()?;
-                let byte_size = self.parse_u32()?;
+                let byte_size = self.parse_len()?;
                 let bytes = self.read_bytes(byte_size as usize)?;
                 Data {
                     init: bytes,
@@ -471,7 +809,7 @@ impl Parser {
                 }
             }
             1 => {
-                let count = self.parse_u32()?;
+                let count = self.parse_len()?;
                 let buf = self.read_bytes(count as usize)?;
                 Data {
                     init: buf,
@@ -479,123 +817,374 @@ impl Parser {
                 }
             }
             2 => {
-                todo!("active data")
+                let memory = self.parse_memidx()?;
+                let expr = self.parse_expr()?;
+                let byte_size = self.parse_len()?;
+                let bytes = self.read_bytes(byte_size as usize)?;
+                Data {
+                    init: bytes,
+                    mode: Datamode::Active {
+                        memory,
+                        offset: expr,
+                    },
+                }
             }
-            _ => panic!("invalid data kind"),
+            _ => return Err(malformed("invalid data kind")),
         };
         Ok(data)
     }
 
+    fn parse_elem(&mut self) -> Result<Elem, io::Error> {
+        // bit 0: passive or declarative, bit 1: explicit table index (active)
+        // or declarative, bit 2: elements are expressions, not function indices
+        let kind = self.parse_u32()?;
+        if kind > 7 {
+            return Err(malformed("invalid elem kind"));
+        }
+        let uses_exprs = kind & 0b100 != 0;
+        let mode = match kind & 0b011 {
+            0b000 => ElemMode::Active {
+                table: TableIdx(0),
+                offset: self.parse_expr()?,
+            },
+            0b010 => ElemMode::Active {
+                table: self.parse_tableidx()?,
+                offset: self.parse_expr()?,
+            },
+            0b001 => ElemMode::Passive,
+            _ => ElemMode::Declarative,
+        };
+        // kinds 0 and 4 leave out the type, it is always funcref
+        let typ = if kind & 0b011 == 0 {
+            Reftype::Funcref
+        } else if uses_exprs {
+            self.parse_reftype()?
+        } else {
+            self.parse_elemkind()?
+        };
+        let count = self.parse_len()?;
+        let mut init = vec![];
+        for _ in 0..count {
+            if uses_exprs {
+                init.push(self.parse_expr()?);
+            } else {
+                init.push(vec![Inst::RefFunc(self.parse_funcidx()?)]);
+            }
+        }
+        Ok(Elem { typ, init, mode })
+    }
+
+    fn parse_type_section(&mut self) -> Result<Vec<FuncType>, io::Error> {
+        let elems = self.parse_len()?;
+        let mut types = vec![];
+        for _ in 0..elems {
+            types.push(self.parse_functype()?);
+        }
+        Ok(types)
+    }
+
+    fn parse_import_section(&mut self) -> Result<Vec<Import>, io::Error> {
+        let elems = self.parse_len()?;
+        let mut imports = vec![];
+        for _ in 0..elems {
+            let nm_1 = self.parse_name()?;
+            let nm_2 = self.parse_name()?;
+            let desc = self.parse_import_desc()?;
+            imports.push(Import {
+                module: nm_1,
+                nm: nm_2,
+                desc,
+            });
+        }
+        Ok(imports)
+    }
+
+    fn parse_export_section(&mut self) -> Result<Vec<Export>, io::Error> {
+        let elems = self.parse_len()?;
+        let mut exports = vec![];
+        for _ in 0..elems {
+            exports.push(self.parse_export()?);
+        }
+        Ok(exports)
+    }
+
+    /// Parses the contents of a `producers` custom section.
+    fn parse_producers(&mut self) -> Result<Producers, io::Error> {
+        let mut producers = Producers::default();
+        let fields = self.parse_len()?;
+        for _ in 0..fields {
+            let field = self.parse_name()?;
+            let values = match field.as_str() {
+                "language" => &mut producers.language,
+                "processed-by" => &mut producers.processed_by,
+                "sdk" => &mut producers.sdk,
+                _ => return Err(malformed("unknown producers field")),
+            };
+            let len = self.parse_len()?;
+            for _ in 0..len {
+                let name = self.parse_name()?;
+                let version = self.parse_name()?;
+                values.push((name, version));
+            }
+        }
+        self.finish("producers section")?;
+        Ok(producers)
+    }
+
     pub fn parse_module(&mut self) -> Result<Module, io::Error> {
         let mut module = Module::default();
         let mut func_types = vec![];
         self.parse_magic()?;
         self.parse_version()?;
 
-        while !self.stream.fill_buf()?.is_empty() {
+        while self.stream.peek()?.is_some() {
             let (typ, size) = self.parse_section_header()?;
+            let mut section = self.sub_parser(size)?;
 
             match typ {
+                // custom sections don't affect the module, but are kept for
+                // embedders
                 SectionId::Custom => {
-                    let mut content = vec![0u8; size as usize];
-                    self.stream
-                        .read_exact(&mut content)
-                        .expect("failed to read section content");
+                    let name = section.parse_name()?;
+                    let mut contents = vec![];
+                    section.stream.read_to_end(&mut contents)?;
+                    module.custom_sections.push((name, contents));
+                    continue;
                 }
                 SectionId::Type => {
-                    let elems = self.parse_u32()?;
-                    for _ in 0..elems {
-                        let functype = self.parse_functype()?;
-                        module.types.push(functype);
-                    }
+                    module.types.extend(section.parse_type_section()?);
                 }
                 SectionId::Import => {
-                    let elems = self.parse_u32()?;
-                    for _ in 0..elems {
-                        let nm_1 = self.parse_name()?;
-                        let nm_2 = self.parse_name()?;
-                        let desc = self.parse_import_desc()?;
-                        let import = Import {
-                            module: nm_1,
-                            nm: nm_2,
-                            desc,
-                        };
-                        module.imports.push(import.clone());
-                    }
+                    module.imports.extend(section.parse_import_section()?);
                 }
                 SectionId::Function => {
-                    let elems = self.parse_u32()?;
+                    let elems = section.parse_len()?;
                     for _ in 0..elems {
-                        let typidx = self.parse_typeidx()?;
+                        let typidx = section.parse_typeidx()?;
                         func_types.push(typidx);
                     }
                 }
                 SectionId::Table => {
-                    let elems = self.parse_u32()?;
+                    let elems = section.parse_len()?;
                     for _ in 0..elems {
-                        let tabletyp = self.parse_tabletype()?;
+                        let tabletyp = section.parse_tabletype()?;
                         module.tables.push(tabletyp)
                     }
                 }
                 SectionId::Memory => {
-                    let elems = self.parse_u32()?;
+                    let elems = section.parse_len()?;
                     for _ in 0..elems {
-                        let memtype = self.parse_memtype()?;
+                        let memtype = section.parse_memtype()?;
                         module.mems.push(memtype);
                     }
                 }
-                SectionId::Global => todo!(),
-                SectionId::Export => {
-                    let elems = self.parse_u32()?;
+                SectionId::Global => {
+                    let elems = section.parse_len()?;
                     for _ in 0..elems {
-                        let export = self.parse_export()?;
-                        module.exports.push(export);
+                        let typ = section.parse_globaltype()?;
+                        let init = section.parse_expr()?;
+                        module.globals.push(Global { typ, init });
                     }
                 }
+                SectionId::Export => {
+                    module.exports.extend(section.parse_export_section()?);
+                }
                 SectionId::Start => {
-                    let idx = self.parse_funcidx()?;
+                    let idx = section.parse_funcidx()?;
                     module.start = Some(idx)
                 }
                 SectionId::Element => {
-                    let mut content = vec![0u8; size as usize];
-                    self.stream
-                        .read_exact(&mut content)
-                        .expect("failed to read section content");
-                    // TODO
+                    let elems = section.parse_len()?;
+                    for _ in 0..elems {
+                        let elem = section.parse_elem()?;
+                        module.elems.push(elem);
+                    }
                 }
                 SectionId::Code => {
-                    module.funcs.extend(self.parse_code(&func_types)?);
+                    module.funcs.extend(section.parse_code(&func_types)?);
                 }
                 SectionId::Data => {
-                    let elems = self.parse_u32()?;
+                    let elems = section.parse_len()?;
                     for _ in 0..elems {
-                        let data = self.parse_data()?;
+                        let data = section.parse_data()?;
                         module.datas.push(data)
                     }
                 }
-                SectionId::DataCount => todo!(),
+                SectionId::DataCount => {
+                    // only needed for validation
+                    section.parse_u32()?;
+                }
             }
+            section.finish("section")?;
         }
 
+        module.refs = module.collect_refs();
+        if module.duplicate_export().is_some() {
+            return Err(malformed("duplicate export name"));
+        }
         Ok(module)
     }
 
     fn parse_localidx(&mut self) -> Result<LocalIdx, io::Error> {
         Ok(LocalIdx(self.parse_u32()?))
     }
+
+    fn parse_globalidx(&mut self) -> Result<GlobalIdx, io::Error> {
+        Ok(GlobalIdx(self.parse_u32()?))
+    }
+
+    fn parse_memidx(&mut self) -> Result<MemIdx, io::Error> {
+        Ok(MemIdx(self.parse_u32()?))
+    }
 }
 
-pub fn parse_stream(stream: Box<dyn BufRead>) -> Result<Module, io::Error> {
-    let mut parser = Parser {
-        stream: Box::new(stream),
-    };
+/// Walks the sections of a module one at a time without building a [`Module`].
+///
+/// Payloads are only read when asked for; any payload left unread is skipped
+/// when advancing to the next section.
+pub struct SectionReader<R> {
+    parser: Parser<R>,
+    remaining: u32,
+}
+
+impl<R: Read> SectionReader<R> {
+    pub fn new(stream: R) -> Result<Self, io::Error> {
+        let mut parser = Parser::new(stream);
+        parser.parse_magic()?;
+        parser.parse_version()?;
+        Ok(Self {
+            parser,
+            remaining: 0,
+        })
+    }
+
+    /// Advances to the next section and returns its id and payload size.
+    pub fn next_section(&mut self) -> Result<Option<(SectionId, u32)>, io::Error> {
+        let remaining = std::mem::take(&mut self.remaining) as u64;
+        io::copy(
+            &mut self.parser.stream.by_ref().take(remaining),
+            &mut io::sink(),
+        )?;
+        if self.parser.stream.peek()?.is_none() {
+            return Ok(None);
+        }
+        let (id, size) = self.parser.parse_section_header()?;
+        self.remaining = size;
+        Ok(Some((id, size)))
+    }
+
+    /// Reads the raw payload of the current section.
+    pub fn read_payload(&mut self) -> Result<Vec<u8>, io::Error> {
+        let size = std::mem::take(&mut self.remaining);
+        self.parser.read_bytes(size as usize)
+    }
+
+    fn payload_parser(&mut self) -> Result<Parser<Cursor<Vec<u8>>>, io::Error> {
+        let payload = self.read_payload()?;
+        Ok(Parser::bounded(payload))
+    }
+
+    /// Decodes the current section as a type section.
+    pub fn read_types(&mut self) -> Result<Vec<FuncType>, io::Error> {
+        self.payload_parser()?.parse_type_section()
+    }
+
+    /// Decodes the current section as an import section.
+    pub fn read_imports(&mut self) -> Result<Vec<Import>, io::Error> {
+        self.payload_parser()?.parse_import_section()
+    }
+
+    /// Decodes the current section as an export section.
+    pub fn read_exports(&mut self) -> Result<Vec<Export>, io::Error> {
+        self.payload_parser()?.parse_export_section()
+    }
+}
+
+impl<R: Read> Iterator for SectionReader<R> {
+    type Item = Result<(SectionId, Vec<u8>), io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_section() {
+            Ok(Some((id, _size))) => Some(self.read_payload().map(|payload| (id, payload))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+pub fn parse_stream(stream: impl Read) -> Result<Module, io::Error> {
+    let mut parser = Parser::new(stream);
     let module = parser.parse_module()?;
     Ok(module)
 }
 
+/// Why [`parse_module_checked`] rejected its input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// the input ended in the middle of the module, or a length field points
+    /// past its end
+    UnexpectedEof,
+    /// the input is not a valid binary module
+    Malformed(String),
+    /// the input uses a feature this parser does not implement
+    Unsupported(String),
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::UnexpectedEof => ParseError::UnexpectedEof,
+            ErrorKind::Unsupported => ParseError::Unsupported(e.to_string()),
+            _ => ParseError::Malformed(e.to_string()),
+        }
+    }
+}
+
+/// Parses a module from untrusted bytes.
+///
+/// Unlike the other entry points this never panics: every malformed input is
+/// reported as a [`ParseError`], and allocations are bounded by the size of
+/// `bytes` rather than by the length fields inside it.
+pub fn parse_module_checked(bytes: &[u8]) -> Result<Module, ParseError> {
+    let mut parser = Parser::new(bytes);
+    Ok(parser.parse_module()?)
+}
+
 pub fn parse_file(path: impl AsRef<std::path::Path>) -> Result<Module, io::Error> {
     let fd = std::fs::File::open(path.as_ref())?;
-    parse_stream(Box::new(BufReader::new(fd)))
+    parse_stream(BufReader::new(fd))
+}
+
+impl Module {
+    /// Parses a module in the binary format from the file at `path`.
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> Result<Module, io::Error> {
+        parse_file(path)
+    }
+
+    /// Parses a module in the binary format.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Module, io::Error> {
+        parse_stream(bytes)
+    }
+
+    /// Decodes the `producers` custom section, if the module has one.
+    pub fn producers(&self) -> Option<Result<Producers, io::Error>> {
+        let (_, contents) = self
+            .custom_sections()
+            .find(|(name, _)| *name == "producers")?;
+        Some(Parser::bounded(contents.to_vec()).parse_producers())
+    }
+}
+
+/// The toolchain that produced a module, from its `producers` custom section.
+/// Every field lists `(name, version)` pairs, like `("rustc", "1.70.0")`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Producers {
+    /// the source languages, like `Rust`
+    pub language: Vec<(String, String)>,
+    /// the tools that produced or processed the module, like `wasm-bindgen`
+    pub processed_by: Vec<(String, String)>,
+    /// the SDKs the module was built with, like `Emscripten`
+    pub sdk: Vec<(String, String)>,
 }
 
 #[cfg(test)]
@@ -606,14 +1195,26 @@ static ADD_MOD: &'static [u8] = include_bytes!("../../examples/add.wasm");
 
 #[cfg(test)]
 fn parse_bytes(bytes: &'static [u8]) -> io::Result<Module> {
-    let reader = BufReader::new(bytes);
-    let mut parser = Parser {
-        stream: Box::new(reader),
-    };
+    let mut parser = Parser::new(bytes);
     let module = parser.parse_module()?;
     Ok(module)
 }
 
+#[cfg(test)]
+fn parser_for(bytes: &'static [u8]) -> Parser<&'static [u8]> {
+    Parser::new(bytes)
+}
+
+/// A module made of the wasm header followed by `sections`.
+#[cfg(test)]
+fn module_bytes(sections: &[u8]) -> Vec<u8> {
+    [
+        &[0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00][..],
+        sections,
+    ]
+    .concat()
+}
+
 #[cfg(test)]
 #[test]
 fn parse_empty() {
@@ -625,3 +1226,783 @@ fn parse_empty() {
 fn parse_add() {
     parse_bytes(ADD_MOD).expect("could not parse add module");
 }
+
+#[cfg(test)]
+#[test]
+fn read_sections() {
+    let reader = SectionReader::new(ADD_MOD).unwrap();
+    let ids: Vec<_> = reader.map(|section| section.unwrap().0).collect();
+    assert_eq!(
+        ids,
+        [
+            SectionId::Type,
+            SectionId::Function,
+            SectionId::Export,
+            SectionId::Start,
+            SectionId::Code
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn read_only_exports() {
+    let mut reader = SectionReader::new(ADD_MOD).unwrap();
+    let mut exports = vec![];
+    while let Some((id, _size)) = reader.next_section().unwrap() {
+        if id == SectionId::Export {
+            exports = reader.read_exports().unwrap();
+        }
+    }
+    assert_eq!(exports.len(), 1);
+    assert_eq!(exports[0].name, "add");
+}
+
+#[cfg(test)]
+#[test]
+fn parse_limits_flags() {
+    let limits = parser_for(&[0x00, 0x01]).parse_limits().unwrap();
+    assert!(limits.min == 1 && limits.max.is_none() && !limits.is_64 && !limits.shared);
+
+    let limits = parser_for(&[0x03, 0x01, 0x02]).parse_limits().unwrap();
+    assert!(limits.min == 1 && limits.max == Some(2) && !limits.is_64 && limits.shared);
+
+    // min = 2^32 does not fit the 32-bit encoding
+    let limits = parser_for(&[0x05, 0x80, 0x80, 0x80, 0x80, 0x10, 0x7f])
+        .parse_limits()
+        .unwrap();
+    assert!(limits.min == 1 << 32 && limits.max == Some(127) && limits.is_64);
+
+    assert!(parser_for(&[0x08, 0x01]).parse_limits().is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn parse_data_with_memidx() {
+    // (data (memory 1) (i32.const 8) "\aa\bb")
+    let data = parser_for(&[0x02, 0x01, 0x41, 0x08, 0x0b, 0x02, 0xaa, 0xbb])
+        .parse_data()
+        .unwrap();
+    let Datamode::Active { memory, offset } = data.mode else { panic!("expected active data") };
+    assert_eq!(memory.0, 1);
+    assert!(matches!(offset[..], [Inst::I32Const(8)]));
+    assert_eq!(data.init, [0xaa, 0xbb]);
+}
+
+#[cfg(test)]
+#[test]
+fn parse_memarg_with_memidx() {
+    let memarg = parser_for(&[0x02, 0x10]).parse_memarg().unwrap();
+    assert!(memarg.align == 2 && memarg.offset == 16 && memarg.memory.0 == 0);
+
+    let memarg = parser_for(&[0x42, 0x03, 0x10]).parse_memarg().unwrap();
+    assert!(memarg.align == 2 && memarg.offset == 16 && memarg.memory.0 == 3);
+}
+
+#[cfg(test)]
+#[test]
+fn parse_integer_numeric_opcodes() {
+    // every i32/i64 comparison, arithmetic and bit opcode
+    let bytes: Vec<u8> = (0x45..=0x5a).chain(0x67..=0x8a).chain([0x0b]).collect();
+    let expr = parser_for(bytes.leak()).parse_expr().unwrap();
+    assert_eq!(expr.len(), (0x5a - 0x45 + 1) + (0x8a - 0x67 + 1));
+    assert!(matches!(expr[0x5a - 0x45], Inst::I64GeU));
+    assert!(matches!(expr.last(), Some(Inst::I64Rotr)));
+}
+
+#[cfg(test)]
+#[test]
+fn module_exports() {
+    let module = parse_bytes(ADD_MOD).unwrap();
+    let exports: Vec<_> = module.exports().collect();
+    let [("add", ExternType::Func(typ))] = &exports[..] else { panic!("expected the add function") };
+    assert!(matches!(typ.from.types[..], [ValType::I32, ValType::I32]));
+    assert!(matches!(typ.to.types[..], [ValType::I32]));
+}
+
+#[cfg(test)]
+#[test]
+fn module_imports() {
+    // (import "env" "mem" (memory 1 2))
+    let desc = parser_for(&[0x02, 0x01, 0x01, 0x02])
+        .parse_import_desc()
+        .unwrap();
+    let module = Module {
+        imports: vec![Import {
+            module: "env".to_string(),
+            nm: "mem".to_string(),
+            desc,
+        }],
+        ..Module::default()
+    };
+    let imports: Vec<_> = module.imports().collect();
+    let [("env", "mem", ExternType::Mem(mem))] = &imports[..] else { panic!("expected a memory import") };
+    assert!(mem.limits.min == 1 && mem.limits.max == Some(2));
+}
+
+#[cfg(all(test, feature = "serde"))]
+#[test]
+fn serde_round_trip() {
+    let module = parse_bytes(ADD_MOD).unwrap();
+    let json = serde_json::to_string(&module).unwrap();
+    let module: Module = serde_json::from_str(&json).unwrap();
+    assert!(matches!(
+        module.funcs[0].body[..],
+        [Inst::LocalGet(_), Inst::LocalGet(_), Inst::I32Add]
+    ));
+
+    // NaN payloads are kept
+    let nan = f32::from_bits(0x7fa0_0001);
+    let json = serde_json::to_string(&Inst::F32Const(nan)).unwrap();
+    let Inst::F32Const(x) = serde_json::from_str(&json).unwrap() else { panic!("expected f32.const") };
+    assert_eq!(x.to_bits(), nan.to_bits());
+}
+
+#[cfg(test)]
+#[test]
+fn parse_elem_with_funcidxs() {
+    // (elem (i32.const 1) 0 1)
+    let elem = parser_for(&[0x00, 0x41, 0x01, 0x0b, 0x02, 0x00, 0x01])
+        .parse_elem()
+        .unwrap();
+    let ElemMode::Active { table, offset } = elem.mode else { panic!("expected active elem") };
+    assert_eq!(table.0, 0);
+    assert!(matches!(offset[..], [Inst::I32Const(1)]));
+    assert!(matches!(elem.typ, Reftype::Funcref));
+    assert_eq!(elem.init.len(), 2);
+    assert!(matches!(elem.init[1][..], [Inst::RefFunc(FuncIdx(1))]));
+
+    // (elem func 5)
+    let elem = parser_for(&[0x01, 0x00, 0x01, 0x05]).parse_elem().unwrap();
+    assert!(matches!(elem.mode, ElemMode::Passive));
+    assert!(matches!(elem.init[0][..], [Inst::RefFunc(FuncIdx(5))]));
+
+    // (elem (table 1) (i32.const 0) func 3)
+    let elem = parser_for(&[0x02, 0x01, 0x41, 0x00, 0x0b, 0x00, 0x01, 0x03])
+        .parse_elem()
+        .unwrap();
+    let ElemMode::Active { table, .. } = elem.mode else { panic!("expected active elem") };
+    assert_eq!(table.0, 1);
+    assert!(matches!(elem.init[0][..], [Inst::RefFunc(FuncIdx(3))]));
+
+    // (elem declare func 2)
+    let elem = parser_for(&[0x03, 0x00, 0x01, 0x02]).parse_elem().unwrap();
+    assert!(matches!(elem.mode, ElemMode::Declarative));
+    assert!(matches!(elem.init[0][..], [Inst::RefFunc(FuncIdx(2))]));
+}
+
+#[cfg(test)]
+#[test]
+fn parse_elem_with_exprs() {
+    // (elem (i32.const 0) funcref (ref.func 0) (ref.null func))
+    let elem = parser_for(&[
+        0x04, 0x41, 0x00, 0x0b, 0x02, 0xd2, 0x00, 0x0b, 0xd0, 0x70, 0x0b,
+    ])
+    .parse_elem()
+    .unwrap();
+    assert!(matches!(elem.mode, ElemMode::Active { .. }));
+    assert!(matches!(elem.typ, Reftype::Funcref));
+    assert!(matches!(
+        elem.init[1][..],
+        [Inst::RefNull(Reftype::Funcref)]
+    ));
+
+    // (elem externref (ref.null extern))
+    let elem = parser_for(&[0x05, 0x6f, 0x01, 0xd0, 0x6f, 0x0b])
+        .parse_elem()
+        .unwrap();
+    assert!(matches!(elem.mode, ElemMode::Passive));
+    assert!(matches!(elem.typ, Reftype::Externref));
+    assert!(matches!(
+        elem.init[0][..],
+        [Inst::RefNull(Reftype::Externref)]
+    ));
+
+    // (elem (table 2) (i32.const 4) funcref (ref.func 3))
+    let elem = parser_for(&[
+        0x06, 0x02, 0x41, 0x04, 0x0b, 0x70, 0x01, 0xd2, 0x03, 0x0b,
+    ])
+    .parse_elem()
+    .unwrap();
+    let ElemMode::Active { table, offset } = elem.mode else { panic!("expected active elem") };
+    assert_eq!(table.0, 2);
+    assert!(matches!(offset[..], [Inst::I32Const(4)]));
+    assert!(matches!(elem.init[0][..], [Inst::RefFunc(FuncIdx(3))]));
+
+    // (elem declare funcref (ref.func 1))
+    let elem = parser_for(&[0x07, 0x70, 0x01, 0xd2, 0x01, 0x0b])
+        .parse_elem()
+        .unwrap();
+    assert!(matches!(elem.mode, ElemMode::Declarative));
+    assert!(matches!(elem.init[0][..], [Inst::RefFunc(FuncIdx(1))]));
+}
+
+#[cfg(test)]
+#[test]
+fn checked_parse_rejects_malformed_modules() {
+    assert!(parse_module_checked(&ADD_MOD[..ADD_MOD.len() - 3]).is_err());
+    assert!(matches!(
+        parse_module_checked(&module_bytes(&[])[..5]),
+        Err(ParseError::UnexpectedEof)
+    ));
+    // leb with too many continuation bits
+    let bad_leb = module_bytes(&[0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+    assert!(matches!(
+        parse_module_checked(&bad_leb),
+        Err(ParseError::Malformed(_))
+    ));
+    // a custom section claiming to be 4GB long
+    let huge = module_bytes(&[0x00, 0xff, 0xff, 0xff, 0xff, 0x0f]);
+    assert!(matches!(
+        parse_module_checked(&huge),
+        Err(ParseError::UnexpectedEof)
+    ));
+    // a data segment claiming to be 4GB long
+    let huge = module_bytes(&[0x0b, 0x08, 0x01, 0x01, 0xff, 0xff, 0xff, 0xff, 0x0f, 0x00]);
+    assert!(matches!(
+        parse_module_checked(&huge),
+        Err(ParseError::Malformed(_))
+    ));
+    // a function body with an unknown opcode
+    let bad_op = module_bytes(&[
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+        0x03, 0x02, 0x01, 0x00, // function section
+        0x0a, 0x05, 0x01, 0x03, 0x00, 0xff, 0x0b, // code section
+    ]);
+    assert!(matches!(
+        parse_module_checked(&bad_op),
+        Err(ParseError::Malformed(_))
+    ));
+    // a code entry without a function declaration
+    let no_func = module_bytes(&[0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b]);
+    assert!(matches!(
+        parse_module_checked(&no_func),
+        Err(ParseError::Malformed(_))
+    ));
+}
+
+#[cfg(test)]
+#[test]
+fn checked_parse_limits_nesting() {
+    let mut bytes = [0x02, 0x40].repeat(MAX_NESTING + 1);
+    bytes.extend([0x0b].repeat(MAX_NESTING + 2));
+    let err = parser_for(bytes.leak()).parse_expr().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+}
+
+#[cfg(test)]
+#[test]
+fn parse_if_else() {
+    let expr = parser_for(&[0x41, 0x01, 0x04, 0x40, 0x01, 0x05, 0x00, 0x0b, 0x0b])
+        .parse_expr()
+        .unwrap();
+    let [Inst::I32Const(1), Inst::IfElse(_, then, els)] = &expr[..] else { panic!("expected an if") };
+    assert!(matches!(then.as_ref(), [Inst::Nop]));
+    assert!(matches!(els.as_ref(), [Inst::Unreachable]));
+}
+
+#[cfg(test)]
+#[test]
+fn parse_blocktypes() {
+    let parse = |bytes: &'static [u8]| parser_for(bytes).parse_blocktype().unwrap();
+    assert!(matches!(parse(&[0x40]), BlockType::Empty));
+    assert!(matches!(parse(&[0x7e]), BlockType::Inline(ValType::I64)));
+    assert!(matches!(parse(&[0x70]), BlockType::Inline(ValType::FuncRef)));
+    assert!(matches!(parse(&[0x6f]), BlockType::Inline(ValType::ExternRef)));
+    assert!(matches!(parse(&[0x02]), BlockType::Type(TypeIdx(2))));
+}
+
+#[cfg(test)]
+#[test]
+fn parse_if_without_else() {
+    // the inner if has no else, the outer one nests a block in its else
+    let expr = parser_for(&[
+        0x04, 0x7f, 0x41, 0x01, 0x05, 0x02, 0x40, 0x0b, 0x41, 0x02, 0x0b, 0x04, 0x40, 0x01, 0x0b,
+        0x0b,
+    ])
+    .parse_expr()
+    .unwrap();
+    let [Inst::IfElse(typ, then, els), Inst::IfElse(typ2, then2, els2)] = &expr[..] else {
+        panic!("expected two ifs")
+    };
+    assert!(matches!(typ, BlockType::Inline(ValType::I32)));
+    assert!(matches!(typ2, BlockType::Empty));
+    assert!(matches!(then.as_ref(), [Inst::I32Const(1)]));
+    assert!(matches!(els.as_ref(), [Inst::Block(..), Inst::I32Const(2)]));
+    assert!(matches!(then2.as_ref(), [Inst::Nop]));
+    assert!(els2.as_ref().is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn parse_negative_consts() {
+    let expr = parser_for(&[
+        0x41, 0x7f, // i32.const -1
+        0x42, 0x7f, // i64.const -1
+        0x42, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7f, // i64.const MIN
+        0x0b,
+    ])
+    .parse_expr()
+    .unwrap();
+    assert!(matches!(
+        expr[..],
+        [
+            Inst::I32Const(-1),
+            Inst::I64Const(-1),
+            Inst::I64Const(i64::MIN)
+        ]
+    ));
+}
+
+#[cfg(test)]
+#[test]
+fn parse_global_instructions() {
+    let expr = parser_for(&[0x23, 0x01, 0x24, 0x80, 0x01, 0x0b])
+        .parse_expr()
+        .unwrap();
+    assert!(matches!(
+        expr[..],
+        [
+            Inst::GlobalGet(GlobalIdx(1)),
+            Inst::GlobalSet(GlobalIdx(128))
+        ]
+    ));
+}
+
+#[cfg(test)]
+#[test]
+fn memory_index_is_a_leb128() {
+    // memory.size 1, with the index padded to two bytes
+    let expr = parser_for(&[0x3f, 0x81, 0x00, 0x41, 0x01, 0x40, 0x00, 0x0b])
+        .parse_expr()
+        .unwrap();
+    assert!(matches!(
+        expr[..],
+        [
+            Inst::MemorySize(MemIdx(1)),
+            Inst::I32Const(1),
+            Inst::MemoryGrow(MemIdx(0))
+        ]
+    ));
+}
+
+#[cfg(test)]
+#[test]
+fn lengths_are_bounded_by_the_section() {
+    // a type section declaring 4 billion types in 5 bytes
+    let many_types = module_bytes(&[0x01, 0x05, 0xff, 0xff, 0xff, 0xff, 0x0f]);
+    let err = parse_stream(Box::new(Cursor::new(many_types)))
+        .err()
+        .unwrap();
+    assert_eq!(err.to_string(), "length out of bounds");
+    // a function body declaring more locals than it has bytes
+    let many_locals = module_bytes(&[
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+        0x03, 0x02, 0x01, 0x00, // function section
+        0x0a, 0x07, 0x01, 0x05, 0xff, 0xff, 0xff, 0xff, 0x0f, // code section
+    ]);
+    let err = parse_stream(Box::new(Cursor::new(many_locals)))
+        .err()
+        .unwrap();
+    assert_eq!(err.to_string(), "length out of bounds");
+    // two local entries of 4 billion locals each
+    let too_many_locals = module_bytes(&[
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+        0x03, 0x02, 0x01, 0x00, // function section
+        0x07, 0x05, 0x01, 0x01, b'x', 0x00, 0x00, // export section
+        0x0a, 0x0f, 0x01, 0x0d, 0x02, // code section
+        0xff, 0xff, 0xff, 0xff, 0x0f, 0x7f, // (local i32 * 4294967295)
+        0xff, 0xff, 0xff, 0xff, 0x0f, 0x7e, // (local i64 * 4294967295)
+        0x0b,
+    ]);
+    let err = parse_stream(Box::new(Cursor::new(too_many_locals)))
+        .err()
+        .unwrap();
+    assert_eq!(err.to_string(), "too many locals");
+    // a type section with a trailing byte
+    let trailing = module_bytes(&[0x01, 0x05, 0x01, 0x60, 0x00, 0x00, 0x00]);
+    let err = parse_stream(Box::new(Cursor::new(trailing))).err().unwrap();
+    assert_eq!(err.to_string(), "section size mismatch");
+}
+
+#[cfg(test)]
+#[test]
+fn ref_func_needs_a_declaration() {
+    let sections = [
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+        0x03, 0x03, 0x02, 0x00, 0x00, // function section
+        0x0a, 0x0b, 0x02, // code section
+        0x04, 0x00, 0xd2, 0x01, 0x0b, // (ref.func 1)
+        0x04, 0x00, 0xd2, 0x00, 0x0b, // (ref.func 0)
+    ];
+    // decodes fine, validation rejects it
+    let module = parse_stream(Box::new(Cursor::new(module_bytes(&sections)))).unwrap();
+    assert!(module.refs.is_empty());
+    assert_eq!(module.undeclared_ref(), Some(FuncIdx(0)));
+
+    // a declarative element segment declares both functions
+    let declared = module_bytes(
+        &[
+            &sections[..11],
+            &[0x09, 0x06, 0x01, 0x03, 0x00, 0x02, 0x00, 0x01][..],
+            &sections[11..],
+        ]
+        .concat(),
+    );
+    let module = parse_stream(Box::new(Cursor::new(declared))).unwrap();
+    assert!(module.refs.iter().eq([&FuncIdx(0), &FuncIdx(1)]));
+    assert!(module.undeclared_ref().is_none());
+}
+
+#[cfg(test)]
+#[test]
+fn function_bodies_are_bounded_by_their_size() {
+    let module = |code: &[u8]| {
+        let sections = [
+            &[0x01, 0x04, 0x01, 0x60, 0x00, 0x00][..], // type section
+            &[0x03, 0x03, 0x02, 0x00, 0x00][..],       // function section
+            &[0x0a, code.len() as u8][..],
+            code,
+        ];
+        module_bytes(&sections.concat())
+    };
+
+    let ok = parse_stream(Box::new(Cursor::new(module(&[
+        0x02, 0x02, 0x00, 0x0b, 0x03, 0x00, 0x01, 0x0b,
+    ]))));
+    assert_eq!(ok.unwrap().funcs.len(), 2);
+    // the first body claims a byte of the second one
+    let too_long = module(&[0x02, 0x03, 0x00, 0x0b, 0x03, 0x00, 0x01, 0x0b]);
+    let err = parse_stream(Box::new(Cursor::new(too_long))).err().unwrap();
+    assert_eq!(err.to_string(), "function body size mismatch");
+    // the first body ends before its `end`
+    let too_short = module(&[0x02, 0x01, 0x00, 0x0b, 0x03, 0x00, 0x01, 0x0b]);
+    let err = parse_stream(Box::new(Cursor::new(too_short)))
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[cfg(test)]
+#[test]
+fn parse_typed_select() {
+    let expr = parser_for(&[0x1c, 0x01, 0x6f, 0x0b]).parse_expr().unwrap();
+    assert!(matches!(expr[..], [Inst::SelectTyped(ValType::ExternRef)]));
+    let err = parser_for(&[0x1c, 0x02, 0x7f, 0x7f, 0x0b])
+        .parse_expr()
+        .unwrap_err();
+    assert_eq!(err.to_string(), "invalid result arity");
+}
+
+#[cfg(test)]
+#[test]
+fn parse_conversion_opcodes() {
+    let bytes: Vec<u8> = (0xa7..=0xc4).chain([0x0b]).collect();
+    let expr = parser_for(bytes.leak()).parse_expr().unwrap();
+    assert_eq!(expr.len(), 0xc4 - 0xa7 + 1);
+    assert!(matches!(expr[0xb8 - 0xa7], Inst::F64ConvertI32U));
+    assert!(matches!(expr[0xba - 0xa7], Inst::F64ConvertI64U));
+    assert!(matches!(expr[0xc4 - 0xa7], Inst::I64Extend32S));
+}
+
+#[cfg(test)]
+#[test]
+fn parse_table_and_memory_imports() {
+    // (import "env" "t" (table 1 10 funcref))
+    let desc = parser_for(&[0x01, 0x70, 0x01, 0x01, 0x0a]).parse_import_desc().unwrap();
+    let ImportDesc::Table(typ) = desc else { panic!("expected a table import") };
+    assert!(matches!(typ.reftype, Reftype::Funcref));
+    assert_eq!((typ.limits.min, typ.limits.max), (1, Some(10)));
+    // (import "env" "memory" (memory 2))
+    let desc = parser_for(&[0x02, 0x00, 0x02]).parse_import_desc().unwrap();
+    let ImportDesc::Mem(typ) = desc else { panic!("expected a memory import") };
+    assert_eq!((typ.limits.min, typ.limits.max), (2, None));
+}
+
+#[cfg(test)]
+#[test]
+fn parse_globals() {
+    // (import "env" "g" (global (mut i64)))
+    let desc = parser_for(&[0x03, 0x7e, 0x01]).parse_import_desc().unwrap();
+    let ImportDesc::Global(typ) = desc else { panic!("expected a global import") };
+    assert_eq!(typ.valtype, ValType::I64);
+    assert!(typ.mutable);
+    let Err(err) = parser_for(&[0x03, 0x7e, 0x02]).parse_import_desc() else { panic!("expected an error") };
+    assert_eq!(err.to_string(), "invalid mutability");
+
+    // (global i32 (i32.const 7)) (export "g" (global 0))
+    let sections = [
+        0x06, 0x06, 0x01, 0x7f, 0x00, 0x41, 0x07, 0x0b, // global section
+        0x07, 0x05, 0x01, 0x01, b'g', 0x03, 0x00, // export section
+    ];
+    let module = parse_stream(Box::new(Cursor::new(module_bytes(&sections)))).unwrap();
+    let [Global { typ, init }] = &module.globals[..] else { panic!("expected a global") };
+    assert!(!typ.mutable);
+    assert!(matches!(init[..], [Inst::I32Const(7)]));
+    let exports: Vec<_> = module.exports().collect();
+    let [("g", ExternType::Global(typ))] = &exports[..] else { panic!("expected a global export") };
+    assert_eq!(typ.valtype, ValType::I32);
+}
+
+#[cfg(test)]
+#[test]
+fn parse_from_any_read() {
+    /// Hands out a single byte per read, and isn't a `BufRead`.
+    struct Trickle(&'static [u8]);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((first, rest)) = self.0.split_first() else { return Ok(0) };
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = *first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    let module = parse_stream(Trickle(ADD_MOD)).unwrap();
+    assert_eq!(module.funcs.len(), 2);
+    assert_eq!(module.exports[0].name, "add");
+
+    let reader = SectionReader::new(Trickle(ADD_MOD)).unwrap();
+    assert_eq!(reader.count(), 5);
+}
+
+#[cfg(test)]
+#[test]
+fn names_are_checked() {
+    let exports = |second: u8| {
+        module_bytes(&[
+            0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+            0x03, 0x02, 0x01, 0x00, // function section
+            0x07, 0x09, 0x02, // export section
+            0x01, b'f', 0x00, 0x00, // (export "f" (func 0))
+            0x01, second, 0x00, 0x00, // (export "?" (func 0))
+            0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b, // code section
+        ])
+    };
+    let parsed = parse_stream(Cursor::new(exports(b'g'))).unwrap();
+    assert_eq!(parsed.exports.len(), 2);
+    let err = parse_stream(Cursor::new(exports(b'f'))).err().unwrap();
+    assert_eq!(err.to_string(), "duplicate export name");
+
+    let bad_import = module_bytes(&[
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+        0x02, 0x07, 0x01, // import section
+        0x01, 0xff, 0x01, b'f', 0x00, 0x00, // (import "\xff" "f" (func 0))
+    ]);
+    let err = parse_stream(Cursor::new(bad_import)).err().unwrap();
+    assert_eq!(err.to_string(), "invalid utf8");
+}
+
+#[cfg(test)]
+#[test]
+fn simd_is_rejected_cleanly() {
+    // (func v128.const 0 drop)
+    let simd_op = module_bytes(&[
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+        0x03, 0x02, 0x01, 0x00, // function section
+        0x0a, 0x17, 0x01, 0x15, 0x00, 0xfd, 0x0c, // code section
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x1a, 0x0b,
+    ]);
+    let err = parse_module_checked(&simd_op).err().unwrap();
+    assert_eq!(
+        err,
+        ParseError::Unsupported("simd instruction 0xfd 12".into())
+    );
+    // (type (func (param v128)))
+    let simd_type = module_bytes(&[0x01, 0x05, 0x01, 0x60, 0x01, 0x7b, 0x00]);
+    let err = parse_stream(Cursor::new(simd_type)).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+}
+
+#[cfg(test)]
+#[test]
+fn custom_sections_are_kept() {
+    let bytes = module_bytes(&[
+        0x00, 0x06, 0x03, b'o', b'n', b'e', 0x01, 0x02, // custom "one"
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+        0x00, 0x04, 0x03, b't', b'w', b'o', // empty custom "two"
+    ]);
+    let module = parse_stream(Cursor::new(bytes)).unwrap();
+    let sections: Vec<_> = module.custom_sections().collect();
+    assert_eq!(sections, [("one", &[1, 2][..]), ("two", &[][..])]);
+    assert_eq!(module.types.len(), 1);
+
+    let bad_name = module_bytes(&[0x00, 0x02, 0x01, 0xff]);
+    let err = parse_stream(Cursor::new(bad_name)).err().unwrap();
+    assert_eq!(err.to_string(), "invalid utf8");
+}
+
+#[cfg(test)]
+#[test]
+fn producers_are_decoded() {
+    let name = |s: &str| [&[s.len() as u8][..], s.as_bytes()].concat();
+    let contents = [
+        &[0x02][..],
+        &name("language"),
+        &[0x01],
+        &name("Rust"),
+        &name(""),
+        &name("processed-by"),
+        &[0x02],
+        &name("rustc"),
+        &name("1.70.0"),
+        &name("wasm-bindgen"),
+        &name("0.2.87"),
+    ]
+    .concat();
+    let section = |contents: &[u8]| {
+        let payload = [&name("producers")[..], contents].concat();
+        module_bytes(&[&[0x00, payload.len() as u8][..], &payload].concat())
+    };
+
+    let module = parse_stream(Cursor::new(section(&contents))).unwrap();
+    let producers = module.producers().unwrap().unwrap();
+    let pair = |a: &str, b: &str| (a.to_string(), b.to_string());
+    assert_eq!(producers.language, [pair("Rust", "")]);
+    assert_eq!(
+        producers.processed_by,
+        [pair("rustc", "1.70.0"), pair("wasm-bindgen", "0.2.87")]
+    );
+    assert!(producers.sdk.is_empty());
+
+    let truncated = section(&contents[..contents.len() - 1]);
+    let module = parse_stream(Cursor::new(truncated)).unwrap();
+    assert!(module.producers().unwrap().is_err());
+
+    let module = parse_stream(Cursor::new(module_bytes(&[]))).unwrap();
+    assert!(module.producers().is_none());
+}
+
+#[cfg(test)]
+#[test]
+fn every_comparison_opcode() {
+    let mut body = vec![0x00]; // no locals
+    body.extend(0x45..=0x66);
+    body.push(0x0b);
+    let code = [&[0x01, body.len() as u8][..], &body].concat();
+    let sections = [
+        &[0x01, 0x04, 0x01, 0x60, 0x00, 0x00][..], // type section
+        &[0x03, 0x02, 0x01, 0x00],                 // function section
+        &[0x0a, code.len() as u8],                 // code section
+        &code,
+    ];
+    let bytes = module_bytes(&sections.concat());
+    let module = parse_stream(Cursor::new(bytes)).unwrap();
+    let ops: Vec<_> = module.funcs[0].body.iter().map(Inst::to_string).collect();
+    #[rustfmt::skip]
+    let expected = [
+        "i32.eqz", "i32.eq", "i32.ne", "i32.lt_s", "i32.lt_u", "i32.gt_s", "i32.gt_u",
+        "i32.le_s", "i32.le_u", "i32.ge_s", "i32.ge_u",
+        "i64.eqz", "i64.eq", "i64.ne", "i64.lt_s", "i64.lt_u", "i64.gt_s", "i64.gt_u",
+        "i64.le_s", "i64.le_u", "i64.ge_s", "i64.ge_u",
+        "f32.eq", "f32.ne", "f32.lt", "f32.gt", "f32.le", "f32.ge",
+        "f64.eq", "f64.ne", "f64.lt", "f64.gt", "f64.le", "f64.ge",
+    ];
+    assert_eq!(ops, expected);
+}
+
+/// A xorshift generator, for tests that want many arbitrary values.
+#[cfg(test)]
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+#[cfg(test)]
+#[test]
+fn leb128_round_trips() {
+    use super::encoder::{encode_i32, encode_i64, encode_u32};
+
+    let mut values = vec![0, 1, 63, 64, 127, 128, 8191, 8192, 1 << 31, 1 << 63];
+    for x in [u32::MAX as u64, i32::MAX as u64, i32::MIN as u64] {
+        values.extend([x, x.wrapping_add(1), x.wrapping_sub(1)]);
+    }
+    for x in [u64::MAX, i64::MAX as u64, i64::MIN as u64] {
+        values.extend([x, x.wrapping_add(1), x.wrapping_sub(1)]);
+    }
+    let mut state = 0x2545_f491_4f6c_dd1d;
+    for _ in 0..10_000 {
+        // shifted, so small values are as common as large ones
+        let shift = xorshift(&mut state) % 64;
+        values.push(xorshift(&mut state) >> shift);
+    }
+
+    let parser = |bytes: &Vec<u8>| Parser::new(Cursor::new(bytes.clone()));
+    for x in values {
+        let mut bytes = vec![];
+        encode_u32(x as u32, &mut bytes);
+        let mut p = parser(&bytes);
+        assert_eq!(p.parse_u32().unwrap(), x as u32, "{bytes:x?}");
+        p.finish("u32").unwrap();
+
+        let mut bytes = vec![];
+        encode_i32(x as i32, &mut bytes);
+        let mut p = parser(&bytes);
+        assert_eq!(p.parse_i32().unwrap(), x as i32, "{bytes:x?}");
+        p.finish("i32").unwrap();
+
+        let mut bytes = vec![];
+        encode_i64(x as i64, &mut bytes);
+        let mut p = parser(&bytes);
+        assert_eq!(p.parse_i64().unwrap(), x as i64, "{bytes:x?}");
+        p.finish("i64").unwrap();
+    }
+
+    let len = |encode: fn(&mut Vec<u8>)| {
+        let mut bytes = vec![];
+        encode(&mut bytes);
+        bytes.len()
+    };
+    assert_eq!(len(|b| encode_u32(u32::MAX, b)), 5);
+    assert_eq!(len(|b| encode_i32(i32::MIN, b)), 5);
+    assert_eq!(len(|b| encode_i32(-64, b)), 1);
+    assert_eq!(len(|b| encode_i32(64, b)), 2);
+    assert_eq!(len(|b| encode_i64(i64::MIN, b)), 10);
+}
+
+#[cfg(test)]
+#[test]
+fn leb128_unused_bits_are_checked() {
+    let parse = |bytes: &[u8], f: fn(&mut Parser<Cursor<Vec<u8>>>) -> io::Result<i64>| {
+        f(&mut Parser::new(Cursor::new(bytes.to_vec()))).map_err(|e| e.to_string())
+    };
+    let u32 = |bytes: &[u8]| parse(bytes, |p| p.parse_u32().map(i64::from));
+    let i32 = |bytes: &[u8]| parse(bytes, |p| p.parse_i32().map(i64::from));
+    let u64 = |bytes: &[u8]| parse(bytes, |p| p.parse_u64().map(|x| x as i64));
+    let i64 = |bytes: &[u8]| parse(bytes, |p| p.parse_i64());
+    let too_large = Err("integer too large".to_string());
+    let too_long = Err("integer too long".to_string());
+
+    // padding is fine as long as it fits
+    assert_eq!(u32(&[0x80, 0x80, 0x80, 0x80, 0x00]), Ok(0));
+    assert_eq!(u32(&[0xff, 0xff, 0xff, 0xff, 0x0f]), Ok(u32::MAX.into()));
+    assert_eq!(u32(&[0xff, 0xff, 0xff, 0xff, 0x1f]), too_large);
+    assert_eq!(u32(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00]), too_long);
+
+    assert_eq!(i32(&[0xff, 0xff, 0xff, 0xff, 0x07]), Ok(i32::MAX.into()));
+    assert_eq!(i32(&[0x80, 0x80, 0x80, 0x80, 0x78]), Ok(i32::MIN.into()));
+    assert_eq!(i32(&[0xff, 0xff, 0xff, 0xff, 0x7f]), Ok(-1));
+    assert_eq!(i32(&[0xff, 0xff, 0xff, 0xff, 0x0f]), too_large);
+    assert_eq!(i32(&[0x80, 0x80, 0x80, 0x80, 0x70]), too_large);
+
+    let mut max = [0xff; 10];
+    max[9] = 0x01;
+    assert_eq!(u64(&max), Ok(-1));
+    max[9] = 0x03;
+    assert_eq!(u64(&max), too_large);
+    max[9] = 0x00;
+    assert_eq!(i64(&max), Ok(i64::MAX));
+    let mut min = [0x80; 10];
+    min[9] = 0x7f;
+    assert_eq!(i64(&min), Ok(i64::MIN));
+    max[9] = 0x01;
+    assert_eq!(i64(&max), too_large);
+    min[9] = 0x7e;
+    assert_eq!(i64(&min), too_large);
+}
@@ -0,0 +1,526 @@
+//! A high-level interface over parsing, instantiation and execution.
+//!
+//! ```ignore
+//! let engine = Engine::new();
+//! let module = Module::from_file("add.wasm")?;
+//! let mut instance = Instance::new(&engine, &module, Externals::default())?;
+//! let results = instance.call("add", &[Val::I32(1), Val::I32(2)])?;
+//! ```
+//!
+//! Everything underneath, like the [`Store`] and the [`Machine`], is still
+//! available for uses this doesn't cover.
+
+use alloc::{rc::Rc, string::String, vec::Vec};
+use core::cell::RefCell;
+
+use crate::{
+    instance::{
+        CompiledModule, ExternAddr, Externals, FuncAddr, InstantiationError, Linker, ModuleInst,
+        Store,
+    },
+    repr::Module,
+    rt::{Exception, Machine, Val},
+};
+
+/// Settings shared by the instances created with it.
+pub struct Engine {
+    deterministic: bool,
+    run_start: bool,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self {
+            deterministic: false,
+            run_start: true,
+        }
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Canonicalizes the NaNs produced by float arithmetic, see
+    /// [`Machine::deterministic`].
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Whether instantiating runs the start function, which it does by
+    /// default. Without it, instantiating has no side effects, and the start
+    /// function can be run later with [`Instance::run_start`].
+    pub fn run_start(mut self, run_start: bool) -> Self {
+        self.run_start = run_start;
+        self
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Link(InstantiationError),
+    /// Nothing is exported under the name.
+    ExportNotFound(String),
+    /// The export of the name isn't a function.
+    NotAFunction(String),
+    Trap(Exception),
+}
+
+/// The outcome of running a function to completion, where a trap is part of
+/// the result rather than an error.
+#[derive(Debug)]
+pub struct RunResult {
+    /// The results of the function, empty if it trapped.
+    pub returned: Vec<Val>,
+    pub trapped: Option<Exception>,
+}
+
+impl From<Result<Vec<Val>, Exception>> for RunResult {
+    fn from(result: Result<Vec<Val>, Exception>) -> Self {
+        match result {
+            Ok(returned) => RunResult {
+                returned,
+                trapped: None,
+            },
+            Err(e) => RunResult {
+                returned: Vec::new(),
+                trapped: Some(e),
+            },
+        }
+    }
+}
+
+/// An instantiated module, with a store of its own.
+pub struct Instance {
+    store: Store,
+    inst: Rc<RefCell<ModuleInst>>,
+    deterministic: bool,
+    start: Option<FuncAddr>,
+}
+
+impl Instance {
+    /// Instantiates `module` with host values from `imports` and runs its
+    /// start function.
+    pub fn new(engine: &Engine, module: &Module, imports: Externals) -> Result<Self, Error> {
+        Self::link(engine, module, imports, |linker, store| {
+            linker.instantiate(module, store)
+        })
+    }
+
+    /// Like [`Instance::new`], but skips the work already done when
+    /// compiling the module.
+    pub fn from_compiled(
+        engine: &Engine,
+        module: &CompiledModule,
+        imports: Externals,
+    ) -> Result<Self, Error> {
+        Self::link(engine, module.module(), imports, |linker, store| {
+            linker.instantiate_compiled(module, store)
+        })
+    }
+
+    /// Instantiates `module`, runs its start function unless the engine
+    /// turned that off, and calls the exported function `name`. Traps in
+    /// either function end up in the [`RunResult`], only failing to link or
+    /// to find the export is an error.
+    pub fn run_to_completion(
+        engine: &Engine,
+        module: &Module,
+        imports: Externals,
+        name: &str,
+        args: &[Val],
+    ) -> Result<RunResult, Error> {
+        let deferred = Engine {
+            run_start: false,
+            ..*engine
+        };
+        let mut instance = Self::new(&deferred, module, imports)?;
+        if engine.run_start {
+            if let Err(Error::Trap(e)) = instance.run_start() {
+                return Ok(Err(e).into());
+            }
+        }
+        instance.run(name, args)
+    }
+
+    fn link(
+        engine: &Engine,
+        module: &Module,
+        imports: Externals,
+        instantiate: impl FnOnce(
+            &mut Linker,
+            &mut Store,
+        ) -> Result<Rc<RefCell<ModuleInst>>, InstantiationError>,
+    ) -> Result<Self, Error> {
+        let mut linker = Linker::new();
+        for (name, val) in imports.values {
+            linker.define(name.module(), name.name(), val);
+        }
+        let mut store = Store::new();
+        let inst = instantiate(&mut linker, &mut store).map_err(Error::Link)?;
+        let start = module
+            .start
+            .map(|start| {
+                let addr = inst.borrow().func_addrs.get(start.0 as usize).copied();
+                addr.ok_or(Error::Link(InstantiationError::UnknownFunction(start)))
+            })
+            .transpose()?;
+        let mut instance = Self {
+            store,
+            inst,
+            deterministic: engine.deterministic,
+            start,
+        };
+        if engine.run_start {
+            instance.run_start()?;
+        }
+        Ok(instance)
+    }
+
+    /// The start function of the module, if it has one.
+    pub fn start(&self) -> Option<FuncAddr> {
+        self.start
+    }
+
+    /// Runs the start function, if the module has one. Instantiating already
+    /// does that unless [`Engine::run_start`] turned it off.
+    pub fn run_start(&mut self) -> Result<(), Error> {
+        let Some(start) = self.start else { return Ok(()) };
+        self.machine().call(start).map_err(Error::Trap)
+    }
+
+    fn machine(&mut self) -> Machine<'_> {
+        let mut m = Machine::new(&mut self.store);
+        m.deterministic = self.deterministic;
+        m
+    }
+
+    /// Calls the exported function `name` and returns its results, in the
+    /// order of the function's result types.
+    pub fn call(&mut self, name: &str, args: &[Val]) -> Result<Vec<Val>, Error> {
+        let addr = self
+            .get_export(name)
+            .ok_or_else(|| Error::ExportNotFound(name.into()))?;
+        let ExternAddr::Func(func_addr) = addr else {
+            return Err(Error::NotAFunction(name.into()));
+        };
+        self.machine().invoke(func_addr, args).map_err(Error::Trap)
+    }
+
+    /// Like [`Instance::call`], but returns a trap as part of the result.
+    pub fn run(&mut self, name: &str, args: &[Val]) -> Result<RunResult, Error> {
+        match self.call(name, args) {
+            Ok(returned) => Ok(Ok(returned).into()),
+            Err(Error::Trap(e)) => Ok(Err(e).into()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The store address of the export `name`, see [`ModuleInst::get_export`].
+    pub fn get_export(&self, name: &str) -> Option<ExternAddr> {
+        self.inst.borrow().get_export(name)
+    }
+
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut Store {
+        &mut self.store
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::{Engine, Error, Instance, RunResult};
+    use crate::instance::{CompiledModule, ExternAddr, Externals};
+    use crate::repr::{
+        Export, ExportDesc, Func, FuncIdx, FuncType, Inst, Limits, LocalIdx, MemArg, MemIdx,
+        MemType, Module, ResultType, TypeIdx, ValType,
+    };
+    use crate::rt::{self, Exception, Val};
+
+    fn add_module() -> Module {
+        Module {
+            types: vec![FuncType {
+                from: ResultType {
+                    types: vec![ValType::I32, ValType::I32],
+                },
+                to: ResultType {
+                    types: vec![ValType::I32],
+                },
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body: vec![
+                    Inst::LocalGet(LocalIdx(0)),
+                    Inst::LocalGet(LocalIdx(1)),
+                    Inst::I32Add,
+                ],
+            }],
+            exports: vec![Export {
+                name: "add".into(),
+                desc: ExportDesc::Func(FuncIdx(0)),
+            }],
+            ..Module::default()
+        }
+    }
+
+    #[test]
+    fn call_export() {
+        let engine = Engine::new();
+        let mut instance = Instance::new(&engine, &add_module(), Externals::default()).unwrap();
+        let results = instance.call("add", &[Val::I32(1), Val::I32(2)]).unwrap();
+        assert!(matches!(results[..], [Val::I32(3)]));
+
+        let res = instance.call("sub", &[]);
+        assert!(matches!(res, Err(Error::ExportNotFound(name)) if name == "sub"));
+        let res = instance.call("add", &[Val::I32(1)]);
+        assert!(matches!(res, Err(Error::Trap(_))));
+
+        let mut module = add_module();
+        module.mems.push(MemType {
+            limits: Limits {
+                min: 0,
+                max: None,
+                is_64: false,
+                shared: false,
+            },
+        });
+        module.exports.push(Export {
+            name: "mem".into(),
+            desc: ExportDesc::Mem(MemIdx(0)),
+        });
+        let mut instance = Instance::new(&engine, &module, Externals::default()).unwrap();
+        let res = instance.call("mem", &[]);
+        assert!(matches!(res, Err(Error::NotAFunction(name)) if name == "mem"));
+    }
+
+    #[test]
+    fn read_memory_through_an_export() {
+        // "write" stores 42 at address 3 of the exported memory
+        let module = Module {
+            types: vec![FuncType {
+                from: ResultType { types: vec![] },
+                to: ResultType { types: vec![] },
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body: vec![
+                    Inst::I32Const(3),
+                    Inst::I32Const(42),
+                    Inst::I32Store8(MemArg {
+                        align: 0,
+                        offset: 0,
+                        memory: MemIdx(0),
+                    }),
+                ],
+            }],
+            mems: vec![MemType {
+                limits: Limits {
+                    min: 1,
+                    max: None,
+                    is_64: false,
+                    shared: false,
+                },
+            }],
+            exports: vec![
+                Export {
+                    name: "write".into(),
+                    desc: ExportDesc::Func(FuncIdx(0)),
+                },
+                Export {
+                    name: "out".into(),
+                    desc: ExportDesc::Mem(MemIdx(0)),
+                },
+            ],
+            ..Module::default()
+        };
+        let mut instance = Instance::new(&Engine::new(), &module, Externals::default()).unwrap();
+        drop(module);
+        instance.call("write", &[]).unwrap();
+        let Some(ExternAddr::Mem(out)) = instance.get_export("out") else { panic!("expected a memory") };
+        assert_eq!(instance.store().mem(out).read(3, 1).unwrap(), [42]);
+        assert!(matches!(
+            instance.get_export("write"),
+            Some(ExternAddr::Func(_))
+        ));
+        assert!(instance.get_export("missing").is_none());
+    }
+
+    #[test]
+    fn instantiate_compiled_repeatedly() {
+        let engine = Engine::new();
+        let compiled = CompiledModule::new(add_module()).unwrap();
+        for i in 0..3 {
+            let mut instance =
+                Instance::from_compiled(&engine, &compiled, Externals::default()).unwrap();
+            let results = instance.call("add", &[Val::I32(i), Val::I32(2)]).unwrap();
+            assert!(matches!(results[..], [Val::I32(r)] if r == i + 2));
+        }
+    }
+
+    #[test]
+    fn call_multi_value() {
+        let results = || ResultType {
+            types: vec![ValType::I32, ValType::I64],
+        };
+        let module = Module {
+            types: vec![
+                FuncType {
+                    from: ResultType {
+                        types: vec![ValType::I32],
+                    },
+                    to: results(),
+                },
+                FuncType {
+                    from: ResultType { types: vec![] },
+                    to: results(),
+                },
+            ],
+            funcs: vec![
+                Func {
+                    typ: TypeIdx(0),
+                    locals: vec![],
+                    body: vec![Inst::LocalGet(LocalIdx(0)), Inst::I64Const(-2)],
+                },
+                Func {
+                    typ: TypeIdx(1),
+                    locals: vec![],
+                    body: vec![Inst::I32Const(5), Inst::Call(FuncIdx(0))],
+                },
+            ],
+            exports: vec![
+                Export {
+                    name: "pair".into(),
+                    desc: ExportDesc::Func(FuncIdx(0)),
+                },
+                Export {
+                    name: "call_pair".into(),
+                    desc: ExportDesc::Func(FuncIdx(1)),
+                },
+            ],
+            ..Module::default()
+        };
+        let engine = Engine::new();
+        let mut instance = Instance::new(&engine, &module, Externals::default()).unwrap();
+        let results = instance.call("pair", &[Val::I32(1)]).unwrap();
+        assert!(matches!(results[..], [Val::I32(1), Val::I64(-2)]));
+        let results = instance.call("call_pair", &[]).unwrap();
+        assert!(matches!(results[..], [Val::I32(5), Val::I64(-2)]));
+    }
+
+    #[test]
+    fn start_can_be_deferred() {
+        // the start function stores 7 at address 0
+        let module = Module {
+            types: vec![FuncType {
+                from: ResultType { types: vec![] },
+                to: ResultType { types: vec![] },
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body: vec![
+                    Inst::I32Const(0),
+                    Inst::I32Const(7),
+                    Inst::I32Store8(MemArg {
+                        align: 0,
+                        offset: 0,
+                        memory: MemIdx(0),
+                    }),
+                ],
+            }],
+            mems: vec![MemType {
+                limits: Limits {
+                    min: 1,
+                    max: None,
+                    is_64: false,
+                    shared: false,
+                },
+            }],
+            start: Some(FuncIdx(0)),
+            ..Module::default()
+        };
+        let first_byte = |instance: &Instance| instance.store().mems[0].read(0, 1).unwrap()[0];
+
+        let instance = Instance::new(&Engine::new(), &module, Externals::default()).unwrap();
+        assert_eq!(first_byte(&instance), 7);
+
+        let engine = Engine::new().run_start(false);
+        let mut instance = Instance::new(&engine, &module, Externals::default()).unwrap();
+        assert_eq!(first_byte(&instance), 0);
+        assert!(instance.start().is_some());
+        instance.run_start().unwrap();
+        assert_eq!(first_byte(&instance), 7);
+
+        let mut instance = Instance::new(&engine, &add_module(), Externals::default()).unwrap();
+        assert!(instance.start().is_none());
+        assert!(instance.run_start().is_ok());
+    }
+
+    #[test]
+    fn run_to_completion_reports_traps() {
+        let engine = Engine::new();
+        let run = |module: &Module, args: &[Val]| {
+            Instance::run_to_completion(&engine, module, Externals::default(), "add", args)
+        };
+        let result = run(&add_module(), &[Val::I32(1), Val::I32(2)]).unwrap();
+        assert!(matches!(result.returned[..], [Val::I32(3)]));
+        assert!(result.trapped.is_none());
+
+        let mut module = add_module();
+        module.funcs[0].body[2] = Inst::I32DivU;
+        let result = run(&module, &[Val::I32(1), Val::I32(0)]).unwrap();
+        assert!(result.returned.is_empty());
+        assert!(matches!(
+            result.trapped,
+            Some(Exception::Runtime(rt::Error::IntegerDivideByZero))
+        ));
+
+        // a start function that divides by zero
+        module.types.push(FuncType {
+            from: ResultType { types: vec![] },
+            to: ResultType { types: vec![] },
+        });
+        module.funcs.push(Func {
+            typ: TypeIdx(1),
+            locals: vec![],
+            body: vec![
+                Inst::I32Const(1),
+                Inst::I32Const(0),
+                Inst::I32DivU,
+                Inst::Drop,
+            ],
+        });
+        module.start = Some(FuncIdx(1));
+        let result = run(&module, &[Val::I32(1), Val::I32(1)]).unwrap();
+        assert!(matches!(
+            result,
+            RunResult {
+                trapped: Some(Exception::Runtime(rt::Error::IntegerDivideByZero)),
+                ..
+            }
+        ));
+
+        let engine = Engine::new().run_start(false);
+        let result = Instance::run_to_completion(
+            &engine,
+            &module,
+            Externals::default(),
+            "add",
+            &[Val::I32(4), Val::I32(2)],
+        )
+        .unwrap();
+        assert!(matches!(result.returned[..], [Val::I32(2)]));
+
+        let res = Instance::run_to_completion(&engine, &module, Externals::default(), "sub", &[]);
+        assert!(matches!(res, Err(Error::ExportNotFound(_))));
+    }
+}
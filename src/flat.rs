@@ -0,0 +1,624 @@
+//! Flattening of structured control flow.
+//!
+//! Function bodies nest blocks, loops and ifs as `Vec<Inst>`s and branch by
+//! label depth. Before execution they are compiled into a single linear
+//! buffer where every branch is a jump to an instruction offset, so the
+//! machine can run them with a plain instruction pointer.
+//!
+//! A branch also drops the operands its block pushed below the values it
+//! carries. Flattening works out the stack height at every label, so a jump
+//! knows how many values to keep and where to put them.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::repr::{BlockType, Branch, FuncType, ImportDesc, Inst, LabelIdx, Module};
+
+/// How many values a function or block takes and returns.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Arity {
+    pub params: usize,
+    pub results: usize,
+}
+
+impl From<&FuncType> for Arity {
+    fn from(typ: &FuncType) -> Self {
+        Self {
+            params: typ.from.types.len(),
+            results: typ.to.types.len(),
+        }
+    }
+}
+
+/// The arities of the types and functions a body can refer to, which
+/// decide how the stack height changes at calls and blocks.
+#[derive(Default)]
+pub struct Signatures {
+    /// by type index
+    pub types: Vec<Arity>,
+    /// by function index, imported functions first
+    pub funcs: Vec<Arity>,
+}
+
+impl Signatures {
+    pub fn new(module: &Module) -> Result<Self, CompileError> {
+        let types: Vec<Arity> = module.types.iter().map(Arity::from).collect();
+        let funcs = module
+            .imports
+            .iter()
+            .filter_map(|i| match i.desc {
+                ImportDesc::Func(t) => Some(t),
+                _ => None,
+            })
+            .chain(module.funcs.iter().map(|f| f.typ))
+            .map(|t| types.get(t.0 as usize).copied())
+            .collect::<Option<_>>()
+            .ok_or(CompileError::UnknownType)?;
+        Ok(Self { types, funcs })
+    }
+
+    fn block(&self, typ: BlockType) -> Result<Arity, CompileError> {
+        match typ {
+            BlockType::Empty => Ok(Arity::default()),
+            BlockType::Inline(_) => Ok(Arity {
+                params: 0,
+                results: 1,
+            }),
+            BlockType::Type(t) => self
+                .types
+                .get(t.0 as usize)
+                .copied()
+                .ok_or(CompileError::UnknownType),
+        }
+    }
+}
+
+/// Code that can't be flattened because it refers to something that isn't
+/// there.
+#[derive(Debug)]
+pub enum CompileError {
+    /// A branch to a label deeper than the blocks around it.
+    UnknownLabel(u32),
+    UnknownType,
+    UnknownFunction,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::UnknownLabel(depth) => write!(f, "unknown label {depth}"),
+            CompileError::UnknownType => f.write_str("unknown type"),
+            CompileError::UnknownFunction => f.write_str("unknown function"),
+        }
+    }
+}
+
+/// Compiles `body` into a linear buffer without `Block`, `Loop`, `IfElse`,
+/// `Break*` or `Return` instructions. Branches out of the body, and
+/// `return`, jump past its end and keep `results` values, or with `None`
+/// everything the body leaves on the stack.
+pub fn flatten(
+    body: &[Inst],
+    results: Option<usize>,
+    signatures: &Signatures,
+) -> Result<Vec<Inst>, CompileError> {
+    let mut flattener = Flattener {
+        signatures,
+        code: Vec::new(),
+        labels: Vec::new(),
+        scopes: Vec::new(),
+        height: 0,
+    };
+    let end = flattener.new_label(0, results.unwrap_or(0));
+    flattener.scopes.push(end);
+    flattener.emit(body)?;
+    flattener.scopes.pop();
+    flattener.bind(end);
+    if results.is_none() {
+        flattener.labels[end].arity = flattener.height;
+    }
+
+    // jumps were emitted with label ids, now that every label is bound they
+    // can be replaced with the labels themselves
+    let Flattener {
+        mut code, labels, ..
+    } = flattener;
+    for inst in &mut code {
+        match inst {
+            Inst::Jump(b) | Inst::JumpIf(b) => *b = labels[b.target],
+            Inst::JumpUnless(t) => *t = labels[*t].target,
+            Inst::JumpTable(bs, default) => {
+                for b in bs {
+                    *b = labels[b.target];
+                }
+                *default = labels[default.target];
+            }
+            _ => {}
+        }
+    }
+    Ok(code)
+}
+
+/// A jump to the label with id `label`, which is filled in with the label
+/// once that is bound.
+fn unbound(label: usize) -> Branch {
+    Branch {
+        target: label,
+        height: 0,
+        arity: 0,
+    }
+}
+
+struct Flattener<'a> {
+    signatures: &'a Signatures,
+    code: Vec<Inst>,
+    /// every label by id, with its offset once it is bound
+    labels: Vec<Branch>,
+    /// ids of the labels in scope, innermost last
+    scopes: Vec<usize>,
+    /// the number of operands on the stack after the code emitted so far
+    height: usize,
+}
+
+impl Flattener<'_> {
+    fn new_label(&mut self, height: usize, arity: usize) -> usize {
+        self.labels.push(Branch {
+            target: usize::MAX,
+            height,
+            arity,
+        });
+        self.labels.len() - 1
+    }
+
+    /// Makes `label` refer to the next emitted instruction.
+    fn bind(&mut self, label: usize) {
+        self.labels[label].target = self.code.len();
+    }
+
+    /// A jump to the label `idx` in scope.
+    fn branch(&self, idx: &LabelIdx) -> Result<Branch, CompileError> {
+        let depth = idx.0 as usize;
+        let label = depth
+            .checked_add(1)
+            .and_then(|n| self.scopes.len().checked_sub(n))
+            .ok_or(CompileError::UnknownLabel(idx.0))?;
+        Ok(unbound(self.scopes[label]))
+    }
+
+    fn pop(&mut self, n: usize) {
+        // after an unconditional branch the stack is unknown, and the code
+        // that follows never runs
+        self.height = self.height.saturating_sub(n);
+    }
+
+    /// Emits the body of a block, loop or if, which branches to `label`.
+    fn emit_block(&mut self, label: usize, body: &[Inst]) -> Result<(), CompileError> {
+        self.scopes.push(label);
+        self.emit(body)?;
+        self.scopes.pop();
+        Ok(())
+    }
+
+    fn emit(&mut self, instructions: &[Inst]) -> Result<(), CompileError> {
+        for inst in instructions {
+            match inst {
+                Inst::Block(typ, body) => {
+                    let arity = self.signatures.block(*typ)?;
+                    self.pop(arity.params);
+                    let height = self.height;
+                    let end = self.new_label(height, arity.results);
+                    self.height += arity.params;
+                    self.emit_block(end, body.as_ref())?;
+                    self.bind(end);
+                    self.height = height + arity.results;
+                }
+                // branching to a loop continues it
+                Inst::Loop(typ, body) => {
+                    let arity = self.signatures.block(*typ)?;
+                    self.pop(arity.params);
+                    let height = self.height;
+                    let start = self.new_label(height, arity.params);
+                    self.bind(start);
+                    self.height += arity.params;
+                    self.emit_block(start, body.as_ref())?;
+                    self.height = height + arity.results;
+                }
+                Inst::IfElse(typ, then, els) => {
+                    let arity = self.signatures.block(*typ)?;
+                    self.pop(1 + arity.params);
+                    let height = self.height;
+                    let else_start = self.new_label(height, arity.params);
+                    let end = self.new_label(height, arity.results);
+                    self.code.push(Inst::JumpUnless(else_start));
+                    self.height += arity.params;
+                    self.emit_block(end, then.as_ref())?;
+                    self.code.push(Inst::Jump(unbound(end)));
+                    self.bind(else_start);
+                    self.height = height + arity.params;
+                    self.emit_block(end, els.as_ref())?;
+                    self.bind(end);
+                    self.height = height + arity.results;
+                }
+                Inst::Break(l) => self.code.push(Inst::Jump(self.branch(l)?)),
+                Inst::BreakIf(l) => {
+                    self.pop(1);
+                    self.code.push(Inst::JumpIf(self.branch(l)?));
+                }
+                Inst::BreakTable(ls, default) => {
+                    self.pop(1);
+                    let branches = ls
+                        .iter()
+                        .map(|l| self.branch(l))
+                        .collect::<Result<_, _>>()?;
+                    self.code
+                        .push(Inst::JumpTable(branches, self.branch(default)?));
+                }
+                // `return` branches out of the outermost block, the body
+                Inst::Return => self.code.push(Inst::Jump(unbound(self.scopes[0]))),
+                inst => {
+                    let (pops, pushes) = stack_effect(inst, self.signatures)?;
+                    self.pop(pops);
+                    self.height += pushes;
+                    self.code.push(inst.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How many operands an instruction without a body pops and pushes.
+pub(crate) fn stack_effect(
+    inst: &Inst,
+    signatures: &Signatures,
+) -> Result<(usize, usize), CompileError> {
+    let effect = match inst {
+        Inst::Call(f) => {
+            let arity = signatures
+                .funcs
+                .get(f.0 as usize)
+                .ok_or(CompileError::UnknownFunction)?;
+            (arity.params, arity.results)
+        }
+        Inst::CallIndirect(t, _) => {
+            let arity = signatures.block(BlockType::Type(*t))?;
+            (arity.params + 1, arity.results)
+        }
+        Inst::Unreachable | Inst::Nop | Inst::ElemDrop(_) | Inst::Jump(_) => (0, 0),
+        Inst::Drop
+        | Inst::LocalSet(_)
+        | Inst::GlobalSet(_)
+        | Inst::JumpIf(_)
+        | Inst::JumpUnless(_)
+        | Inst::JumpTable(..) => (1, 0),
+        Inst::RefNull(_)
+        | Inst::RefFunc(_)
+        | Inst::LocalGet(_)
+        | Inst::GlobalGet(_)
+        | Inst::MemorySize(_)
+        | Inst::I32Const(_)
+        | Inst::I64Const(_)
+        | Inst::F32Const(_)
+        | Inst::F64Const(_) => (0, 1),
+        Inst::Select | Inst::SelectTyped(_) => (3, 1),
+        Inst::TableInit(..) | Inst::TableCopy(..) => (3, 0),
+        Inst::I32Store(_)
+        | Inst::I64Store(_)
+        | Inst::F32Store(_)
+        | Inst::F64Store(_)
+        | Inst::I32Store8(_)
+        | Inst::I32Store16(_)
+        | Inst::I64Store8(_)
+        | Inst::I64Store16(_)
+        | Inst::I64Store32(_) => (2, 0),
+        Inst::RefIsNull
+        | Inst::LocalTee(_)
+        | Inst::MemoryGrow(_)
+        | Inst::I32Load(_)
+        | Inst::I64Load(_)
+        | Inst::F32Load(_)
+        | Inst::F64Load(_)
+        | Inst::I32Load8S(_)
+        | Inst::I32Load8U(_)
+        | Inst::I32Load16S(_)
+        | Inst::I32Load16U(_)
+        | Inst::I64Load8S(_)
+        | Inst::I64Load8U(_)
+        | Inst::I64Load16S(_)
+        | Inst::I64Load16U(_)
+        | Inst::I64Load32S(_)
+        | Inst::I64Load32U(_)
+        | Inst::I32Eqz
+        | Inst::I64Eqz
+        | Inst::I32Clz
+        | Inst::I32Ctz
+        | Inst::I32Popcnt
+        | Inst::I64Clz
+        | Inst::I64Ctz
+        | Inst::I64Popcnt
+        | Inst::F32Abs
+        | Inst::F32Neg
+        | Inst::F32Ceil
+        | Inst::F32Floor
+        | Inst::F32Trunc
+        | Inst::F32Nearest
+        | Inst::F32Sqrt
+        | Inst::F64Abs
+        | Inst::F64Neg
+        | Inst::F64Ceil
+        | Inst::F64Floor
+        | Inst::F64Trunc
+        | Inst::F64Nearest
+        | Inst::F64Sqrt
+        | Inst::I32WrapI64
+        | Inst::I32TruncF32S
+        | Inst::I32TruncF32U
+        | Inst::I32TruncF64S
+        | Inst::I32TruncF64U
+        | Inst::I64ExtendI32S
+        | Inst::I64ExtendI32U
+        | Inst::I64TruncF32S
+        | Inst::I64TruncF32U
+        | Inst::I64TruncF64S
+        | Inst::I64TruncF64U
+        | Inst::F32ConvertI32S
+        | Inst::F32ConvertI32U
+        | Inst::F32ConvertI64S
+        | Inst::F32ConvertI64U
+        | Inst::F32DemoteF64
+        | Inst::F64ConvertI32S
+        | Inst::F64ConvertI32U
+        | Inst::F64ConvertI64S
+        | Inst::F64ConvertI64U
+        | Inst::F64PromoteF32
+        | Inst::I32ReinterpretF32
+        | Inst::I64ReinterpretF64
+        | Inst::F32ReinterpretI32
+        | Inst::F64ReinterpretI64
+        | Inst::I32Extend8S
+        | Inst::I32Extend16S
+        | Inst::I64Extend8S
+        | Inst::I64Extend16S
+        | Inst::I64Extend32S => (1, 1),
+        Inst::I32Eq
+        | Inst::I32Ne
+        | Inst::I32GeS
+        | Inst::I32LtS
+        | Inst::I32LtU
+        | Inst::I32LeU
+        | Inst::I32GtS
+        | Inst::I32GtU
+        | Inst::I32LeS
+        | Inst::I32GeU
+        | Inst::I64Eq
+        | Inst::I64Ne
+        | Inst::I64LtS
+        | Inst::I64LtU
+        | Inst::I64GtS
+        | Inst::I64GtU
+        | Inst::I64LeS
+        | Inst::I64LeU
+        | Inst::I64GeS
+        | Inst::I64GeU
+        | Inst::F32Eq
+        | Inst::F32Ne
+        | Inst::F32Lt
+        | Inst::F32Gt
+        | Inst::F32Le
+        | Inst::F32Ge
+        | Inst::F64Eq
+        | Inst::F64Ne
+        | Inst::F64Le
+        | Inst::F64Ge
+        | Inst::F64Lt
+        | Inst::F64Gt
+        | Inst::I32Add
+        | Inst::I32Sub
+        | Inst::I32Mul
+        | Inst::I32And
+        | Inst::I32Or
+        | Inst::I32Xor
+        | Inst::I32DivS
+        | Inst::I32DivU
+        | Inst::I32RemS
+        | Inst::I32RemU
+        | Inst::I32ShrS
+        | Inst::I32ShrU
+        | Inst::I32Rotl
+        | Inst::I32Shl
+        | Inst::I32Rotr
+        | Inst::I64Mul
+        | Inst::I64Add
+        | Inst::I64Sub
+        | Inst::I64DivS
+        | Inst::I64DivU
+        | Inst::I64RemS
+        | Inst::I64RemU
+        | Inst::I64Or
+        | Inst::I64ShrS
+        | Inst::I64ShrU
+        | Inst::I64Rotl
+        | Inst::I64Rotr
+        | Inst::I64Xor
+        | Inst::I64Shl
+        | Inst::I64And
+        | Inst::F32Add
+        | Inst::F32Sub
+        | Inst::F32Mul
+        | Inst::F32Div
+        | Inst::F32Min
+        | Inst::F32Max
+        | Inst::F32Copysign
+        | Inst::F64Add
+        | Inst::F64Sub
+        | Inst::F64Mul
+        | Inst::F64Div
+        | Inst::F64Min
+        | Inst::F64Max
+        | Inst::F64Copysign => (2, 1),
+        Inst::Block(..)
+        | Inst::Loop(..)
+        | Inst::IfElse(..)
+        | Inst::Break(_)
+        | Inst::BreakIf(_)
+        | Inst::BreakTable(..)
+        | Inst::Return => unreachable!("structured instructions are emitted separately"),
+    };
+    Ok(effect)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::{flatten, CompileError, Signatures};
+    use crate::repr::{BlockType, Branch, Inst, LabelIdx, ValType};
+
+    const EMPTY: BlockType = BlockType::Empty;
+    const I32: BlockType = BlockType::Inline(ValType::I32);
+
+    fn flat(body: &[Inst]) -> Vec<Inst> {
+        flatten(body, Some(0), &Signatures::default()).unwrap()
+    }
+
+    #[test]
+    fn block_branches_jump_past_the_block() {
+        let code = flat(&[
+            Inst::Block(
+                EMPTY,
+                vec![Inst::I32Const(1), Inst::BreakIf(LabelIdx(0)), Inst::Nop].into(),
+            ),
+            Inst::Nop,
+        ]);
+        assert!(matches!(
+            code[..],
+            [
+                Inst::I32Const(1),
+                Inst::JumpIf(Branch { target: 3, .. }),
+                Inst::Nop,
+                Inst::Nop
+            ]
+        ));
+    }
+
+    #[test]
+    fn loop_branches_jump_to_the_start() {
+        let code = flat(&[
+            Inst::Nop,
+            Inst::Loop(EMPTY, vec![Inst::Nop, Inst::Break(LabelIdx(0))].into()),
+        ]);
+        assert!(matches!(
+            code[..],
+            [Inst::Nop, Inst::Nop, Inst::Jump(Branch { target: 1, .. })]
+        ));
+    }
+
+    #[test]
+    fn if_else() {
+        let code = flat(&[
+            Inst::I32Const(0),
+            Inst::IfElse(
+                I32,
+                vec![Inst::I32Const(1)].into(),
+                vec![Inst::I32Const(2)].into(),
+            ),
+            Inst::Drop,
+        ]);
+        assert!(matches!(
+            code[..],
+            [
+                Inst::I32Const(0),
+                Inst::JumpUnless(4),
+                Inst::I32Const(1),
+                Inst::Jump(Branch {
+                    target: 5,
+                    height: 0,
+                    arity: 1
+                }),
+                Inst::I32Const(2),
+                Inst::Drop,
+            ]
+        ));
+    }
+
+    #[test]
+    fn branch_tables_resolve_every_label() {
+        let code = flat(&[Inst::Block(
+            EMPTY,
+            vec![Inst::Loop(
+                EMPTY,
+                vec![
+                    Inst::I32Const(0),
+                    Inst::BreakTable(vec![LabelIdx(0), LabelIdx(1)], LabelIdx(2)),
+                ]
+                .into(),
+            )]
+            .into(),
+        )]);
+        let [Inst::I32Const(0), Inst::JumpTable(targets, default)] = &code[..] else { panic!("expected a single jump table") };
+        let targets: Vec<_> = targets.iter().map(|b| b.target).collect();
+        assert_eq!(targets[..], [0, 2]);
+        assert_eq!(default.target, 2);
+    }
+
+    #[test]
+    fn branches_know_the_height_of_their_label() {
+        // (i32.const 1) (block (result i32) (i32.const 2) (i32.const 3) (br 0))
+        let code = flat(&[
+            Inst::I32Const(1),
+            Inst::Block(
+                I32,
+                vec![
+                    Inst::I32Const(2),
+                    Inst::I32Const(3),
+                    Inst::Break(LabelIdx(0)),
+                ]
+                .into(),
+            ),
+        ]);
+        assert!(matches!(
+            code[3],
+            Inst::Jump(Branch {
+                target: 4,
+                height: 1,
+                arity: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn return_jumps_past_the_end() {
+        let code = flatten(
+            &[
+                Inst::I32Const(1),
+                Inst::Block(EMPTY, vec![Inst::I32Const(2), Inst::Return].into()),
+            ],
+            Some(1),
+            &Signatures::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            code[2],
+            Inst::Jump(Branch {
+                target: 3,
+                height: 0,
+                arity: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn unknown_labels_are_an_error() {
+        let res = flatten(&[Inst::Break(LabelIdx(5))], Some(0), &Signatures::default());
+        assert!(matches!(res, Err(CompileError::UnknownLabel(5))));
+        let res = flatten(
+            &[
+                Inst::Block(EMPTY, vec![].into()),
+                Inst::BreakIf(LabelIdx(1)),
+            ],
+            Some(0),
+            &Signatures::default(),
+        );
+        assert!(matches!(res, Err(CompileError::UnknownLabel(1))));
+    }
+}
@@ -0,0 +1,122 @@
+//! Float operations that need `std`, with `libm` fallbacks for `no_std` builds.
+
+#[cfg(feature = "std")]
+mod imp {
+    pub fn abs_f32(x: f32) -> f32 {
+        x.abs()
+    }
+
+    pub fn abs_f64(x: f64) -> f64 {
+        x.abs()
+    }
+
+    pub fn ceil_f32(x: f32) -> f32 {
+        x.ceil()
+    }
+
+    pub fn ceil_f64(x: f64) -> f64 {
+        x.ceil()
+    }
+
+    pub fn floor_f32(x: f32) -> f32 {
+        x.floor()
+    }
+
+    pub fn floor_f64(x: f64) -> f64 {
+        x.floor()
+    }
+
+    pub fn trunc_f32(x: f32) -> f32 {
+        x.trunc()
+    }
+
+    pub fn trunc_f64(x: f64) -> f64 {
+        x.trunc()
+    }
+
+    pub fn nearest_f32(x: f32) -> f32 {
+        x.round_ties_even()
+    }
+
+    pub fn nearest_f64(x: f64) -> f64 {
+        x.round_ties_even()
+    }
+
+    pub fn sqrt_f32(x: f32) -> f32 {
+        x.sqrt()
+    }
+
+    pub fn sqrt_f64(x: f64) -> f64 {
+        x.sqrt()
+    }
+
+    pub fn copysign_f32(x: f32, y: f32) -> f32 {
+        x.copysign(y)
+    }
+
+    pub fn copysign_f64(x: f64, y: f64) -> f64 {
+        x.copysign(y)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod imp {
+    pub fn abs_f32(x: f32) -> f32 {
+        libm::fabsf(x)
+    }
+
+    pub fn abs_f64(x: f64) -> f64 {
+        libm::fabs(x)
+    }
+
+    pub fn ceil_f32(x: f32) -> f32 {
+        libm::ceilf(x)
+    }
+
+    pub fn ceil_f64(x: f64) -> f64 {
+        libm::ceil(x)
+    }
+
+    pub fn floor_f32(x: f32) -> f32 {
+        libm::floorf(x)
+    }
+
+    pub fn floor_f64(x: f64) -> f64 {
+        libm::floor(x)
+    }
+
+    pub fn trunc_f32(x: f32) -> f32 {
+        libm::truncf(x)
+    }
+
+    pub fn trunc_f64(x: f64) -> f64 {
+        libm::trunc(x)
+    }
+
+    // rint rounds in the default mode, which is ties to even
+    pub fn nearest_f32(x: f32) -> f32 {
+        libm::rintf(x)
+    }
+
+    pub fn nearest_f64(x: f64) -> f64 {
+        libm::rint(x)
+    }
+
+    pub fn sqrt_f32(x: f32) -> f32 {
+        libm::sqrtf(x)
+    }
+
+    pub fn sqrt_f64(x: f64) -> f64 {
+        libm::sqrt(x)
+    }
+
+    pub fn copysign_f32(x: f32, y: f32) -> f32 {
+        libm::copysignf(x, y)
+    }
+
+    pub fn copysign_f64(x: f64, y: f64) -> f64 {
+        libm::copysign(x, y)
+    }
+}
+
+pub(crate) use imp::*;
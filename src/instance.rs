@@ -1,42 +1,135 @@
-use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+use alloc::{boxed::Box, collections::BTreeMap, rc::Rc, string::String, vec, vec::Vec};
+use core::{cell::RefCell, cmp::Ordering, ffi::CStr};
 
 use crate::{
-    repr::{Datamode, Func, FuncType, MemType, Module, TableIdx, TableType},
-    rt::{self, Locals, Machine, Stack, Val},
+    flat::{flatten, Arity, CompileError, Signatures},
+    repr::{
+        Datamode, ElemIdx, ElemMode, ExportDesc, Func, FuncIdx, FuncType, GlobalIdx, GlobalType,
+        Import, ImportDesc, Inst, Limits, MemIdx, MemType, Module, Reftype, TableIdx, TableType,
+        TypeIdx,
+    },
+    rt::{self, Locals, Machine, Val},
 };
 
 pub enum FuncInst {
     Local {
-        typ: FuncType,
+        typ: Rc<FuncType>,
         module: Rc<RefCell<ModuleInst>>,
-        code: Func,
+        /// the function with its body already flattened
+        code: Rc<Func>,
     },
     External {
-        typ: FuncType,
+        typ: Rc<FuncType>,
         func: Box<dyn WasmFfi>,
     },
 }
 
+impl FuncInst {
+    pub fn typ(&self) -> &FuncType {
+        match self {
+            FuncInst::Local { typ, .. } | FuncInst::External { typ, .. } => typ,
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct Store {
     pub funcs: Vec<Rc<FuncInst>>,
     pub mems: Vec<MemInstInner>,
     pub tables: Vec<TableInstInner>,
+    pub globals: Vec<GlobalInst>,
+    pub elems: Vec<ElemInst>,
+    /// asked before a memory grows, see [`Store::set_memory_limiter`]
+    memory_limiter: Option<Box<dyn FnMut(u64, u64) -> bool>>,
 }
 
 impl Store {
-    fn allocfunc(&mut self, func: Func, moduleinst: Rc<RefCell<ModuleInst>>) -> FuncAddr {
+    /// An empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Snapshots the mutable state of the store: memories, tables, globals
+    /// and element segments.
+    ///
+    /// This copies every linear memory in full, so it is only cheap for small
+    /// memories. Functions are immutable and are shared with the checkpoint.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            funcs: self.funcs.len(),
+            mems: self.mems.clone(),
+            tables: self.tables.clone(),
+            globals: self.globals.clone(),
+            elems: self.elems.clone(),
+        }
+    }
+
+    /// The memory at `addr`, e.g. one found with [`ModuleInst::get_export`].
+    pub fn mem(&self, addr: MemAddr) -> &MemInstInner {
+        &self.mems[addr.0]
+    }
+
+    pub fn mem_mut(&mut self, addr: MemAddr) -> &mut MemInstInner {
+        &mut self.mems[addr.0]
+    }
+
+    /// Installs a callback that runs before every `memory.grow` with the
+    /// current and the requested size in pages. If it returns `false` the
+    /// memory keeps its size and the instruction fails with `-1`.
+    pub fn set_memory_limiter(&mut self, limiter: impl FnMut(u64, u64) -> bool + 'static) {
+        self.memory_limiter = Some(Box::new(limiter));
+    }
+
+    /// Grows the memory at `addr` by `delta` pages and returns its previous
+    /// size, or `None` if the memory can't or may not grow that far.
+    pub(crate) fn grow_memory(&mut self, addr: MemAddr, delta: u64) -> Option<u64> {
+        let mem = &mut self.mems[addr.0];
+        let current = mem.pages();
+        let requested = current
+            .checked_add(delta)
+            .filter(|&pages| pages <= mem.max_pages())?;
+        if let Some(limiter) = &mut self.memory_limiter {
+            if !limiter(current, requested) {
+                return None;
+            }
+        }
+        // failing to allocate fails the grow, rather than aborting
+        let bytes = requested as usize * WASM_PAGE_SIZE;
+        mem.data.try_reserve_exact(bytes - mem.len()).ok()?;
+        mem.data.resize(bytes, 0);
+        Some(current)
+    }
+
+    /// Rolls the store back to `checkpoint`. Anything allocated since, e.g. by
+    /// instantiating another module, is dropped again.
+    pub fn restore(&mut self, checkpoint: Checkpoint) {
+        self.funcs.truncate(checkpoint.funcs);
+        self.mems = checkpoint.mems;
+        self.tables = checkpoint.tables;
+        self.globals = checkpoint.globals;
+        self.elems = checkpoint.elems;
+    }
+
+    fn allocfunc(
+        &mut self,
+        code: Rc<Func>,
+        moduleinst: Rc<RefCell<ModuleInst>>,
+    ) -> Result<FuncAddr, InstantiationError> {
         let addr = self.funcs.len();
-        let functype = moduleinst.borrow().types[func.typ.0 as usize].clone();
+        let functype = moduleinst
+            .borrow()
+            .func_type(code.typ)
+            .ok_or(InstantiationError::UnknownType(code.typ))?;
         let funcinst = FuncInst::Local {
-            code: func,
+            code,
             typ: functype,
             module: moduleinst.clone(),
         };
         self.funcs.push(Rc::new(funcinst));
-        return FuncAddr(addr);
+        Ok(FuncAddr(addr))
     }
 
-    fn allochostfunc(&mut self, functype: FuncType, hostfunc: Box<dyn WasmFfi>) -> FuncAddr {
+    fn allochostfunc(&mut self, functype: Rc<FuncType>, hostfunc: Box<dyn WasmFfi>) -> FuncAddr {
         let addr = self.funcs.len();
         let funcinst = FuncInst::External {
             typ: functype,
@@ -46,53 +139,223 @@ impl Store {
         return FuncAddr(addr);
     }
 
-    fn allocmem(&mut self, memtype: MemType) -> MemAddr {
+    /// Like growing, failing to allocate the initial pages is an error
+    /// rather than an abort.
+    fn allocmem(&mut self, memtype: MemType) -> Result<MemAddr, InstantiationError> {
         let addr = self.mems.len();
-        let mem = MemInstInner::new(memtype.limits.min as usize * WASM_PAGE_SIZE);
-        self.mems.push(mem);
-        return MemAddr(addr);
+        let bytes = Some(memtype.limits.min)
+            .filter(|&pages| pages <= MAX_PAGES)
+            .and_then(|pages| usize::try_from(pages).ok()?.checked_mul(WASM_PAGE_SIZE))
+            .ok_or(InstantiationError::MemoryTooLarge)?;
+        let mut data = Vec::new();
+        data.try_reserve_exact(bytes)
+            .map_err(|_| InstantiationError::MemoryTooLarge)?;
+        data.resize(bytes, 0);
+        self.mems.push(MemInstInner {
+            data,
+            max: memtype.limits.max,
+        });
+        Ok(MemAddr(addr))
     }
 
-    fn alloctable(&mut self, tabletype: TableType, init: rt::Ref) -> TableAddr {
+    fn alloctable(
+        &mut self,
+        tabletype: TableType,
+        init: rt::Ref,
+    ) -> Result<TableAddr, InstantiationError> {
         let addr = self.tables.len();
-        let n = tabletype.limits.min;
-        let tableinst = TableInstInner {
+        let n =
+            usize::try_from(tabletype.limits.min).map_err(|_| InstantiationError::TableTooLarge)?;
+        let mut elem = Vec::new();
+        elem.try_reserve_exact(n)
+            .map_err(|_| InstantiationError::TableTooLarge)?;
+        elem.resize(n, init);
+        self.tables.push(TableInstInner {
             typ: tabletype,
-            elem: vec![init; n as usize],
-        };
-        self.tables.push(tableinst);
-        return TableAddr(addr);
+            elem,
+        });
+        Ok(TableAddr(addr))
+    }
+
+    fn allocglobal(&mut self, typ: GlobalType, value: Val) -> GlobalAddr {
+        let addr = self.globals.len();
+        self.globals.push(GlobalInst { typ, value });
+        return GlobalAddr(addr);
+    }
+
+    fn allocelem(&mut self, typ: Reftype, elem: Vec<rt::Ref>) -> ElemAddr {
+        let addr = self.elems.len();
+        self.elems.push(ElemInst { typ, elem });
+        return ElemAddr(addr);
     }
 }
 
+/// The state of a [`Store`] saved by [`Store::checkpoint`].
+pub struct Checkpoint {
+    funcs: usize,
+    mems: Vec<MemInstInner>,
+    tables: Vec<TableInstInner>,
+    globals: Vec<GlobalInst>,
+    elems: Vec<ElemInst>,
+}
+
 pub const WASM_PAGE_SIZE: usize = 65536;
 
+#[derive(Clone)]
 pub struct MemInstInner {
     pub data: Vec<u8>,
+    /// the most pages the memory may grow to
+    pub max: Option<u64>,
 }
 
+/// A 32 bit memory can't address more pages than this.
+const MAX_PAGES: u64 = 65536;
+
 impl MemInstInner {
     fn new(bytes: usize) -> Self {
         Self {
             data: vec![0u8; bytes],
+            max: None,
         }
     }
 
     pub fn len(&self) -> usize {
         self.data.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The size of the memory in pages.
+    pub fn pages(&self) -> u64 {
+        (self.len() / WASM_PAGE_SIZE) as u64
+    }
+
+    fn max_pages(&self) -> u64 {
+        self.max.unwrap_or(MAX_PAGES).min(MAX_PAGES)
+    }
+
+    fn check_bounds(&self, offset: usize, len: usize) -> Result<(), rt::Error> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len() => Ok(()),
+            _ => Err(rt::Error::OobAccess { addr: offset, len }),
+        }
+    }
+
+    pub fn read(&self, offset: usize, len: usize) -> Result<&[u8], rt::Error> {
+        self.check_bounds(offset, len)?;
+        Ok(&self.data[offset..offset + len])
+    }
+
+    /// The `len` bytes at `offset` to write to in place, like a buffer whose
+    /// pointer and length came from guest code. Out of bounds ranges are an
+    /// error instead of a panic, as with [`MemInstInner::read`].
+    pub fn read_mut(&mut self, offset: usize, len: usize) -> Result<&mut [u8], rt::Error> {
+        self.check_bounds(offset, len)?;
+        Ok(&mut self.data[offset..offset + len])
+    }
+
+    pub fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<(), rt::Error> {
+        self.read_mut(offset, bytes.len())?.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn read_u32(&self, offset: usize) -> Result<u32, rt::Error> {
+        let bytes = self.read(offset, 4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn write_u32(&mut self, offset: usize, val: u32) -> Result<(), rt::Error> {
+        self.write(offset, &val.to_le_bytes())
+    }
+
+    /// Reads the NUL-terminated string starting at `offset`.
+    pub fn read_cstr(&self, offset: usize) -> Result<&CStr, rt::Error> {
+        let rest = self.read(offset, self.len().saturating_sub(offset))?;
+        CStr::from_bytes_until_nul(rest).map_err(|_| rt::Error::OobAccess {
+            addr: offset,
+            len: rest.len() + 1,
+        })
+    }
 }
 
+fn check_table_bounds(offset: usize, len: usize, size: usize) -> Result<(), rt::Error> {
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(rt::Error::OobTableAccess { idx: offset, len }),
+    }
+}
+
+#[derive(Clone)]
 pub struct TableInstInner {
     typ: TableType,
     elem: Vec<rt::Ref>,
 }
 
+impl TableInstInner {
+    pub fn len(&self) -> usize {
+        self.elem.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elem.is_empty()
+    }
+
+    pub fn read(&self, offset: usize, len: usize) -> Result<&[rt::Ref], rt::Error> {
+        check_table_bounds(offset, len, self.len())?;
+        Ok(&self.elem[offset..offset + len])
+    }
+
+    pub fn write(&mut self, offset: usize, refs: &[rt::Ref]) -> Result<(), rt::Error> {
+        check_table_bounds(offset, refs.len(), self.len())?;
+        self.elem[offset..offset + refs.len()].copy_from_slice(refs);
+        Ok(())
+    }
+}
+
+/// An element segment; `elem.drop` clears it.
+#[derive(Clone)]
+pub struct ElemInst {
+    typ: Reftype,
+    elem: Vec<rt::Ref>,
+}
+
+impl ElemInst {
+    pub fn len(&self) -> usize {
+        self.elem.len()
+    }
+
+    /// Whether the segment is empty, e.g. because it was dropped.
+    pub fn is_empty(&self) -> bool {
+        self.elem.is_empty()
+    }
+
+    pub fn read(&self, offset: usize, len: usize) -> Result<&[rt::Ref], rt::Error> {
+        check_table_bounds(offset, len, self.len())?;
+        Ok(&self.elem[offset..offset + len])
+    }
+
+    pub fn clear(&mut self) {
+        self.elem.clear();
+    }
+}
+
+#[derive(Clone)]
+pub struct GlobalInst {
+    pub typ: GlobalType,
+    pub value: Val,
+}
+
 pub struct ModuleInst {
-    types: Vec<FuncType>,
+    types: Vec<Rc<FuncType>>,
     pub func_addrs: Vec<FuncAddr>,
     pub mem_addrs: Vec<MemAddr>,
     pub table_addrs: Vec<TableAddr>,
+    pub global_addrs: Vec<GlobalAddr>,
+    pub elem_addrs: Vec<ElemAddr>,
+    /// the module's exports, resolved to store addresses
+    pub(crate) exports: Vec<(String, ExternAddr)>,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -104,16 +367,67 @@ pub struct TableAddr(pub(crate) usize);
 #[derive(Copy, Clone, Debug)]
 pub struct MemAddr(pub(crate) usize);
 
+#[derive(Copy, Clone, Debug)]
+pub struct GlobalAddr(pub(crate) usize);
+
+#[derive(Copy, Clone, Debug)]
+pub struct ElemAddr(pub(crate) usize);
+
+/// The address of anything in a [`Store`] that can satisfy an import.
+#[derive(Copy, Clone, Debug)]
+pub enum ExternAddr {
+    Func(FuncAddr),
+    Table(TableAddr),
+    Mem(MemAddr),
+    Global(GlobalAddr),
+}
+
 impl ModuleInst {
+    /// The store address of the export `name`.
+    pub fn get_export(&self, name: &str) -> Option<ExternAddr> {
+        self.exports
+            .iter()
+            .find_map(|(export, addr)| (export == name).then_some(*addr))
+    }
+
+    /// The arities of the module's types and functions, for flattening code
+    /// that runs in the module.
+    pub(crate) fn signatures(&self, store: &Store) -> Signatures {
+        Signatures {
+            types: self.types.iter().map(|t| Arity::from(&**t)).collect(),
+            funcs: self
+                .func_addrs
+                .iter()
+                .map(|addr| Arity::from(store.funcs[addr.0].typ()))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn func_type(&self, idx: TypeIdx) -> Option<Rc<FuncType>> {
+        self.types.get(idx.0 as usize).cloned()
+    }
+
     pub(crate) fn table_addr(&self, idx: TableIdx) -> Option<TableAddr> {
         self.table_addrs.get(idx.0 as usize).copied()
     }
+
+    pub(crate) fn mem_addr(&self, idx: MemIdx) -> Option<MemAddr> {
+        self.mem_addrs.get(idx.0 as usize).copied()
+    }
+
+    pub(crate) fn global_addr(&self, idx: GlobalIdx) -> Option<GlobalAddr> {
+        self.global_addrs.get(idx.0 as usize).copied()
+    }
+
+    pub(crate) fn elem_addr(&self, idx: ElemIdx) -> Option<ElemAddr> {
+        self.elem_addrs.get(idx.0 as usize).copied()
+    }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Name {
-    toplevel: String,
-    secondlevel: String,
+    toplevel: Box<str>,
+    secondlevel: Box<str>,
 }
 
 impl Name {
@@ -123,8 +437,72 @@ impl Name {
             secondlevel: second.into(),
         }
     }
+
+    pub fn module(&self) -> &str {
+        &self.toplevel
+    }
+
+    pub fn name(&self) -> &str {
+        &self.secondlevel
+    }
+}
+
+impl From<(&str, &str)> for Name {
+    fn from((top, second): (&str, &str)) -> Self {
+        Self::new(top, second)
+    }
+}
+
+/// A name that can be looked up in a map keyed by [`Name`], e.g.
+/// `("env", "memory")`, without allocating an owned `Name` first.
+pub trait NameRef {
+    fn parts(&self) -> (&str, &str);
+}
+
+impl NameRef for Name {
+    fn parts(&self) -> (&str, &str) {
+        (&self.toplevel, &self.secondlevel)
+    }
+}
+
+impl NameRef for (&str, &str) {
+    fn parts(&self) -> (&str, &str) {
+        (self.0, self.1)
+    }
+}
+
+impl<'a> core::borrow::Borrow<dyn NameRef + 'a> for Name {
+    fn borrow(&self) -> &(dyn NameRef + 'a) {
+        self
+    }
+}
+
+// has to agree with the derived order of `Name`
+impl Ord for dyn NameRef + '_ {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.parts().cmp(&other.parts())
+    }
+}
+
+impl PartialOrd for dyn NameRef + '_ {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
+impl PartialEq for dyn NameRef + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.parts() == other.parts()
+    }
+}
+
+impl Eq for dyn NameRef + '_ {}
+
+/// A function implemented by the host.
+///
+/// With `std`, a panic in [`WasmFfi::call`] is caught and turned into a
+/// [`rt::Error::HostTrap`]. Changes the function made to the store before it
+/// panicked are not rolled back.
 pub trait WasmFfi {
     fn call(&self, store: &mut Store, args: &[Val]) -> Vec<Val>;
 }
@@ -141,84 +519,973 @@ where
 
 pub enum ExternVal {
     ExternalFunc(Box<dyn WasmFfi>),
+    Global(Val),
 }
 
+#[derive(Default)]
 pub struct Externals {
     pub values: BTreeMap<Name, ExternVal>,
 }
 
 impl Externals {
-    fn get_func(&mut self, name: Name) -> Option<Box<dyn WasmFfi>> {
-        let v = self.values.remove(&name)?;
-        match v {
-            ExternVal::ExternalFunc(func) => Some(func),
+    pub fn get(&self, module: &str, name: &str) -> Option<&ExternVal> {
+        self.values.get(&(module, name) as &dyn NameRef)
+    }
+}
+
+/// Why a module couldn't be instantiated.
+#[derive(Debug)]
+pub enum InstantiationError {
+    /// Nothing is defined under the name of an import.
+    MissingImport(Name),
+    /// The definition for an import is of the wrong kind or type.
+    ImportTypeMismatch(Name),
+    /// A function body refers to a label, type or function that doesn't
+    /// exist.
+    Compile(CompileError),
+    /// A function or function import has a type the module doesn't define.
+    UnknownType(TypeIdx),
+    /// The start function isn't defined.
+    UnknownFunction(FuncIdx),
+    /// An export refers to something the module doesn't define.
+    UnknownExport(String),
+    /// A memory is larger than 32 bit addresses reach, or than the host can
+    /// allocate.
+    MemoryTooLarge,
+    /// A table is larger than the host can allocate.
+    TableTooLarge,
+    /// Evaluating the initializer of a global trapped.
+    GlobalInitFailed(rt::Exception),
+    /// Evaluating an element segment or its offset trapped.
+    ElemInitFailed(rt::Exception),
+    /// Evaluating the offset of a data segment trapped.
+    DataOffsetEvalFailed(rt::Exception),
+    /// An active segment doesn't fit its table or memory.
+    SegmentOutOfBounds(rt::Error),
+}
+
+impl From<CompileError> for InstantiationError {
+    fn from(e: CompileError) -> Self {
+        InstantiationError::Compile(e)
+    }
+}
+
+/// Resolves the imports of modules by name, against host definitions and the
+/// exports of registered instances.
+///
+/// Host values are allocated in the store the first time they are imported
+/// and shared by every later import, so a linker must only be used with a
+/// single [`Store`].
+#[derive(Default)]
+pub struct Linker {
+    /// host values that haven't been allocated yet
+    host: BTreeMap<Name, ExternVal>,
+    defs: BTreeMap<Name, ExternAddr>,
+}
+
+impl Linker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Defines `module::name` as a host value.
+    pub fn define(&mut self, module: &str, name: &str, val: ExternVal) {
+        let name = Name::new(module, name);
+        self.defs.remove(&name);
+        self.host.insert(name, val);
+    }
+
+    /// Makes every export of `instance` importable from `module`.
+    pub fn register_instance(&mut self, module: &str, instance: &ModuleInst) {
+        for (name, addr) in &instance.exports {
+            let name = Name::new(module, name);
+            self.host.remove(&name);
+            self.defs.insert(name, *addr);
+        }
+    }
+
+    fn resolve(
+        &mut self,
+        store: &mut Store,
+        module: &Module,
+        import: &Import,
+    ) -> Result<ExternAddr, InstantiationError> {
+        let key = (import.module.as_str(), import.nm.as_str());
+        let name = || Name::from(key);
+        if let ImportDesc::Func(t) = import.desc {
+            module
+                .types
+                .get(t.0 as usize)
+                .ok_or(InstantiationError::UnknownType(t))?;
+        }
+        if let Some(val) = self.host.remove(&key as &dyn NameRef) {
+            match alloc_host(store, module, &import.desc, val) {
+                Ok(addr) => self.defs.insert(name(), addr),
+                // another import may still match it
+                Err(val) => {
+                    self.host.insert(name(), val);
+                    return Err(InstantiationError::ImportTypeMismatch(name()));
+                }
+            };
+        }
+        let Some(addr) = self.defs.get(&key as &dyn NameRef).copied() else {
+            return Err(InstantiationError::MissingImport(name()));
+        };
+        let compatible = match (&import.desc, addr) {
+            (ImportDesc::Func(t), ExternAddr::Func(a)) => {
+                module.types.get(t.0 as usize) == Some(store.funcs[a.0].typ())
+            }
+            (ImportDesc::Table(t), ExternAddr::Table(a)) => {
+                let table = &store.tables[a.0];
+                table.typ.reftype == t.reftype
+                    && limits_match(table.len() as u64, table.typ.limits.max, &t.limits)
+            }
+            (ImportDesc::Mem(m), ExternAddr::Mem(a)) => {
+                let mem = &store.mems[a.0];
+                limits_match(mem.pages(), mem.max, &m.limits)
+            }
+            (ImportDesc::Global(t), ExternAddr::Global(a)) => store.globals[a.0].typ == *t,
+            _ => false,
+        };
+        if !compatible {
+            return Err(InstantiationError::ImportTypeMismatch(name()));
+        }
+        Ok(addr)
+    }
+
+    fn resolve_all(
+        &mut self,
+        store: &mut Store,
+        module: &Module,
+    ) -> Result<Vec<ExternAddr>, InstantiationError> {
+        module
+            .imports
+            .iter()
+            .map(|import| self.resolve(store, module, import))
+            .collect()
+    }
+
+    /// Resolves the imports of `module` and instantiates it.
+    pub fn instantiate(
+        &mut self,
+        module: &Module,
+        store: &mut Store,
+    ) -> Result<Rc<RefCell<ModuleInst>>, InstantiationError> {
+        let imports = self.resolve_all(store, module)?;
+        let (types, code) = compile(module)?;
+        instantiate_resolved(module, &types, &code, store, &imports)
+    }
+
+    /// Like [`Linker::instantiate`], but reuses the work already done for a
+    /// [`CompiledModule`].
+    pub fn instantiate_compiled(
+        &mut self,
+        compiled: &CompiledModule,
+        store: &mut Store,
+    ) -> Result<Rc<RefCell<ModuleInst>>, InstantiationError> {
+        let imports = self.resolve_all(store, &compiled.module)?;
+        instantiate_resolved(
+            &compiled.module,
+            &compiled.types,
+            &compiled.code,
+            store,
+            &imports,
+        )
+    }
+}
+
+/// A module prepared for instantiation: function bodies are flattened and
+/// types are shared, so each instance only allocates its own state.
+///
+/// Instantiating the same module many times, e.g. once per request in a
+/// fresh [`Store`], should compile it once and use
+/// [`Linker::instantiate_compiled`].
+pub struct CompiledModule {
+    module: Module,
+    types: Vec<Rc<FuncType>>,
+    code: Vec<Rc<Func>>,
+}
+
+impl CompiledModule {
+    pub fn new(module: Module) -> Result<Self, InstantiationError> {
+        let (types, code) = compile(&module)?;
+        Ok(Self {
+            module,
+            types,
+            code,
+        })
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+}
+
+/// The types of a module, shared between instances, and its functions with
+/// flattened bodies.
+type Code = (Vec<Rc<FuncType>>, Vec<Rc<Func>>);
+
+/// The per-module work of instantiation, which doesn't depend on the store.
+fn compile(module: &Module) -> Result<Code, CompileError> {
+    let types = module.types.iter().cloned().map(Rc::new).collect();
+    // this checks the type of every function, too
+    let signatures = Signatures::new(module)?;
+    // the flattened body replaces the original, so only the locals are copied
+    let code = module
+        .funcs
+        .iter()
+        .map(|func| {
+            let results = signatures.types[func.typ.0 as usize].results;
+            Ok(Rc::new(Func {
+                typ: func.typ,
+                locals: func.locals.clone(),
+                body: flatten(&func.body, Some(results), &signatures)?,
+            }))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok((types, code))
+}
+
+/// Whether a table or memory of `min` elements or pages, that may grow to
+/// `max`, can be imported as one with the `expected` limits.
+fn limits_match(min: u64, max: Option<u64>, expected: &Limits) -> bool {
+    let max_fits = match expected.max {
+        Some(expected) => max.is_some_and(|max| max <= expected),
+        None => true,
+    };
+    min >= expected.min && max_fits
+}
+
+/// Allocates a host value for an import, or hands it back if it doesn't
+/// match the import.
+fn alloc_host(
+    store: &mut Store,
+    module: &Module,
+    desc: &ImportDesc,
+    val: ExternVal,
+) -> Result<ExternAddr, ExternVal> {
+    match (desc, val) {
+        (ImportDesc::Func(t), ExternVal::ExternalFunc(func)) => {
+            let Some(functype) = module.types.get(t.0 as usize) else {
+                return Err(ExternVal::ExternalFunc(func));
+            };
+            let functype = Rc::new(functype.clone());
+            Ok(ExternAddr::Func(store.allochostfunc(functype, func)))
+        }
+        (ImportDesc::Global(t), ExternVal::Global(val)) if val.typ() == t.valtype => {
+            Ok(ExternAddr::Global(store.allocglobal(*t, val)))
         }
+        (_, val) => Err(val),
     }
 }
 
+// TODO: this whole thing is entirely not to spec: improve
+fn evaluate(
+    store: &mut Store,
+    inst: Rc<RefCell<ModuleInst>>,
+    expr: &[Inst],
+) -> Result<Val, rt::Exception> {
+    let mut m = Machine::new(store);
+    m.execute(inst, expr, &mut Locals::empty())?;
+    Ok(m.stack.pop()?)
+}
+
+/// Instantiates `module` with imports from `externals`. Use a [`Linker`] to
+/// import from other instances.
 pub fn instantiate(
     module: &Module,
     store: &mut Store,
-    mut externals: Externals,
-) -> Rc<RefCell<ModuleInst>> {
+    externals: Externals,
+) -> Result<Rc<RefCell<ModuleInst>>, InstantiationError> {
+    let mut linker = Linker {
+        host: externals.values,
+        defs: BTreeMap::new(),
+    };
+    linker.instantiate(module, store)
+}
+
+/// Instantiates `module`, compiled to `types` and `code`, with `imports`
+/// holding the address of every import.
+fn instantiate_resolved(
+    module: &Module,
+    types: &[Rc<FuncType>],
+    code: &[Rc<Func>],
+    store: &mut Store,
+    imports: &[ExternAddr],
+) -> Result<Rc<RefCell<ModuleInst>>, InstantiationError> {
     let inst = Rc::new(RefCell::new(ModuleInst {
-        types: vec![],
+        types: types.to_vec(),
         func_addrs: vec![],
         mem_addrs: vec![],
         table_addrs: vec![],
+        global_addrs: vec![],
+        elem_addrs: vec![],
+        exports: vec![],
     }));
-    for typ in &module.types {
-        inst.borrow_mut().types.push(typ.clone());
-    }
-    for import in &module.imports {
-        println!("{:?}::{:?}", import.module, import.nm);
-        match import.desc {
-            crate::repr::ImportDesc::Func(t) => {
-                let functype = module.types[t.0 as usize].clone();
-                let hostfunc = externals
-                    .get_func(Name::new(&import.module, &import.nm))
-                    .unwrap();
-                let funcaddr = store.allochostfunc(functype, hostfunc);
-                inst.borrow_mut().func_addrs.push(funcaddr);
-            }
-            crate::repr::ImportDesc::Table {} => todo!(),
-            crate::repr::ImportDesc::Mem {} => todo!(),
-            crate::repr::ImportDesc::Global {} => todo!(),
+    // imports come first in every index space, so they are resolved before
+    // anything defined by the module itself
+    for (import, addr) in module.imports.iter().zip(imports) {
+        trace!("{:?}::{:?}", import.module, import.nm);
+        let mut inst = inst.borrow_mut();
+        match *addr {
+            ExternAddr::Func(addr) => inst.func_addrs.push(addr),
+            ExternAddr::Table(addr) => inst.table_addrs.push(addr),
+            ExternAddr::Mem(addr) => inst.mem_addrs.push(addr),
+            ExternAddr::Global(addr) => inst.global_addrs.push(addr),
         }
     }
 
-    for func in &module.funcs {
-        let funcaddr = store.allocfunc(func.clone(), inst.clone());
+    for func in code {
+        let funcaddr = store.allocfunc(func.clone(), inst.clone())?;
         inst.borrow_mut().func_addrs.push(funcaddr);
     }
 
     for table in &module.tables {
         let typ = table.reftype;
-        let tableaddr = store.alloctable(table.clone(), rt::Ref::Null(typ));
+        let tableaddr = store.alloctable(table.clone(), rt::Ref::Null(typ))?;
         inst.borrow_mut().table_addrs.push(tableaddr);
     }
 
     for mem in &module.mems {
-        let memaddr = store.allocmem(mem.clone());
+        let memaddr = store.allocmem(mem.clone())?;
         inst.borrow_mut().mem_addrs.push(memaddr);
     }
 
+    // global initializers may refer to imported globals, which are in place by now
+    for global in &module.globals {
+        let val = evaluate(store, inst.clone(), &global.init)
+            .map_err(InstantiationError::GlobalInitFailed)?;
+        let globaladdr = store.allocglobal(global.typ, val);
+        inst.borrow_mut().global_addrs.push(globaladdr);
+    }
+
+    for elem in &module.elems {
+        let refs = elem
+            .init
+            .iter()
+            .map(|expr| match evaluate(store, inst.clone(), expr)? {
+                Val::Reference(r) => Ok(r),
+                _ => Err(rt::Error::WrongValType.into()),
+            })
+            .collect::<Result<_, rt::Exception>>()
+            .map_err(InstantiationError::ElemInitFailed)?;
+        let elemaddr = store.allocelem(elem.typ, refs);
+        inst.borrow_mut().elem_addrs.push(elemaddr);
+    }
+
+    // active segments are copied into their table right away, and like
+    // declarative ones they can't be used afterwards
+    for (elem, elemaddr) in module.elems.iter().zip(inst.borrow().elem_addrs.clone()) {
+        if let ElemMode::Active { table, offset } = &elem.mode {
+            let offset = evaluate(store, inst.clone(), offset);
+            let Val::I32(offset) = offset.map_err(InstantiationError::ElemInitFailed)? else {
+                return Err(InstantiationError::ElemInitFailed(
+                    rt::Error::WrongValType.into(),
+                ));
+            };
+            let table_addr =
+                inst.borrow()
+                    .table_addr(*table)
+                    .ok_or(InstantiationError::SegmentOutOfBounds(
+                        rt::Error::TableNotFound,
+                    ))?;
+            let Store { tables, elems, .. } = &mut *store;
+            tables[table_addr.0]
+                .write(offset as u32 as usize, &elems[elemaddr.0].elem)
+                .map_err(InstantiationError::SegmentOutOfBounds)?;
+        }
+        if !matches!(elem.mode, ElemMode::Passive) {
+            store.elems[elemaddr.0].clear();
+        }
+    }
+
     for data in &module.datas {
         if let Datamode::Active { memory, offset } = &data.mode {
-            assert!(memory.0 == 0);
-            // TODO: this whole thing is entirely not to spec: improve
-            let mut m = Machine {
-                stack: Stack::new(),
-                store,
+            let offset = evaluate(store, inst.clone(), offset);
+            let Val::I32(offset) = offset.map_err(InstantiationError::DataOffsetEvalFailed)? else {
+                return Err(InstantiationError::DataOffsetEvalFailed(
+                    rt::Error::WrongValType.into(),
+                ));
+            };
+            let mem_addr =
+                inst.borrow()
+                    .mem_addr(*memory)
+                    .ok_or(InstantiationError::SegmentOutOfBounds(
+                        rt::Error::MemoryNotFound,
+                    ))?;
+            store.mems[mem_addr.0]
+                .write(offset as u32 as usize, &data.init)
+                .map_err(InstantiationError::SegmentOutOfBounds)?;
+        }
+    }
+
+    let exports = module
+        .exports
+        .iter()
+        .map(|export| {
+            let inst = inst.borrow();
+            let addr = match export.desc {
+                ExportDesc::Func(idx) => inst
+                    .func_addrs
+                    .get(idx.0 as usize)
+                    .copied()
+                    .map(ExternAddr::Func),
+                ExportDesc::Table(idx) => inst.table_addr(idx).map(ExternAddr::Table),
+                ExportDesc::Mem(idx) => inst.mem_addr(idx).map(ExternAddr::Mem),
+                ExportDesc::Global(idx) => inst.global_addr(idx).map(ExternAddr::Global),
+            };
+            let addr =
+                addr.ok_or_else(|| InstantiationError::UnknownExport(export.name.clone()))?;
+            Ok((export.name.clone(), addr))
+        })
+        .collect::<Result<_, InstantiationError>>()?;
+    inst.borrow_mut().exports = exports;
+    Ok(inst)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::BTreeMap, rc::Rc, vec, vec::Vec};
+
+    use super::{
+        instantiate, CompileError, CompiledModule, ExternVal, Externals, FuncInst,
+        InstantiationError, Linker, MemInstInner, Name, Store,
+    };
+    use crate::repr::{
+        BlockType, Data, Datamode, Elem, ElemMode, Export, ExportDesc, Func, FuncIdx, FuncType,
+        Global, GlobalIdx, GlobalType, Import, ImportDesc, Inst, LabelIdx, Limits, MemIdx, MemType,
+        Module, Reftype, ResultType, TableIdx, TableType, TypeIdx, ValType,
+    };
+    use crate::rt::{Error, Exception, Ref, Val};
+
+    const I32_CONST: GlobalType = GlobalType {
+        valtype: ValType::I32,
+        mutable: false,
+    };
+
+    #[test]
+    fn globals_initialized_from_imports() {
+        let mut module = Module::default();
+        module.imports.push(Import {
+            module: "env".into(),
+            nm: "base".into(),
+            desc: ImportDesc::Global(I32_CONST),
+        });
+        module.globals.push(Global {
+            typ: I32_CONST,
+            init: vec![Inst::GlobalGet(GlobalIdx(0))],
+        });
+        module.globals.push(Global {
+            typ: I32_CONST,
+            init: vec![Inst::I32Const(7)],
+        });
+
+        let mut values = BTreeMap::new();
+        values.insert(Name::new("env", "base"), ExternVal::Global(Val::I32(42)));
+        let mut store = Store::new();
+        let inst = instantiate(&module, &mut store, Externals { values }).unwrap();
+
+        let values: Vec<_> = inst
+            .borrow()
+            .global_addrs
+            .iter()
+            .map(|addr| store.globals[addr.0].value)
+            .collect();
+        assert!(matches!(
+            values[..],
+            [Val::I32(42), Val::I32(42), Val::I32(7)]
+        ));
+    }
+
+    #[test]
+    fn compiled_modules_share_code_across_stores() {
+        let mut module = Module::default();
+        module.types.push(FuncType {
+            from: ResultType { types: vec![] },
+            to: ResultType { types: vec![] },
+        });
+        module.funcs.push(Func {
+            typ: TypeIdx(0),
+            locals: vec![],
+            body: vec![Inst::Block(BlockType::Empty, vec![Inst::Nop].into())],
+        });
+        module.globals.push(Global {
+            typ: GlobalType {
+                valtype: ValType::I32,
+                mutable: true,
+            },
+            init: vec![Inst::I32Const(1)],
+        });
+        let compiled = CompiledModule::new(module).unwrap();
+
+        let mut stores = [Store::new(), Store::new()];
+        let insts = stores.each_mut().map(|store| {
+            let inst = Linker::new().instantiate_compiled(&compiled, store);
+            inst.unwrap()
+        });
+        stores[0].globals[0].value = Val::I32(2);
+        assert!(matches!(stores[1].globals[0].value, Val::I32(1)));
+
+        let code = |store: &Store| match &*store.funcs[0] {
+            FuncInst::Local { code, .. } => code.clone(),
+            FuncInst::External { .. } => unreachable!(),
+        };
+        assert!(Rc::ptr_eq(&code(&stores[0]), &code(&stores[1])));
+        // the body is flattened once, when compiling
+        assert!(!matches!(code(&stores[0]).body[0], Inst::Block(..)));
+        assert!(!Rc::ptr_eq(&insts[0], &insts[1]));
+    }
+
+    #[test]
+    fn memory_read_write() {
+        let mut mem = MemInstInner::new(16);
+        mem.write(4, &[1, 2, 3]).unwrap();
+        assert_eq!(mem.read(4, 3).unwrap(), [1, 2, 3]);
+        mem.write_u32(12, 0xdead_beef).unwrap();
+        assert_eq!(mem.read_u32(12).unwrap(), 0xdead_beef);
+        assert_eq!(mem.read(12, 4).unwrap(), [0xef, 0xbe, 0xad, 0xde]);
+    }
+
+    #[test]
+    fn memory_access_out_of_bounds() {
+        let mut mem = MemInstInner::new(16);
+        assert!(matches!(
+            mem.read(15, 2),
+            Err(Error::OobAccess { addr: 15, len: 2 })
+        ));
+        assert!(mem.write(16, &[1]).is_err());
+        assert!(mem.read_u32(13).is_err());
+        assert!(mem.read(usize::MAX, 2).is_err());
+        assert!(mem.read(16, 0).is_ok());
+        assert!(matches!(
+            mem.read_mut(8, 9),
+            Err(Error::OobAccess { addr: 8, len: 9 })
+        ));
+        assert!(mem.read_mut(usize::MAX, 1).is_err());
+    }
+
+    #[test]
+    fn memory_read_mut() {
+        let mut mem = MemInstInner::new(16);
+        mem.read_mut(2, 3).unwrap().fill(7);
+        assert_eq!(mem.read(1, 5).unwrap(), [0, 7, 7, 7, 0]);
+    }
+
+    #[test]
+    fn memory_read_cstr() {
+        let mut mem = MemInstInner::new(16);
+        mem.write(2, b"hi\0").unwrap();
+        assert_eq!(mem.read_cstr(2).unwrap().to_bytes(), b"hi");
+        mem.write(10, b"nonul!").unwrap();
+        assert!(mem.read_cstr(10).is_err());
+    }
+
+    #[test]
+    fn checkpoint_restore() {
+        let mut store = Store::new();
+        let addr = store
+            .allocmem(MemType {
+                limits: Limits {
+                    min: 1,
+                    max: None,
+                    is_64: false,
+                    shared: false,
+                },
+            })
+            .unwrap();
+        store.allocglobal(I32_CONST, Val::I32(1));
+        store.mems[addr.0].write(0, &[1, 2, 3]).unwrap();
+
+        let checkpoint = store.checkpoint();
+        store.mems[addr.0].write(0, &[9, 9, 9]).unwrap();
+        store.globals[0].value = Val::I32(2);
+        store.allocglobal(I32_CONST, Val::I32(3));
+
+        store.restore(checkpoint);
+        assert_eq!(store.mems[addr.0].read(0, 3).unwrap(), [1, 2, 3]);
+        assert_eq!(store.globals.len(), 1);
+        assert!(matches!(store.globals[0].value, Val::I32(1)));
+    }
+
+    fn import(module: &str, nm: &str, desc: ImportDesc) -> Import {
+        Import {
+            module: module.into(),
+            nm: nm.into(),
+            desc,
+        }
+    }
+
+    #[test]
+    fn names_can_be_looked_up_borrowed() {
+        let mut externals = Externals::default();
+        externals
+            .values
+            .insert(("env", "base").into(), ExternVal::Global(Val::I32(1)));
+        externals
+            .values
+            .insert(Name::new("env", "basement"), ExternVal::Global(Val::I32(2)));
+        assert!(matches!(
+            externals.get("env", "base"),
+            Some(ExternVal::Global(Val::I32(1)))
+        ));
+        assert!(externals.get("env", "bas").is_none());
+        assert!(externals.get("en", "vbase").is_none());
+    }
+
+    #[test]
+    fn linker_shares_exports() {
+        let memtype = MemType {
+            limits: Limits {
+                min: 1,
+                max: None,
+                is_64: false,
+                shared: false,
+            },
+        };
+        let exporter = Module {
+            mems: vec![memtype],
+            globals: vec![Global {
+                typ: I32_CONST,
+                init: vec![Inst::I32Const(7)],
+            }],
+            exports: vec![
+                Export {
+                    name: "mem".into(),
+                    desc: ExportDesc::Mem(MemIdx(0)),
+                },
+                Export {
+                    name: "g".into(),
+                    desc: ExportDesc::Global(GlobalIdx(0)),
+                },
+            ],
+            ..Module::default()
+        };
+        let importer = Module {
+            imports: vec![
+                import("a", "mem", ImportDesc::Mem(memtype)),
+                import("a", "g", ImportDesc::Global(I32_CONST)),
+                import("env", "g", ImportDesc::Global(I32_CONST)),
+            ],
+            ..Module::default()
+        };
+
+        let mut store = Store::new();
+        let mut linker = Linker::new();
+        let a = linker.instantiate(&exporter, &mut store).unwrap();
+        linker.register_instance("a", &a.borrow());
+        linker.define("env", "g", ExternVal::Global(Val::I32(1)));
+        let b = linker.instantiate(&importer, &mut store).unwrap();
+        let c = linker.instantiate(&importer, &mut store).unwrap();
+
+        let (a, b, c) = (a.borrow(), b.borrow(), c.borrow());
+        assert_eq!(a.mem_addrs[0].0, b.mem_addrs[0].0);
+        assert_eq!(a.global_addrs[0].0, b.global_addrs[0].0);
+        // host values are allocated once and shared by every importer
+        assert_eq!(b.global_addrs[1].0, c.global_addrs[1].0);
+        assert_eq!(store.globals.len(), 2);
+    }
+
+    #[test]
+    fn linker_errors() {
+        let mut store = Store::new();
+        let mut linker = Linker::new();
+        linker.define("env", "g", ExternVal::Global(Val::I32(1)));
+
+        let missing = Module {
+            imports: vec![import("env", "h", ImportDesc::Global(I32_CONST))],
+            ..Module::default()
+        };
+        let res = linker.instantiate(&missing, &mut store);
+        assert!(matches!(res, Err(InstantiationError::MissingImport(_))));
+
+        let wrong_kind = Module {
+            imports: vec![import("env", "g", ImportDesc::Func(TypeIdx(0)))],
+            types: vec![FuncType {
+                from: ResultType { types: vec![] },
+                to: ResultType { types: vec![] },
+            }],
+            ..Module::default()
+        };
+        let res = linker.instantiate(&wrong_kind, &mut store);
+        assert!(matches!(
+            res,
+            Err(InstantiationError::ImportTypeMismatch(_))
+        ));
+
+        let wrong_type = GlobalType {
+            valtype: ValType::I64,
+            mutable: false,
+        };
+        let wrong_type = Module {
+            imports: vec![import("env", "g", ImportDesc::Global(wrong_type))],
+            ..Module::default()
+        };
+        let res = linker.instantiate(&wrong_type, &mut store);
+        assert!(matches!(
+            res,
+            Err(InstantiationError::ImportTypeMismatch(_))
+        ));
+
+        // the first import allocated "g" as an immutable global
+        let mutable = GlobalType {
+            valtype: ValType::I32,
+            mutable: true,
+        };
+        let mutable = Module {
+            imports: vec![
+                import("env", "g", ImportDesc::Global(I32_CONST)),
+                import("env", "g", ImportDesc::Global(mutable)),
+            ],
+            ..Module::default()
+        };
+        let res = linker.instantiate(&mutable, &mut store);
+        assert!(matches!(
+            res,
+            Err(InstantiationError::ImportTypeMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn linker_checks_table_and_memory_types() {
+        let limits = |min, max| Limits {
+            min,
+            max,
+            is_64: false,
+            shared: false,
+        };
+        let table = |reftype, limits| TableType { reftype, limits };
+        let exporter = Module {
+            tables: vec![table(Reftype::Funcref, limits(1, None))],
+            mems: vec![MemType {
+                limits: limits(1, Some(2)),
+            }],
+            exports: vec![
+                Export {
+                    name: "t".into(),
+                    desc: ExportDesc::Table(TableIdx(0)),
+                },
+                Export {
+                    name: "mem".into(),
+                    desc: ExportDesc::Mem(MemIdx(0)),
+                },
+            ],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let mut linker = Linker::new();
+        let a = linker.instantiate(&exporter, &mut store).unwrap();
+        linker.register_instance("a", &a.borrow());
+
+        let mut importer = |desc| {
+            let name = match desc {
+                ImportDesc::Mem(_) => "mem",
+                _ => "t",
+            };
+            let module = Module {
+                imports: vec![import("a", name, desc)],
+                ..Module::default()
             };
-            m.execute(inst.clone(), &offset, &mut Locals::empty())
-                .unwrap();
-            let Val::I32(offset) = m.stack.pop().unwrap() else { panic!() };
-            let offset = offset as usize;
-            let len = data.init.len();
-            let mem = &mut m.store.mems[inst.borrow().mem_addrs[0].0];
-            mem.data[offset..offset + len].copy_from_slice(&data.init);
+            linker.instantiate(&module, &mut store).map(|_| ())
+        };
+        let table = |reftype, min, max| ImportDesc::Table(table(reftype, limits(min, max)));
+        let mem = |min, max| {
+            ImportDesc::Mem(MemType {
+                limits: limits(min, max),
+            })
+        };
+        for ok in [
+            table(Reftype::Funcref, 0, None),
+            table(Reftype::Funcref, 1, None),
+            mem(1, None),
+            mem(0, Some(2)),
+            mem(1, Some(3)),
+        ] {
+            assert!(importer(ok).is_ok());
+        }
+        for mismatch in [
+            table(Reftype::Externref, 1, None),
+            table(Reftype::Funcref, 2, None),
+            // the table may grow without bound
+            table(Reftype::Funcref, 1, Some(5)),
+            mem(2, None),
+            mem(1, Some(1)),
+        ] {
+            assert!(matches!(
+                importer(mismatch),
+                Err(InstantiationError::ImportTypeMismatch(_))
+            ));
         }
     }
-    return inst;
+
+    #[test]
+    fn instantiation_errors_are_returned() {
+        let mut store = Store::new();
+        let missing = Module {
+            imports: vec![import("env", "h", ImportDesc::Global(I32_CONST))],
+            ..Module::default()
+        };
+        let res = instantiate(&missing, &mut store, Externals::default());
+        assert!(matches!(
+            res,
+            Err(InstantiationError::MissingImport(name)) if name == Name::new("env", "h")
+        ));
+
+        let data = |offset| Module {
+            mems: vec![MemType {
+                limits: Limits {
+                    min: 1,
+                    max: None,
+                    is_64: false,
+                    shared: false,
+                },
+            }],
+            datas: vec![Data {
+                init: vec![1, 2, 3, 4],
+                mode: Datamode::Active {
+                    memory: MemIdx(0),
+                    offset: vec![Inst::I32Const(offset)],
+                },
+            }],
+            ..Module::default()
+        };
+        assert!(instantiate(&data(65532), &mut store, Externals::default()).is_ok());
+        let res = instantiate(&data(65533), &mut store, Externals::default());
+        assert!(matches!(
+            res,
+            Err(InstantiationError::SegmentOutOfBounds(
+                Error::OobAccess { .. }
+            ))
+        ));
+
+        let mut wrong_offset = data(0);
+        wrong_offset.datas[0].mode = Datamode::Active {
+            memory: MemIdx(0),
+            offset: vec![Inst::I64Const(0)],
+        };
+        let res = instantiate(&wrong_offset, &mut store, Externals::default());
+        assert!(matches!(
+            res,
+            Err(InstantiationError::DataOffsetEvalFailed(
+                Exception::Runtime(Error::WrongValType)
+            ))
+        ));
+
+        let global = Module {
+            globals: vec![Global {
+                typ: I32_CONST,
+                init: vec![],
+            }],
+            ..Module::default()
+        };
+        let res = instantiate(&global, &mut store, Externals::default());
+        assert!(matches!(
+            res,
+            Err(InstantiationError::GlobalInitFailed(Exception::Runtime(
+                Error::StackEmpty
+            )))
+        ));
+
+        let branch = Module {
+            types: vec![FuncType {
+                from: ResultType { types: vec![] },
+                to: ResultType { types: vec![] },
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body: vec![Inst::Break(LabelIdx(5))],
+            }],
+            ..Module::default()
+        };
+        let res = instantiate(&branch, &mut store, Externals::default());
+        assert!(matches!(
+            res,
+            Err(InstantiationError::Compile(CompileError::UnknownLabel(5)))
+        ));
+
+        let export = Module {
+            exports: vec![Export {
+                name: "f".into(),
+                desc: ExportDesc::Func(FuncIdx(7)),
+            }],
+            ..Module::default()
+        };
+        let res = instantiate(&export, &mut store, Externals::default());
+        assert!(matches!(res, Err(InstantiationError::UnknownExport(name)) if name == "f"));
+
+        let func_import = Module {
+            imports: vec![import("env", "f", ImportDesc::Func(TypeIdx(3)))],
+            ..Module::default()
+        };
+        let res = instantiate(&func_import, &mut store, Externals::default());
+        assert!(matches!(
+            res,
+            Err(InstantiationError::UnknownType(TypeIdx(3)))
+        ));
+    }
+
+    #[test]
+    fn memories_are_limited_to_32_bit_addresses() {
+        let memory = |min| Module {
+            mems: vec![MemType {
+                limits: Limits {
+                    min,
+                    max: None,
+                    is_64: false,
+                    shared: false,
+                },
+            }],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        for min in [65537, 0xffff_ffff, u64::MAX] {
+            let res = instantiate(&memory(min), &mut store, Externals::default());
+            assert!(matches!(res, Err(InstantiationError::MemoryTooLarge)));
+        }
+        assert!(store.mems.is_empty());
+    }
+
+    #[test]
+    fn active_elems_fill_tables() {
+        let module = Module {
+            types: vec![FuncType {
+                from: ResultType { types: vec![] },
+                to: ResultType { types: vec![] },
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body: vec![],
+            }],
+            tables: vec![TableType {
+                reftype: Reftype::Funcref,
+                limits: Limits {
+                    min: 3,
+                    max: None,
+                    is_64: false,
+                    shared: false,
+                },
+            }],
+            elems: vec![Elem {
+                typ: Reftype::Funcref,
+                init: vec![vec![Inst::RefFunc(FuncIdx(0))]],
+                mode: ElemMode::Active {
+                    table: TableIdx(0),
+                    offset: vec![Inst::I32Const(1)],
+                },
+            }],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let externals = Externals {
+            values: BTreeMap::new(),
+        };
+        instantiate(&module, &mut store, externals).unwrap();
+        assert!(matches!(
+            store.tables[0].read(0, 3).unwrap(),
+            [Ref::Null(_), Ref::Func(0), Ref::Null(_)]
+        ));
+        // the segment was dropped after use
+        assert!(store.elems[0].is_empty());
+    }
 }
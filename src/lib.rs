@@ -1,12 +1,31 @@
 // TODO: remove this when done
 #![allow(dead_code, unused_imports, unused_variables)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
+
+/// Execution tracing, only compiled in with the `trace` feature since it
+/// prints every instruction and stack operation.
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "trace")]
+        println!($($arg)*);
+    };
+}
+
+#[cfg(feature = "std")]
 pub mod binary;
+pub mod engine;
+pub mod flat;
+mod float;
 pub mod instance;
 pub mod repr;
 pub mod rt;
+#[cfg(feature = "std")]
 pub mod scripts;
+#[cfg(feature = "std")]
 pub mod text;
+pub mod valid;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 pub mod tests;
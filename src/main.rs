@@ -1,123 +1,446 @@
+use std::cell::RefCell;
 use std::path::Path;
+use std::rc::Rc;
 use std::{collections::BTreeMap, path::PathBuf};
 
-use wasm::instance::{instantiate, ExternVal, Externals, FFiFunc, Name, Store};
-use wasm::rt::{Machine, Stack, Val};
-use wasm::scripts::run_script;
-use wasm::{instance, text};
+use wasm::engine::{Engine, Instance};
+use wasm::instance::{ExternVal, Externals, FFiFunc, Store};
+use wasm::repr::{
+    ExportDesc, ExternType, FuncType, GlobalType, ImportDesc, Limits, Module, ValType,
+};
+use wasm::rt::{default_value, Val};
+use wasm::scripts::{run_script, ScriptError};
+use wasm::text;
 
 pub struct Args {
     wasm: PathBuf,
+    dump: bool,
+    invoke: Option<String>,
+    /// the arguments for `--invoke`, everything after the file
+    args: Vec<String>,
+    /// the export the rocket example calls once per frame
+    step: String,
+    frames: u32,
 }
 
 impl Args {
     fn from_env() -> Self {
-        let env = std::env::args();
+        let mut env = std::env::args().skip(1);
         let mut wasm = None;
+        let mut dump = false;
+        let mut invoke = None;
+        let mut args = vec![];
+        let mut step = "update".to_string();
+        let mut frames = 60;
         let mut count = 0;
-        for arg in env.skip(1) {
+        while let Some(arg) = env.next() {
+            if arg == "--dump" {
+                dump = true;
+                continue;
+            }
+            if arg == "--invoke" {
+                let Some(name) = env.next() else { panic!("--invoke expects an export name") };
+                invoke = Some(name);
+                continue;
+            }
+            if arg == "--step" {
+                let Some(name) = env.next() else { panic!("--step expects an export name") };
+                step = name;
+                continue;
+            }
+            if arg == "--frames" {
+                let Some(n) = env.next().and_then(|n| n.parse().ok()) else { panic!("--frames expects a number") };
+                frames = n;
+                continue;
+            }
             if count == 0 {
                 wasm = Some(PathBuf::from(arg));
+            } else {
+                args.push(arg);
             }
             count += 1;
         }
         let Some(wasm) = wasm else { panic!("no file") };
-        Self { wasm }
+        Self {
+            wasm,
+            dump,
+            invoke,
+            args,
+            step,
+            frames,
+        }
     }
 }
 
-fn rocket_externals() -> Externals {
+fn valtype_name(t: &ValType) -> &'static str {
+    match t {
+        ValType::I32 => "i32",
+        ValType::I64 => "i64",
+        ValType::F32 => "f32",
+        ValType::F64 => "f64",
+        ValType::V128 => "v128",
+        ValType::FuncRef => "funcref",
+        ValType::ExternRef => "externref",
+    }
+}
+
+fn signature(typ: &FuncType) -> String {
+    let from: Vec<_> = typ.from.types.iter().map(valtype_name).collect();
+    let to: Vec<_> = typ.to.types.iter().map(valtype_name).collect();
+    format!("({}) -> ({})", from.join(", "), to.join(", "))
+}
+
+fn limits(limits: &Limits) -> String {
+    match limits.max {
+        Some(max) => format!("min {} max {}", limits.min, max),
+        None => format!("min {}", limits.min),
+    }
+}
+
+fn globaltype(typ: &GlobalType) -> String {
+    match typ.mutable {
+        true => format!("mut {}", valtype_name(&typ.valtype)),
+        false => valtype_name(&typ.valtype).to_string(),
+    }
+}
+
+fn dump_module(module: &Module) {
+    println!("types:");
+    for (i, typ) in module.types.iter().enumerate() {
+        println!("  {i}: {}", signature(typ));
+    }
+
+    println!("imports:");
+    let mut imported_funcs = 0;
+    let mut imported_globals = 0;
+    for import in &module.imports {
+        let desc = match &import.desc {
+            ImportDesc::Func(t) => {
+                imported_funcs += 1;
+                format!("func {}", signature(&module[*t]))
+            }
+            ImportDesc::Table(t) => format!("table {:?} {}", t.reftype, limits(&t.limits)),
+            ImportDesc::Mem(m) => format!("memory {}", limits(&m.limits)),
+            ImportDesc::Global(g) => {
+                imported_globals += 1;
+                format!("global {}", globaltype(g))
+            }
+        };
+        println!("  {}::{}: {desc}", import.module, import.nm);
+    }
+
+    println!("funcs:");
+    for (i, func) in module.funcs.iter().enumerate() {
+        let idx = imported_funcs + i;
+        println!("  {idx}: {}", signature(&module[func.typ]));
+    }
+
+    println!("tables:");
+    for (i, table) in module.tables.iter().enumerate() {
+        println!("  {i}: {:?} {}", table.reftype, limits(&table.limits));
+    }
+
+    println!("memories:");
+    for (i, mem) in module.mems.iter().enumerate() {
+        println!("  {i}: {}", limits(&mem.limits));
+    }
+
+    println!("globals:");
+    for (i, global) in module.globals.iter().enumerate() {
+        let idx = imported_globals + i;
+        let init: Vec<_> = global.init.iter().map(|inst| inst.to_string()).collect();
+        println!("  {idx}: {} = {}", globaltype(&global.typ), init.join(" "));
+    }
+
+    println!("exports:");
+    for export in &module.exports {
+        let desc = match &export.desc {
+            ExportDesc::Func(idx) => format!("func {}", idx.0),
+            ExportDesc::Table(idx) => format!("table {}", idx.0),
+            ExportDesc::Mem(idx) => format!("memory {}", idx.0),
+            ExportDesc::Global(idx) => format!("global {}", idx.0),
+        };
+        println!("  {:?}: {desc}", export.name);
+    }
+
+    match module.start {
+        Some(start) => println!("start: func {}", start.0),
+        None => println!("start: none"),
+    }
+
+    match module.producers() {
+        Some(Ok(producers)) => {
+            println!("producers:");
+            let fields = [
+                ("language", &producers.language),
+                ("processed-by", &producers.processed_by),
+                ("sdk", &producers.sdk),
+            ];
+            for (field, values) in fields {
+                if values.is_empty() {
+                    continue;
+                }
+                let values: Vec<_> = values
+                    .iter()
+                    .map(|(name, version)| format!("{name} {version}").trim_end().to_string())
+                    .collect();
+                println!("  {field}: {}", values.join(", "));
+            }
+        }
+        Some(Err(e)) => println!("producers: malformed, {e}"),
+        None => {}
+    }
+}
+
+/// What the rocket module drew since it last cleared the screen.
+#[derive(Default)]
+struct Frame {
+    bullets: usize,
+    enemies: usize,
+    particles: usize,
+    score: f64,
+}
+
+fn rocket_externals(frame: &Rc<RefCell<Frame>>) -> Externals {
     let mut vals = BTreeMap::new();
-    let atan = Box::new(FFiFunc(|_a: &mut Store, _vals: &[Val]| vec![]));
-    let clear_screen = Box::new(FFiFunc(|_a: &mut Store, _vals: &[Val]| vec![]));
-    let cos = Box::new(FFiFunc(|_a: &mut Store, _vals: &[Val]| vec![]));
-    let sin = Box::new(FFiFunc(|_a: &mut Store, _vals: &[Val]| vec![]));
-    let draw_bullet = Box::new(FFiFunc(|_a: &mut Store, _vals: &[Val]| vec![]));
-    let draw_enemy = Box::new(FFiFunc(|_a: &mut Store, _vals: &[Val]| vec![]));
-    let draw_particle = Box::new(FFiFunc(|_a: &mut Store, _vals: &[Val]| vec![]));
-    let draw_player = Box::new(FFiFunc(|_a: &mut Store, _vals: &[Val]| vec![]));
-    let draw_score = Box::new(FFiFunc(|_a: &mut Store, _vals: &[Val]| vec![]));
-    vals.insert(Name::new("env", "Math_atan"), ExternVal::ExternalFunc(atan));
-    vals.insert(
-        Name::new("env", "clear_screen"),
-        ExternVal::ExternalFunc(clear_screen),
-    );
-    vals.insert(Name::new("env", "cos"), ExternVal::ExternalFunc(cos));
-    vals.insert(Name::new("env", "sin"), ExternVal::ExternalFunc(sin));
-    vals.insert(
-        Name::new("env", "draw_bullet"),
-        ExternVal::ExternalFunc(draw_bullet),
-    );
-    vals.insert(
-        Name::new("env", "draw_enemy"),
-        ExternVal::ExternalFunc(draw_enemy),
-    );
-    vals.insert(
-        Name::new("env", "draw_particle"),
-        ExternVal::ExternalFunc(draw_particle),
-    );
-    vals.insert(
-        Name::new("env", "draw_player"),
-        ExternVal::ExternalFunc(draw_player),
-    );
-    vals.insert(
-        Name::new("env", "draw_score"),
-        ExternVal::ExternalFunc(draw_score),
-    );
+    let mut define = |name: &str, f: fn(&mut Frame, &[Val]) -> Vec<Val>| {
+        let frame = frame.clone();
+        let func = FFiFunc(move |_: &mut Store, args: &[Val]| f(&mut frame.borrow_mut(), args));
+        vals.insert(
+            ("env", name).into(),
+            ExternVal::ExternalFunc(Box::new(func)),
+        );
+    };
+    fn math(args: &[Val], op: fn(f64) -> f64) -> Vec<Val> {
+        match args {
+            [Val::F64(x)] => vec![Val::F64(op(*x))],
+            _ => panic!("expected an f64, got {args:?}"),
+        }
+    }
+    define("Math_atan", |_, args| math(args, f64::atan));
+    define("cos", |_, args| math(args, f64::cos));
+    define("sin", |_, args| math(args, f64::sin));
+    define("clear_screen", |frame, _| {
+        *frame = Frame {
+            score: frame.score,
+            ..Frame::default()
+        };
+        vec![]
+    });
+    define("draw_bullet", |frame, _| {
+        frame.bullets += 1;
+        vec![]
+    });
+    define("draw_enemy", |frame, _| {
+        frame.enemies += 1;
+        vec![]
+    });
+    define("draw_particle", |frame, _| {
+        frame.particles += 1;
+        vec![]
+    });
+    define("draw_player", |_, _| vec![]);
+    define("draw_score", |frame, args| {
+        if let [Val::F64(score)] = args {
+            frame.score = *score;
+        }
+        vec![]
+    });
     Externals { values: vals }
 }
 
-fn rocket_example(path: &Path) {
-    let add_mod = wasm::binary::parser::parse_file(path).unwrap();
-    let mut store = instance::Store {
-        funcs: vec![],
-        mems: vec![],
-        tables: vec![],
+/// The arguments for the `frame`th call of a step function: the frame
+/// counter for an integer parameter, or the seconds since the last frame at
+/// 60 fps for a float one, which is what the rocket's `update` expects.
+fn step_args(typ: &FuncType, frame: u32) -> Vec<Val> {
+    typ.from
+        .types
+        .iter()
+        .map(|t| match t {
+            ValType::I32 => Val::I32(frame as i32),
+            ValType::I64 => Val::I64(frame as i64),
+            ValType::F32 => Val::F32(1.0 / 60.0),
+            ValType::F64 => Val::F64(1.0 / 60.0),
+            t => panic!("can't pass a frame as {}", valtype_name(t)),
+        })
+        .collect()
+}
+
+/// Runs the rocket game for `frames` frames, driven by the host like the
+/// browser does: every frame calls the `step` export, then `draw`.
+fn rocket_example(path: &Path, step: &str, frames: u32) {
+    let module = load_module(path);
+    let Some(step_type) = module.exports().find_map(|(name, typ)| match typ {
+        ExternType::Func(typ) if name == step => Some(typ),
+        _ => None,
+    }) else {
+        panic!("no function exported as {step:?}")
     };
 
-    let externals = rocket_externals();
+    let frame = Rc::new(RefCell::new(Frame::default()));
+    let engine = Engine::new();
+    // runs the start function
+    let mut instance = Instance::new(&engine, &module, rocket_externals(&frame)).unwrap();
+    instance
+        .call("resize", &[Val::F64(1024.0), Val::F64(600.0)])
+        .unwrap();
 
-    let instance = instantiate(&add_mod, &mut store, externals);
-    let mut m = Machine {
-        stack: Stack::default(),
-        store: &mut store,
-    };
+    for i in 0..frames {
+        for (name, args) in [(step, step_args(&step_type, i)), ("draw", vec![])] {
+            if let Some(trap) = instance.run(name, &args).unwrap().trapped {
+                eprintln!("frame {i}: {name} trapped: {trap}");
+                return;
+            }
+        }
+        let frame = frame.borrow();
+        println!(
+            "frame {i}: {} enemies, {} bullets, {} particles, score {}",
+            frame.enemies, frame.bullets, frame.particles, frame.score
+        );
+    }
+}
+
+/// Host functions for the function imports of `module`, which print their
+/// name and arguments and return zeros.
+fn stub_imports(module: &Module) -> Externals {
+    let mut values = BTreeMap::new();
+    for (modname, name, typ) in module.imports() {
+        let ExternType::Func(typ) = typ else { continue };
+        let label = format!("{modname}::{name}");
+        let func = FFiFunc(move |_: &mut Store, args: &[Val]| {
+            println!("{label}{args:?}");
+            typ.to.types.iter().copied().map(default_value).collect()
+        });
+        values.insert(
+            (modname, name).into(),
+            ExternVal::ExternalFunc(Box::new(func)),
+        );
+    }
+    Externals { values }
+}
 
-    if let Some(start) = add_mod.start {
-        let start_func_addr = instance.borrow().func_addrs[start.0 as usize];
-        m.call(start_func_addr).unwrap();
+/// Parses the command line arguments of `--invoke` as the params of `typ`.
+fn invoke_args(typ: &FuncType, args: &[String]) -> Result<Vec<Val>, String> {
+    let params = &typ.from.types;
+    if args.len() != params.len() {
+        return Err(format!(
+            "expected {} arguments, got {}",
+            params.len(),
+            args.len()
+        ));
+    }
+    params
+        .iter()
+        .zip(args)
+        .map(|(t, arg)| {
+            let val = match t {
+                ValType::I32 => arg.parse().map(Val::I32).ok(),
+                ValType::I64 => arg.parse().map(Val::I64).ok(),
+                ValType::F32 => arg.parse().map(Val::F32).ok(),
+                ValType::F64 => arg.parse().map(Val::F64).ok(),
+                _ => None,
+            };
+            val.ok_or_else(|| format!("can't pass {arg:?} as {}", valtype_name(t)))
+        })
+        .collect()
+}
+
+fn run_module(module: &Module, invoke: Option<&str>, args: &[String]) {
+    let engine = Engine::new();
+    let imports = stub_imports(module);
+    let Some(name) = invoke else {
+        if let Err(e) = Instance::new(&engine, module, imports) {
+            eprintln!("failed to instantiate: {e:?}");
+            std::process::exit(1);
+        }
+        return;
+    };
+    let typ = module.exports().find_map(|(export, typ)| match typ {
+        ExternType::Func(typ) if export == name => Some(typ),
+        _ => None,
+    });
+    let Some(typ) = typ else {
+        eprintln!("no function exported as {name:?}");
+        std::process::exit(1);
+    };
+    let args = match invoke_args(&typ, args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{name}: {e}");
+            std::process::exit(1);
+        }
+    };
+    let result = match Instance::run_to_completion(&engine, module, imports, name, &args) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("failed to instantiate: {e:?}");
+            std::process::exit(1);
+        }
+    };
+    match result.trapped {
+        Some(trap) => {
+            eprintln!("{name} trapped: {trap}");
+            std::process::exit(1);
+        }
+        None => println!("{name}: {:?}", result.returned),
     }
 }
 
 fn run_wast(path: &Path) {
     let script_str = std::fs::read_to_string(&path).unwrap();
-    run_script(&script_str).unwrap();
+    match run_script(&script_str) {
+        Ok(()) => {}
+        Err(ScriptError::Failed { commands, errors }) => {
+            for error in &errors {
+                match error {
+                    ScriptError::At(pos, e) => eprintln!("{}:{}: {:?}", path.display(), pos, e),
+                    e => eprintln!("{}: {:?}", path.display(), e),
+                }
+            }
+            eprintln!("{} of {} commands failed", errors.len(), commands);
+            std::process::exit(1);
+        }
+        Err(e) => panic!("{:?}", e),
+    }
+}
+
+/// Reads a module in the text format from a `.wat` file, or in the binary
+/// format from any other. Exits if it can't be read.
+fn load_module(path: &Path) -> Module {
+    let module = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("wat") => std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|input| text::parse_module(&input).map_err(|e| e.to_string())),
+        _ => wasm::binary::parser::parse_file(path).map_err(|e| e.to_string()),
+    };
+    match module {
+        Ok(module) => module,
+        Err(e) => {
+            eprintln!("{}: {e}", path.display());
+            std::process::exit(1);
+        }
+    }
 }
 
 fn main() {
     let args = Args::from_env();
-    let ext = args
-        .wasm
-        .extension()
-        .expect("file should have an extension")
-        .to_str()
-        .unwrap();
+    let ext = args.wasm.extension().and_then(|ext| ext.to_str());
 
-    if ext == "wast" {
-        run_wast(&args.wasm);
+    if args.dump {
+        dump_module(&load_module(&args.wasm));
         return;
     }
 
-    if ext == "wat" {
-        let input = std::fs::read_to_string(&args.wasm).unwrap();
-        let sexpr = text::sexpr::parse_module_to_sexpr(&input).unwrap();
-        //println!("sexpr: {:#?}", sexpr);
-        //std::process::exit(0);
-        let module = text::parse_module(&input).unwrap();
+    if ext == Some("wast") {
+        run_wast(&args.wasm);
+        return;
     }
 
     if args.wasm.ends_with("rocket.wasm") {
-        rocket_example(&args.wasm);
+        rocket_example(&args.wasm, &args.step, args.frames);
         return;
     }
+
+    let module = load_module(&args.wasm);
+    run_module(&module, args.invoke.as_deref(), &args.args);
 }
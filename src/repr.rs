@@ -1,6 +1,8 @@
-use std::ops::Index;
+use alloc::{collections::BTreeSet, string::String, vec::Vec};
+use core::{fmt, ops::Index};
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum ValType {
     I32 = 0x7F,
@@ -32,95 +34,131 @@ impl TryFrom<u8> for ValType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ResultType {
     pub types: Vec<ValType>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FuncType {
     pub from: ResultType,
     pub to: ResultType,
 }
 
 #[derive(Debug, Copy, Clone)]
-pub struct TypeIdx(pub(crate) u32);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TypeIdx(pub u32);
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FuncIdx(pub u32);
 
 #[derive(Debug, Copy, Clone)]
-pub struct TableIdx(pub(crate) u32);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TableIdx(pub u32);
 
-pub struct MemIdx(pub(crate) u32);
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MemIdx(pub u32);
+
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GlobalIdx(pub u32);
 
-pub struct GlobalIdx(pub(crate) u32);
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ElemIdx(pub u32);
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Locals {
     pub n: u32,
     pub t: ValType,
 }
 
-pub struct ExprBytes(pub Vec<u8>);
-
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Func {
     pub typ: TypeIdx,
     pub locals: Vec<Locals>,
     pub body: Vec<Inst>,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TableType {
-    pub(crate) reftype: Reftype,
-    pub(crate) limits: Limits,
+    pub reftype: Reftype,
+    pub limits: Limits,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MemType {
-    pub(crate) limits: Limits,
+    pub limits: Limits,
 }
 
-pub struct Global {}
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GlobalType {
+    pub valtype: ValType,
+    pub mutable: bool,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Global {
+    pub typ: GlobalType,
+    pub init: Vec<Inst>,
+}
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ElemMode {
     Passive,
-    Active { table: TableIdx, offset: ExprBytes },
+    Active { table: TableIdx, offset: Vec<Inst> },
     Declarative,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Elem {
-    typ: Reftype,
-    init: Vec<ExprBytes>,
-    mode: ElemMode,
+    pub(crate) typ: Reftype,
+    /// a constant expression per element; plain function indices are
+    /// stored as `ref.func`
+    pub(crate) init: Vec<Vec<Inst>>,
+    pub(crate) mode: ElemMode,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Datamode {
     Passive,
     Active { memory: MemIdx, offset: Vec<Inst> },
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Data {
     pub(crate) init: Vec<u8>,
     pub(crate) mode: Datamode,
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ImportDesc {
     Func(TypeIdx),
-    Table {},
-    Mem {},
-    Global {},
+    Table(TableType),
+    Mem(MemType),
+    Global(GlobalType),
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Import {
-    pub(crate) module: String,
-    pub(crate) nm: String,
-    pub(crate) desc: ImportDesc,
+    pub module: String,
+    pub nm: String,
+    pub desc: ImportDesc,
 }
 
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ExportDesc {
     Func(FuncIdx),
     Table(TableIdx),
@@ -128,24 +166,41 @@ pub enum ExportDesc {
     Global(GlobalIdx),
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Export {
     pub name: String,
     pub desc: ExportDesc,
 }
 
-#[derive(Clone, Copy, Debug)]
+/// The type of an import or export, with type indices resolved.
+#[derive(Debug, Clone)]
+pub enum ExternType {
+    Func(FuncType),
+    Table(TableType),
+    Mem(MemType),
+    Global(GlobalType),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Reftype {
     Funcref,
     Externref,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Limits {
-    pub(crate) min: u32,
-    pub(crate) max: Option<u32>,
+    pub min: u64,
+    pub max: Option<u64>,
+    /// memory64: the memory is indexed with i64 addresses
+    pub is_64: bool,
+    /// threads: the memory may be shared between agents
+    pub shared: bool,
 }
 
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Module {
     pub types: Vec<FuncType>,
     pub funcs: Vec<Func>,
@@ -157,6 +212,135 @@ pub struct Module {
     pub start: Option<FuncIdx>,
     pub imports: Vec<Import>,
     pub exports: Vec<Export>,
+    /// the custom sections as `(name, contents)`, in binary order
+    pub custom_sections: Vec<(String, Vec<u8>)>,
+    /// functions that function bodies may reference with `ref.func`
+    pub refs: BTreeSet<FuncIdx>,
+}
+
+impl Module {
+    /// Collects the functions referenced outside of function bodies, in
+    /// globals, element segments and exports. Only those may be used with
+    /// `ref.func` inside a function body.
+    pub(crate) fn collect_refs(&self) -> BTreeSet<FuncIdx> {
+        let mut refs = BTreeSet::new();
+        let inits = self.globals.iter().map(|g| &g.init);
+        let elems = self.elems.iter().flat_map(|e| &e.init);
+        for expr in inits.chain(elems) {
+            collect_refs_in(expr, &mut refs);
+        }
+        for export in &self.exports {
+            if let ExportDesc::Func(idx) = export.desc {
+                refs.insert(idx);
+            }
+        }
+        refs
+    }
+
+    /// Finds a `ref.func` in a function body that refers to a function not
+    /// in [`Module::refs`], which makes the module invalid.
+    pub fn undeclared_ref(&self) -> Option<FuncIdx> {
+        let mut used = BTreeSet::new();
+        for func in &self.funcs {
+            collect_refs_in(&func.body, &mut used);
+        }
+        used.difference(&self.refs).next().copied()
+    }
+
+    /// Finds an export name that is used more than once, which makes the
+    /// module invalid.
+    pub fn duplicate_export(&self) -> Option<&str> {
+        let mut names = BTreeSet::new();
+        self.exports
+            .iter()
+            .map(|export| export.name.as_str())
+            .find(|name| !names.insert(*name))
+    }
+
+    /// The imports of the module as `(module, name, type)`.
+    pub fn imports(&self) -> impl Iterator<Item = (&str, &str, ExternType)> + '_ {
+        self.imports.iter().map(|import| {
+            let typ = match &import.desc {
+                ImportDesc::Func(t) => ExternType::Func(self[*t].clone()),
+                ImportDesc::Table(t) => ExternType::Table(*t),
+                ImportDesc::Mem(m) => ExternType::Mem(*m),
+                ImportDesc::Global(g) => ExternType::Global(*g),
+            };
+            (import.module.as_str(), import.nm.as_str(), typ)
+        })
+    }
+
+    /// The custom sections of the module as `(name, contents)`, like
+    /// `producers` or `target_features`, in the order they appeared in the
+    /// binary.
+    pub fn custom_sections(&self) -> impl Iterator<Item = (&str, &[u8])> + '_ {
+        self.custom_sections
+            .iter()
+            .map(|(name, contents)| (name.as_str(), contents.as_slice()))
+    }
+
+    /// The exports of the module as `(name, type)`.
+    ///
+    /// Panics if an export refers to an index the module does not define.
+    pub fn exports(&self) -> impl Iterator<Item = (&str, ExternType)> + '_ {
+        self.exports.iter().map(|export| {
+            let typ = match export.desc {
+                ExportDesc::Func(idx) => {
+                    let t = self
+                        .imports
+                        .iter()
+                        .filter_map(|i| match i.desc {
+                            ImportDesc::Func(t) => Some(t),
+                            _ => None,
+                        })
+                        .chain(self.funcs.iter().map(|f| f.typ))
+                        .nth(idx.0 as usize)
+                        .expect("exported function does not exist");
+                    ExternType::Func(self[t].clone())
+                }
+                ExportDesc::Table(idx) => {
+                    let t = self
+                        .imports
+                        .iter()
+                        .filter_map(|i| match i.desc {
+                            ImportDesc::Table(t) => Some(t),
+                            _ => None,
+                        })
+                        .chain(self.tables.iter().copied())
+                        .nth(idx.0 as usize)
+                        .expect("exported table does not exist");
+                    ExternType::Table(t)
+                }
+                ExportDesc::Mem(idx) => {
+                    let m = self
+                        .imports
+                        .iter()
+                        .filter_map(|i| match i.desc {
+                            ImportDesc::Mem(m) => Some(m),
+                            _ => None,
+                        })
+                        .chain(self.mems.iter().copied())
+                        .nth(idx.0 as usize)
+                        .expect("exported memory does not exist");
+                    ExternType::Mem(m)
+                }
+                ExportDesc::Global(idx) => {
+                    let g = self
+                        .imports
+                        .iter()
+                        .filter_map(|i| match i.desc {
+                            ImportDesc::Global(g) => Some(g),
+                            _ => None,
+                        })
+                        .chain(self.globals.iter().map(|g| g.typ))
+                        .nth(idx.0 as usize)
+                        .expect("exported global does not exist");
+                    ExternType::Global(g)
+                }
+            };
+            (export.name.as_str(), typ)
+        })
+    }
 }
 
 impl Index<FuncIdx> for Module {
@@ -167,6 +351,14 @@ impl Index<FuncIdx> for Module {
     }
 }
 
+fn collect_refs_in(insts: &[Inst], refs: &mut BTreeSet<FuncIdx>) {
+    for inst in Insts::new(insts) {
+        if let Inst::RefFunc(idx) = inst {
+            refs.insert(*idx);
+        }
+    }
+}
+
 impl Index<TypeIdx> for Module {
     type Output = FuncType;
 
@@ -175,6 +367,7 @@ impl Index<TypeIdx> for Module {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(u8)]
 pub enum SectionId {
     Custom = 0,
@@ -220,17 +413,25 @@ impl TryFrom<u8> for SectionId {
 }
 
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MemArg {
     pub(crate) align: u32,
     pub(crate) offset: u32,
+    pub(crate) memory: MemIdx,
 }
 
 #[derive(Debug, Clone)]
-pub struct LabelIdx(pub(crate) u32);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LabelIdx(pub u32);
 
 #[derive(Debug, Copy, Clone)]
-pub struct LocalIdx(pub(crate) u32);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LocalIdx(pub u32);
 
+/// The type of a block, loop or if: what it takes from the stack and leaves
+/// on it.
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BlockType {
     Empty,
     Inline(ValType),
@@ -238,6 +439,8 @@ pub enum BlockType {
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct Expr {
     pub instructions: Vec<Inst>,
 }
@@ -256,21 +459,139 @@ impl AsRef<[Inst]> for Expr {
     }
 }
 
+impl Expr {
+    /// Every instruction of the expression, including nested ones, see
+    /// [`Insts`].
+    pub fn insts(&self) -> Insts<'_> {
+        Insts::new(&self.instructions)
+    }
+}
+
+impl Inst {
+    /// The memarg of a load or store.
+    pub fn memarg(&self) -> Option<&MemArg> {
+        match self {
+            Inst::I32Load(m)
+            | Inst::I64Load(m)
+            | Inst::F32Load(m)
+            | Inst::F64Load(m)
+            | Inst::I32Load8S(m)
+            | Inst::I32Load8U(m)
+            | Inst::I32Load16S(m)
+            | Inst::I32Load16U(m)
+            | Inst::I64Load8S(m)
+            | Inst::I64Load8U(m)
+            | Inst::I64Load16S(m)
+            | Inst::I64Load16U(m)
+            | Inst::I64Load32S(m)
+            | Inst::I64Load32U(m)
+            | Inst::I32Store(m)
+            | Inst::I64Store(m)
+            | Inst::F32Store(m)
+            | Inst::F64Store(m)
+            | Inst::I32Store8(m)
+            | Inst::I32Store16(m)
+            | Inst::I64Store8(m)
+            | Inst::I64Store16(m)
+            | Inst::I64Store32(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// The alignment of a load or store that matches the size of its access,
+    /// as a power of two like [`MemArg`]'s: 2 for `i32.load`, 0 for
+    /// `i64.load8_u`.
+    pub fn natural_alignment(&self) -> Option<u32> {
+        let align = match self {
+            Inst::I32Load8S(_)
+            | Inst::I32Load8U(_)
+            | Inst::I64Load8S(_)
+            | Inst::I64Load8U(_)
+            | Inst::I32Store8(_)
+            | Inst::I64Store8(_) => 0,
+            Inst::I32Load16S(_)
+            | Inst::I32Load16U(_)
+            | Inst::I64Load16S(_)
+            | Inst::I64Load16U(_)
+            | Inst::I32Store16(_)
+            | Inst::I64Store16(_) => 1,
+            Inst::I32Load(_)
+            | Inst::F32Load(_)
+            | Inst::I64Load32S(_)
+            | Inst::I64Load32U(_)
+            | Inst::I32Store(_)
+            | Inst::F32Store(_)
+            | Inst::I64Store32(_) => 2,
+            Inst::I64Load(_) | Inst::F64Load(_) | Inst::I64Store(_) | Inst::F64Store(_) => 3,
+            _ => return None,
+        };
+        Some(align)
+    }
+}
+
+impl Func {
+    /// Every instruction of the body, including nested ones, see [`Insts`].
+    pub fn insts(&self) -> Insts<'_> {
+        Insts::new(&self.body)
+    }
+}
+
+/// Iterates over instructions in order, descending into blocks, loops and
+/// ifs. A structured instruction is yielded before the instructions inside
+/// it, and the then branch of an if before its else branch.
+pub struct Insts<'a> {
+    /// the remaining instructions of every block entered, innermost last
+    stack: Vec<core::slice::Iter<'a, Inst>>,
+}
+
+impl<'a> Insts<'a> {
+    pub fn new(insts: &'a [Inst]) -> Self {
+        Self {
+            stack: alloc::vec![insts.iter()],
+        }
+    }
+}
+
+impl<'a> Iterator for Insts<'a> {
+    type Item = &'a Inst;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some(inst) = self.stack.last_mut()?.next() else {
+                self.stack.pop();
+                continue;
+            };
+            match inst {
+                Inst::Block(_, body) | Inst::Loop(_, body) => {
+                    self.stack.push(body.as_ref().iter())
+                }
+                Inst::IfElse(_, then, els) => {
+                    self.stack.push(els.as_ref().iter());
+                    self.stack.push(then.as_ref().iter());
+                }
+                _ => {}
+            }
+            return Some(inst);
+        }
+    }
+}
+
 impl core::fmt::Debug for Expr {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_tuple("Expr").finish()
     }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum Inst {
     /// Control Instructions
     Unreachable = 0x00,
     Nop = 0x01,
-    Block(Expr) = 0x02,
-    Loop(Expr) = 0x03,
-    IfElse(Expr, Expr) = 0x04,
+    Block(BlockType, Expr) = 0x02,
+    Loop(BlockType, Expr) = 0x03,
+    IfElse(BlockType, Expr, Expr) = 0x04,
     Break(LabelIdx) = 0x0C,
     BreakIf(LabelIdx) = 0x0D,
     BreakTable(Vec<LabelIdx>, LabelIdx),
@@ -281,11 +602,26 @@ pub enum Inst {
     /// Parametric Instructions
     Drop,
     Select,
+    /// `select` with an explicit result type, required for references
+    SelectTyped(ValType),
+
+    /// Reference Instructions
+    RefNull(Reftype),
+    RefIsNull,
+    RefFunc(FuncIdx),
+
+    /// Table Instructions
+    TableInit(ElemIdx, TableIdx),
+    ElemDrop(ElemIdx),
+    /// destination, then source
+    TableCopy(TableIdx, TableIdx),
 
     /// Variable Instructions
     LocalGet(LocalIdx),
     LocalSet(LocalIdx),
     LocalTee(LocalIdx),
+    GlobalGet(GlobalIdx),
+    GlobalSet(GlobalIdx),
 
     /// Memory instructions
     I32Load(MemArg),
@@ -299,19 +635,26 @@ pub enum Inst {
     F64Store(MemArg),
     F64Load(MemArg),
     F32Load(MemArg),
+    F32Store(MemArg),
     I32Load8S(MemArg),
     I32Load16S(MemArg),
     I64Store8(MemArg),
     I64Store16(MemArg),
     I64Store32(MemArg),
+    I64Load8S(MemArg),
+    I64Load8U(MemArg),
+    I64Load16S(MemArg),
+    I64Load16U(MemArg),
+    I64Load32S(MemArg),
     I64Load32U(MemArg),
-    MemorySize,
-    MemoryGrow,
+    MemorySize(MemIdx),
+    MemoryGrow(MemIdx),
 
     /// Numeric const instructions
     I32Const(i32),
     I64Const(i64),
-    F64Const(f64),
+    F32Const(#[cfg_attr(feature = "serde", serde(with = "float_bits::f32"))] f32),
+    F64Const(#[cfg_attr(feature = "serde", serde(with = "float_bits::f64"))] f64),
 
     /// Numeric instructions
     /// 1. I32 compare
@@ -335,8 +678,18 @@ pub enum Inst {
     I64LtU,
     I64GtS,
     I64GtU,
+    I64LeS,
+    I64LeU,
+    I64GeS,
+    I64GeU,
 
     /// 3. F32 compare
+    F32Eq,
+    F32Ne,
+    F32Lt,
+    F32Gt,
+    F32Le,
+    F32Ge,
 
     /// 4. F64 compare
     F64Eq,
@@ -367,16 +720,40 @@ pub enum Inst {
     I32Rotr,
 
     /// 6. I64 math
+    I64Clz,
+    I64Ctz,
+    I64Popcnt,
     I64Mul,
     I64Add,
+    I64Sub,
+    I64DivS,
+    I64DivU,
+    I64RemS,
+    I64RemU,
     I64Or,
+    I64ShrS,
     I64ShrU,
+    I64Rotl,
+    I64Rotr,
     I64Xor,
     I64Shl,
     I64And,
 
     /// 7. F32 math
+    F32Abs,
+    F32Neg,
+    F32Ceil,
+    F32Floor,
+    F32Trunc,
+    F32Nearest,
+    F32Sqrt,
     F32Add,
+    F32Sub,
+    F32Mul,
+    F32Div,
+    F32Min,
+    F32Max,
+    F32Copysign,
 
     /// 8. F64 math
     F64Add,
@@ -392,10 +769,460 @@ pub enum Inst {
     F64Trunc,
     F64Nearest,
     F64Sqrt,
+    F64Copysign,
 
     /// 9. convert
     I32WrapI64,
-    F64ReinterpretI64,
-    F64ConvertI64U,
+    I32TruncF32S,
+    I32TruncF32U,
+    I32TruncF64S,
+    I32TruncF64U,
+    I64ExtendI32S,
     I64ExtendI32U,
+    I64TruncF32S,
+    I64TruncF32U,
+    I64TruncF64S,
+    I64TruncF64U,
+    F32ConvertI32S,
+    F32ConvertI32U,
+    F32ConvertI64S,
+    F32ConvertI64U,
+    F32DemoteF64,
+    F64ConvertI32S,
+    F64ConvertI32U,
+    F64ConvertI64S,
+    F64ConvertI64U,
+    F64PromoteF32,
+    I32ReinterpretF32,
+    I64ReinterpretF64,
+    F32ReinterpretI32,
+    F64ReinterpretI64,
+    I32Extend8S,
+    I32Extend16S,
+    I64Extend8S,
+    I64Extend16S,
+    I64Extend32S,
+
+    /// 10. jumps to instruction offsets, only produced by [`crate::flat::flatten`]
+    Jump(Branch),
+    JumpIf(Branch),
+    /// the else branch of an if, which starts at the same height
+    JumpUnless(usize),
+    JumpTable(Vec<Branch>, Branch),
+}
+
+/// The target of a jump in flat code, and the operands it keeps: the top
+/// `arity` values stay on the stack, the ones below them down to `height`
+/// are dropped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Branch {
+    /// the offset of the instruction to continue with
+    pub target: usize,
+    /// the stack height at the target, counted from the start of the function
+    pub height: usize,
+    /// how many values the branch carries
+    pub arity: usize,
+}
+
+impl fmt::Display for ValType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ValType::I32 => "i32",
+            ValType::I64 => "i64",
+            ValType::F32 => "f32",
+            ValType::F64 => "f64",
+            ValType::V128 => "v128",
+            ValType::FuncRef => "funcref",
+            ValType::ExternRef => "externref",
+        })
+    }
+}
+
+/// Renders the instruction as in the text format, like `i32.const 5` or
+/// `i32.load offset=8`. Structured instructions only render their opening
+/// keyword, without the body. The jumps of flat code, which have no text
+/// format, render as `jump 5`, `jump_if 5` and so on.
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Inst::Unreachable => "unreachable",
+            Inst::Nop => "nop",
+            Inst::Block(..) => "block",
+            Inst::Loop(..) => "loop",
+            Inst::IfElse(..) => "if",
+            Inst::Break(l) => return write!(f, "br {}", l.0),
+            Inst::BreakIf(l) => return write!(f, "br_if {}", l.0),
+            Inst::BreakTable(labels, default) => {
+                f.write_str("br_table")?;
+                for l in labels {
+                    write!(f, " {}", l.0)?;
+                }
+                return write!(f, " {}", default.0);
+            }
+            Inst::Return => "return",
+            Inst::Call(func) => return write!(f, "call {}", func.0),
+            Inst::CallIndirect(typ, table) => {
+                return write!(f, "call_indirect {} (type {})", table.0, typ.0)
+            }
+            Inst::Drop => "drop",
+            Inst::Select => "select",
+            Inst::SelectTyped(t) => return write!(f, "select (result {t})"),
+            Inst::RefNull(Reftype::Funcref) => "ref.null func",
+            Inst::RefNull(Reftype::Externref) => "ref.null extern",
+            Inst::RefIsNull => "ref.is_null",
+            Inst::RefFunc(func) => return write!(f, "ref.func {}", func.0),
+            Inst::TableInit(elem, table) => return write!(f, "table.init {} {}", table.0, elem.0),
+            Inst::ElemDrop(elem) => return write!(f, "elem.drop {}", elem.0),
+            Inst::TableCopy(dst, src) => return write!(f, "table.copy {} {}", dst.0, src.0),
+            Inst::LocalGet(l) => return write!(f, "local.get {}", l.0),
+            Inst::LocalSet(l) => return write!(f, "local.set {}", l.0),
+            Inst::LocalTee(l) => return write!(f, "local.tee {}", l.0),
+            Inst::GlobalGet(g) => return write!(f, "global.get {}", g.0),
+            Inst::GlobalSet(g) => return write!(f, "global.set {}", g.0),
+            Inst::MemorySize(MemIdx(0)) => "memory.size",
+            Inst::MemorySize(mem) => return write!(f, "memory.size {}", mem.0),
+            Inst::MemoryGrow(MemIdx(0)) => "memory.grow",
+            Inst::MemoryGrow(mem) => return write!(f, "memory.grow {}", mem.0),
+            Inst::I32Const(x) => return write!(f, "i32.const {x}"),
+            Inst::I64Const(x) => return write!(f, "i64.const {x}"),
+            Inst::F32Const(x) => {
+                f.write_str("f32.const ")?;
+                return float(
+                    f,
+                    x.is_nan(),
+                    x.is_sign_negative(),
+                    u64::from(x.to_bits() & 0x7f_ffff),
+                    0x40_0000,
+                    x,
+                );
+            }
+            Inst::F64Const(x) => {
+                f.write_str("f64.const ")?;
+                return float(
+                    f,
+                    x.is_nan(),
+                    x.is_sign_negative(),
+                    x.to_bits() & 0xf_ffff_ffff_ffff,
+                    0x8_0000_0000_0000,
+                    x,
+                );
+            }
+            Inst::I32Load(_) => "i32.load",
+            Inst::I64Load(_) => "i64.load",
+            Inst::I32Store(_) => "i32.store",
+            Inst::I32Store8(_) => "i32.store8",
+            Inst::I32Load8U(_) => "i32.load8_u",
+            Inst::I32Load16U(_) => "i32.load16_u",
+            Inst::I32Store16(_) => "i32.store16",
+            Inst::I64Store(_) => "i64.store",
+            Inst::F64Store(_) => "f64.store",
+            Inst::F64Load(_) => "f64.load",
+            Inst::F32Load(_) => "f32.load",
+            Inst::F32Store(_) => "f32.store",
+            Inst::I32Load8S(_) => "i32.load8_s",
+            Inst::I32Load16S(_) => "i32.load16_s",
+            Inst::I64Store8(_) => "i64.store8",
+            Inst::I64Store16(_) => "i64.store16",
+            Inst::I64Store32(_) => "i64.store32",
+            Inst::I64Load8S(_) => "i64.load8_s",
+            Inst::I64Load8U(_) => "i64.load8_u",
+            Inst::I64Load16S(_) => "i64.load16_s",
+            Inst::I64Load16U(_) => "i64.load16_u",
+            Inst::I64Load32S(_) => "i64.load32_s",
+            Inst::I64Load32U(_) => "i64.load32_u",
+            Inst::I32Eqz => "i32.eqz",
+            Inst::I32Eq => "i32.eq",
+            Inst::I32Ne => "i32.ne",
+            Inst::I32GeS => "i32.ge_s",
+            Inst::I32LtS => "i32.lt_s",
+            Inst::I32LtU => "i32.lt_u",
+            Inst::I32LeU => "i32.le_u",
+            Inst::I32GtS => "i32.gt_s",
+            Inst::I32GtU => "i32.gt_u",
+            Inst::I32LeS => "i32.le_s",
+            Inst::I32GeU => "i32.ge_u",
+            Inst::I64Eqz => "i64.eqz",
+            Inst::I64Eq => "i64.eq",
+            Inst::I64Ne => "i64.ne",
+            Inst::I64LtS => "i64.lt_s",
+            Inst::I64LtU => "i64.lt_u",
+            Inst::I64GtS => "i64.gt_s",
+            Inst::I64GtU => "i64.gt_u",
+            Inst::I64LeS => "i64.le_s",
+            Inst::I64LeU => "i64.le_u",
+            Inst::I64GeS => "i64.ge_s",
+            Inst::I64GeU => "i64.ge_u",
+            Inst::F32Eq => "f32.eq",
+            Inst::F32Ne => "f32.ne",
+            Inst::F32Lt => "f32.lt",
+            Inst::F32Gt => "f32.gt",
+            Inst::F32Le => "f32.le",
+            Inst::F32Ge => "f32.ge",
+            Inst::F64Eq => "f64.eq",
+            Inst::F64Ne => "f64.ne",
+            Inst::F64Le => "f64.le",
+            Inst::F64Ge => "f64.ge",
+            Inst::F64Lt => "f64.lt",
+            Inst::F64Gt => "f64.gt",
+            Inst::I32Clz => "i32.clz",
+            Inst::I32Ctz => "i32.ctz",
+            Inst::I32Add => "i32.add",
+            Inst::I32Sub => "i32.sub",
+            Inst::I32Mul => "i32.mul",
+            Inst::I32And => "i32.and",
+            Inst::I32Or => "i32.or",
+            Inst::I32Xor => "i32.xor",
+            Inst::I32DivS => "i32.div_s",
+            Inst::I32DivU => "i32.div_u",
+            Inst::I32RemS => "i32.rem_s",
+            Inst::I32RemU => "i32.rem_u",
+            Inst::I32ShrS => "i32.shr_s",
+            Inst::I32ShrU => "i32.shr_u",
+            Inst::I32Rotl => "i32.rotl",
+            Inst::I32Popcnt => "i32.popcnt",
+            Inst::I32Shl => "i32.shl",
+            Inst::I32Rotr => "i32.rotr",
+            Inst::I64Clz => "i64.clz",
+            Inst::I64Ctz => "i64.ctz",
+            Inst::I64Popcnt => "i64.popcnt",
+            Inst::I64Mul => "i64.mul",
+            Inst::I64Add => "i64.add",
+            Inst::I64Sub => "i64.sub",
+            Inst::I64DivS => "i64.div_s",
+            Inst::I64DivU => "i64.div_u",
+            Inst::I64RemS => "i64.rem_s",
+            Inst::I64RemU => "i64.rem_u",
+            Inst::I64Or => "i64.or",
+            Inst::I64ShrS => "i64.shr_s",
+            Inst::I64ShrU => "i64.shr_u",
+            Inst::I64Rotl => "i64.rotl",
+            Inst::I64Rotr => "i64.rotr",
+            Inst::I64Xor => "i64.xor",
+            Inst::I64Shl => "i64.shl",
+            Inst::I64And => "i64.and",
+            Inst::F32Abs => "f32.abs",
+            Inst::F32Neg => "f32.neg",
+            Inst::F32Ceil => "f32.ceil",
+            Inst::F32Floor => "f32.floor",
+            Inst::F32Trunc => "f32.trunc",
+            Inst::F32Nearest => "f32.nearest",
+            Inst::F32Sqrt => "f32.sqrt",
+            Inst::F32Add => "f32.add",
+            Inst::F32Sub => "f32.sub",
+            Inst::F32Mul => "f32.mul",
+            Inst::F32Div => "f32.div",
+            Inst::F32Min => "f32.min",
+            Inst::F32Max => "f32.max",
+            Inst::F32Copysign => "f32.copysign",
+            Inst::F64Add => "f64.add",
+            Inst::F64Sub => "f64.sub",
+            Inst::F64Mul => "f64.mul",
+            Inst::F64Abs => "f64.abs",
+            Inst::F64Neg => "f64.neg",
+            Inst::F64Div => "f64.div",
+            Inst::F64Min => "f64.min",
+            Inst::F64Max => "f64.max",
+            Inst::F64Ceil => "f64.ceil",
+            Inst::F64Floor => "f64.floor",
+            Inst::F64Trunc => "f64.trunc",
+            Inst::F64Nearest => "f64.nearest",
+            Inst::F64Sqrt => "f64.sqrt",
+            Inst::F64Copysign => "f64.copysign",
+            Inst::I32WrapI64 => "i32.wrap_i64",
+            Inst::I32TruncF32S => "i32.trunc_f32_s",
+            Inst::I32TruncF32U => "i32.trunc_f32_u",
+            Inst::I32TruncF64S => "i32.trunc_f64_s",
+            Inst::I32TruncF64U => "i32.trunc_f64_u",
+            Inst::I64ExtendI32S => "i64.extend_i32_s",
+            Inst::I64ExtendI32U => "i64.extend_i32_u",
+            Inst::I64TruncF32S => "i64.trunc_f32_s",
+            Inst::I64TruncF32U => "i64.trunc_f32_u",
+            Inst::I64TruncF64S => "i64.trunc_f64_s",
+            Inst::I64TruncF64U => "i64.trunc_f64_u",
+            Inst::F32ConvertI32S => "f32.convert_i32_s",
+            Inst::F32ConvertI32U => "f32.convert_i32_u",
+            Inst::F32ConvertI64S => "f32.convert_i64_s",
+            Inst::F32ConvertI64U => "f32.convert_i64_u",
+            Inst::F32DemoteF64 => "f32.demote_f64",
+            Inst::F64ConvertI32S => "f64.convert_i32_s",
+            Inst::F64ConvertI32U => "f64.convert_i32_u",
+            Inst::F64ConvertI64S => "f64.convert_i64_s",
+            Inst::F64ConvertI64U => "f64.convert_i64_u",
+            Inst::F64PromoteF32 => "f64.promote_f32",
+            Inst::I32ReinterpretF32 => "i32.reinterpret_f32",
+            Inst::I64ReinterpretF64 => "i64.reinterpret_f64",
+            Inst::F32ReinterpretI32 => "f32.reinterpret_i32",
+            Inst::F64ReinterpretI64 => "f64.reinterpret_i64",
+            Inst::I32Extend8S => "i32.extend8_s",
+            Inst::I32Extend16S => "i32.extend16_s",
+            Inst::I64Extend8S => "i64.extend8_s",
+            Inst::I64Extend16S => "i64.extend16_s",
+            Inst::I64Extend32S => "i64.extend32_s",
+            Inst::Jump(to) => return write!(f, "jump {}", to.target),
+            Inst::JumpIf(to) => return write!(f, "jump_if {}", to.target),
+            Inst::JumpUnless(to) => return write!(f, "jump_unless {to}"),
+            Inst::JumpTable(targets, default) => {
+                f.write_str("jump_table")?;
+                for to in targets {
+                    write!(f, " {}", to.target)?;
+                }
+                return write!(f, " {}", default.target);
+            }
+        };
+        f.write_str(name)?;
+        if let (Some(arg), Some(natural)) = (self.memarg(), self.natural_alignment()) {
+            if arg.memory.0 != 0 {
+                write!(f, " {}", arg.memory.0)?;
+            }
+            if arg.offset != 0 {
+                write!(f, " offset={}", arg.offset)?;
+            }
+            if arg.align != natural {
+                write!(f, " align={}", 1u64 << arg.align)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes a float constant the way the text format spells it, with `inf`,
+/// `nan` and `nan:0x...` for a NaN with a payload other than the canonical
+/// one.
+fn float(
+    f: &mut fmt::Formatter<'_>,
+    nan: bool,
+    negative: bool,
+    payload: u64,
+    canonical: u64,
+    x: &dyn fmt::Display,
+) -> fmt::Result {
+    if !nan {
+        return x.fmt(f);
+    }
+    if negative {
+        f.write_str("-")?;
+    }
+    if payload == canonical {
+        f.write_str("nan")
+    } else {
+        write!(f, "nan:{payload:#x}")
+    }
+}
+
+/// Float constants are serialized as their bit patterns, so NaN payloads and
+/// the sign of zero survive formats that can't represent them.
+#[cfg(feature = "serde")]
+mod float_bits {
+    pub mod f32 {
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(x: &f32, s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_u32(x.to_bits())
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<f32, D::Error> {
+            u32::deserialize(d).map(f32::from_bits)
+        }
+    }
+
+    pub mod f64 {
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(x: &f64, s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_u64(x.to_bits())
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
+            u64::deserialize(d).map(f64::from_bits)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec, vec::Vec};
+
+    use super::{
+        BlockType, Func, Inst, LabelIdx, LocalIdx, MemArg, MemIdx, Reftype, TypeIdx, ValType,
+    };
+
+    #[test]
+    fn insts_descend_into_blocks() {
+        let func = Func {
+            typ: TypeIdx(0),
+            locals: vec![],
+            body: vec![
+                Inst::Block(
+                    BlockType::Empty,
+                    vec![
+                        Inst::Loop(BlockType::Empty, vec![Inst::I32Const(1)].into()),
+                        Inst::Nop,
+                    ]
+                    .into(),
+                ),
+                Inst::IfElse(
+                    BlockType::Empty,
+                    vec![Inst::I32Const(2)].into(),
+                    vec![Inst::I32Const(3)].into(),
+                ),
+                Inst::Drop,
+            ],
+        };
+        let consts: Vec<_> = func
+            .insts()
+            .map(|inst| match inst {
+                Inst::Block(..) => "block",
+                Inst::Loop(..) => "loop",
+                Inst::IfElse(..) => "if",
+                Inst::I32Const(1) => "1",
+                Inst::I32Const(2) => "2",
+                Inst::I32Const(3) => "3",
+                Inst::Nop => "nop",
+                Inst::Drop => "drop",
+                _ => "?",
+            })
+            .collect();
+        assert_eq!(
+            consts,
+            ["block", "loop", "1", "nop", "if", "2", "3", "drop"]
+        );
+    }
+
+    #[test]
+    fn display_as_text() {
+        let arg = |align, offset| MemArg {
+            align,
+            offset,
+            memory: MemIdx(0),
+        };
+        let cases = [
+            (Inst::I32Const(5), "i32.const 5"),
+            (Inst::LocalGet(LocalIdx(0)), "local.get 0"),
+            (Inst::Break(LabelIdx(2)), "br 2"),
+            (
+                Inst::BreakTable(vec![LabelIdx(0), LabelIdx(1)], LabelIdx(2)),
+                "br_table 0 1 2",
+            ),
+            (Inst::I32Load(arg(2, 8)), "i32.load offset=8"),
+            (Inst::I64Load8U(arg(1, 0)), "i64.load8_u align=2"),
+            (Inst::I64ExtendI32U, "i64.extend_i32_u"),
+            (Inst::F32Const(-0.5), "f32.const -0.5"),
+            (Inst::F64Const(f64::NEG_INFINITY), "f64.const -inf"),
+            (Inst::F32Const(f32::NAN), "f32.const nan"),
+            (
+                Inst::F64Const(f64::from_bits(0x7ff0_0000_0000_0001)),
+                "f64.const nan:0x1",
+            ),
+            (Inst::SelectTyped(ValType::I64), "select (result i64)"),
+            (Inst::RefNull(Reftype::Externref), "ref.null extern"),
+            (
+                Inst::Block(BlockType::Empty, vec![Inst::Nop].into()),
+                "block",
+            ),
+        ];
+        for (inst, text) in cases {
+            assert_eq!(inst.to_string(), text);
+        }
+    }
 }
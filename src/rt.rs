@@ -1,13 +1,23 @@
-use std::{
+use alloc::{
+    rc::Rc,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{
     cell::RefCell,
+    fmt,
     ops::{self, Index, IndexMut},
-    rc::Rc,
-    sync::atomic::AtomicUsize,
 };
 
 use crate::{
-    instance::{FuncAddr, FuncInst, ModuleInst, Store},
-    repr::{self, Inst, LocalIdx, MemArg, ResultType},
+    flat::{flatten, CompileError},
+    float,
+    instance::{FuncAddr, FuncInst, ModuleInst, Store, WasmFfi},
+    repr::{
+        self, Branch, ElemIdx, Inst, LocalIdx, MemArg, MemIdx, ResultType, TableIdx, TypeIdx,
+        ValType,
+    },
 };
 
 pub struct Locals {
@@ -18,30 +28,87 @@ impl Locals {
     pub(crate) fn empty() -> Self {
         Self { locals: vec![] }
     }
-}
 
-impl Index<LocalIdx> for Locals {
-    type Output = Val;
+    /// The values of the locals, parameters first.
+    pub fn as_slice(&self) -> &[Val] {
+        &self.locals
+    }
 
-    fn index(&self, index: LocalIdx) -> &Self::Output {
-        self.locals.index(index.0 as usize)
+    fn get(&self, idx: LocalIdx) -> Result<Val, Error> {
+        self.locals
+            .get(idx.0 as usize)
+            .copied()
+            .ok_or(Error::LocalNotFound)
     }
-}
 
-impl IndexMut<LocalIdx> for Locals {
-    fn index_mut(&mut self, index: LocalIdx) -> &mut Self::Output {
-        self.locals.index_mut(index.0 as usize)
+    fn get_mut(&mut self, idx: LocalIdx) -> Result<&mut Val, Error> {
+        self.locals
+            .get_mut(idx.0 as usize)
+            .ok_or(Error::LocalNotFound)
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone)]
 pub enum Val {
     I32(i32),
     F32(f32),
     I64(i64),
+    F64(f64),
     Reference(Ref),
 }
 
+impl Val {
+    pub fn typ(&self) -> ValType {
+        match self {
+            Val::I32(_) => ValType::I32,
+            Val::F32(_) => ValType::F32,
+            Val::I64(_) => ValType::I64,
+            Val::F64(_) => ValType::F64,
+            Val::Reference(Ref::Null(repr::Reftype::Funcref) | Ref::Func(_)) => ValType::FuncRef,
+            Val::Reference(Ref::Null(repr::Reftype::Externref) | Ref::Extern(_)) => {
+                ValType::ExternRef
+            }
+        }
+    }
+
+    /// Compares two values exactly: floats are equal when their bits are, so
+    /// `0.0` and `-0.0` differ and a NaN equals a NaN with the same payload.
+    pub fn bit_eq(&self, other: &Val) -> bool {
+        match (self, other) {
+            (Val::I32(a), Val::I32(b)) => a == b,
+            (Val::I64(a), Val::I64(b)) => a == b,
+            (Val::F32(a), Val::F32(b)) => a.to_bits() == b.to_bits(),
+            (Val::F64(a), Val::F64(b)) => a.to_bits() == b.to_bits(),
+            (Val::Reference(a), Val::Reference(b)) => match (a, b) {
+                (Ref::Null(a), Ref::Null(b)) => {
+                    matches!(
+                        (a, b),
+                        (repr::Reftype::Funcref, repr::Reftype::Funcref)
+                            | (repr::Reftype::Externref, repr::Reftype::Externref)
+                    )
+                }
+                (Ref::Func(a), Ref::Func(b)) | (Ref::Extern(a), Ref::Extern(b)) => a == b,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Floats are printed with their bit pattern, which tells `-0.0` from `0.0`
+/// and shows the sign and payload of NaNs.
+impl core::fmt::Debug for Val {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Val::I32(x) => f.debug_tuple("I32").field(x).finish(),
+            Val::I64(x) => f.debug_tuple("I64").field(x).finish(),
+            Val::F32(x) => write!(f, "F32({x:?} {:#010x})", x.to_bits()),
+            Val::F64(x) => write!(f, "F64({x:?} {:#018x})", x.to_bits()),
+            Val::Reference(r) => f.debug_tuple("Reference").field(r).finish(),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum Ref {
     Null(repr::Reftype),
@@ -49,29 +116,77 @@ pub enum Ref {
     Extern(usize),
 }
 
-#[derive(Default, Debug)]
+/// The default [`Stack::max_depth`], a million values or 16MB.
+pub const DEFAULT_MAX_STACK_DEPTH: usize = 1 << 20;
+
+#[derive(Debug)]
 pub struct Stack {
     items: Vec<Val>,
+    /// How many values the stack holds at most, pushing more traps with
+    /// [`Error::StackOverflow`]. This keeps untrusted code from using up the
+    /// host's memory. It also bounds the locals of a single call.
+    pub max_depth: usize,
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Stack {
     pub(crate) fn new() -> Self {
-        Self { items: vec![] }
+        Self {
+            items: vec![],
+            max_depth: DEFAULT_MAX_STACK_DEPTH,
+        }
     }
-    fn push(&mut self, item: Val) {
-        println!("\tpush: {:?}", item);
+
+    pub(crate) fn push(&mut self, item: Val) -> Result<(), Error> {
+        if self.items.len() >= self.max_depth {
+            return Err(Error::StackOverflow);
+        }
+        trace!("\tpush: {:?}", item);
         self.items.push(item);
+        Ok(())
+    }
+
+    pub(crate) fn into_values(self) -> Vec<Val> {
+        self.items
+    }
+
+    /// The values on the stack, the top of the stack last.
+    pub fn as_slice(&self) -> &[Val] {
+        &self.items
+    }
+
+    /// The number of values on the stack.
+    pub fn depth(&self) -> usize {
+        self.items.len()
     }
 
     pub(crate) fn pop(&mut self) -> Result<Val, Error> {
         let val = self.items.pop().ok_or(Error::StackEmpty)?;
-        println!("\tpop: {:?}", val);
+        trace!("\tpop: {:?}", val);
         return Ok(val);
     }
 
+    /// Drops the values between `height` and the top `keep` values, for a
+    /// branch out of a block.
+    fn unwind(&mut self, height: usize, keep: usize) -> Result<(), Error> {
+        let top = self
+            .items
+            .len()
+            .checked_sub(keep)
+            .filter(|&top| top >= height)
+            .ok_or(Error::StackEmpty)?;
+        self.items.drain(height..top);
+        Ok(())
+    }
+
     fn peek(&self) -> Result<Val, Error> {
         let val = self.items.last().copied().ok_or(Error::StackEmpty)?;
-        println!("\tpeeked: {:?}", val);
+        trace!("\tpeeked: {:?}", val);
         return Ok(val);
     }
 }
@@ -82,9 +197,111 @@ pub enum Error {
     SegFault,
     FunctionNotFound,
     LocalNotFound,
+    LabelNotFound,
+    MemoryNotFound,
+    TypeNotFound,
+    GlobalNotFound,
+    TableNotFound,
+    ElemNotFound,
     WrongValType,
+    /// A function returned more or fewer values than its type says.
+    WrongResultCount,
     OobAccess { addr: usize, len: usize },
-    InvalidAlignment,
+    OobTableAccess { idx: usize, len: usize },
+    IntegerDivideByZero,
+    IntegerOverflow,
+    /// A host function panicked.
+    HostTrap,
+    /// A NaN was truncated to an integer.
+    InvalidConversionToInteger,
+    /// Calls were nested deeper than [`Machine::max_call_depth`].
+    CallStackExhausted,
+    /// The operand stack grew past [`Stack::max_depth`], or a function has
+    /// more locals than that.
+    StackOverflow,
+    /// An `unreachable` instruction was executed.
+    Unreachable,
+    /// `call_indirect` found a null or out of bounds table element.
+    UndefinedElement,
+    /// `call_indirect` found a function of a different type than expected.
+    IndirectCallTypeMismatch,
+}
+
+impl Error {
+    /// A stable name for the kind of error, like `integer_divide_by_zero`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::StackEmpty => "stack_empty",
+            Error::SegFault => "seg_fault",
+            Error::FunctionNotFound => "function_not_found",
+            Error::LocalNotFound => "local_not_found",
+            Error::LabelNotFound => "label_not_found",
+            Error::MemoryNotFound => "memory_not_found",
+            Error::TypeNotFound => "type_not_found",
+            Error::GlobalNotFound => "global_not_found",
+            Error::TableNotFound => "table_not_found",
+            Error::ElemNotFound => "elem_not_found",
+            Error::WrongValType => "wrong_val_type",
+            Error::WrongResultCount => "wrong_result_count",
+            Error::OobAccess { .. } => "oob_access",
+            Error::OobTableAccess { .. } => "oob_table_access",
+            Error::IntegerDivideByZero => "integer_divide_by_zero",
+            Error::IntegerOverflow => "integer_overflow",
+            Error::HostTrap => "host_trap",
+            Error::InvalidConversionToInteger => "invalid_conversion_to_integer",
+            Error::CallStackExhausted => "call_stack_exhausted",
+            Error::StackOverflow => "stack_overflow",
+            Error::Unreachable => "unreachable",
+            Error::UndefinedElement => "undefined_element",
+            Error::IndirectCallTypeMismatch => "indirect_call_type_mismatch",
+        }
+    }
+}
+
+/// Traps are described like the spec's reference interpreter does, so
+/// `assert_trap` messages can be compared against them.
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::StackEmpty => f.write_str("operand stack is empty"),
+            Error::SegFault => f.write_str("segmentation fault"),
+            Error::FunctionNotFound => f.write_str("function not found"),
+            Error::LocalNotFound => f.write_str("local not found"),
+            Error::LabelNotFound => f.write_str("label not found"),
+            Error::MemoryNotFound => f.write_str("memory not found"),
+            Error::TypeNotFound => f.write_str("type not found"),
+            Error::GlobalNotFound => f.write_str("global not found"),
+            Error::TableNotFound => f.write_str("table not found"),
+            Error::ElemNotFound => f.write_str("element segment not found"),
+            Error::WrongValType => f.write_str("type mismatch"),
+            Error::WrongResultCount => f.write_str("wrong number of results"),
+            Error::OobAccess { addr, len } => {
+                write!(f, "out of bounds memory access, {len} bytes at {addr}")
+            }
+            Error::OobTableAccess { idx, len } => {
+                write!(f, "out of bounds table access, {len} elements at {idx}")
+            }
+            Error::IntegerDivideByZero => f.write_str("integer divide by zero"),
+            Error::IntegerOverflow => f.write_str("integer overflow"),
+            Error::HostTrap => f.write_str("host function panicked"),
+            Error::InvalidConversionToInteger => f.write_str("invalid conversion to integer"),
+            Error::CallStackExhausted => f.write_str("call stack exhausted"),
+            Error::StackOverflow => f.write_str("operand stack exhausted"),
+            Error::Unreachable => f.write_str("unreachable executed"),
+            Error::UndefinedElement => f.write_str("undefined element"),
+            Error::IndirectCallTypeMismatch => f.write_str("indirect call type mismatch"),
+        }
+    }
+}
+
+impl From<CompileError> for Error {
+    fn from(e: CompileError) -> Self {
+        match e {
+            CompileError::UnknownLabel(_) => Error::LabelNotFound,
+            CompileError::UnknownType => Error::TypeNotFound,
+            CompileError::UnknownFunction => Error::FunctionNotFound,
+        }
+    }
 }
 
 impl From<Error> for Exception {
@@ -96,32 +313,175 @@ impl From<Error> for Exception {
 #[derive(Debug)]
 pub enum Exception {
     Runtime(Error),
-    Break(usize),
-    Return,
+}
+
+impl Exception {
+    /// Describes the exception for tools, see [`TrapReport`].
+    pub fn report(&self) -> TrapReport {
+        match self {
+            Exception::Runtime(e) => TrapReport {
+                kind: e.kind().into(),
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exception::Runtime(e) => e.fmt(f),
+        }
+    }
+}
+
+/// An [`Exception`] as plain data, for harnesses and editors that want to
+/// record or compare failures, for example as JSON with the `serde` feature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TrapReport {
+    /// what went wrong, see [`Error::kind`]
+    pub kind: String,
+    /// a description for humans, with details like the address of an out of
+    /// bounds access
+    pub message: String,
 }
 
 pub struct Machine<'a> {
     pub stack: Stack,
     pub store: &'a mut Store,
+    /// Replace every NaN produced by float arithmetic with the canonical NaN,
+    /// so results don't depend on the payloads the host FPU picks.
+    pub deterministic: bool,
+    /// How deeply calls may nest before trapping with
+    /// [`Error::CallStackExhausted`]. Calls between wasm functions don't
+    /// recurse on the native stack, so this only bounds the memory their
+    /// frames take.
+    pub max_call_depth: usize,
+    /// how many calls are currently running
+    depth: usize,
+}
+
+/// The default [`Machine::max_call_depth`].
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 100_000;
+
+/// A call to a wasm function made by running code, waiting for the code it
+/// calls to return.
+struct Frame {
+    /// always a [`FuncInst::Local`]
+    func: Rc<FuncInst>,
+    locals: Locals,
+    /// the height of the stack below the function's operands
+    base: usize,
+    /// where the function continues
+    pc: usize,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(store: &'a mut Store) -> Self {
+        Self {
+            stack: Stack::new(),
+            store,
+            deterministic: false,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            depth: 0,
+        }
+    }
+
+    /// The operand stack, for inspecting a machine between calls.
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
 }
 
 fn binop_i32(stack: &mut Stack, op: impl FnOnce(i32, i32) -> i32) -> Result<(), Exception> {
     let Val::I32(c2) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
     let Val::I32(c1) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
     let res = op(c1, c2);
-    println!("\t{:?} {:?} -> {:?}", c1, c2, res);
-    stack.push(Val::I32(res));
+    trace!("\t{:?} {:?} -> {:?}", c1, c2, res);
+    stack.push(Val::I32(res))?;
+    Ok(())
+}
+
+fn binop_i32_trap(
+    stack: &mut Stack,
+    op: impl FnOnce(i32, i32) -> Result<i32, Error>,
+) -> Result<(), Exception> {
+    let Val::I32(c2) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let Val::I32(c1) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let res = op(c1, c2)?;
+    trace!("\t{:?} {:?} -> {:?}", c1, c2, res);
+    stack.push(Val::I32(res))?;
     Ok(())
 }
 
 fn unop_i32(stack: &mut Stack, op: impl FnOnce(i32) -> i32) -> Result<(), Exception> {
     let Val::I32(val) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
     let res = op(val);
-    println!("\t{:?} -> {:?}", val, res);
-    stack.push(Val::I32(res));
+    trace!("\t{:?} -> {:?}", val, res);
+    stack.push(Val::I32(res))?;
+    Ok(())
+}
+
+fn binop_i64(stack: &mut Stack, op: impl FnOnce(i64, i64) -> i64) -> Result<(), Exception> {
+    let Val::I64(c2) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let Val::I64(c1) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let res = op(c1, c2);
+    trace!("\t{:?} {:?} -> {:?}", c1, c2, res);
+    stack.push(Val::I64(res))?;
+    Ok(())
+}
+
+fn binop_i64_trap(
+    stack: &mut Stack,
+    op: impl FnOnce(i64, i64) -> Result<i64, Error>,
+) -> Result<(), Exception> {
+    let Val::I64(c2) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let Val::I64(c1) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let res = op(c1, c2)?;
+    trace!("\t{:?} {:?} -> {:?}", c1, c2, res);
+    stack.push(Val::I64(res))?;
+    Ok(())
+}
+
+fn unop_i64(stack: &mut Stack, op: impl FnOnce(i64) -> i64) -> Result<(), Exception> {
+    let Val::I64(val) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let res = op(val);
+    trace!("\t{:?} -> {:?}", val, res);
+    stack.push(Val::I64(res))?;
+    Ok(())
+}
+
+fn relop_i64(stack: &mut Stack, op: impl FnOnce(i64, i64) -> bool) -> Result<(), Exception> {
+    let Val::I64(c2) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let Val::I64(c1) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let res = if op(c1, c2) { 1 } else { 0 };
+    trace!("\t{:?} {:?} -> {:?}", c1, c2, res);
+    stack.push(Val::I32(res))?;
     Ok(())
 }
 
+/// Calls a host function, catching a panic in it as a trap.
+#[cfg(feature = "std")]
+fn call_host(func: &dyn WasmFfi, store: &mut Store, args: &[Val]) -> Result<Vec<Val>, Exception> {
+    // the store may be left half-modified, which is documented on `WasmFfi`
+    let call = std::panic::AssertUnwindSafe(|| func.call(store, args));
+    std::panic::catch_unwind(call).map_err(|_| Exception::Runtime(Error::HostTrap))
+}
+
+#[cfg(not(feature = "std"))]
+fn call_host(func: &dyn WasmFfi, store: &mut Store, args: &[Val]) -> Result<Vec<Val>, Exception> {
+    Ok(func.call(store, args))
+}
+
+/// Pops the destination, source and length operands of the bulk instructions.
+fn pop_range(stack: &mut Stack) -> Result<(usize, usize, usize), Exception> {
+    let Val::I32(n) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let Val::I32(s) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let Val::I32(d) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    Ok((d as u32 as usize, s as u32 as usize, n as u32 as usize))
+}
+
 fn i32gt_u(a: i32, b: i32) -> i32 {
     let a = a as u32;
     let b = b as u32;
@@ -146,243 +506,969 @@ fn i32le_u(a: i32, b: i32) -> i32 {
     return if a <= b { 1 } else { 0 };
 }
 
+fn i32div_s(a: i32, b: i32) -> Result<i32, Error> {
+    if b == 0 {
+        return Err(Error::IntegerDivideByZero);
+    }
+    // the quotient of i32::MIN / -1 doesn't fit
+    return a.checked_div(b).ok_or(Error::IntegerOverflow);
+}
+
+fn i32div_u(a: i32, b: i32) -> Result<i32, Error> {
+    if b == 0 {
+        return Err(Error::IntegerDivideByZero);
+    }
+    return Ok(((a as u32) / (b as u32)) as i32);
+}
+
+fn i32rem_s(a: i32, b: i32) -> Result<i32, Error> {
+    if b == 0 {
+        return Err(Error::IntegerDivideByZero);
+    }
+    // unlike division, i32::MIN % -1 is defined and 0
+    return Ok(a.wrapping_rem(b));
+}
+
+fn i32rem_u(a: i32, b: i32) -> Result<i32, Error> {
+    if b == 0 {
+        return Err(Error::IntegerDivideByZero);
+    }
+    return Ok(((a as u32) % (b as u32)) as i32);
+}
+
+fn i64div_s(a: i64, b: i64) -> Result<i64, Error> {
+    if b == 0 {
+        return Err(Error::IntegerDivideByZero);
+    }
+    // the quotient of i64::MIN / -1 doesn't fit
+    a.checked_div(b).ok_or(Error::IntegerOverflow)
+}
+
+fn i64div_u(a: i64, b: i64) -> Result<i64, Error> {
+    if b == 0 {
+        return Err(Error::IntegerDivideByZero);
+    }
+    Ok(((a as u64) / (b as u64)) as i64)
+}
+
+fn i64rem_s(a: i64, b: i64) -> Result<i64, Error> {
+    if b == 0 {
+        return Err(Error::IntegerDivideByZero);
+    }
+    // unlike division, i64::MIN % -1 is defined and 0
+    Ok(a.wrapping_rem(b))
+}
+
+fn i64rem_u(a: i64, b: i64) -> Result<i64, Error> {
+    if b == 0 {
+        return Err(Error::IntegerDivideByZero);
+    }
+    Ok(((a as u64) % (b as u64)) as i64)
+}
+
+/// Truncates `x` towards zero for a conversion to an integer type whose
+/// values lie in `min..max`. Both bounds are exact in `f64`.
+fn trunc_to_int(x: f64, min: f64, max: f64) -> Result<f64, Error> {
+    if x.is_nan() {
+        return Err(Error::InvalidConversionToInteger);
+    }
+    let t = float::trunc_f64(x);
+    if t < min || t >= max {
+        return Err(Error::IntegerOverflow);
+    }
+    Ok(t)
+}
+
+/// Implements the `trunc` instruction `inst` on `x`.
+fn trunc_to(inst: &Inst, x: f64) -> Result<Val, Error> {
+    let val = match inst {
+        Inst::I32TruncF32S | Inst::I32TruncF64S => {
+            Val::I32(trunc_to_int(x, -2147483648.0, 2147483648.0)? as i32)
+        }
+        Inst::I32TruncF32U | Inst::I32TruncF64U => {
+            Val::I32(trunc_to_int(x, 0.0, 4294967296.0)? as u32 as i32)
+        }
+        Inst::I64TruncF32S | Inst::I64TruncF64S => {
+            Val::I64(trunc_to_int(x, -9223372036854775808.0, 9223372036854775808.0)? as i64)
+        }
+        _ => Val::I64(trunc_to_int(x, 0.0, 18446744073709551616.0)? as u64 as i64),
+    };
+    Ok(val)
+}
+
+fn canonicalize_f32(val: f32, deterministic: bool) -> f32 {
+    if deterministic && val.is_nan() {
+        return f32::from_bits(0x7fc0_0000);
+    }
+    return val;
+}
+
+fn binop_f32(
+    stack: &mut Stack,
+    deterministic: bool,
+    op: impl FnOnce(f32, f32) -> f32,
+) -> Result<(), Exception> {
+    let Val::F32(c2) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let Val::F32(c1) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let res = canonicalize_f32(op(c1, c2), deterministic);
+    trace!("\t{:?} {:?} -> {:?}", c1, c2, res);
+    stack.push(Val::F32(res))?;
+    Ok(())
+}
+
+fn unop_f32(
+    stack: &mut Stack,
+    deterministic: bool,
+    op: impl FnOnce(f32) -> f32,
+) -> Result<(), Exception> {
+    let Val::F32(val) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let res = canonicalize_f32(op(val), deterministic);
+    trace!("\t{:?} -> {:?}", val, res);
+    stack.push(Val::F32(res))?;
+    Ok(())
+}
+
+fn canonicalize_f64(val: f64, deterministic: bool) -> f64 {
+    if deterministic && val.is_nan() {
+        return f64::from_bits(0x7ff8_0000_0000_0000);
+    }
+    return val;
+}
+
+fn binop_f64(
+    stack: &mut Stack,
+    deterministic: bool,
+    op: impl FnOnce(f64, f64) -> f64,
+) -> Result<(), Exception> {
+    let Val::F64(c2) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let Val::F64(c1) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let res = canonicalize_f64(op(c1, c2), deterministic);
+    trace!("\t{:?} {:?} -> {:?}", c1, c2, res);
+    stack.push(Val::F64(res))?;
+    Ok(())
+}
+
+fn unop_f64(
+    stack: &mut Stack,
+    deterministic: bool,
+    op: impl FnOnce(f64) -> f64,
+) -> Result<(), Exception> {
+    let Val::F64(val) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let res = canonicalize_f64(op(val), deterministic);
+    trace!("\t{:?} -> {:?}", val, res);
+    stack.push(Val::F64(res))?;
+    Ok(())
+}
+
+fn relop_f32(stack: &mut Stack, op: impl FnOnce(f32, f32) -> bool) -> Result<(), Exception> {
+    let Val::F32(c2) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let Val::F32(c1) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let res = if op(c1, c2) { 1 } else { 0 };
+    trace!("\t{:?} {:?} -> {:?}", c1, c2, res);
+    stack.push(Val::I32(res))?;
+    Ok(())
+}
+
+fn relop_f64(stack: &mut Stack, op: impl FnOnce(f64, f64) -> bool) -> Result<(), Exception> {
+    let Val::F64(c2) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let Val::F64(c1) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+    let res = if op(c1, c2) { 1 } else { 0 };
+    trace!("\t{:?} {:?} -> {:?}", c1, c2, res);
+    stack.push(Val::I32(res))?;
+    Ok(())
+}
+
+// unlike f32::min/max, wasm propagates NaN and orders -0 below +0
+fn f32min(a: f32, b: f32) -> f32 {
+    if a.is_nan() || b.is_nan() {
+        return f32::NAN;
+    }
+    if a == b {
+        return if a.is_sign_negative() { a } else { b };
+    }
+    return a.min(b);
+}
+
+fn f32max(a: f32, b: f32) -> f32 {
+    if a.is_nan() || b.is_nan() {
+        return f32::NAN;
+    }
+    if a == b {
+        return if a.is_sign_positive() { a } else { b };
+    }
+    return a.max(b);
+}
+
+fn f64min(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        return f64::NAN;
+    }
+    if a == b {
+        return if a.is_sign_negative() { a } else { b };
+    }
+    a.min(b)
+}
+
+fn f64max(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        return f64::NAN;
+    }
+    if a == b {
+        return if a.is_sign_positive() { a } else { b };
+    }
+    a.max(b)
+}
+
+// shift counts are taken modulo the bit width
+fn i32shl(a: i32, b: i32) -> i32 {
+    return a << (b & 31);
+}
+
+fn i32shr_s(a: i32, b: i32) -> i32 {
+    return a >> (b & 31);
+}
+
 fn i32shr_u(a: i32, b: i32) -> i32 {
     let a = a as u32;
-    let b = b as u32;
+    let b = (b & 31) as u32;
     let res = a >> b;
     return res as i32;
 }
 
+fn i32rotl(a: i32, b: i32) -> i32 {
+    return a.rotate_left((b & 31) as u32);
+}
+
+fn i32rotr(a: i32, b: i32) -> i32 {
+    return a.rotate_right((b & 31) as u32);
+}
+
+fn i64shl(a: i64, b: i64) -> i64 {
+    return a << (b & 63);
+}
+
+fn i64shr_s(a: i64, b: i64) -> i64 {
+    a >> (b & 63)
+}
+
+fn i64shr_u(a: i64, b: i64) -> i64 {
+    let a = a as u64;
+    let b = (b & 63) as u64;
+    let res = a >> b;
+    return res as i64;
+}
+
+fn i64rotl(a: i64, b: i64) -> i64 {
+    a.rotate_left((b & 63) as u32)
+}
+
+fn i64rotr(a: i64, b: i64) -> i64 {
+    a.rotate_right((b & 63) as u32)
+}
+
+/// Pops an address and adds the offset of `memarg` to it. The alignment is
+/// only a hint, misaligned accesses work all the same.
 fn effective_address(stack: &mut Stack, memarg: MemArg) -> Result<usize, Exception> {
     let Val::I32(i) = stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
-    let ea = i as usize + memarg.offset as usize;
-    println!("\tea: 0x{:0x?}", ea);
-    if memarg.align != 0 {
-        let is_aligned = ea & ((1 << (memarg.align - 1)) - 1) == 0;
-        if !is_aligned {
-            return Err(Exception::Runtime(Error::InvalidAlignment));
-        }
-    }
-    return Ok(ea);
+    // the address is unsigned and the sum can take 33 bits, anything past
+    // the end of the memory fails its bounds check
+    let ea = i as u32 as u64 + memarg.offset as u64;
+    trace!("\tea: 0x{:0x?}", ea);
+    Ok(usize::try_from(ea).unwrap_or(usize::MAX))
 }
 
 impl Machine<'_> {
+    /// Pops an address and reads `N` bytes at it from the memory `memarg` refers to.
+    fn load_n<const N: usize>(
+        &mut self,
+        module: &RefCell<ModuleInst>,
+        memarg: MemArg,
+    ) -> Result<[u8; N], Exception> {
+        let mem_addr = module
+            .borrow()
+            .mem_addr(memarg.memory)
+            .ok_or(Error::MemoryNotFound)?;
+        let ea = effective_address(&mut self.stack, memarg)?;
+        let bytes = self.store.mems[mem_addr.0].read(ea, N)?;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    /// Pops an address and writes `bytes` at it to the memory `memarg` refers to.
+    fn store_n<const N: usize>(
+        &mut self,
+        module: &RefCell<ModuleInst>,
+        memarg: MemArg,
+        bytes: [u8; N],
+    ) -> Result<(), Exception> {
+        let mem_addr = module
+            .borrow()
+            .mem_addr(memarg.memory)
+            .ok_or(Error::MemoryNotFound)?;
+        let ea = effective_address(&mut self.stack, memarg)?;
+        self.store.mems[mem_addr.0].write(ea, &bytes)?;
+        Ok(())
+    }
+
+    /// Drops the operands `branch` leaves behind, in code whose operands
+    /// start at `base`, and returns where to continue.
+    fn branch(&mut self, base: usize, branch: &Branch) -> Result<usize, Exception> {
+        self.stack.unwind(base + branch.height, branch.arity)?;
+        Ok(branch.target)
+    }
+
+    /// Pops the table index of a `call_indirect` and looks up the function
+    /// there, checking that it has the expected type.
+    fn resolve_indirect(
+        &mut self,
+        module: &RefCell<ModuleInst>,
+        typeidx: TypeIdx,
+        tableidx: TableIdx,
+    ) -> Result<FuncAddr, Exception> {
+        let Val::I32(i) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+        let expected = module
+            .borrow()
+            .func_type(typeidx)
+            .ok_or(Error::TypeNotFound)?;
+        let table_addr = module
+            .borrow()
+            .table_addr(tableidx)
+            .ok_or(Error::TableNotFound)?;
+        let table = &self.store.tables[table_addr.0];
+        let Ok([Ref::Func(addr)]) = table.read(i as u32 as usize, 1) else {
+            return Err(Exception::Runtime(Error::UndefinedElement));
+        };
+        let func_addr = FuncAddr(*addr);
+        if *self.store.funcs[func_addr.0].typ() != *expected {
+            return Err(Exception::Runtime(Error::IndirectCallTypeMismatch));
+        }
+        Ok(func_addr)
+    }
+
+    /// Pops the operands of `table.init` and copies part of an element
+    /// segment into a table.
+    fn table_init(
+        &mut self,
+        module: &RefCell<ModuleInst>,
+        elemidx: ElemIdx,
+        tableidx: TableIdx,
+    ) -> Result<(), Exception> {
+        let elem_addr = module
+            .borrow()
+            .elem_addr(elemidx)
+            .ok_or(Error::ElemNotFound)?;
+        let table_addr = module
+            .borrow()
+            .table_addr(tableidx)
+            .ok_or(Error::TableNotFound)?;
+        let (d, s, n) = pop_range(&mut self.stack)?;
+        let refs = self.store.elems[elem_addr.0].read(s, n)?;
+        self.store.tables[table_addr.0].write(d, refs)?;
+        Ok(())
+    }
+
+    /// Pops the operands of `table.copy` and copies between two tables, or
+    /// within one.
+    fn table_copy(
+        &mut self,
+        module: &RefCell<ModuleInst>,
+        dst: TableIdx,
+        src: TableIdx,
+    ) -> Result<(), Exception> {
+        let dst_addr = module
+            .borrow()
+            .table_addr(dst)
+            .ok_or(Error::TableNotFound)?;
+        let src_addr = module
+            .borrow()
+            .table_addr(src)
+            .ok_or(Error::TableNotFound)?;
+        let (d, s, n) = pop_range(&mut self.stack)?;
+        // copying out first keeps overlapping ranges of one table intact
+        let refs = self.store.tables[src_addr.0].read(s, n)?.to_vec();
+        self.store.tables[dst_addr.0].write(d, &refs)?;
+        Ok(())
+    }
+
     pub fn call(&mut self, func_addr: FuncAddr) -> Result<(), Exception> {
+        if self.depth >= self.max_call_depth {
+            return Err(Exception::Runtime(Error::CallStackExhausted));
+        }
+        self.depth += 1;
+        let res = self.call_unchecked(func_addr);
+        self.depth -= 1;
+        res
+    }
+
+    fn call_unchecked(&mut self, func_addr: FuncAddr) -> Result<(), Exception> {
         let func = self.store.funcs[func_addr.0].clone();
         match func.as_ref() {
             FuncInst::Local { typ, module, code } => {
                 let mut locals = get_locals(&mut self.stack, &typ.from, &code.locals)?;
-                match self.execute(module.clone(), &code.body, &mut locals) {
-                    Ok(()) => {}
-                    Err(Exception::Return) => {}
-                    Err(Exception::Break(_n)) => panic!("can't break through function"),
-                    Err(e) => return Err(e),
-                }
-                // TODO: check stack return effect
+                let base = self.stack.items.len();
+                self.execute_flat(module, &code.body, &mut locals)?;
+                check_results(&self.stack, base, &typ.to)?;
             }
             FuncInst::External { typ, func } => {
-                todo!()
+                let args = get_locals(&mut self.stack, &typ.from, &[])?;
+                let results = call_host(func.as_ref(), self.store, args.as_slice())?;
+                let base = self.stack.items.len();
+                for val in results {
+                    self.stack.push(val)?;
+                }
+                check_results(&self.stack, base, &typ.to)?;
             }
         }
         Ok(())
     }
+    /// Calls the function with `args` and takes its results off the stack,
+    /// in the order of the function's result types. Whatever was on the
+    /// stack before is left alone.
+    pub fn invoke(&mut self, func_addr: FuncAddr, args: &[Val]) -> Result<Vec<Val>, Exception> {
+        let base = self.stack.items.len();
+        for arg in args {
+            self.stack.push(*arg)?;
+        }
+        if let Err(e) = self.call(func_addr) {
+            self.stack.items.truncate(base);
+            return Err(e);
+        }
+        // call checked that the results are all that's left above `base`,
+        // the last one on top
+        Ok(self.stack.items.split_off(base))
+    }
+
+    /// Runs a structured instruction sequence, see [`flatten`]. Unlike a
+    /// function it has no result type, branching out of it keeps everything
+    /// it would leave on the stack at its end.
     pub fn execute(
         &mut self,
         module: Rc<RefCell<ModuleInst>>,
         instructions: &[Inst],
         locals: &mut Locals,
     ) -> Result<(), Exception> {
-        static COUNT: AtomicUsize = AtomicUsize::new(0);
-        for inst in instructions {
-            println!(
-                "{}: {:?}",
-                COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
-                inst
-            );
+        let signatures = module.borrow().signatures(self.store);
+        let code = flatten(instructions, None, &signatures).map_err(Error::from)?;
+        self.execute_flat(&module, &code, locals)
+    }
+
+    /// Runs flattened code until it falls off the end, which is also where
+    /// `return` jumps to. Calls to wasm functions push a [`Frame`] instead of
+    /// recursing, so how deeply they nest doesn't depend on the native stack.
+    fn execute_flat(
+        &mut self,
+        module: &RefCell<ModuleInst>,
+        code: &[Inst],
+        locals: &mut Locals,
+    ) -> Result<(), Exception> {
+        // the heights of branches count from here
+        let base = self.stack.items.len();
+        let mut pc = 0;
+        let mut frames: Vec<Frame> = vec![];
+        loop {
+            let call = match frames.last_mut() {
+                None => self.run_until_call(module, code, locals, base, &mut pc)?,
+                Some(Frame {
+                    func,
+                    locals,
+                    base,
+                    pc,
+                }) => {
+                    let FuncInst::Local { module, code, .. } = func.as_ref() else {
+                        unreachable!("frame of a host function")
+                    };
+                    self.run_until_call(module, &code.body, locals, *base, pc)?
+                }
+            };
+            let Some(func_addr) = call else {
+                let Some(frame) = frames.pop() else { return Ok(()) };
+                check_results(&self.stack, frame.base, &frame.func.typ().to)?;
+                continue;
+            };
+            if self.depth + frames.len() >= self.max_call_depth {
+                return Err(Exception::Runtime(Error::CallStackExhausted));
+            }
+            let func = self.store.funcs[func_addr.0].clone();
+            let FuncInst::Local { typ, code, .. } = func.as_ref() else {
+                self.call_unchecked(func_addr)?;
+                continue;
+            };
+            let locals = get_locals(&mut self.stack, &typ.from, &code.locals)?;
+            frames.push(Frame {
+                func,
+                locals,
+                base: self.stack.items.len(),
+                pc: 0,
+            });
+        }
+    }
+
+    /// Runs flattened code from `pc` until it falls off the end, or until it
+    /// calls a function. That function is returned, with `pc` right after the
+    /// call.
+    fn run_until_call(
+        &mut self,
+        module: &RefCell<ModuleInst>,
+        code: &[Inst],
+        locals: &mut Locals,
+        base: usize,
+        resume: &mut usize,
+    ) -> Result<Option<FuncAddr>, Exception> {
+        let mut pc = *resume;
+        while let Some(inst) = code.get(pc) {
+            pc += 1;
+            trace!("{pc}: {inst:?}");
             match inst {
-                Inst::Unreachable => panic!("reached unreachable"),
-                Inst::Nop => todo!(),
-                Inst::Block(instructions) => {
-                    match self.execute(module.clone(), instructions.as_ref(), locals) {
-                        Ok(()) => {}
-                        Err(Exception::Break(0)) => return Ok(()),
-                        Err(Exception::Break(n)) => return Err(Exception::Break(n - 1)),
-                        Err(e) => return Err(e),
+                Inst::Unreachable => return Err(Exception::Runtime(Error::Unreachable)),
+                Inst::Nop => {}
+                Inst::Jump(branch) => pc = self.branch(base, branch)?,
+                Inst::JumpIf(branch) => {
+                    let Val::I32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    if c != 0 {
+                        trace!("\tbreaking");
+                        pc = self.branch(base, branch)?;
                     }
                 }
-                Inst::Loop(instructions) => loop {
-                    match self.execute(module.clone(), instructions.as_ref(), locals) {
-                        Ok(()) => break,
-                        Err(Exception::Break(0)) => continue,
-                        Err(Exception::Break(n)) => return Err(Exception::Break(n - 1)),
-                        Err(e) => return Err(e),
-                    }
-                },
-                Inst::IfElse(_, _) => todo!(),
-                Inst::Break(b) => return Err(Exception::Break(b.0 as usize)),
-                Inst::BreakIf(b) => {
+                Inst::JumpUnless(target) => {
                     let Val::I32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
-                    if c != 0 {
-                        println!("\tbreaking");
-                        return Err(Exception::Break(b.0 as usize));
+                    if c == 0 {
+                        pc = *target;
                     }
                 }
-                Inst::Return => return Err(Exception::Return),
+                Inst::JumpTable(branches, default) => {
+                    let Val::I32(i) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    let branch = branches.get(i as u32 as usize).unwrap_or(default);
+                    pc = self.branch(base, branch)?;
+                }
+                Inst::Block(..)
+                | Inst::Loop(..)
+                | Inst::IfElse(..)
+                | Inst::Break(_)
+                | Inst::BreakIf(_)
+                | Inst::BreakTable(_, _)
+                | Inst::Return => {
+                    unreachable!("structured control flow in flattened code")
+                }
                 Inst::Call(func) => {
-                    let func_addr = module.borrow().func_addrs[func.0 as usize];
-                    self.call(func_addr)?
+                    let func_addr = module.borrow().func_addrs.get(func.0 as usize).copied();
+                    let func_addr = func_addr.ok_or(Error::FunctionNotFound)?;
+                    *resume = pc;
+                    return Ok(Some(func_addr));
                 }
-                Inst::CallIndirect(typidx, tableidx) => {
-                    todo!();
+                Inst::CallIndirect(typeidx, tableidx) => {
+                    let func_addr = self.resolve_indirect(module, *typeidx, *tableidx)?;
+                    *resume = pc;
+                    return Ok(Some(func_addr));
                 }
-                Inst::Select => {
+                Inst::Select | Inst::SelectTyped(_) => {
                     let Val::I32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
                     let val2 = self.stack.pop()?;
                     let val1 = self.stack.pop()?;
+                    // untyped select only works on numbers, references need
+                    // the annotation
+                    let typ = match inst {
+                        Inst::SelectTyped(typ) => *typ,
+                        _ if matches!(val1, Val::Reference(_)) => {
+                            return Err(Exception::Runtime(Error::WrongValType))
+                        }
+                        _ => val1.typ(),
+                    };
+                    if val1.typ() != typ || val2.typ() != typ {
+                        return Err(Exception::Runtime(Error::WrongValType));
+                    }
                     if c != 0 {
-                        self.stack.push(val1);
+                        self.stack.push(val1)?;
                     } else {
-                        self.stack.push(val2);
+                        self.stack.push(val2)?;
                     }
                 }
+                Inst::RefNull(t) => self.stack.push(Val::Reference(Ref::Null(*t)))?,
+                Inst::RefIsNull => {
+                    let Val::Reference(r) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    let is_null = matches!(r, Ref::Null(_));
+                    self.stack.push(Val::I32(if is_null { 1 } else { 0 }))?;
+                }
+                Inst::RefFunc(func) => {
+                    let func_addr = module.borrow().func_addrs.get(func.0 as usize).copied();
+                    let func_addr = func_addr.ok_or(Error::FunctionNotFound)?;
+                    self.stack.push(Val::Reference(Ref::Func(func_addr.0)))?;
+                }
+                Inst::TableInit(elemidx, tableidx) => {
+                    self.table_init(module, *elemidx, *tableidx)?
+                }
+                Inst::ElemDrop(elemidx) => {
+                    let elem_addr = module
+                        .borrow()
+                        .elem_addr(*elemidx)
+                        .ok_or(Error::ElemNotFound)?;
+                    self.store.elems[elem_addr.0].clear();
+                }
+                Inst::TableCopy(dst, src) => self.table_copy(module, *dst, *src)?,
                 Inst::LocalGet(idx) => {
-                    let local = locals[*idx];
-                    self.stack.push(local);
+                    let local = locals.get(*idx)?;
+                    self.stack.push(local)?;
                 }
                 Inst::LocalSet(idx) => {
                     let val = self.stack.pop()?;
-                    locals[*idx] = val;
+                    *locals.get_mut(*idx)? = val;
                 }
                 Inst::LocalTee(idx) => {
                     let val = self.stack.peek()?;
-                    locals[*idx] = val;
+                    *locals.get_mut(*idx)? = val;
                 }
-                Inst::I32Add => binop_i32(&mut self.stack, ops::Add::add)?,
-                Inst::I32Sub => binop_i32(&mut self.stack, ops::Sub::sub)?,
-                Inst::I32GtU => binop_i32(&mut self.stack, i32gt_u)?,
-                Inst::I32LtU => binop_i32(&mut self.stack, i32lt_u)?,
+                Inst::GlobalGet(idx) => {
+                    let global_addr = module
+                        .borrow()
+                        .global_addr(*idx)
+                        .ok_or(Error::GlobalNotFound)?;
+                    let val = self.store.globals[global_addr.0].value;
+                    self.stack.push(val)?;
+                }
+                // validation rejects setting an immutable global
+                Inst::GlobalSet(idx) => {
+                    let val = self.stack.pop()?;
+                    let global_addr = module
+                        .borrow()
+                        .global_addr(*idx)
+                        .ok_or(Error::GlobalNotFound)?;
+                    let global = &mut self.store.globals[global_addr.0];
+                    if val.typ() != global.typ.valtype {
+                        return Err(Exception::Runtime(Error::WrongValType));
+                    }
+                    global.value = val;
+                }
+                Inst::MemorySize(idx) => {
+                    let mem_addr = module
+                        .borrow()
+                        .mem_addr(*idx)
+                        .ok_or(Error::MemoryNotFound)?;
+                    let pages = self.store.mems[mem_addr.0].pages();
+                    self.stack.push(Val::I32(pages as i32))?;
+                }
+                Inst::MemoryGrow(idx) => {
+                    let Val::I32(delta) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    let mem_addr = module
+                        .borrow()
+                        .mem_addr(*idx)
+                        .ok_or(Error::MemoryNotFound)?;
+                    let old = self.store.grow_memory(mem_addr, delta as u32 as u64);
+                    self.stack
+                        .push(Val::I32(old.map_or(-1, |pages| pages as i32)))?;
+                }
+                Inst::I32Add => binop_i32(&mut self.stack, i32::wrapping_add)?,
+                Inst::I32Sub => binop_i32(&mut self.stack, i32::wrapping_sub)?,
+                Inst::I32Mul => binop_i32(&mut self.stack, i32::wrapping_mul)?,
+                Inst::I32DivS => binop_i32_trap(&mut self.stack, i32div_s)?,
+                Inst::I32DivU => binop_i32_trap(&mut self.stack, i32div_u)?,
+                Inst::I32RemS => binop_i32_trap(&mut self.stack, i32rem_s)?,
+                Inst::I32RemU => binop_i32_trap(&mut self.stack, i32rem_u)?,
+                Inst::I32GtU => binop_i32(&mut self.stack, i32gt_u)?,
+                Inst::I32LtU => binop_i32(&mut self.stack, i32lt_u)?,
                 Inst::I32GeU => binop_i32(&mut self.stack, i32ge_u)?,
                 Inst::I32LeU => binop_i32(&mut self.stack, i32le_u)?,
+                Inst::I32GtS => binop_i32(&mut self.stack, |a, b| if a > b { 1 } else { 0 })?,
+                Inst::I32LtS => binop_i32(&mut self.stack, |a, b| if a < b { 1 } else { 0 })?,
+                Inst::I32GeS => binop_i32(&mut self.stack, |a, b| if a >= b { 1 } else { 0 })?,
+                Inst::I32LeS => binop_i32(&mut self.stack, |a, b| if a <= b { 1 } else { 0 })?,
                 Inst::I32And => binop_i32(&mut self.stack, ops::BitAnd::bitand)?,
                 Inst::I32ShrU => binop_i32(&mut self.stack, i32shr_u)?,
-                Inst::I32Shl => binop_i32(&mut self.stack, ops::Shl::shl)?,
+                Inst::I32ShrS => binop_i32(&mut self.stack, i32shr_s)?,
+                Inst::I32Shl => binop_i32(&mut self.stack, i32shl)?,
                 Inst::I32Or => binop_i32(&mut self.stack, ops::BitOr::bitor)?,
                 Inst::I32Xor => binop_i32(&mut self.stack, ops::BitXor::bitxor)?,
-                Inst::I32Rotl => binop_i32(&mut self.stack, |a, b| a.rotate_left(b as u32))?,
+                Inst::I32Rotl => binop_i32(&mut self.stack, i32rotl)?,
+                Inst::I32Rotr => binop_i32(&mut self.stack, i32rotr)?,
+                Inst::I32Clz => unop_i32(&mut self.stack, |a| a.leading_zeros() as i32)?,
+                Inst::I32Ctz => unop_i32(&mut self.stack, |a| a.trailing_zeros() as i32)?,
+                Inst::I32Popcnt => unop_i32(&mut self.stack, |a| a.count_ones() as i32)?,
+                Inst::I64Add => binop_i64(&mut self.stack, i64::wrapping_add)?,
+                Inst::I64Sub => binop_i64(&mut self.stack, i64::wrapping_sub)?,
+                Inst::I64Mul => binop_i64(&mut self.stack, i64::wrapping_mul)?,
+                Inst::I64And => binop_i64(&mut self.stack, ops::BitAnd::bitand)?,
+                Inst::I64Or => binop_i64(&mut self.stack, ops::BitOr::bitor)?,
+                Inst::I64Xor => binop_i64(&mut self.stack, ops::BitXor::bitxor)?,
+                Inst::I64Clz => unop_i64(&mut self.stack, |a| a.leading_zeros() as i64)?,
+                Inst::I64Ctz => unop_i64(&mut self.stack, |a| a.trailing_zeros() as i64)?,
+                Inst::I64Popcnt => unop_i64(&mut self.stack, |a| a.count_ones() as i64)?,
+                Inst::I64DivS => binop_i64_trap(&mut self.stack, i64div_s)?,
+                Inst::I64DivU => binop_i64_trap(&mut self.stack, i64div_u)?,
+                Inst::I64RemS => binop_i64_trap(&mut self.stack, i64rem_s)?,
+                Inst::I64RemU => binop_i64_trap(&mut self.stack, i64rem_u)?,
+                Inst::I64Shl => binop_i64(&mut self.stack, i64shl)?,
+                Inst::I64ShrS => binop_i64(&mut self.stack, i64shr_s)?,
+                Inst::I64ShrU => binop_i64(&mut self.stack, i64shr_u)?,
+                Inst::I64Rotl => binop_i64(&mut self.stack, i64rotl)?,
+                Inst::I64Rotr => binop_i64(&mut self.stack, i64rotr)?,
                 Inst::I32Eq => binop_i32(&mut self.stack, |a, b| if a == b { 1 } else { 0 })?,
+                Inst::I32Ne => binop_i32(&mut self.stack, |a, b| if a != b { 1 } else { 0 })?,
                 Inst::I32Eqz => unop_i32(&mut self.stack, |b| if b == 0 { 1 } else { 0 })?,
-                Inst::F32Add => todo!(),
-                Inst::I32Const(v) => self.stack.push(Val::I32(*v)),
-                Inst::I64Const(v) => self.stack.push(Val::I64(*v)),
+                Inst::I64Eq => relop_i64(&mut self.stack, |a, b| a == b)?,
+                Inst::I64Ne => relop_i64(&mut self.stack, |a, b| a != b)?,
+                Inst::I64Eqz => {
+                    let Val::I64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::I32(if c == 0 { 1 } else { 0 }))?;
+                }
+                Inst::I64LtS => relop_i64(&mut self.stack, |a, b| a < b)?,
+                Inst::I64LtU => relop_i64(&mut self.stack, |a, b| (a as u64) < (b as u64))?,
+                Inst::I64GtS => relop_i64(&mut self.stack, |a, b| a > b)?,
+                Inst::I64GtU => relop_i64(&mut self.stack, |a, b| a as u64 > b as u64)?,
+                Inst::I64LeS => relop_i64(&mut self.stack, |a, b| a <= b)?,
+                Inst::I64LeU => relop_i64(&mut self.stack, |a, b| a as u64 <= b as u64)?,
+                Inst::I64GeS => relop_i64(&mut self.stack, |a, b| a >= b)?,
+                Inst::I64GeU => relop_i64(&mut self.stack, |a, b| a as u64 >= b as u64)?,
+                Inst::F32Eq => relop_f32(&mut self.stack, |a, b| a == b)?,
+                Inst::F32Ne => relop_f32(&mut self.stack, |a, b| a != b)?,
+                Inst::F32Lt => relop_f32(&mut self.stack, |a, b| a < b)?,
+                Inst::F32Gt => relop_f32(&mut self.stack, |a, b| a > b)?,
+                Inst::F32Le => relop_f32(&mut self.stack, |a, b| a <= b)?,
+                Inst::F32Ge => relop_f32(&mut self.stack, |a, b| a >= b)?,
+                // abs and neg only flip the sign bit, NaN payloads must survive them
+                Inst::F32Abs => unop_f32(&mut self.stack, false, float::abs_f32)?,
+                Inst::F32Neg => unop_f32(&mut self.stack, false, ops::Neg::neg)?,
+                Inst::F32Ceil => unop_f32(&mut self.stack, self.deterministic, float::ceil_f32)?,
+                Inst::F32Floor => unop_f32(&mut self.stack, self.deterministic, float::floor_f32)?,
+                Inst::F32Trunc => unop_f32(&mut self.stack, self.deterministic, float::trunc_f32)?,
+                Inst::F32Nearest => {
+                    unop_f32(&mut self.stack, self.deterministic, float::nearest_f32)?
+                }
+                Inst::F32Sqrt => unop_f32(&mut self.stack, self.deterministic, float::sqrt_f32)?,
+                Inst::F32Add => binop_f32(&mut self.stack, self.deterministic, ops::Add::add)?,
+                Inst::F32Sub => binop_f32(&mut self.stack, self.deterministic, ops::Sub::sub)?,
+                Inst::F32Mul => binop_f32(&mut self.stack, self.deterministic, ops::Mul::mul)?,
+                Inst::F32Div => binop_f32(&mut self.stack, self.deterministic, ops::Div::div)?,
+                Inst::F32Min => binop_f32(&mut self.stack, self.deterministic, f32min)?,
+                Inst::F32Max => binop_f32(&mut self.stack, self.deterministic, f32max)?,
+                Inst::F32Copysign => binop_f32(&mut self.stack, false, float::copysign_f32)?,
+                Inst::F32ConvertI32S => {
+                    let Val::I32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::F32(c as f32))?;
+                }
+                Inst::F32ConvertI32U => {
+                    let Val::I32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::F32(c as u32 as f32))?;
+                }
+                Inst::F32DemoteF64 => {
+                    let Val::F64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack
+                        .push(Val::F32(canonicalize_f32(c as f32, self.deterministic)))?;
+                }
+                Inst::F64PromoteF32 => {
+                    let Val::F32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack
+                        .push(Val::F64(canonicalize_f64(c as f64, self.deterministic)))?;
+                }
+                Inst::F32ConvertI64S => {
+                    let Val::I64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::F32(c as f32))?;
+                }
+                Inst::F32ConvertI64U => {
+                    let Val::I64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::F32(c as u64 as f32))?;
+                }
+                Inst::F64ConvertI32S => {
+                    let Val::I32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::F64(c as f64))?;
+                }
+                Inst::F64ConvertI32U => {
+                    let Val::I32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::F64(c as u32 as f64))?;
+                }
+                Inst::F64ConvertI64S => {
+                    let Val::I64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::F64(c as f64))?;
+                }
+                Inst::F64ConvertI64U => {
+                    let Val::I64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::F64(c as u64 as f64))?;
+                }
+                // f32 converts to f64 exactly, so both truncate through f64
+                Inst::I32TruncF32S | Inst::I32TruncF32U | Inst::I64TruncF32S | Inst::I64TruncF32U => {
+                    let Val::F32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(trunc_to(inst, c as f64)?)?;
+                }
+                Inst::I32TruncF64S | Inst::I32TruncF64U | Inst::I64TruncF64S | Inst::I64TruncF64U => {
+                    let Val::F64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(trunc_to(inst, c)?)?;
+                }
+                Inst::I32WrapI64 => {
+                    let Val::I64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::I32(c as i32))?;
+                }
+                Inst::I64ExtendI32S => {
+                    let Val::I32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::I64(c as i64))?;
+                }
+                Inst::I64ExtendI32U => {
+                    let Val::I32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::I64(c as u32 as i64))?;
+                }
+                // reinterpretations keep the bits, NaN payloads included
+                Inst::I32ReinterpretF32 => {
+                    let Val::F32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::I32(c.to_bits() as i32))?;
+                }
+                Inst::I64ReinterpretF64 => {
+                    let Val::F64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::I64(c.to_bits() as i64))?;
+                }
+                Inst::F32ReinterpretI32 => {
+                    let Val::I32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::F32(f32::from_bits(c as u32)))?;
+                }
+                Inst::F64ReinterpretI64 => {
+                    let Val::I64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.stack.push(Val::F64(f64::from_bits(c as u64)))?;
+                }
+                Inst::I32Extend8S => unop_i32(&mut self.stack, |a| a as i8 as i32)?,
+                Inst::I32Extend16S => unop_i32(&mut self.stack, |a| a as i16 as i32)?,
+                Inst::I64Extend8S => unop_i64(&mut self.stack, |a| a as i8 as i64)?,
+                Inst::I64Extend16S => unop_i64(&mut self.stack, |a| a as i16 as i64)?,
+                Inst::I64Extend32S => unop_i64(&mut self.stack, |a| a as i32 as i64)?,
+                // comparisons involving NaN are false, except for ne
+                Inst::F64Eq => relop_f64(&mut self.stack, |a, b| a == b)?,
+                Inst::F64Ne => relop_f64(&mut self.stack, |a, b| a != b)?,
+                Inst::F64Lt => relop_f64(&mut self.stack, |a, b| a < b)?,
+                Inst::F64Gt => relop_f64(&mut self.stack, |a, b| a > b)?,
+                Inst::F64Le => relop_f64(&mut self.stack, |a, b| a <= b)?,
+                Inst::F64Ge => relop_f64(&mut self.stack, |a, b| a >= b)?,
+                // abs and neg only change the sign bit, even of a NaN
+                Inst::F64Abs => unop_f64(&mut self.stack, false, float::abs_f64)?,
+                Inst::F64Neg => unop_f64(&mut self.stack, false, ops::Neg::neg)?,
+                Inst::F64Ceil => unop_f64(&mut self.stack, self.deterministic, float::ceil_f64)?,
+                Inst::F64Floor => unop_f64(&mut self.stack, self.deterministic, float::floor_f64)?,
+                Inst::F64Trunc => unop_f64(&mut self.stack, self.deterministic, float::trunc_f64)?,
+                Inst::F64Nearest => {
+                    unop_f64(&mut self.stack, self.deterministic, float::nearest_f64)?
+                }
+                Inst::F64Sqrt => unop_f64(&mut self.stack, self.deterministic, float::sqrt_f64)?,
+                Inst::F64Add => binop_f64(&mut self.stack, self.deterministic, ops::Add::add)?,
+                Inst::F64Sub => binop_f64(&mut self.stack, self.deterministic, ops::Sub::sub)?,
+                Inst::F64Mul => binop_f64(&mut self.stack, self.deterministic, ops::Mul::mul)?,
+                Inst::F64Div => binop_f64(&mut self.stack, self.deterministic, ops::Div::div)?,
+                Inst::F64Min => binop_f64(&mut self.stack, self.deterministic, f64min)?,
+                Inst::F64Max => binop_f64(&mut self.stack, self.deterministic, f64max)?,
+                Inst::F64Copysign => binop_f64(&mut self.stack, false, float::copysign_f64)?,
+                Inst::I32Const(v) => self.stack.push(Val::I32(*v))?,
+                Inst::I64Const(v) => self.stack.push(Val::I64(*v))?,
+                Inst::F32Const(v) => self.stack.push(Val::F32(*v))?,
+                Inst::F64Const(v) => self.stack.push(Val::F64(*v))?,
                 Inst::Drop => {
                     self.stack.pop()?;
                 }
                 Inst::I32Load(memarg) => {
-                    let mem_addr = module.borrow().mem_addrs[0];
-                    let mem = &mut self.store.mems[mem_addr.0];
-                    let ea = effective_address(&mut self.stack, *memarg)?;
-                    const N: usize = 32;
-                    if ea + N / 8 > mem.len() {
-                        return Err(Exception::Runtime(Error::OobAccess {
-                            addr: ea,
-                            len: N / 8,
-                        }));
-                    }
-                    let val = &mem.data[ea..ea + N / 8];
-                    let val = i32::from_le_bytes(val.try_into().unwrap());
-                    self.stack.push(Val::I32(val))
+                    let bytes = self.load_n(module, *memarg)?;
+                    self.stack.push(Val::I32(i32::from_le_bytes(bytes)))?
                 }
                 Inst::I32Load8U(memarg) => {
-                    let mem_addr = module.borrow().mem_addrs[0];
-                    let mem = &mut self.store.mems[mem_addr.0];
-                    let ea = effective_address(&mut self.stack, *memarg)?;
-                    const N: usize = 8;
-                    if ea + N / 8 > mem.len() {
-                        return Err(Exception::Runtime(Error::OobAccess {
-                            addr: ea,
-                            len: N / 8,
-                        }));
-                    }
-                    let val = &mem.data[ea..ea + N / 8];
-                    let val = u8::from_le_bytes(val.try_into().unwrap());
-                    self.stack.push(Val::I32(val as i32))
+                    let bytes = self.load_n(module, *memarg)?;
+                    self.stack.push(Val::I32(u8::from_le_bytes(bytes) as i32))?
+                }
+                Inst::I32Load8S(memarg) => {
+                    let bytes = self.load_n(module, *memarg)?;
+                    self.stack.push(Val::I32(i8::from_le_bytes(bytes) as i32))?
+                }
+                Inst::I32Load16S(memarg) => {
+                    let bytes = self.load_n(module, *memarg)?;
+                    self.stack
+                        .push(Val::I32(i16::from_le_bytes(bytes) as i32))?
+                }
+                Inst::I32Load16U(memarg) => {
+                    let bytes = self.load_n(module, *memarg)?;
+                    self.stack
+                        .push(Val::I32(u16::from_le_bytes(bytes) as i32))?
+                }
+                // float loads and stores keep the bits, NaN payloads included
+                Inst::F32Load(memarg) => {
+                    let bytes = self.load_n(module, *memarg)?;
+                    self.stack.push(Val::F32(f32::from_le_bytes(bytes)))?
+                }
+                Inst::F64Load(memarg) => {
+                    let bytes = self.load_n(module, *memarg)?;
+                    self.stack.push(Val::F64(f64::from_le_bytes(bytes)))?
                 }
                 Inst::I64Load(memarg) => {
-                    let mem_addr = module.borrow().mem_addrs[0];
-                    let mem = &mut self.store.mems[mem_addr.0];
-                    let ea = effective_address(&mut self.stack, *memarg)?;
-                    const N: usize = 64;
-                    if ea + N / 8 > mem.len() {
-                        return Err(Exception::Runtime(Error::OobAccess {
-                            addr: ea,
-                            len: N / 8,
-                        }));
-                    }
-                    let val = &mem.data[ea..ea + N / 8];
-                    let val = i64::from_le_bytes(val.try_into().unwrap());
-                    self.stack.push(Val::I64(val))
+                    let bytes = self.load_n(module, *memarg)?;
+                    self.stack.push(Val::I64(i64::from_le_bytes(bytes)))?
+                }
+                Inst::I64Load8S(memarg) => {
+                    let bytes = self.load_n(module, *memarg)?;
+                    self.stack.push(Val::I64(i8::from_le_bytes(bytes) as i64))?
+                }
+                Inst::I64Load8U(memarg) => {
+                    let bytes = self.load_n(module, *memarg)?;
+                    self.stack.push(Val::I64(u8::from_le_bytes(bytes) as i64))?
+                }
+                Inst::I64Load16S(memarg) => {
+                    let bytes = self.load_n(module, *memarg)?;
+                    self.stack
+                        .push(Val::I64(i16::from_le_bytes(bytes) as i64))?
+                }
+                Inst::I64Load16U(memarg) => {
+                    let bytes = self.load_n(module, *memarg)?;
+                    self.stack
+                        .push(Val::I64(u16::from_le_bytes(bytes) as i64))?
+                }
+                Inst::I64Load32S(memarg) => {
+                    let bytes = self.load_n(module, *memarg)?;
+                    self.stack
+                        .push(Val::I64(i32::from_le_bytes(bytes) as i64))?
+                }
+                Inst::I64Load32U(memarg) => {
+                    let bytes = self.load_n(module, *memarg)?;
+                    self.stack
+                        .push(Val::I64(u32::from_le_bytes(bytes) as i64))?
                 }
                 Inst::I32Store(memarg) => {
-                    let mem_addr = module.borrow().mem_addrs[0];
-                    let mem = &mut self.store.mems[mem_addr.0];
                     let Val::I32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
-                    let ea = effective_address(&mut self.stack, *memarg)?;
-                    const N: usize = 32;
-                    if ea + N / 8 > mem.len() {
-                        return Err(Exception::Runtime(Error::OobAccess {
-                            addr: ea,
-                            len: N / 8,
-                        }));
-                    }
-                    let bytes = c.to_le_bytes();
-                    mem.data[ea..ea + N / 8].copy_from_slice(&bytes);
+                    self.store_n(module, *memarg, c.to_le_bytes())?;
                 }
                 Inst::I32Store8(memarg) => {
-                    let mem_addr = module.borrow().mem_addrs[0];
-                    let mem = &mut self.store.mems[mem_addr.0];
                     let Val::I32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
-                    let ea = effective_address(&mut self.stack, *memarg)?;
-                    const N: usize = 8;
-                    if ea + N / 8 > mem.len() {
-                        return Err(Exception::Runtime(Error::OobAccess {
-                            addr: ea,
-                            len: N / 8,
-                        }));
-                    }
-                    let bytes = (c as u8).to_le_bytes();
-                    mem.data[ea..ea + N / 8].copy_from_slice(&bytes);
+                    self.store_n(module, *memarg, (c as u8).to_le_bytes())?;
+                }
+                Inst::I32Store16(memarg) => {
+                    let Val::I32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.store_n(module, *memarg, (c as u16).to_le_bytes())?;
+                }
+                Inst::F32Store(memarg) => {
+                    let Val::F32(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.store_n(module, *memarg, c.to_le_bytes())?;
+                }
+                Inst::F64Store(memarg) => {
+                    let Val::F64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.store_n(module, *memarg, c.to_le_bytes())?;
                 }
                 Inst::I64Store(memarg) => {
-                    let mem_addr = module.borrow().mem_addrs[0];
-                    let mem = &mut self.store.mems[mem_addr.0];
                     let Val::I64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
-                    let ea = effective_address(&mut self.stack, *memarg)?;
-                    const N: usize = 64;
-                    if ea + N / 8 > mem.len() {
-                        return Err(Exception::Runtime(Error::OobAccess {
-                            addr: ea,
-                            len: N / 8,
-                        }));
-                    }
-                    let bytes = c.to_le_bytes();
-                    mem.data[ea..ea + N / 8].copy_from_slice(&bytes);
+                    self.store_n(module, *memarg, c.to_le_bytes())?;
+                }
+                Inst::I64Store8(memarg) => {
+                    let Val::I64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.store_n(module, *memarg, (c as u8).to_le_bytes())?;
+                }
+                Inst::I64Store16(memarg) => {
+                    let Val::I64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.store_n(module, *memarg, (c as u16).to_le_bytes())?;
+                }
+                Inst::I64Store32(memarg) => {
+                    let Val::I64(c) = self.stack.pop()? else { return Err(Exception::Runtime(Error::WrongValType))};
+                    self.store_n(module, *memarg, (c as u32).to_le_bytes())?;
                 }
-                x => todo!("{:?}", x),
             }
         }
-        Ok(())
+        Ok(None)
     }
 }
 
-fn default_value(t: repr::ValType) -> Val {
+/// The zero value of `t`, which locals start out with.
+pub fn default_value(t: repr::ValType) -> Val {
     match t {
         repr::ValType::I32 => Val::I32(0),
         repr::ValType::I64 => Val::I64(0),
         repr::ValType::F32 => Val::F32(0.0),
-        repr::ValType::F64 => todo!(),
+        repr::ValType::F64 => Val::F64(0.0),
         repr::ValType::V128 => todo!(),
-        repr::ValType::FuncRef => todo!(),
-        repr::ValType::ExternRef => todo!(),
+        repr::ValType::FuncRef => Val::Reference(Ref::Null(repr::Reftype::Funcref)),
+        repr::ValType::ExternRef => Val::Reference(Ref::Null(repr::Reftype::Externref)),
     }
 }
 
@@ -391,18 +1477,1912 @@ fn get_locals(
     from: &ResultType,
     locals: &[repr::Locals],
 ) -> Result<Locals, Exception> {
-    let mut vars = vec![];
-    for param in from.types.iter() {
-        println!("\tparam: {param:?}");
-        let arg = stack.pop()?;
-        // TODO: assert type
-        vars.push(arg);
-    }
-    vars.reverse();
-    for extra in locals {
-        for _ in 0..extra.n {
-            vars.push(default_value(extra.t));
+    // the arguments are the top values of the stack, the last one on top
+    let params = from.types.len();
+    let start = stack
+        .items
+        .len()
+        .checked_sub(params)
+        .ok_or(Error::StackEmpty)?;
+    let count = locals
+        .iter()
+        .try_fold(params, |count, l| count.checked_add(l.n as usize))
+        .filter(|&count| count <= stack.max_depth)
+        .ok_or(Error::StackOverflow)?;
+    let mut vars = Vec::with_capacity(count);
+    vars.extend(stack.items.drain(start..));
+    for (arg, param) in vars.iter().zip(&from.types) {
+        if arg.typ() != *param {
+            return Err(Exception::Runtime(Error::WrongValType));
         }
     }
+    for extra in locals {
+        vars.extend(core::iter::repeat_n(
+            default_value(extra.t),
+            extra.n as usize,
+        ));
+    }
     Ok(Locals { locals: vars })
 }
+
+/// Checks that the values above `base` are exactly the results `to`.
+fn check_results(stack: &Stack, base: usize, to: &ResultType) -> Result<(), Error> {
+    let Some(results) = stack.items.get(base..) else { return Err(Error::WrongResultCount) };
+    if results.len() != to.types.len() {
+        return Err(Error::WrongResultCount);
+    }
+    for (val, typ) in results.iter().zip(&to.types) {
+        if val.typ() != *typ {
+            return Err(Error::WrongValType);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{
+        boxed::Box, collections::BTreeMap, format, rc::Rc, string::ToString, vec, vec::Vec,
+    };
+    use core::cell::RefCell;
+
+    use crate::instance::{instantiate, ExternVal, Externals, FFiFunc, Store, WASM_PAGE_SIZE};
+    use crate::repr::{
+        BlockType, Elem, ElemIdx, ElemMode, Func, FuncIdx, FuncType, Global, GlobalIdx, GlobalType,
+        Import, ImportDesc, Inst, LabelIdx, Limits, LocalIdx, MemArg, MemIdx, MemType, Module,
+        Reftype, ResultType, TableIdx, TableType, TypeIdx, ValType,
+    };
+
+    use super::{Error, Exception, Locals, Machine, Ref, Val};
+
+    const I32: BlockType = BlockType::Inline(ValType::I32);
+
+    fn run(instructions: &[Inst]) -> Result<Vec<Val>, Exception> {
+        run_in(&Module::default(), instructions)
+    }
+
+    /// Runs `instructions` in a module with a single page of memory.
+    fn run_with_memory(instructions: &[Inst]) -> Result<Vec<Val>, Exception> {
+        let module = Module {
+            mems: vec![MemType {
+                limits: Limits {
+                    min: 1,
+                    max: None,
+                    is_64: false,
+                    shared: false,
+                },
+            }],
+            ..Module::default()
+        };
+        run_in(&module, instructions)
+    }
+
+    fn run_in(module: &Module, instructions: &[Inst]) -> Result<Vec<Val>, Exception> {
+        let mut store = Store::new();
+        let externals = Externals {
+            values: BTreeMap::new(),
+        };
+        let module = instantiate(module, &mut store, externals).unwrap();
+        let mut m = Machine::new(&mut store);
+        m.execute(module, instructions, &mut Locals::empty())?;
+        Ok(m.stack.items)
+    }
+
+    #[test]
+    fn recursive_fib() {
+        // fib(n) = if n < 2 { n } else { fib(n - 1) + fib(n - 2) }
+        let n = || Inst::LocalGet(LocalIdx(0));
+        let body = vec![
+            n(),
+            Inst::I32Const(2),
+            Inst::I32LtU,
+            Inst::IfElse(
+                I32,
+                vec![n()].into(),
+                vec![
+                    n(),
+                    Inst::I32Const(1),
+                    Inst::I32Sub,
+                    Inst::Call(FuncIdx(0)),
+                    n(),
+                    Inst::I32Const(2),
+                    Inst::I32Sub,
+                    Inst::Call(FuncIdx(0)),
+                    Inst::I32Add,
+                ]
+                .into(),
+            ),
+        ];
+        let i32s = || ResultType {
+            types: vec![ValType::I32],
+        };
+        let module = Module {
+            types: vec![FuncType {
+                from: i32s(),
+                to: i32s(),
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body,
+            }],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let externals = Externals {
+            values: BTreeMap::new(),
+        };
+        let inst = instantiate(&module, &mut store, externals).unwrap();
+        let fib = inst.borrow().func_addrs[0];
+        let mut m = Machine::new(&mut store);
+        m.stack.push(Val::I32(15)).unwrap();
+        m.call(fib).unwrap();
+        assert!(matches!(m.stack.items[..], [Val::I32(610)]));
+    }
+
+    #[test]
+    fn deep_recursion_fits_any_thread() {
+        // counts its parameter down to 0 with one call per step, deeper than
+        // the native stack of a test thread could hold if calls recursed
+        let n = || Inst::LocalGet(LocalIdx(0));
+        let body = vec![
+            n(),
+            Inst::I32Eqz,
+            Inst::IfElse(
+                I32,
+                vec![Inst::I32Const(0)].into(),
+                vec![
+                    n(),
+                    Inst::I32Const(1),
+                    Inst::I32Sub,
+                    Inst::Call(FuncIdx(0)),
+                    Inst::I32Const(1),
+                    Inst::I32Add,
+                ]
+                .into(),
+            ),
+        ];
+        let i32s = || ResultType {
+            types: vec![ValType::I32],
+        };
+        let module = Module {
+            types: vec![FuncType {
+                from: i32s(),
+                to: i32s(),
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body,
+            }],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let inst = instantiate(&module, &mut store, Externals::default()).unwrap();
+        let f = inst.borrow().func_addrs[0];
+        let mut m = Machine::new(&mut store);
+        let results = m.invoke(f, &[Val::I32(50_000)]).unwrap();
+        assert!(matches!(results[..], [Val::I32(50_000)]));
+        assert_eq!(m.depth, 0);
+    }
+
+    #[test]
+    fn infinite_recursion_exhausts_the_call_stack() {
+        let module = Module {
+            types: vec![FuncType {
+                from: ResultType { types: vec![] },
+                to: ResultType { types: vec![] },
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body: vec![Inst::Call(FuncIdx(0))],
+            }],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let inst = instantiate(&module, &mut store, Externals::default()).unwrap();
+        let f = inst.borrow().func_addrs[0];
+        let mut m = Machine::new(&mut store);
+        assert!(matches!(
+            m.invoke(f, &[]),
+            Err(Exception::Runtime(Error::CallStackExhausted))
+        ));
+        // the machine is usable again afterwards
+        m.max_call_depth = 3;
+        assert!(matches!(
+            m.invoke(f, &[]),
+            Err(Exception::Runtime(Error::CallStackExhausted))
+        ));
+        assert_eq!(m.depth, 0);
+    }
+
+    #[test]
+    fn operand_stack_is_limited() {
+        let mut store = Store::new();
+        let module = instantiate(&Module::default(), &mut store, Externals::default()).unwrap();
+        let mut m = Machine::new(&mut store);
+        m.stack.max_depth = 2;
+        let consts = [Inst::I32Const(1), Inst::I32Const(2), Inst::I32Const(3)];
+        let res = m.execute(module.clone(), &consts, &mut Locals::empty());
+        assert!(matches!(res, Err(Exception::Runtime(Error::StackOverflow))));
+        assert_eq!(m.stack.depth(), 2);
+
+        m.stack.max_depth = 3;
+        let res = m.execute(module, &consts[..1], &mut Locals::empty());
+        assert!(res.is_ok());
+    }
+
+    // without std, panics can't be caught
+    #[cfg(feature = "std")]
+    #[test]
+    fn panicking_host_functions_trap() {
+        let module = Module {
+            types: vec![FuncType {
+                from: ResultType {
+                    types: vec![ValType::I32],
+                },
+                to: ResultType {
+                    types: vec![ValType::I32],
+                },
+            }],
+            imports: vec![
+                Import {
+                    module: "env".into(),
+                    nm: "boom".into(),
+                    desc: ImportDesc::Func(TypeIdx(0)),
+                },
+                Import {
+                    module: "env".into(),
+                    nm: "double".into(),
+                    desc: ImportDesc::Func(TypeIdx(0)),
+                },
+            ],
+            mems: vec![MemType {
+                limits: Limits {
+                    min: 1,
+                    max: None,
+                    is_64: false,
+                    shared: false,
+                },
+            }],
+            ..Module::default()
+        };
+        let boom = FFiFunc(|store: &mut Store, _args: &[Val]| {
+            store.mems[0].write(0, &[1]).unwrap();
+            panic!("host function failed");
+        });
+        let double = FFiFunc(|_store: &mut Store, args: &[Val]| {
+            let [Val::I32(x)] = args[..] else { panic!("expected an i32") };
+            vec![Val::I32(x * 2)]
+        });
+        let mut externals = Externals::default();
+        externals.values.insert(
+            ("env", "boom").into(),
+            ExternVal::ExternalFunc(Box::new(boom)),
+        );
+        externals.values.insert(
+            ("env", "double").into(),
+            ExternVal::ExternalFunc(Box::new(double)),
+        );
+        let mut store = Store::new();
+        let inst = instantiate(&module, &mut store, externals).unwrap();
+        let [boom, double] = inst.borrow().func_addrs[..] else { panic!("expected two functions") };
+
+        let mut m = Machine::new(&mut store);
+        m.stack.push(Val::I32(1)).unwrap();
+        assert!(matches!(
+            m.call(boom),
+            Err(Exception::Runtime(Error::HostTrap))
+        ));
+        // the write before the panic is kept, and the store is still usable
+        assert_eq!(m.store.mems[0].read(0, 1).unwrap(), [1]);
+        m.stack.push(Val::I32(21)).unwrap();
+        m.call(double).unwrap();
+        assert!(matches!(m.stack.items[..], [Val::I32(42)]));
+    }
+
+    #[test]
+    fn host_functions_take_arguments_in_order() {
+        let module = Module {
+            types: vec![FuncType {
+                from: ResultType {
+                    types: vec![ValType::I32, ValType::I64],
+                },
+                to: ResultType {
+                    types: vec![ValType::I64],
+                },
+            }],
+            imports: ["sub", "none"]
+                .map(|name| Import {
+                    module: "env".into(),
+                    nm: name.into(),
+                    desc: ImportDesc::Func(TypeIdx(0)),
+                })
+                .into(),
+            ..Module::default()
+        };
+        let sub = FFiFunc(|_store: &mut Store, args: &[Val]| {
+            let [Val::I32(a), Val::I64(b)] = args[..] else { panic!("expected i32 and i64") };
+            vec![Val::I64(a as i64 - b)]
+        });
+        let none = FFiFunc(|_store: &mut Store, _args: &[Val]| vec![]);
+        let mut externals = Externals::default();
+        externals.values.insert(
+            ("env", "sub").into(),
+            ExternVal::ExternalFunc(Box::new(sub)),
+        );
+        externals.values.insert(
+            ("env", "none").into(),
+            ExternVal::ExternalFunc(Box::new(none)),
+        );
+        let mut store = Store::new();
+        let inst = instantiate(&module, &mut store, externals).unwrap();
+        let [sub, none] = inst.borrow().func_addrs[..] else { panic!("expected two functions") };
+
+        let mut m = Machine::new(&mut store);
+        let results = m.invoke(sub, &[Val::I32(10), Val::I64(3)]).unwrap();
+        assert!(matches!(results[..], [Val::I64(7)]));
+        // the number of results has to match the type
+        let res = m.invoke(none, &[Val::I32(10), Val::I64(3)]);
+        assert!(matches!(
+            res,
+            Err(Exception::Runtime(Error::WrongResultCount))
+        ));
+    }
+
+    #[test]
+    fn invoke_takes_only_the_results() {
+        let module = Module {
+            types: vec![FuncType {
+                from: ResultType {
+                    types: vec![ValType::I32],
+                },
+                to: ResultType {
+                    types: vec![ValType::I64, ValType::I32],
+                },
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body: vec![Inst::I64Const(7), Inst::LocalGet(LocalIdx(0))],
+            }],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let inst = instantiate(&module, &mut store, Externals::default()).unwrap();
+        let func = inst.borrow().func_addrs[0];
+        let mut m = Machine::new(&mut store);
+        m.stack.push(Val::I32(1)).unwrap();
+        let results = m.invoke(func, &[Val::I32(2)]).unwrap();
+        assert!(matches!(results[..], [Val::I64(7), Val::I32(2)]));
+        assert!(matches!(m.stack().as_slice(), [Val::I32(1)]));
+
+        // a failed call doesn't leave its arguments behind
+        let res = m.invoke(func, &[Val::I64(2)]);
+        assert!(matches!(res, Err(Exception::Runtime(Error::WrongValType))));
+        assert!(matches!(m.stack().as_slice(), [Val::I32(1)]));
+    }
+
+    #[test]
+    fn too_many_locals_trap() {
+        let module = Module {
+            types: vec![FuncType {
+                from: ResultType { types: vec![] },
+                to: ResultType { types: vec![] },
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![crate::repr::Locals {
+                    n: u32::MAX,
+                    t: ValType::I64,
+                }],
+                body: vec![],
+            }],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let inst = instantiate(&module, &mut store, Externals::default()).unwrap();
+        let func = inst.borrow().func_addrs[0];
+        let mut m = Machine::new(&mut store);
+        let res = m.invoke(func, &[]);
+        assert!(matches!(res, Err(Exception::Runtime(Error::StackOverflow))));
+    }
+
+    #[test]
+    fn inspect_stack_and_locals() {
+        let mut store = Store::new();
+        let module = instantiate(&Module::default(), &mut store, Externals::default()).unwrap();
+        let mut m = Machine::new(&mut store);
+        let mut locals = Locals {
+            locals: vec![Val::I32(1), Val::I64(2)],
+        };
+        let code = [
+            Inst::I32Const(3),
+            Inst::LocalGet(LocalIdx(1)),
+            Inst::I32Const(4),
+            Inst::LocalSet(LocalIdx(0)),
+        ];
+        m.execute(module, &code, &mut locals).unwrap();
+        assert_eq!(m.stack().depth(), 2);
+        assert!(matches!(m.stack().as_slice(), [Val::I32(3), Val::I64(2)]));
+        assert!(matches!(locals.as_slice(), [Val::I32(4), Val::I64(2)]));
+    }
+
+    #[test]
+    fn out_of_range_local_traps() {
+        let mut store = Store::new();
+        let module = instantiate(&Module::default(), &mut store, Externals::default()).unwrap();
+        let mut m = Machine::new(&mut store);
+        let mut locals = Locals {
+            locals: vec![Val::I32(1)],
+        };
+        for code in [
+            vec![Inst::LocalGet(LocalIdx(1))],
+            vec![Inst::I32Const(2), Inst::LocalSet(LocalIdx(1))],
+            vec![Inst::I32Const(2), Inst::LocalTee(LocalIdx(1))],
+        ] {
+            let res = m.execute(module.clone(), &code, &mut locals);
+            assert!(matches!(res, Err(Exception::Runtime(Error::LocalNotFound))));
+        }
+        assert!(matches!(locals.as_slice(), [Val::I32(1)]));
+    }
+
+    #[test]
+    fn floats_compare_and_print_by_bits() {
+        assert!(Val::F32(0.0).bit_eq(&Val::F32(0.0)));
+        assert!(!Val::F32(0.0).bit_eq(&Val::F32(-0.0)));
+        assert!(!Val::F64(0.0).bit_eq(&Val::F64(-0.0)));
+        assert!(Val::F64(f64::NAN).bit_eq(&Val::F64(f64::NAN)));
+        assert!(!Val::F64(f64::NAN).bit_eq(&Val::F64(-f64::NAN)));
+        assert!(!Val::I32(0).bit_eq(&Val::I64(0)));
+        assert!(Val::Reference(Ref::Null(Reftype::Funcref))
+            .bit_eq(&Val::Reference(Ref::Null(Reftype::Funcref))));
+        assert!(!Val::Reference(Ref::Null(Reftype::Funcref))
+            .bit_eq(&Val::Reference(Ref::Null(Reftype::Externref))));
+
+        assert_eq!(format!("{:?}", Val::F32(-0.0)), "F32(-0.0 0x80000000)");
+        assert_eq!(
+            format!("{:?}", Val::F64(f64::from_bits(0x7ff0_0000_0000_0001))),
+            "F64(NaN 0x7ff0000000000001)"
+        );
+        assert_eq!(format!("{:?}", Val::I32(-1)), "I32(-1)");
+    }
+
+    #[test]
+    fn select_checks_operand_types() {
+        let select = |a, b, op| run(&[a, b, Inst::I32Const(0), op]);
+        let null = || Inst::RefNull(Reftype::Funcref);
+        assert!(matches!(
+            select(Inst::I32Const(1), Inst::I32Const(2), Inst::Select).unwrap()[..],
+            [Val::I32(2)]
+        ));
+        assert!(matches!(
+            select(Inst::I32Const(1), Inst::I64Const(2), Inst::Select),
+            Err(Exception::Runtime(Error::WrongValType))
+        ));
+        assert!(matches!(
+            select(null(), null(), Inst::Select),
+            Err(Exception::Runtime(Error::WrongValType))
+        ));
+        assert!(matches!(
+            select(null(), null(), Inst::SelectTyped(ValType::FuncRef)).unwrap()[..],
+            [Val::Reference(Ref::Null(Reftype::Funcref))]
+        ));
+        assert!(matches!(
+            select(null(), null(), Inst::SelectTyped(ValType::ExternRef)),
+            Err(Exception::Runtime(Error::WrongValType))
+        ));
+    }
+
+    #[test]
+    fn structured_control_flow() {
+        let res = run(&[
+            Inst::I32Const(0),
+            Inst::IfElse(
+                I32,
+                vec![Inst::I32Const(1)].into(),
+                vec![Inst::I32Const(2)].into(),
+            ),
+        ])
+        .unwrap();
+        assert!(matches!(res[..], [Val::I32(2)]));
+
+        // the loop runs once and is left through the enclosing block
+        let res = run(&[Inst::Block(
+            I32,
+            vec![Inst::Loop(
+                BlockType::Empty,
+                vec![
+                    Inst::I32Const(1),
+                    Inst::I32Const(1),
+                    Inst::BreakIf(LabelIdx(1)),
+                    Inst::Break(LabelIdx(0)),
+                ]
+                .into(),
+            )]
+            .into(),
+        )])
+        .unwrap();
+        assert!(matches!(res[..], [Val::I32(1)]));
+    }
+
+    #[test]
+    fn branches_drop_the_operands_below_their_values() {
+        // (block (result i32) (i32.const 1) (i32.const 2) (br 0))
+        let res = run(&[
+            Inst::I32Const(7),
+            Inst::Block(
+                I32,
+                vec![
+                    Inst::I32Const(1),
+                    Inst::I32Const(2),
+                    Inst::Break(LabelIdx(0)),
+                ]
+                .into(),
+            ),
+        ])
+        .unwrap();
+        assert!(matches!(res[..], [Val::I32(7), Val::I32(2)]));
+
+        // counts the parameter down to 0, every iteration leaves a value
+        // behind before it branches
+        let n = LocalIdx(0);
+        let body = vec![
+            Inst::Block(
+                BlockType::Empty,
+                vec![Inst::Loop(
+                    BlockType::Empty,
+                    vec![
+                        Inst::I32Const(99),
+                        Inst::LocalGet(n),
+                        Inst::I32Const(-1),
+                        Inst::I32Add,
+                        Inst::LocalTee(n),
+                        Inst::I32Eqz,
+                        Inst::BreakIf(LabelIdx(1)),
+                        Inst::Break(LabelIdx(0)),
+                    ]
+                    .into(),
+                )]
+                .into(),
+            ),
+            Inst::LocalGet(n),
+        ];
+        let i32s = || ResultType {
+            types: vec![ValType::I32],
+        };
+        let module = Module {
+            types: vec![FuncType {
+                from: i32s(),
+                to: i32s(),
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body,
+            }],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let inst = instantiate(&module, &mut store, Externals::default()).unwrap();
+        let countdown = inst.borrow().func_addrs[0];
+        let mut m = Machine::new(&mut store);
+        let res = m.invoke(countdown, &[Val::I32(3)]).unwrap();
+        assert!(matches!(res[..], [Val::I32(0)]));
+        assert_eq!(m.stack.depth(), 0);
+    }
+
+    #[test]
+    fn branch_table() {
+        // the inner block adds 5 to the value both blocks carry
+        let switch = |i| {
+            run(&[Inst::Block(
+                I32,
+                vec![
+                    Inst::Block(
+                        I32,
+                        vec![
+                            Inst::I32Const(10),
+                            Inst::I32Const(i),
+                            Inst::BreakTable(vec![LabelIdx(0), LabelIdx(1)], LabelIdx(1)),
+                        ]
+                        .into(),
+                    ),
+                    Inst::I32Const(5),
+                    Inst::I32Add,
+                ]
+                .into(),
+            )])
+            .unwrap()
+        };
+        assert!(matches!(switch(0)[..], [Val::I32(15)]));
+        assert!(matches!(switch(1)[..], [Val::I32(10)]));
+        assert!(matches!(switch(-1)[..], [Val::I32(10)]));
+    }
+
+    #[test]
+    fn i32_shift_count_is_masked() {
+        let res = run(&[Inst::I32Const(1), Inst::I32Const(32), Inst::I32Shl]).unwrap();
+        assert!(matches!(res[..], [Val::I32(1)]));
+        let res = run(&[Inst::I32Const(1), Inst::I32Const(33), Inst::I32Shl]).unwrap();
+        assert!(matches!(res[..], [Val::I32(2)]));
+        let res = run(&[Inst::I32Const(-8), Inst::I32Const(33), Inst::I32ShrS]).unwrap();
+        assert!(matches!(res[..], [Val::I32(-4)]));
+        let res = run(&[Inst::I32Const(-8), Inst::I32Const(33), Inst::I32ShrU]).unwrap();
+        assert!(matches!(res[..], [Val::I32(0x7FFF_FFFC)]));
+        let res = run(&[Inst::I32Const(1), Inst::I32Const(33), Inst::I32Rotr]).unwrap();
+        assert!(matches!(res[..], [Val::I32(i32::MIN)]));
+    }
+
+    #[test]
+    fn i64_shift_count_is_masked() {
+        let res = run(&[Inst::I64Const(1), Inst::I64Const(64), Inst::I64Shl]).unwrap();
+        assert!(matches!(res[..], [Val::I64(1)]));
+        let res = run(&[Inst::I64Const(4), Inst::I64Const(65), Inst::I64ShrU]).unwrap();
+        assert!(matches!(res[..], [Val::I64(2)]));
+        let res = run(&[Inst::I64Const(-8), Inst::I64Const(65), Inst::I64ShrS]).unwrap();
+        assert!(matches!(res[..], [Val::I64(-4)]));
+        let res = run(&[Inst::I64Const(1), Inst::I64Const(65), Inst::I64Rotr]).unwrap();
+        assert!(matches!(res[..], [Val::I64(i64::MIN)]));
+        let res = run(&[Inst::I64Const(i64::MIN), Inst::I64Const(-63), Inst::I64Rotl]).unwrap();
+        assert!(matches!(res[..], [Val::I64(1)]));
+    }
+
+    #[test]
+    fn i32_arithmetic_wraps() {
+        let op = |a, b, inst| run(&[Inst::I32Const(a), Inst::I32Const(b), inst]).unwrap();
+        assert!(matches!(
+            op(i32::MAX, 1, Inst::I32Add)[..],
+            [Val::I32(i32::MIN)]
+        ));
+        assert!(matches!(
+            op(i32::MIN, 1, Inst::I32Sub)[..],
+            [Val::I32(i32::MAX)]
+        ));
+        assert!(matches!(op(-6, 7, Inst::I32Mul)[..], [Val::I32(-42)]));
+        assert!(matches!(
+            op(0x10000, 0x10001, Inst::I32Mul)[..],
+            [Val::I32(0x10000)]
+        ));
+    }
+
+    #[test]
+    fn add_and_compare_i64_locals() {
+        // (a + b) <u c
+        let module = Module {
+            types: vec![FuncType {
+                from: ResultType {
+                    types: vec![ValType::I64; 3],
+                },
+                to: ResultType {
+                    types: vec![ValType::I32],
+                },
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body: vec![
+                    Inst::LocalGet(LocalIdx(0)),
+                    Inst::LocalGet(LocalIdx(1)),
+                    Inst::I64Add,
+                    Inst::LocalGet(LocalIdx(2)),
+                    Inst::I64LtU,
+                ],
+            }],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let inst = instantiate(&module, &mut store, Externals::default()).unwrap();
+        let func = inst.borrow().func_addrs[0];
+        let mut m = Machine::new(&mut store);
+        let mut less = |a, b, c| match m.invoke(func, &[Val::I64(a), Val::I64(b), Val::I64(c)]) {
+            Ok(res) => matches!(res[..], [Val::I32(1)]),
+            Err(e) => panic!("{e}"),
+        };
+        assert!(less(1, 2, 4));
+        assert!(!less(2, 2, 4));
+        // -1 is the largest unsigned value
+        assert!(less(1 << 62, 1 << 62, -1));
+        assert!(!less(-1, 0, 0));
+    }
+
+    #[test]
+    fn i64_arithmetic_wraps() {
+        let op = |a, b, inst| run(&[Inst::I64Const(a), Inst::I64Const(b), inst]).unwrap();
+        assert!(matches!(
+            op(i64::MAX, 1, Inst::I64Add)[..],
+            [Val::I64(i64::MIN)]
+        ));
+        assert!(matches!(
+            op(i64::MIN, 1, Inst::I64Sub)[..],
+            [Val::I64(i64::MAX)]
+        ));
+        assert!(matches!(op(5, 7, Inst::I64Sub)[..], [Val::I64(-2)]));
+        assert!(matches!(op(i64::MAX, 2, Inst::I64Mul)[..], [Val::I64(-2)]));
+        assert!(matches!(
+            op(0b1100, 0b1010, Inst::I64And)[..],
+            [Val::I64(0b1000)]
+        ));
+        assert!(matches!(
+            op(0b1100, 0b1010, Inst::I64Or)[..],
+            [Val::I64(0b1110)]
+        ));
+        assert!(matches!(
+            op(0b1100, 0b1010, Inst::I64Xor)[..],
+            [Val::I64(0b0110)]
+        ));
+    }
+
+    #[test]
+    fn memory_grow_asks_the_limiter() {
+        let module = Module {
+            mems: vec![MemType {
+                limits: Limits {
+                    min: 1,
+                    max: Some(4),
+                    is_64: false,
+                    shared: false,
+                },
+            }],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let requests = Rc::new(RefCell::new(vec![]));
+        let seen = requests.clone();
+        store.set_memory_limiter(move |current, requested| {
+            seen.borrow_mut().push((current, requested));
+            requested <= 3
+        });
+        let module = instantiate(&module, &mut store, Externals::default()).unwrap();
+        let mut m = Machine::new(&mut store);
+        let grow = |delta| [Inst::I32Const(delta), Inst::MemoryGrow(MemIdx(0))];
+        let code = [grow(2), grow(1), grow(0), grow(-1)].concat();
+        m.execute(module, &code, &mut Locals::empty()).unwrap();
+        assert!(matches!(
+            m.stack.items[..],
+            [Val::I32(1), Val::I32(-1), Val::I32(3), Val::I32(-1)]
+        ));
+        assert_eq!(m.store.mems[0].len(), 3 * WASM_PAGE_SIZE);
+        // growing past the maximum fails without asking the limiter
+        assert_eq!(requests.borrow()[..], [(1, 3), (3, 4), (3, 3)]);
+    }
+
+    #[test]
+    fn narrow_and_float_loads_and_stores() {
+        let memarg = |offset| MemArg { offset, ..MEMARG };
+        let res = run_with_memory(&[
+            Inst::I32Const(0),
+            Inst::I32Const(0x1_8001),
+            Inst::I32Store16(memarg(0)),
+            Inst::I32Const(0),
+            Inst::I32Load16U(memarg(0)),
+            Inst::I32Const(0),
+            Inst::I32Load16S(memarg(0)),
+            Inst::I32Const(0),
+            Inst::I32Load(memarg(0)),
+        ])
+        .unwrap();
+        // the store only writes the low 16 bits
+        assert!(matches!(
+            res[..],
+            [Val::I32(0x8001), Val::I32(-0x7fff), Val::I32(0x8001)]
+        ));
+
+        let nan = f32::from_bits(0x7fa0_0001);
+        let res = run_with_memory(&[
+            Inst::I32Const(8),
+            Inst::F32Const(nan),
+            Inst::F32Store(memarg(0)),
+            Inst::I32Const(0),
+            Inst::F64Const(-1.5),
+            Inst::F64Store(memarg(16)),
+            Inst::I32Const(0),
+            Inst::F32Load(memarg(8)),
+            Inst::I32Const(16),
+            Inst::F64Load(memarg(0)),
+            Inst::I32Const(8),
+            Inst::I32Load(memarg(0)),
+        ])
+        .unwrap();
+        let [Val::F32(f), Val::F64(d), Val::I32(bits)] = res[..] else { panic!("got {res:?}") };
+        assert_eq!(f.to_bits(), 0x7fa0_0001);
+        assert_eq!(d, -1.5);
+        assert_eq!(bits, 0x7fa0_0001);
+
+        let res = run_with_memory(&[
+            Inst::I32Const(65535),
+            Inst::F64Const(1.0),
+            Inst::F64Store(MEMARG),
+        ]);
+        assert!(matches!(
+            res,
+            Err(Exception::Runtime(Error::OobAccess {
+                addr: 65535,
+                len: 8
+            }))
+        ));
+    }
+
+    #[test]
+    fn alignment_is_a_hint() {
+        let memarg = MemArg {
+            align: 2,
+            offset: 1,
+            memory: MemIdx(0),
+        };
+        let res = run_with_memory(&[
+            Inst::I32Const(0),
+            Inst::I32Const(0x0403_0201),
+            Inst::I32Store(memarg),
+            Inst::I32Const(0),
+            Inst::I32Load(memarg),
+            Inst::I32Const(2),
+            Inst::I32Load(memarg),
+        ])
+        .unwrap();
+        assert!(matches!(res[..], [Val::I32(0x0403_0201), Val::I32(0x0403)]));
+    }
+
+    #[test]
+    fn addresses_are_unsigned() {
+        let memarg = MemArg {
+            offset: 1,
+            ..MEMARG
+        };
+        let res = run_with_memory(&[Inst::I32Const(-1), Inst::I32Load8U(memarg)]);
+        assert!(matches!(
+            res,
+            Err(Exception::Runtime(Error::OobAccess { .. }))
+        ));
+    }
+
+    #[test]
+    fn memory_grow_keeps_the_contents() {
+        let module = Module {
+            mems: vec![MemType {
+                limits: Limits {
+                    min: 1,
+                    max: None,
+                    is_64: false,
+                    shared: false,
+                },
+            }],
+            ..Module::default()
+        };
+        let res = run_in(
+            &module,
+            &[
+                Inst::I32Const(WASM_PAGE_SIZE as i32 - 4),
+                Inst::I32Const(0x1234_5678),
+                Inst::I32Store(MEMARG),
+                Inst::I32Const(1),
+                Inst::MemoryGrow(MemIdx(0)),
+                Inst::I32Const(WASM_PAGE_SIZE as i32 - 4),
+                Inst::I64Load(MEMARG),
+                Inst::MemorySize(MemIdx(0)),
+            ],
+        )
+        .unwrap();
+        // the new page is zeroed
+        assert!(matches!(
+            res[..],
+            [Val::I32(1), Val::I64(0x1234_5678), Val::I32(2)]
+        ));
+    }
+
+    #[test]
+    fn memory_instructions_use_their_index() {
+        let mem = |min| MemType {
+            limits: Limits {
+                min,
+                max: None,
+                is_64: false,
+                shared: false,
+            },
+        };
+        let module = Module {
+            mems: vec![mem(1), mem(2)],
+            ..Module::default()
+        };
+        let res = run_in(
+            &module,
+            &[
+                Inst::I32Const(3),
+                Inst::MemoryGrow(MemIdx(1)),
+                Inst::MemorySize(MemIdx(0)),
+                Inst::MemorySize(MemIdx(1)),
+            ],
+        )
+        .unwrap();
+        assert!(matches!(res[..], [Val::I32(2), Val::I32(1), Val::I32(5)]));
+        assert!(matches!(
+            run_in(&module, &[Inst::MemorySize(MemIdx(2))]),
+            Err(Exception::Runtime(Error::MemoryNotFound))
+        ));
+    }
+
+    #[test]
+    fn i32_signed_comparisons() {
+        let cmp = |a, b, op| match run(&[Inst::I32Const(a), Inst::I32Const(b), op]).unwrap()[..] {
+            [Val::I32(c)] => c,
+            _ => panic!("expected a single i32"),
+        };
+        // -1 is the largest value when compared unsigned
+        assert_eq!(cmp(-1, 1, Inst::I32LtS), 1);
+        assert_eq!(cmp(-1, 1, Inst::I32LtU), 0);
+        assert_eq!(cmp(-1, 1, Inst::I32GtS), 0);
+        assert_eq!(cmp(-1, 1, Inst::I32GtU), 1);
+        assert_eq!(cmp(i32::MIN, -1, Inst::I32LeS), 1);
+        assert_eq!(cmp(-1, -1, Inst::I32LeS), 1);
+        assert_eq!(cmp(-1, i32::MIN, Inst::I32GeS), 1);
+        assert_eq!(cmp(-2, -1, Inst::I32GeS), 0);
+        assert_eq!(cmp(-1, 1, Inst::I32Ne), 1);
+        assert_eq!(cmp(-1, -1, Inst::I32Ne), 0);
+    }
+
+    #[test]
+    fn i64_comparisons() {
+        let cmp = |a, b, op| match run(&[Inst::I64Const(a), Inst::I64Const(b), op]).unwrap()[..] {
+            [Val::I32(c)] => c,
+            _ => panic!("expected a single i32"),
+        };
+        // i64::MIN is the smallest value signed but larger than i64::MAX unsigned
+        let (min, max) = (i64::MIN, i64::MAX);
+        assert_eq!(cmp(min, max, Inst::I64LtS), 1);
+        assert_eq!(cmp(min, max, Inst::I64LtU), 0);
+        assert_eq!(cmp(min, max, Inst::I64GtS), 0);
+        assert_eq!(cmp(min, max, Inst::I64GtU), 1);
+        assert_eq!(cmp(min, max, Inst::I64LeS), 1);
+        assert_eq!(cmp(min, max, Inst::I64LeU), 0);
+        assert_eq!(cmp(min, max, Inst::I64GeS), 0);
+        assert_eq!(cmp(min, max, Inst::I64GeU), 1);
+        assert_eq!(cmp(max, max, Inst::I64LeU), 1);
+        assert_eq!(cmp(-1, -1, Inst::I64GeS), 1);
+        assert_eq!(cmp(1 << 32, 0, Inst::I64Eq), 0);
+        assert_eq!(cmp(1 << 32, 0, Inst::I64Ne), 1);
+        assert_eq!(cmp(-1, -1, Inst::I64Eq), 1);
+
+        let eqz = |a| run(&[Inst::I64Const(a), Inst::I64Eqz]).unwrap();
+        assert!(matches!(eqz(0)[..], [Val::I32(1)]));
+        // only the low half is zero
+        assert!(matches!(eqz(1 << 32)[..], [Val::I32(0)]));
+    }
+
+    #[test]
+    fn traps_are_reported() {
+        let err = run(&[Inst::I32Const(1), Inst::I32Const(0), Inst::I32DivU]).unwrap_err();
+        let report = err.report();
+        assert_eq!(report.kind, "integer_divide_by_zero");
+        assert_eq!(report.message, "integer divide by zero");
+
+        let err = run_with_memory(&[Inst::I32Const(65535), Inst::I32Load(MEMARG)]).unwrap_err();
+        assert_eq!(err.report().kind, "oob_access");
+        assert_eq!(
+            err.to_string(),
+            "out of bounds memory access, 4 bytes at 65535"
+        );
+    }
+
+    #[test]
+    fn unreachable_traps() {
+        let module = Module {
+            types: vec![FuncType {
+                from: ResultType { types: vec![] },
+                to: ResultType { types: vec![] },
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body: vec![Inst::Unreachable],
+            }],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let inst = instantiate(&module, &mut store, Externals::default()).unwrap();
+        let func = inst.borrow().func_addrs[0];
+        let err = Machine::new(&mut store).invoke(func, &[]).unwrap_err();
+        assert!(matches!(err, Exception::Runtime(Error::Unreachable)));
+        assert_eq!(err.to_string(), "unreachable executed");
+
+        let res = run(&[Inst::Nop, Inst::I32Const(1), Inst::Nop]).unwrap();
+        assert!(matches!(res[..], [Val::I32(1)]));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn trap_reports_serialize() {
+        let report = Exception::Runtime(Error::CallStackExhausted).report();
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(
+            json,
+            r#"{"kind":"call_stack_exhausted","message":"call stack exhausted"}"#
+        );
+    }
+
+    #[test]
+    fn dividing_locals_by_zero_traps() {
+        let module = Module {
+            types: vec![FuncType {
+                from: ResultType {
+                    types: vec![ValType::I32, ValType::I32],
+                },
+                to: ResultType {
+                    types: vec![ValType::I32],
+                },
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body: vec![
+                    Inst::LocalGet(LocalIdx(0)),
+                    Inst::LocalGet(LocalIdx(1)),
+                    Inst::I32DivU,
+                ],
+            }],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let inst = instantiate(&module, &mut store, Externals::default()).unwrap();
+        let func = inst.borrow().func_addrs[0];
+        let mut m = Machine::new(&mut store);
+        let results = m.invoke(func, &[Val::I32(-1), Val::I32(2)]).unwrap();
+        assert!(matches!(results[..], [Val::I32(0x7fff_ffff)]));
+        let res = m.invoke(func, &[Val::I32(1), Val::I32(0)]);
+        assert!(matches!(
+            res,
+            Err(Exception::Runtime(Error::IntegerDivideByZero))
+        ));
+    }
+
+    #[test]
+    fn i32_division_traps() {
+        let div = |a, b, op| run(&[Inst::I32Const(a), Inst::I32Const(b), op]);
+        assert!(matches!(
+            div(i32::MIN, -1, Inst::I32DivS),
+            Err(Exception::Runtime(Error::IntegerOverflow))
+        ));
+        assert!(matches!(
+            div(1, 0, Inst::I32DivS),
+            Err(Exception::Runtime(Error::IntegerDivideByZero))
+        ));
+        assert!(matches!(
+            div(1, 0, Inst::I32RemU),
+            Err(Exception::Runtime(Error::IntegerDivideByZero))
+        ));
+        assert!(matches!(
+            div(i32::MIN, -1, Inst::I32RemS).unwrap()[..],
+            [Val::I32(0)]
+        ));
+        assert!(matches!(
+            div(-7, 2, Inst::I32DivS).unwrap()[..],
+            [Val::I32(-3)]
+        ));
+        assert!(matches!(
+            div(-7, 2, Inst::I32RemS).unwrap()[..],
+            [Val::I32(-1)]
+        ));
+        assert!(matches!(
+            div(-7, 2, Inst::I32DivU).unwrap()[..],
+            [Val::I32(0x7FFF_FFFC)]
+        ));
+        assert!(matches!(
+            div(-7, 2, Inst::I32RemU).unwrap()[..],
+            [Val::I32(1)]
+        ));
+    }
+
+    #[test]
+    fn i64_division_traps() {
+        let div = |a, b, op| run(&[Inst::I64Const(a), Inst::I64Const(b), op]);
+        assert!(matches!(
+            div(i64::MIN, -1, Inst::I64DivS),
+            Err(Exception::Runtime(Error::IntegerOverflow))
+        ));
+        for op in [Inst::I64DivS, Inst::I64DivU, Inst::I64RemS, Inst::I64RemU] {
+            assert!(matches!(
+                div(1, 0, op),
+                Err(Exception::Runtime(Error::IntegerDivideByZero))
+            ));
+        }
+        assert!(matches!(
+            div(i64::MIN, -1, Inst::I64RemS).unwrap()[..],
+            [Val::I64(0)]
+        ));
+        assert!(matches!(
+            div(i64::MIN, -1, Inst::I64DivU).unwrap()[..],
+            [Val::I64(0)]
+        ));
+        assert!(matches!(
+            div(i64::MIN, -1, Inst::I64RemU).unwrap()[..],
+            [Val::I64(i64::MIN)]
+        ));
+        assert!(matches!(
+            div(-7, 2, Inst::I64DivS).unwrap()[..],
+            [Val::I64(-3)]
+        ));
+        assert!(matches!(
+            div(-7, 2, Inst::I64RemS).unwrap()[..],
+            [Val::I64(-1)]
+        ));
+        assert!(matches!(
+            div(-7, 2, Inst::I64DivU).unwrap()[..],
+            [Val::I64(0x7FFF_FFFF_FFFF_FFFC)]
+        ));
+        assert!(matches!(
+            div(-7, 2, Inst::I64RemU).unwrap()[..],
+            [Val::I64(1)]
+        ));
+    }
+
+    #[test]
+    fn bit_counting() {
+        let i32s = |a, op| run(&[Inst::I32Const(a), op]).unwrap();
+        assert!(matches!(i32s(0, Inst::I32Clz)[..], [Val::I32(32)]));
+        assert!(matches!(i32s(0, Inst::I32Ctz)[..], [Val::I32(32)]));
+        assert!(matches!(i32s(0, Inst::I32Popcnt)[..], [Val::I32(0)]));
+        assert!(matches!(i32s(-1, Inst::I32Clz)[..], [Val::I32(0)]));
+        assert!(matches!(i32s(-1, Inst::I32Ctz)[..], [Val::I32(0)]));
+        assert!(matches!(i32s(-1, Inst::I32Popcnt)[..], [Val::I32(32)]));
+        assert!(matches!(
+            i32s(0x0000_8000, Inst::I32Clz)[..],
+            [Val::I32(16)]
+        ));
+        assert!(matches!(
+            i32s(0x0000_8000, Inst::I32Ctz)[..],
+            [Val::I32(15)]
+        ));
+
+        let i64s = |a, op| run(&[Inst::I64Const(a), op]).unwrap();
+        assert!(matches!(i64s(0, Inst::I64Clz)[..], [Val::I64(64)]));
+        assert!(matches!(i64s(0, Inst::I64Ctz)[..], [Val::I64(64)]));
+        assert!(matches!(i64s(0, Inst::I64Popcnt)[..], [Val::I64(0)]));
+        assert!(matches!(i64s(-1, Inst::I64Clz)[..], [Val::I64(0)]));
+        assert!(matches!(i64s(-1, Inst::I64Ctz)[..], [Val::I64(0)]));
+        assert!(matches!(i64s(-1, Inst::I64Popcnt)[..], [Val::I64(64)]));
+        assert!(matches!(
+            i64s(0x8000_0000, Inst::I64Clz)[..],
+            [Val::I64(32)]
+        ));
+    }
+
+    fn f32_result(instructions: &[Inst]) -> f32 {
+        let res = run(instructions).unwrap();
+        let [Val::F32(c)] = res[..] else { panic!("expected a single f32, got {res:?}") };
+        c
+    }
+
+    #[test]
+    fn f32_arithmetic() {
+        let add = f32_result(&[Inst::F32Const(1.5), Inst::F32Const(2.25), Inst::F32Add]);
+        assert_eq!(add, 3.75);
+        let div = f32_result(&[Inst::F32Const(1.0), Inst::F32Const(4.0), Inst::F32Div]);
+        assert_eq!(div, 0.25);
+        let sqrt = f32_result(&[Inst::F32Const(2.0), Inst::F32Sqrt]);
+        assert_eq!(sqrt.to_bits(), 0x3fb504f3);
+        let nearest = f32_result(&[Inst::F32Const(2.5), Inst::F32Nearest]);
+        assert_eq!(nearest, 2.0);
+        let nearest = f32_result(&[Inst::F32Const(-3.5), Inst::F32Nearest]);
+        assert_eq!(nearest, -4.0);
+        let demoted = f32_result(&[Inst::F64Const(0.1), Inst::F32DemoteF64]);
+        assert_eq!(demoted, 0.1f32);
+    }
+
+    #[test]
+    fn truncation_traps_out_of_range() {
+        let trunc = |val, op| run(&[val, op]);
+        assert!(matches!(
+            trunc(Inst::F32Const(-1.9), Inst::I32TruncF32S).unwrap()[..],
+            [Val::I32(-1)]
+        ));
+        assert!(matches!(
+            trunc(Inst::F32Const(-0.9), Inst::I32TruncF32U).unwrap()[..],
+            [Val::I32(0)]
+        ));
+        assert!(matches!(
+            trunc(Inst::F64Const(4294967295.9), Inst::I32TruncF64U).unwrap()[..],
+            [Val::I32(-1)]
+        ));
+        assert!(matches!(
+            trunc(Inst::F64Const(-2147483648.9), Inst::I32TruncF64S).unwrap()[..],
+            [Val::I32(i32::MIN)]
+        ));
+        assert!(matches!(
+            trunc(Inst::F64Const(2147483648.0), Inst::I32TruncF64S),
+            Err(Exception::Runtime(Error::IntegerOverflow))
+        ));
+        assert!(matches!(
+            trunc(Inst::F32Const(-1.0), Inst::I64TruncF32U),
+            Err(Exception::Runtime(Error::IntegerOverflow))
+        ));
+        assert!(matches!(
+            trunc(Inst::F64Const(9223372036854775808.0), Inst::I64TruncF64S),
+            Err(Exception::Runtime(Error::IntegerOverflow))
+        ));
+        assert!(matches!(
+            trunc(Inst::F64Const(18446744073709549568.0), Inst::I64TruncF64U).unwrap()[..],
+            [Val::I64(-2048)]
+        ));
+        assert!(matches!(
+            trunc(Inst::F32Const(f32::NAN), Inst::I64TruncF32S),
+            Err(Exception::Runtime(Error::InvalidConversionToInteger))
+        ));
+    }
+
+    #[test]
+    fn integer_conversions() {
+        let convert = |val, op| run(&[val, op]).unwrap();
+        assert!(matches!(
+            convert(Inst::I64Const(0x1_0000_0005), Inst::I32WrapI64)[..],
+            [Val::I32(5)]
+        ));
+        assert!(matches!(
+            convert(Inst::I32Const(-1), Inst::I64ExtendI32S)[..],
+            [Val::I64(-1)]
+        ));
+        assert!(matches!(
+            convert(Inst::I32Const(-1), Inst::I64ExtendI32U)[..],
+            [Val::I64(0xffff_ffff)]
+        ));
+        assert!(matches!(
+            convert(Inst::I32Const(0x80), Inst::I32Extend8S)[..],
+            [Val::I32(-128)]
+        ));
+        assert!(matches!(
+            convert(Inst::I32Const(0x7fff), Inst::I32Extend16S)[..],
+            [Val::I32(0x7fff)]
+        ));
+        assert!(matches!(
+            convert(Inst::I64Const(0x8000_0000), Inst::I64Extend32S)[..],
+            [Val::I64(-0x8000_0000)]
+        ));
+    }
+
+    #[test]
+    fn float_conversions() {
+        let convert = |val, op| run(&[val, op]).unwrap();
+        assert!(matches!(
+            convert(Inst::I32Const(-1), Inst::F64ConvertI32U)[..],
+            [Val::F64(c)] if c == 4294967295.0
+        ));
+        assert!(matches!(
+            convert(Inst::I64Const(-1), Inst::F32ConvertI64U)[..],
+            [Val::F32(c)] if c == 18446744073709551616.0
+        ));
+        // rounds to nearest, ties to even
+        assert!(matches!(
+            convert(Inst::I64Const(0x0100_0000_0000_0001), Inst::F32ConvertI64S)[..],
+            [Val::F32(c)] if c == 72057594037927936.0
+        ));
+        assert!(matches!(
+            convert(Inst::I64Const(-(1 << 53) - 1), Inst::F64ConvertI64S)[..],
+            [Val::F64(c)] if c == -9007199254740992.0
+        ));
+        let nan = f32::from_bits(0x7fa0_0001);
+        assert!(matches!(
+            convert(Inst::F32Const(nan), Inst::I32ReinterpretF32)[..],
+            [Val::I32(0x7fa0_0001)]
+        ));
+        assert!(matches!(
+            convert(Inst::I64Const(-1), Inst::F64ReinterpretI64)[..],
+            [Val::F64(c)] if c.to_bits() == u64::MAX
+        ));
+        assert!(matches!(
+            convert(Inst::F64Const(-0.0), Inst::I64ReinterpretF64)[..],
+            [Val::I64(i64::MIN)]
+        ));
+        assert!(matches!(
+            convert(Inst::I32Const(0x3f80_0000), Inst::F32ReinterpretI32)[..],
+            [Val::F32(c)] if c == 1.0
+        ));
+    }
+
+    #[test]
+    fn f32_min_max() {
+        let min = f32_result(&[Inst::F32Const(0.0), Inst::F32Const(-0.0), Inst::F32Min]);
+        assert!(min == 0.0 && min.is_sign_negative());
+        let max = f32_result(&[Inst::F32Const(-0.0), Inst::F32Const(0.0), Inst::F32Max]);
+        assert!(max == 0.0 && max.is_sign_positive());
+        let min = f32_result(&[Inst::F32Const(f32::NAN), Inst::F32Const(1.0), Inst::F32Min]);
+        assert!(min.is_nan());
+        let max = f32_result(&[Inst::F32Const(1.0), Inst::F32Const(f32::NAN), Inst::F32Max]);
+        assert!(max.is_nan());
+    }
+
+    fn f64_result(instructions: &[Inst]) -> f64 {
+        let res = run(instructions).unwrap();
+        let [Val::F64(c)] = res[..] else { panic!("expected a single f64, got {res:?}") };
+        c
+    }
+
+    #[test]
+    fn f64_arithmetic() {
+        let f64s = |a, b, op| f64_result(&[Inst::F64Const(a), Inst::F64Const(b), op]);
+        assert_eq!(f64s(1.5, 2.25, Inst::F64Add), 3.75);
+        assert_eq!(f64s(1.5, 2.25, Inst::F64Sub), -0.75);
+        assert_eq!(f64s(1.5, -4.0, Inst::F64Mul), -6.0);
+        assert_eq!(f64s(1.0, 0.0, Inst::F64Div), f64::INFINITY);
+        assert!(f64s(0.0, 0.0, Inst::F64Div).is_nan());
+
+        let f64s = |a, op| f64_result(&[Inst::F64Const(a), op]);
+        assert_eq!(f64s(2.0, Inst::F64Sqrt).to_bits(), 0x3ff6a09e667f3bcd);
+        assert!(f64s(-1.0, Inst::F64Sqrt).is_nan());
+        assert_eq!(f64s(-1.5, Inst::F64Abs), 1.5);
+        assert_eq!(f64s(1.5, Inst::F64Neg), -1.5);
+        assert_eq!(f64s(-1.5, Inst::F64Ceil), -1.0);
+        assert_eq!(f64s(-1.5, Inst::F64Floor), -2.0);
+        assert_eq!(f64s(-1.5, Inst::F64Trunc), -1.0);
+        assert_eq!(f64s(2.5, Inst::F64Nearest), 2.0);
+        assert_eq!(f64s(-3.5, Inst::F64Nearest), -4.0);
+        let ceil = f64s(-0.5, Inst::F64Ceil);
+        assert!(ceil == 0.0 && ceil.is_sign_negative());
+        let neg = f64s(f64::from_bits(0x7ff4_0000_0000_0001), Inst::F64Neg);
+        assert_eq!(neg.to_bits(), 0xfff4_0000_0000_0001);
+    }
+
+    #[test]
+    fn f64_min_max() {
+        let f64s = |a, b, op| f64_result(&[Inst::F64Const(a), Inst::F64Const(b), op]);
+        let min = f64s(0.0, -0.0, Inst::F64Min);
+        assert!(min == 0.0 && min.is_sign_negative());
+        let max = f64s(-0.0, 0.0, Inst::F64Max);
+        assert!(max == 0.0 && max.is_sign_positive());
+        assert!(f64s(f64::NAN, 1.0, Inst::F64Min).is_nan());
+        assert!(f64s(1.0, f64::NAN, Inst::F64Max).is_nan());
+        assert_eq!(f64s(-1.0, 2.0, Inst::F64Min), -1.0);
+        assert_eq!(f64s(-1.0, 2.0, Inst::F64Max), 2.0);
+    }
+
+    #[test]
+    fn deterministic_nans() {
+        // 0x7fa00000 is a quiet NaN with a payload other than the canonical one
+        let nan = f32::from_bits(0x7fa0_0000);
+        let program = [Inst::F32Const(nan), Inst::F32Const(1.0), Inst::F32Add];
+        let mut store = Store::new();
+        let externals = Externals {
+            values: BTreeMap::new(),
+        };
+        let module = instantiate(&Module::default(), &mut store, externals).unwrap();
+        let mut m = Machine::new(&mut store);
+        m.deterministic = true;
+        m.execute(module.clone(), &program, &mut Locals::empty())
+            .unwrap();
+        m.execute(
+            module.clone(),
+            &[Inst::F32Const(nan), Inst::F32Neg],
+            &mut Locals::empty(),
+        )
+        .unwrap();
+        let nan = f64::from_bits(0x7ff4_0000_0000_0000);
+        m.execute(
+            module,
+            &[Inst::F64Const(nan), Inst::F64Sqrt],
+            &mut Locals::empty(),
+        )
+        .unwrap();
+        let [Val::F32(sum), Val::F32(neg), Val::F64(sqrt)] = m.stack.items[..] else {
+            panic!("expected two f32s and an f64")
+        };
+        assert_eq!(sum.to_bits(), 0x7fc0_0000);
+        // neg keeps the payload even in deterministic mode
+        assert_eq!(neg.to_bits(), 0xffa0_0000);
+        assert_eq!(sqrt.to_bits(), 0x7ff8_0000_0000_0000);
+    }
+
+    #[test]
+    fn copysign() {
+        let f32s = |a, b| run(&[Inst::F32Const(a), Inst::F32Const(b), Inst::F32Copysign]).unwrap();
+        let [Val::F32(x)] = f32s(0.0, -0.0)[..] else { panic!("expected an f32") };
+        assert_eq!(x.to_bits(), (-0.0f32).to_bits());
+        let [Val::F32(x)] = f32s(-1.5, 0.0)[..] else { panic!("expected an f32") };
+        assert_eq!(x, 1.5);
+
+        let f64s = |a, b| run(&[Inst::F64Const(a), Inst::F64Const(b), Inst::F64Copysign]).unwrap();
+        let [Val::F64(x)] = f64s(-0.0, 0.0)[..] else { panic!("expected an f64") };
+        assert_eq!(x.to_bits(), 0.0f64.to_bits());
+        let [Val::F64(x)] = f64s(2.0, -f64::NAN)[..] else { panic!("expected an f64") };
+        assert_eq!(x, -2.0);
+    }
+
+    #[test]
+    fn f32_compare() {
+        let res = run(&[Inst::F32Const(1.0), Inst::F32Const(2.0), Inst::F32Lt]).unwrap();
+        assert!(matches!(res[..], [Val::I32(1)]));
+        let res = run(&[
+            Inst::F32Const(f32::NAN),
+            Inst::F32Const(f32::NAN),
+            Inst::F32Ne,
+        ])
+        .unwrap();
+        assert!(matches!(res[..], [Val::I32(1)]));
+        let res = run(&[Inst::F32Const(f32::NAN), Inst::F32Const(1.0), Inst::F32Ge]).unwrap();
+        assert!(matches!(res[..], [Val::I32(0)]));
+    }
+
+    fn compare_f64(a: f64, b: f64, op: Inst) -> i32 {
+        let res = run(&[Inst::F64Const(a), Inst::F64Const(b), op]).unwrap();
+        let [Val::I32(c)] = res[..] else { panic!("expected a single i32, got {res:?}") };
+        c
+    }
+
+    #[test]
+    fn f64_compare() {
+        assert_eq!(compare_f64(1.0, 1.0, Inst::F64Eq), 1);
+        assert_eq!(compare_f64(0.0, -0.0, Inst::F64Eq), 1);
+        assert_eq!(compare_f64(1.0, 2.0, Inst::F64Ne), 1);
+        assert_eq!(compare_f64(1.0, 2.0, Inst::F64Lt), 1);
+        assert_eq!(compare_f64(1.0, 2.0, Inst::F64Gt), 0);
+        assert_eq!(compare_f64(2.0, 2.0, Inst::F64Le), 1);
+        assert_eq!(compare_f64(1.0, 2.0, Inst::F64Ge), 0);
+    }
+
+    #[test]
+    fn f64_compare_nan() {
+        let nan = f64::NAN;
+        assert_eq!(compare_f64(nan, nan, Inst::F64Eq), 0);
+        assert_eq!(compare_f64(nan, nan, Inst::F64Ne), 1);
+        assert_eq!(compare_f64(nan, 1.0, Inst::F64Ne), 1);
+        assert_eq!(compare_f64(nan, 1.0, Inst::F64Lt), 0);
+        assert_eq!(compare_f64(1.0, nan, Inst::F64Gt), 0);
+        assert_eq!(compare_f64(nan, 1.0, Inst::F64Le), 0);
+        assert_eq!(compare_f64(1.0, nan, Inst::F64Ge), 0);
+    }
+
+    const MEMARG: MemArg = MemArg {
+        align: 0,
+        offset: 0,
+        memory: MemIdx(0),
+    };
+
+    fn load_after_store(value: i64, store: Inst, load: Inst) -> Val {
+        let res = run_with_memory(&[
+            Inst::I32Const(8),
+            Inst::I64Const(value),
+            store,
+            Inst::I32Const(8),
+            load,
+        ])
+        .unwrap();
+        let [v] = res[..] else { panic!("expected a single value, got {res:?}") };
+        v
+    }
+
+    #[test]
+    fn i64_sub_width_loads_extend() {
+        let v = load_after_store(-1, Inst::I64Store8(MEMARG), Inst::I64Load8S(MEMARG));
+        assert!(matches!(v, Val::I64(-1)));
+        let v = load_after_store(-1, Inst::I64Store8(MEMARG), Inst::I64Load8U(MEMARG));
+        assert!(matches!(v, Val::I64(0xff)));
+        let v = load_after_store(-2, Inst::I64Store16(MEMARG), Inst::I64Load16S(MEMARG));
+        assert!(matches!(v, Val::I64(-2)));
+        let v = load_after_store(-2, Inst::I64Store16(MEMARG), Inst::I64Load16U(MEMARG));
+        assert!(matches!(v, Val::I64(0xfffe)));
+        let v = load_after_store(-3, Inst::I64Store32(MEMARG), Inst::I64Load32S(MEMARG));
+        assert!(matches!(v, Val::I64(-3)));
+        let v = load_after_store(-3, Inst::I64Store32(MEMARG), Inst::I64Load32U(MEMARG));
+        assert!(matches!(v, Val::I64(0xffff_fffd)));
+    }
+
+    #[test]
+    fn i64_sub_width_stores_truncate() {
+        // the narrow store must leave the bytes after it untouched
+        let res = run_with_memory(&[
+            Inst::I32Const(0),
+            Inst::I64Const(0),
+            Inst::I64Store(MEMARG),
+            Inst::I32Const(0),
+            Inst::I64Const(0x1234_5678_9abc),
+            Inst::I64Store16(MEMARG),
+            Inst::I32Const(0),
+            Inst::I64Load(MEMARG),
+        ])
+        .unwrap();
+        assert!(matches!(res[..], [Val::I64(0x9abc)]));
+    }
+
+    #[test]
+    fn i32_signed_loads_extend() {
+        let res = run_with_memory(&[
+            Inst::I32Const(4),
+            Inst::I32Const(0xff),
+            Inst::I32Store8(MEMARG),
+            Inst::I32Const(4),
+            Inst::I32Load8S(MEMARG),
+        ])
+        .unwrap();
+        assert!(matches!(res[..], [Val::I32(-1)]));
+        let res = run_with_memory(&[
+            Inst::I32Const(4),
+            Inst::I32Const(0x8000),
+            Inst::I32Store(MEMARG),
+            Inst::I32Const(4),
+            Inst::I32Load16S(MEMARG),
+        ])
+        .unwrap();
+        assert!(matches!(res[..], [Val::I32(-32768)]));
+    }
+
+    #[test]
+    fn load_out_of_bounds() {
+        let res = run_with_memory(&[Inst::I32Const(65535), Inst::I64Load16U(MEMARG)]);
+        assert!(matches!(
+            res,
+            Err(Exception::Runtime(Error::OobAccess {
+                addr: 65535,
+                len: 2
+            }))
+        ));
+    }
+
+    #[test]
+    fn table_init_copy_and_elem_drop() {
+        let nothing = || ResultType { types: vec![] };
+        let func = Func {
+            typ: TypeIdx(0),
+            locals: vec![],
+            body: vec![],
+        };
+        let module = Module {
+            types: vec![FuncType {
+                from: nothing(),
+                to: nothing(),
+            }],
+            funcs: vec![func.clone(), func],
+            tables: vec![TableType {
+                reftype: Reftype::Funcref,
+                limits: Limits {
+                    min: 4,
+                    max: None,
+                    is_64: false,
+                    shared: false,
+                },
+            }],
+            elems: vec![Elem {
+                typ: Reftype::Funcref,
+                init: vec![
+                    vec![Inst::RefFunc(FuncIdx(0))],
+                    vec![Inst::RefFunc(FuncIdx(1))],
+                ],
+                mode: ElemMode::Passive,
+            }],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let externals = Externals {
+            values: BTreeMap::new(),
+        };
+        let inst = instantiate(&module, &mut store, externals).unwrap();
+        let mut m = Machine::new(&mut store);
+        let program = [
+            // table[1..3] = elem[0..2]
+            Inst::I32Const(1),
+            Inst::I32Const(0),
+            Inst::I32Const(2),
+            Inst::TableInit(ElemIdx(0), TableIdx(0)),
+            // table[2..4] = table[1..3], overlapping
+            Inst::I32Const(2),
+            Inst::I32Const(1),
+            Inst::I32Const(2),
+            Inst::TableCopy(TableIdx(0), TableIdx(0)),
+            Inst::ElemDrop(ElemIdx(0)),
+        ];
+        m.execute(inst.clone(), &program, &mut Locals::empty())
+            .unwrap();
+
+        // a dropped segment is empty
+        let program = [
+            Inst::I32Const(0),
+            Inst::I32Const(0),
+            Inst::I32Const(1),
+            Inst::TableInit(ElemIdx(0), TableIdx(0)),
+        ];
+        let res = m.execute(inst, &program, &mut Locals::empty());
+        assert!(matches!(
+            res,
+            Err(Exception::Runtime(Error::OobTableAccess { idx: 0, len: 1 }))
+        ));
+
+        let table = store.tables[0].read(0, 4).unwrap();
+        assert!(matches!(
+            table,
+            [Ref::Null(_), Ref::Func(0), Ref::Func(0), Ref::Func(1)]
+        ));
+    }
+
+    #[test]
+    fn global_set_updates_the_global() {
+        // a counter: g += 1; return g
+        let module = Module {
+            types: vec![FuncType {
+                from: ResultType { types: vec![] },
+                to: ResultType {
+                    types: vec![ValType::I32],
+                },
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body: vec![
+                    Inst::GlobalGet(GlobalIdx(0)),
+                    Inst::I32Const(1),
+                    Inst::I32Add,
+                    Inst::GlobalSet(GlobalIdx(0)),
+                    Inst::GlobalGet(GlobalIdx(0)),
+                ],
+            }],
+            globals: vec![Global {
+                typ: GlobalType {
+                    valtype: ValType::I32,
+                    mutable: true,
+                },
+                init: vec![Inst::I32Const(41)],
+            }],
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let inst = instantiate(&module, &mut store, Externals::default()).unwrap();
+        let func = inst.borrow().func_addrs[0];
+        let mut m = Machine::new(&mut store);
+        assert!(matches!(m.invoke(func, &[]).unwrap()[..], [Val::I32(42)]));
+        assert!(matches!(m.invoke(func, &[]).unwrap()[..], [Val::I32(43)]));
+        assert!(matches!(m.store.globals[0].value, Val::I32(43)));
+
+        let res = m.execute(
+            inst,
+            &[Inst::I64Const(1), Inst::GlobalSet(GlobalIdx(0))],
+            &mut Locals::empty(),
+        );
+        assert!(matches!(res, Err(Exception::Runtime(Error::WrongValType))));
+    }
+
+    #[test]
+    fn if_else_runs_one_branch() {
+        // (if (result i32) (local.get 0) (then i32.const 10) (else i32.const 20))
+        let select = vec![
+            Inst::LocalGet(LocalIdx(0)),
+            Inst::IfElse(
+                I32,
+                vec![Inst::I32Const(10)].into(),
+                vec![Inst::I32Const(20)].into(),
+            ),
+        ];
+        // (block (result i32) (if (local.get 0) (then i32.const 1 br 1)) i32.const 2)
+        let early_exit = vec![Inst::Block(
+            I32,
+            vec![
+                Inst::LocalGet(LocalIdx(0)),
+                Inst::IfElse(
+                    BlockType::Empty,
+                    vec![Inst::I32Const(1), Inst::Break(LabelIdx(1))].into(),
+                    vec![].into(),
+                ),
+                Inst::I32Const(2),
+            ]
+            .into(),
+        )];
+        let module = Module {
+            types: vec![FuncType {
+                from: ResultType {
+                    types: vec![ValType::I32],
+                },
+                to: ResultType {
+                    types: vec![ValType::I32],
+                },
+            }],
+            funcs: [select, early_exit]
+                .map(|body| Func {
+                    typ: TypeIdx(0),
+                    locals: vec![],
+                    body,
+                })
+                .into(),
+            ..Module::default()
+        };
+        let mut store = Store::new();
+        let inst = instantiate(&module, &mut store, Externals::default()).unwrap();
+        let [select, early_exit] = inst.borrow().func_addrs[..] else { panic!("expected two functions") };
+        let mut m = Machine::new(&mut store);
+        let mut call = |func, c| {
+            let res = m.invoke(func, &[Val::I32(c)]).unwrap();
+            let [Val::I32(r)] = res[..] else { panic!("expected an i32, got {res:?}") };
+            r
+        };
+        assert_eq!(call(select, 1), 10);
+        assert_eq!(call(select, -5), 10);
+        assert_eq!(call(select, 0), 20);
+        assert_eq!(call(early_exit, 1), 1);
+        assert_eq!(call(early_exit, 0), 2);
+    }
+
+    #[test]
+    fn call_indirect_checks_the_element() {
+        let returning = |typ| FuncType {
+            from: ResultType { types: vec![] },
+            to: ResultType { types: vec![typ] },
+        };
+        let func = |typ, body| Func {
+            typ: TypeIdx(typ),
+            locals: vec![],
+            body: vec![body],
+        };
+        let module = Module {
+            types: vec![returning(ValType::I32), returning(ValType::I64)],
+            funcs: vec![
+                func(0, Inst::I32Const(1)),
+                func(0, Inst::I32Const(2)),
+                func(1, Inst::I64Const(3)),
+            ],
+            tables: vec![TableType {
+                reftype: Reftype::Funcref,
+                limits: Limits {
+                    min: 4,
+                    max: None,
+                    is_64: false,
+                    shared: false,
+                },
+            }],
+            // table = [f0, f1, f2, null]
+            elems: vec![Elem {
+                typ: Reftype::Funcref,
+                init: (0..3).map(|f| vec![Inst::RefFunc(FuncIdx(f))]).collect(),
+                mode: ElemMode::Active {
+                    table: TableIdx(0),
+                    offset: vec![Inst::I32Const(0)],
+                },
+            }],
+            ..Module::default()
+        };
+        let call = |i, typ| {
+            run_in(
+                &module,
+                &[
+                    Inst::I32Const(i),
+                    Inst::CallIndirect(TypeIdx(typ), TableIdx(0)),
+                ],
+            )
+        };
+        assert!(matches!(call(0, 0).unwrap()[..], [Val::I32(1)]));
+        assert!(matches!(call(1, 0).unwrap()[..], [Val::I32(2)]));
+        assert!(matches!(call(2, 1).unwrap()[..], [Val::I64(3)]));
+        assert!(matches!(
+            call(2, 0),
+            Err(Exception::Runtime(Error::IndirectCallTypeMismatch))
+        ));
+        assert!(matches!(
+            call(3, 0),
+            Err(Exception::Runtime(Error::UndefinedElement))
+        ));
+        assert!(matches!(
+            call(-1, 0),
+            Err(Exception::Runtime(Error::UndefinedElement))
+        ));
+    }
+
+    #[test]
+    fn results_are_checked_on_return() {
+        let call = |body: Vec<Inst>| {
+            let module = Module {
+                types: vec![FuncType {
+                    from: ResultType { types: vec![] },
+                    to: ResultType {
+                        types: vec![ValType::I32],
+                    },
+                }],
+                funcs: vec![Func {
+                    typ: TypeIdx(0),
+                    locals: vec![],
+                    body,
+                }],
+                ..Module::default()
+            };
+            run_in(&module, &[Inst::Call(FuncIdx(0))])
+        };
+        let res = call(vec![Inst::I32Const(1)]).unwrap();
+        assert!(matches!(res[..], [Val::I32(1)]));
+        let res = call(vec![Inst::I32Const(1), Inst::I32Const(2)]);
+        assert!(matches!(
+            res,
+            Err(Exception::Runtime(Error::WrongResultCount))
+        ));
+        let res = call(vec![]);
+        assert!(matches!(
+            res,
+            Err(Exception::Runtime(Error::WrongResultCount))
+        ));
+        let res = call(vec![Inst::F32Const(1.0)]);
+        assert!(matches!(res, Err(Exception::Runtime(Error::WrongValType))));
+    }
+
+    #[test]
+    fn return_and_br_discard_extra_operands() {
+        let call = |body: Vec<Inst>| {
+            let module = Module {
+                types: vec![FuncType {
+                    from: ResultType { types: vec![] },
+                    to: ResultType {
+                        types: vec![ValType::I32],
+                    },
+                }],
+                funcs: vec![Func {
+                    typ: TypeIdx(0),
+                    locals: vec![],
+                    body,
+                }],
+                ..Module::default()
+            };
+            run_in(&module, &[Inst::Call(FuncIdx(0))])
+        };
+        // i32.const 1 i32.const 2 return
+        let res = call(vec![Inst::I32Const(1), Inst::I32Const(2), Inst::Return]).unwrap();
+        assert!(matches!(res[..], [Val::I32(2)]));
+        // block (result i32) i32.const 1 i32.const 2 br 0 end
+        let res = call(vec![Inst::Block(
+            I32,
+            vec![
+                Inst::I32Const(1),
+                Inst::I32Const(2),
+                Inst::Break(LabelIdx(0)),
+            ]
+            .into(),
+        )])
+        .unwrap();
+        assert!(matches!(res[..], [Val::I32(2)]));
+        // block i32.const 7 br 0 end i32.const 3
+        let res = call(vec![
+            Inst::Block(
+                BlockType::Empty,
+                vec![Inst::I32Const(7), Inst::Break(LabelIdx(0))].into(),
+            ),
+            Inst::I32Const(3),
+        ])
+        .unwrap();
+        assert!(matches!(res[..], [Val::I32(3)]));
+        // a return from inside nested blocks
+        let res = call(vec![
+            Inst::I32Const(1),
+            Inst::Block(
+                BlockType::Empty,
+                vec![Inst::I32Const(2), Inst::I32Const(3), Inst::Return].into(),
+            ),
+            Inst::Unreachable,
+        ])
+        .unwrap();
+        assert!(matches!(res[..], [Val::I32(3)]));
+    }
+
+    #[test]
+    fn arguments_are_type_checked() {
+        let module = Module {
+            types: vec![FuncType {
+                from: ResultType {
+                    types: vec![ValType::I32, ValType::F64],
+                },
+                to: ResultType { types: vec![] },
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body: vec![],
+            }],
+            ..Module::default()
+        };
+        let res = run_in(
+            &module,
+            &[
+                Inst::I32Const(1),
+                Inst::F64Const(2.0),
+                Inst::Call(FuncIdx(0)),
+            ],
+        );
+        assert!(res.unwrap().is_empty());
+        let res = run_in(
+            &module,
+            &[
+                Inst::F64Const(2.0),
+                Inst::I32Const(1),
+                Inst::Call(FuncIdx(0)),
+            ],
+        );
+        assert!(matches!(res, Err(Exception::Runtime(Error::WrongValType))));
+    }
+}
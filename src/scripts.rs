@@ -1,18 +1,27 @@
 use std::{
+    cell::RefCell,
     collections::{BTreeMap, VecDeque},
+    io::{self, Cursor},
     iter::Peekable,
+    rc::Rc,
 };
 
-use crate::repr::{self, Module};
+use crate::binary;
+use crate::instance::{ExternAddr, FuncAddr, InstantiationError, Linker, ModuleInst, Store};
+use crate::repr::{self, ExportDesc, Module};
+use crate::rt::{self, Exception, Machine, Ref, Val};
 use crate::text;
-use text::token::Token;
+use crate::valid::{self, ValidationError};
+use text::parser::{signed_immediate, unsigned_immediate, ParseError as TextParseError};
+use text::sexpr::Sexpr;
+use text::token::{Position, Spanned, Token, TokenizeError};
 
 pub struct Script {
     commands: Vec<Command>,
 }
 
 pub enum Command {
-    Module(repr::Module),
+    Module(Box<repr::Module>),
     Action(Action),
     Assert(Assertion),
     Meta(Meta),
@@ -89,6 +98,35 @@ pub fn tokens_to_tree(tokens: Vec<Token>) -> Result<Vec<Tree>, ParseError> {
     return Ok(trees);
 }
 
+/// Builds one tree per top-level command, keeping the position the command starts at.
+pub fn spanned_tokens_to_tree(
+    tokens: Vec<Spanned<Token>>,
+) -> Result<Vec<Spanned<Tree>>, Spanned<ParseError>> {
+    let mut trees = vec![];
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(first) = tokens.peek() {
+        let pos = first.pos;
+        // collect the tokens up to the matching closing paren
+        let mut group = vec![];
+        let mut depth = 0;
+        for token in tokens.by_ref() {
+            match token.item {
+                Token::LeftParen => depth += 1,
+                Token::RightParen => depth -= 1,
+                _ => {}
+            }
+            group.push(token.item);
+            if depth <= 0 {
+                break;
+            }
+        }
+        let mut group = group.into_iter().peekable();
+        let item = tree(&mut group).map_err(|item| Spanned { item, pos })?;
+        trees.push(Spanned { item, pos });
+    }
+    Ok(trees)
+}
+
 fn to_command(tree: Tree) -> Result<(String, VecDeque<Tree>), ParseError> {
     let Tree::List(mut items) = tree else { return Err(ParseError::UnexpectedToken) };
     let cmd = items.pop_front().ok_or(ParseError::UnexpectedEof)?;
@@ -96,35 +134,877 @@ fn to_command(tree: Tree) -> Result<(String, VecDeque<Tree>), ParseError> {
     Ok((cmd, items))
 }
 
+/// A module the script instantiated, so actions can refer to its exports
+/// by name.
+struct Instance {
+    inst: Rc<RefCell<ModuleInst>>,
+}
+
+impl Instance {
+    fn func(&self, name: &str) -> Result<FuncAddr, ScriptError> {
+        let Some(ExternAddr::Func(addr)) = self.inst.borrow().get_export(name) else { return Err(ScriptError::ExportNotFound(name.into())) };
+        Ok(addr)
+    }
+
+    fn global(&self, store: &Store, name: &str) -> Result<Val, ScriptError> {
+        let Some(ExternAddr::Global(addr)) = self.inst.borrow().get_export(name) else { return Err(ScriptError::ExportNotFound(name.into())) };
+        Ok(store.globals[addr.0].value)
+    }
+}
+
 pub struct Context {
-    registered_modules: BTreeMap<String, Module>,
-    last_module: Option<Module>,
+    /// resolves imports against the instances passed to `register`
+    linker: Linker,
+    store: Store,
+    last_instance: Option<Rc<Instance>>,
+    /// instances declared with a `$name`
+    instances: BTreeMap<String, Rc<Instance>>,
     errors: Vec<ScriptError>,
 }
 
 impl Context {
     fn new() -> Self {
         Self {
-            registered_modules: BTreeMap::new(),
-            last_module: None,
+            linker: Linker::new(),
+            store: Store::new(),
+            last_instance: None,
+            instances: BTreeMap::new(),
             errors: vec![],
         }
     }
 }
 
 #[derive(Debug)]
-pub enum ScriptError {}
+pub enum ScriptError {
+    Tokenizing(TokenizeError),
+    Parsing(ParseError),
+    /// A module given as text failed to parse.
+    Text(text::TextError),
+    /// A module given as binary failed to parse.
+    Binary(io::Error),
+    UnknownCommand(String),
+    Unsupported(String),
+    InvalidConst(Sexpr),
+    NoModule,
+    UnknownModule(String),
+    ExportNotFound(String),
+    Link(InstantiationError),
+    Runtime(rt::Error),
+    /// A module that should have been malformed parsed; holds the expected
+    /// message.
+    NotMalformed(String),
+    /// A module that should have been invalid validated; holds the expected
+    /// message.
+    NotInvalid(String),
+    /// A call that should have trapped returned; holds the expected message.
+    NotTrapped(String),
+    /// An action returned other results than expected.
+    WrongResults {
+        expected: Vec<ExpectedVal>,
+        actual: Vec<Val>,
+    },
+    /// A module failed validation.
+    Invalid(ValidationError),
+    /// A module was rejected, but with a different message than expected.
+    WrongMessage {
+        expected: String,
+        actual: String,
+    },
+    At(Position, Box<ScriptError>),
+    /// Some commands of the script failed; the rest still ran.
+    Failed {
+        commands: usize,
+        errors: Vec<ScriptError>,
+    },
+}
+
+/// The NaN forms `assert_return` accepts in place of an exact float.
+#[derive(Debug, Copy, Clone)]
+pub enum NanPattern {
+    Canonical,
+    Arithmetic,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum ExpectedVal {
+    Val(Val),
+    F32Nan(NanPattern),
+    F64Nan(NanPattern),
+}
+
+impl ExpectedVal {
+    /// Whether `val` is the expected result. Exact values are compared bit
+    /// for bit, so `-0.0` doesn't match `0.0`.
+    pub fn matches(&self, val: &Val) -> bool {
+        match (self, val) {
+            (ExpectedVal::Val(expected), val) => expected.bit_eq(val),
+            (ExpectedVal::F32Nan(pattern), Val::F32(f)) => {
+                let payload = f.to_bits() & 0x007f_ffff;
+                f.is_nan()
+                    && match pattern {
+                        NanPattern::Canonical => payload == 0x0040_0000,
+                        NanPattern::Arithmetic => payload & 0x0040_0000 != 0,
+                    }
+            }
+            (ExpectedVal::F64Nan(pattern), Val::F64(f)) => {
+                let payload = f.to_bits() & 0x000f_ffff_ffff_ffff;
+                f.is_nan()
+                    && match pattern {
+                        NanPattern::Canonical => payload == 0x0008_0000_0000_0000,
+                        NanPattern::Arithmetic => payload & 0x0008_0000_0000_0000 != 0,
+                    }
+            }
+            _ => false,
+        }
+    }
+}
+
+fn nan_pattern(s: &Sexpr) -> Option<NanPattern> {
+    match s {
+        Sexpr::Atom(a) if a == "nan:canonical" => Some(NanPattern::Canonical),
+        Sexpr::Atom(a) if a == "nan:arithmetic" => Some(NanPattern::Arithmetic),
+        _ => None,
+    }
+}
+
+fn float_literal(s: &Sexpr) -> Option<f64> {
+    match s {
+        Sexpr::Float(f) => Some(*f),
+        Sexpr::Nat(n) => Some(*n as f64),
+        Sexpr::Int(i) => Some(*i as f64),
+        _ => None,
+    }
+}
+
+/// Converts a constant like `(i32.const 5)` or `(ref.null func)` into a value.
+pub fn sexpr_to_val(s: &Sexpr) -> Result<ExpectedVal, ScriptError> {
+    let invalid = || ScriptError::InvalidConst(s.clone());
+    let Sexpr::List(items) = s else { return Err(invalid()) };
+    let [Sexpr::Atom(head), arg] = &items[..] else { return Err(invalid()) };
+    let val = match (head.as_str(), arg) {
+        ("i32.const", Sexpr::Nat(n)) => {
+            Val::I32(unsigned_immediate(*n, 32).map_err(|_| invalid())? as i32)
+        }
+        ("i32.const", Sexpr::Int(i)) => {
+            Val::I32(signed_immediate(*i, 32).map_err(|_| invalid())? as i32)
+        }
+        ("i64.const", Sexpr::Nat(n)) => {
+            Val::I64(unsigned_immediate(*n, 64).map_err(|_| invalid())?)
+        }
+        ("i64.const", Sexpr::Int(i)) => Val::I64(signed_immediate(*i, 64).map_err(|_| invalid())?),
+        ("f32.const", arg) => {
+            if let Some(pattern) = nan_pattern(arg) {
+                return Ok(ExpectedVal::F32Nan(pattern));
+            }
+            if let Sexpr::Nan(nan) = arg {
+                return Ok(ExpectedVal::Val(Val::F32(
+                    nan.to_f32().ok_or_else(invalid)?,
+                )));
+            }
+            Val::F32(float_literal(arg).ok_or_else(invalid)? as f32)
+        }
+        ("f64.const", arg) => {
+            if let Some(pattern) = nan_pattern(arg) {
+                return Ok(ExpectedVal::F64Nan(pattern));
+            }
+            if let Sexpr::Nan(nan) = arg {
+                return Ok(ExpectedVal::Val(Val::F64(
+                    nan.to_f64().ok_or_else(invalid)?,
+                )));
+            }
+            Val::F64(float_literal(arg).ok_or_else(invalid)?)
+        }
+        ("ref.null", Sexpr::Atom(t)) if t == "func" => {
+            Val::Reference(Ref::Null(repr::Reftype::Funcref))
+        }
+        ("ref.null", Sexpr::Atom(t)) if t == "extern" => {
+            Val::Reference(Ref::Null(repr::Reftype::Externref))
+        }
+        ("ref.extern", Sexpr::Nat(n)) => Val::Reference(Ref::Extern(*n)),
+        _ => return Err(invalid()),
+    };
+    Ok(ExpectedVal::Val(val))
+}
+
+fn push_tokens(tree: Tree, tokens: &mut Vec<Token>) {
+    match tree {
+        Tree::Single(token) => tokens.push(token),
+        Tree::List(items) => {
+            tokens.push(Token::LeftParen);
+            for item in items {
+                push_tokens(item, tokens);
+            }
+            tokens.push(Token::RightParen);
+        }
+    }
+}
+
+/// Concatenates the strings of a `binary` or `quote` module.
+fn module_payload(args: VecDeque<Tree>) -> Result<Vec<u8>, ScriptError> {
+    let mut payload = vec![];
+    for arg in args {
+        let Tree::Single(Token::Text(text)) = arg else { return Err(ScriptError::Parsing(ParseError::UnexpectedToken)) };
+        payload.extend_from_slice(text.as_bytes());
+    }
+    Ok(payload)
+}
+
+/// Parses the arguments of a `(module ...)` command into its optional
+/// `$name` and the module, which may be inline, `binary` or `quote`d text.
+fn script_module(mut args: VecDeque<Tree>) -> Result<(Option<String>, Module), ScriptError> {
+    let name = match args.front() {
+        Some(Tree::Single(Token::Name(name))) => {
+            let name = name.clone();
+            args.pop_front();
+            Some(name)
+        }
+        _ => None,
+    };
+    let module = match args.front() {
+        Some(Tree::Single(Token::Atom(a))) if a == "binary" => {
+            args.pop_front();
+            let bytes = module_payload(args)?;
+            binary::parser::parse_stream(Box::new(Cursor::new(bytes)))
+                .map_err(ScriptError::Binary)?
+        }
+        Some(Tree::Single(Token::Atom(a))) if a == "quote" => {
+            args.pop_front();
+            let source = module_payload(args)?;
+            let Ok(source) = String::from_utf8(source) else { return Err(ScriptError::Parsing(ParseError::UnexpectedToken)) };
+            // the quoted source may leave out the `(module ...)` around its fields
+            let source = if source.trim_start().starts_with("(module") {
+                source
+            } else {
+                format!("(module {source})")
+            };
+            text::parse_module(&source).map_err(ScriptError::Text)?
+        }
+        _ => {
+            let mut tokens = vec![Token::LeftParen, Token::Atom("module".into())];
+            for arg in args {
+                push_tokens(arg, &mut tokens);
+            }
+            tokens.push(Token::RightParen);
+            text::parse_module_tokens(&tokens).map_err(|e| ScriptError::Text(e.into()))?
+        }
+    };
+    Ok((name, module))
+}
+
+fn command_module(ctx: &mut Context, args: VecDeque<Tree>) -> Result<(), ScriptError> {
+    let (name, module) = script_module(args)?;
+    valid::validate(&module).map_err(ScriptError::Invalid)?;
+    let inst = ctx
+        .linker
+        .instantiate(&module, &mut ctx.store)
+        .map_err(ScriptError::Link)?;
+    let instance = Rc::new(Instance { inst });
+    if let Some(name) = name {
+        ctx.instances.insert(name, instance.clone());
+    }
+    ctx.last_instance = Some(instance);
+    Ok(())
+}
+
+/// `(register "name" $instance?)` makes the exports of an instance, the last
+/// one by default, importable from the module `name`.
+fn command_register(ctx: &mut Context, mut args: VecDeque<Tree>) -> Result<(), ScriptError> {
+    let Some(Tree::Single(Token::Text(name))) = args.pop_front() else { return Err(ScriptError::Parsing(ParseError::UnexpectedToken)) };
+    let Ok(name) = name.try_string() else { return Err(ScriptError::Parsing(ParseError::UnexpectedToken)) };
+    let instance = match args.pop_front() {
+        Some(Tree::Single(Token::Name(id))) => ctx
+            .instances
+            .get(&id)
+            .ok_or(ScriptError::UnknownModule(id))?,
+        None => ctx.last_instance.as_ref().ok_or(ScriptError::NoModule)?,
+        Some(_) => return Err(ScriptError::Parsing(ParseError::UnexpectedToken)),
+    };
+    ctx.linker.register_instance(&name, &instance.inst.borrow());
+    Ok(())
+}
+
+fn tree_to_sexpr(tree: Tree) -> Result<Sexpr, ScriptError> {
+    let mut tokens = vec![];
+    push_tokens(tree, &mut tokens);
+    text::sexpr::tokens_to_sexpr(&mut tokens.into_iter().peekable())
+        .ok_or(ScriptError::Parsing(ParseError::UnexpectedEof))
+}
+
+/// Converts an argument of `invoke`, like `(i32.const 1)`, into a value.
+fn invoke_arg(arg: Tree) -> Result<Val, ScriptError> {
+    let sexpr = tree_to_sexpr(arg)?;
+    match sexpr_to_val(&sexpr)? {
+        ExpectedVal::Val(val) => Ok(val),
+        _ => Err(ScriptError::InvalidConst(sexpr)),
+    }
+}
+
+/// Performs `(invoke ...)` or `(get ...)` and returns the values it produced.
+fn action(ctx: &mut Context, cmd: &str, mut args: VecDeque<Tree>) -> Result<Vec<Val>, ScriptError> {
+    let instance = match args.front() {
+        Some(Tree::Single(Token::Name(name))) => {
+            let name = name.clone();
+            args.pop_front();
+            ctx.instances
+                .get(&name)
+                .ok_or(ScriptError::UnknownModule(name))?
+        }
+        _ => ctx.last_instance.as_ref().ok_or(ScriptError::NoModule)?,
+    };
+    let Some(Tree::Single(Token::Text(name))) = args.pop_front() else { return Err(ScriptError::Parsing(ParseError::UnexpectedToken)) };
+    let Ok(name) = name.try_string() else { return Err(ScriptError::Parsing(ParseError::UnexpectedToken)) };
+    match cmd {
+        "invoke" => {
+            let func = instance.func(&name)?;
+            let args = args
+                .into_iter()
+                .map(invoke_arg)
+                .collect::<Result<Vec<_>, _>>()?;
+            Machine::new(&mut ctx.store)
+                .invoke(func, &args)
+                .map_err(|Exception::Runtime(e)| ScriptError::Runtime(e))
+        }
+        "get" => Ok(vec![instance.global(&ctx.store, &name)?]),
+        _ => Err(ScriptError::Unsupported(cmd.into())),
+    }
+}
+
+fn command_action(ctx: &mut Context, cmd: &str, args: VecDeque<Tree>) -> Result<(), ScriptError> {
+    action(ctx, cmd, args)?;
+    Ok(())
+}
+
+/// `(assert_return (invoke $instance? "name" ...) result*)` checks that the
+/// action returns exactly the given results.
+fn command_assert_return(ctx: &mut Context, mut args: VecDeque<Tree>) -> Result<(), ScriptError> {
+    let Some(call) = args.pop_front() else { return Err(ScriptError::Parsing(ParseError::UnexpectedEof)) };
+    let expected = args
+        .into_iter()
+        .map(|arg| sexpr_to_val(&tree_to_sexpr(arg)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (cmd, call) = to_command(call).map_err(ScriptError::Parsing)?;
+    let actual = action(ctx, &cmd, call)?;
+    let matches =
+        expected.len() == actual.len() && expected.iter().zip(&actual).all(|(e, a)| e.matches(a));
+    if !matches {
+        return Err(ScriptError::WrongResults { expected, actual });
+    }
+    Ok(())
+}
 
+fn command_assert_trap(ctx: &mut Context, args: VecDeque<Tree>) -> Result<(), ScriptError> {
+    Err(ScriptError::Unsupported("assert_trap".into()))
+}
+
+/// `(assert_exhaustion (invoke ...) "message")` checks that the call runs
+/// out of call stack.
+fn command_assert_exhaustion(ctx: &mut Context, mut args: VecDeque<Tree>) -> Result<(), ScriptError> {
+    let Some(call) = args.pop_front() else { return Err(ScriptError::Parsing(ParseError::UnexpectedEof)) };
+    let Some(Tree::Single(Token::Text(expected))) = args.pop_front() else { return Err(ScriptError::Parsing(ParseError::UnexpectedToken)) };
+    let Ok(expected) = expected.try_string() else { return Err(ScriptError::Parsing(ParseError::UnexpectedToken)) };
+    let (cmd, call) = to_command(call).map_err(ScriptError::Parsing)?;
+    match action(ctx, &cmd, call) {
+        Ok(_) => Err(ScriptError::NotTrapped(expected)),
+        Err(ScriptError::Runtime(e)) => check_message(expected, e.to_string()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The spec's wording for why a module failed to parse, as far as the
+/// parsers' own messages can be mapped to it.
+fn malformed_message(err: &ScriptError) -> String {
+    match err {
+        // the spec's tests expect either this or just "unexpected end"
+        ScriptError::Binary(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            "unexpected end of section or function".into()
+        }
+        ScriptError::Binary(e) => {
+            let msg = e.to_string();
+            let spec = match msg.as_str() {
+                "integer too long" => "integer representation too long",
+                "invalid utf8" => "malformed UTF-8 encoding",
+                "invalid magic" => "magic header not detected",
+                "invalid version" => "unknown binary version",
+                "unknown section id" => "malformed section id",
+                "invalid reftype" => "malformed reference type",
+                "invalid mutability" => "malformed mutability",
+                "invalid import desc" => "malformed import kind",
+                "unknown instruction" => "illegal opcode",
+                "more code entries than functions" => {
+                    "function and code section have inconsistent lengths"
+                }
+                _ => return msg,
+            };
+            spec.into()
+        }
+        ScriptError::Text(text::TextError::Tokenizing { error, .. }) => match error {
+            TokenizeError::UnexpectedNextChar(_) => "unknown operator".into(),
+            e => e.to_string(),
+        },
+        ScriptError::Text(text::TextError::Parsing { error: e, .. }) => {
+            let mut e = e;
+            while let TextParseError::Context(_, inner) = e {
+                e = inner;
+            }
+            let spec = match e {
+                TextParseError::UnknownInstruction(_) => "unknown operator",
+                TextParseError::InvalidUtf8 => "malformed UTF-8 encoding",
+                TextParseError::InvalidAlignment(_) => "alignment",
+                TextParseError::ConstantOutOfRange => "constant out of range",
+                TextParseError::UnknownFunction(_) => "unknown function",
+                TextParseError::FailedExpectedToken
+                | TextParseError::UnexpectedEot
+                | TextParseError::ExpectedLparen
+                | TextParseError::ExpectedRparen
+                | TextParseError::InvalidModulefield(_)
+                | TextParseError::UnexpectedImport => "unexpected token",
+                _ => return e.to_string(),
+            };
+            spec.into()
+        }
+        e => format!("{e:?}"),
+    }
+}
+
+/// Splits the `(module ...) "message"` arguments of an assertion about a
+/// module into the arguments of the module and the expected message.
+fn assertion_module(mut args: VecDeque<Tree>) -> Result<(VecDeque<Tree>, String), ScriptError> {
+    let Some(Tree::List(mut module)) = args.pop_front() else { return Err(ScriptError::Parsing(ParseError::UnexpectedToken)) };
+    let Some(Tree::Single(Token::Text(expected))) = args.pop_front() else { return Err(ScriptError::Parsing(ParseError::UnexpectedToken)) };
+    let Ok(expected) = expected.try_string() else { return Err(ScriptError::Parsing(ParseError::UnexpectedToken)) };
+    let Some(Tree::Single(Token::Atom(cmd))) = module.pop_front() else { return Err(ScriptError::Parsing(ParseError::UnexpectedToken)) };
+    if cmd != "module" {
+        return Err(ScriptError::Parsing(ParseError::UnexpectedToken));
+    }
+    Ok((module, expected))
+}
+
+/// Like the reference interpreter, the expected message only has to be a
+/// prefix of the actual one.
+fn check_message(expected: String, actual: String) -> Result<(), ScriptError> {
+    if !actual.starts_with(&expected) {
+        return Err(ScriptError::WrongMessage { expected, actual });
+    }
+    Ok(())
+}
+
+/// `(assert_malformed (module binary|quote ...) "message")` checks that the
+/// module fails to parse.
+fn command_assert_malformed(ctx: &mut Context, args: VecDeque<Tree>) -> Result<(), ScriptError> {
+    let (module, expected) = assertion_module(args)?;
+    let err = match script_module(module) {
+        Ok(_) => return Err(ScriptError::NotMalformed(expected)),
+        Err(e) => e,
+    };
+    check_message(expected, malformed_message(&err))
+}
+
+/// `(assert_invalid (module ...) "message")` checks that the module parses
+/// but fails validation.
+fn command_assert_invalid(ctx: &mut Context, args: VecDeque<Tree>) -> Result<(), ScriptError> {
+    let (module, expected) = assertion_module(args)?;
+    let (_, module) = script_module(module)?;
+    let Err(err) = valid::validate(&module) else { return Err(ScriptError::NotInvalid(expected)) };
+    check_message(expected, err.to_string())
+}
+
+fn command_assert_unlinkable(ctx: &mut Context, args: VecDeque<Tree>) -> Result<(), ScriptError> {
+    Err(ScriptError::Unsupported("assert_unlinkable".into()))
+}
+
+fn run_command(ctx: &mut Context, tree: Tree) -> Result<(), ScriptError> {
+    let (cmd, args) = to_command(tree).map_err(ScriptError::Parsing)?;
+    match cmd.as_str() {
+        "module" => command_module(ctx, args),
+        "register" => command_register(ctx, args),
+        "invoke" | "get" => command_action(ctx, &cmd, args),
+        "assert_return" => command_assert_return(ctx, args),
+        "assert_trap" => command_assert_trap(ctx, args),
+        "assert_exhaustion" => command_assert_exhaustion(ctx, args),
+        "assert_malformed" => command_assert_malformed(ctx, args),
+        "assert_invalid" => command_assert_invalid(ctx, args),
+        "assert_unlinkable" => command_assert_unlinkable(ctx, args),
+        a => Err(ScriptError::UnknownCommand(a.into())),
+    }
+}
+
+/// Runs every command of a script, collecting failures instead of stopping
+/// at the first one.
 pub fn run_script(input: &str) -> Result<(), ScriptError> {
-    let tokens = text::tokenize_script_without_ws(input).unwrap();
-    let trees = tokens_to_tree(tokens).unwrap();
-    let ctx = Context::new();
+    let tokens = text::tokenize_script_spanned(input)
+        .map_err(|e| ScriptError::At(e.pos, Box::new(ScriptError::Tokenizing(e.item))))?;
+    let trees = spanned_tokens_to_tree(tokens)
+        .map_err(|e| ScriptError::At(e.pos, Box::new(ScriptError::Parsing(e.item))))?;
+    let mut ctx = Context::new();
+    let commands = trees.len();
     for tree in trees {
+        if let Err(e) = run_command(&mut ctx, tree.item) {
+            ctx.errors.push(ScriptError::At(tree.pos, Box::new(e)));
+        }
+    }
+    if !ctx.errors.is_empty() {
+        return Err(ScriptError::Failed {
+            commands,
+            errors: ctx.errors,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rt::{Ref, Val};
+    use crate::text::sexpr::parse_module_to_sexpr;
+
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    use crate::instance::{instantiate, Externals};
+    use crate::repr::{Export, ExportDesc, Global, GlobalIdx, GlobalType, Inst, Module, ValType};
+    use crate::text::tokenize_script_without_ws;
+    use crate::valid::ValidationError;
+
+    use super::{
+        action, run_script, sexpr_to_val, to_command, tokens_to_tree, Context, ExpectedVal,
+        Instance, NanPattern, ScriptError,
+    };
+
+    #[test]
+    fn script_errors_are_collected() {
+        let res = run_script("(frobnicate) (assert_return (invoke \"f\")) (frobnicate)");
+        let Err(ScriptError::Failed { commands, errors }) = res else { panic!("expected failures") };
+        assert_eq!(commands, 3);
+        assert_eq!(errors.len(), 3);
+        let errors: Vec<_> = errors
+            .iter()
+            .map(|e| match e {
+                ScriptError::At(_, e) => e.as_ref(),
+                e => e,
+            })
+            .collect();
+        assert!(matches!(errors[0], ScriptError::UnknownCommand(c) if c == "frobnicate"));
+        assert!(matches!(errors[1], ScriptError::NoModule));
+    }
+
+    #[test]
+    fn malformed_script_is_an_error() {
+        let res = run_script("(module)\n\n(module");
+        let Err(ScriptError::At(pos, e)) = res else { panic!("expected a located error") };
+        assert_eq!((pos.line, pos.column), (3, 1));
+        assert!(matches!(*e, ScriptError::Parsing(_)));
+    }
+
+    #[test]
+    fn command_errors_are_located() {
+        let res = run_script("(module)\n  (frobnicate)");
+        let Err(ScriptError::Failed { errors, .. }) = res else { panic!("expected failures") };
+        let ScriptError::At(pos, _) = &errors[0] else { panic!("expected a located error") };
+        assert_eq!((pos.line, pos.column), (2, 3));
+    }
+
+    fn val(input: &str) -> ExpectedVal {
+        let sexpr = parse_module_to_sexpr(input).unwrap();
+        sexpr_to_val(&sexpr).unwrap()
+    }
+
+    #[test]
+    fn integer_consts() {
+        assert!(matches!(
+            val("(i32.const 5)"),
+            ExpectedVal::Val(Val::I32(5))
+        ));
+        assert!(matches!(
+            val("(i32.const -1)"),
+            ExpectedVal::Val(Val::I32(-1))
+        ));
+        assert!(matches!(
+            val("(i32.const 0xffffffff)"),
+            ExpectedVal::Val(Val::I32(-1))
+        ));
+        assert!(matches!(
+            val("(i64.const -9)"),
+            ExpectedVal::Val(Val::I64(-9))
+        ));
+        let sexpr = parse_module_to_sexpr("(i32.const 0x100000000)").unwrap();
+        assert!(sexpr_to_val(&sexpr).is_err());
+    }
+
+    #[test]
+    fn float_consts() {
+        assert!(matches!(val("(f64.const 1.5)"), ExpectedVal::Val(Val::F64(f)) if f == 1.5));
+        assert!(matches!(val("(f32.const 2)"), ExpectedVal::Val(Val::F32(f)) if f == 2.0));
+        assert!(matches!(
+            val("(f32.const nan:canonical)"),
+            ExpectedVal::F32Nan(NanPattern::Canonical)
+        ));
+        assert!(matches!(
+            val("(f64.const nan:arithmetic)"),
+            ExpectedVal::F64Nan(NanPattern::Arithmetic)
+        ));
+        assert!(val("(f32.const -nan:0x1)").matches(&Val::F32(f32::from_bits(0xff80_0001))));
+        assert!(val("(f64.const nan)").matches(&Val::F64(f64::from_bits(0x7ff8_0000_0000_0000))));
+        assert!(matches!(
+            sexpr_to_val(&parse_module_to_sexpr("(f32.const nan:0x800000)").unwrap()),
+            Err(ScriptError::InvalidConst(_))
+        ));
+    }
+
+    #[test]
+    fn expected_vals_match_exactly() {
+        assert!(val("(f32.const 0.0)").matches(&Val::F32(0.0)));
+        assert!(!val("(f32.const -0.0)").matches(&Val::F32(0.0)));
+        assert!(!val("(f64.const 1)").matches(&Val::F32(1.0)));
+        let canonical = val("(f64.const nan:canonical)");
+        assert!(canonical.matches(&Val::F64(f64::NAN)));
+        assert!(canonical.matches(&Val::F64(-f64::NAN)));
+        assert!(!canonical.matches(&Val::F64(f64::from_bits(0x7ff8_0000_0000_0001))));
+        let arithmetic = val("(f32.const nan:arithmetic)");
+        assert!(arithmetic.matches(&Val::F32(f32::from_bits(0x7fc0_0001))));
+        assert!(!arithmetic.matches(&Val::F32(f32::from_bits(0x7fa0_0000))));
+        assert!(!arithmetic.matches(&Val::F32(f32::INFINITY)));
+    }
+
+    #[test]
+    fn reference_consts() {
+        assert!(matches!(
+            val("(ref.null func)"),
+            ExpectedVal::Val(Val::Reference(Ref::Null(_)))
+        ));
+        assert!(matches!(
+            val("(ref.extern 3)"),
+            ExpectedVal::Val(Val::Reference(Ref::Extern(3)))
+        ));
+    }
+
+    #[test]
+    fn invalid_const() {
+        let sexpr = parse_module_to_sexpr("(i32.const 1.5)").unwrap();
+        assert!(sexpr_to_val(&sexpr).is_err());
+    }
+
+    fn context_with_global(value: i32) -> Context {
+        let module = Module {
+            globals: vec![Global {
+                typ: GlobalType {
+                    valtype: ValType::I32,
+                    mutable: false,
+                },
+                init: vec![Inst::I32Const(value)],
+            }],
+            exports: vec![Export {
+                name: "g".into(),
+                desc: ExportDesc::Global(GlobalIdx(0)),
+            }],
+            ..Module::default()
+        };
+        let mut ctx = Context::new();
+        let externals = Externals {
+            values: BTreeMap::new(),
+        };
+        let inst = instantiate(&module, &mut ctx.store, externals).unwrap();
+        ctx.last_instance = Some(Rc::new(Instance { inst }));
+        ctx
+    }
+
+    fn run_action(ctx: &mut Context, input: &str) -> Result<Vec<Val>, ScriptError> {
+        let tokens = tokenize_script_without_ws(input).unwrap();
+        let tree = tokens_to_tree(tokens).unwrap().pop().unwrap();
         let (cmd, args) = to_command(tree).unwrap();
-        //println!("{:?}", args);
-        match cmd.as_str() {
-            a => panic!("unknown command: {:?}", a),
+        action(ctx, &cmd, args)
+    }
+
+    #[test]
+    fn get_exported_global() {
+        let mut ctx = context_with_global(42);
+        let res = run_action(&mut ctx, "(get \"g\")").unwrap();
+        assert!(matches!(res[..], [Val::I32(42)]));
+        assert!(matches!(
+            run_action(&mut ctx, "(get \"missing\")"),
+            Err(ScriptError::ExportNotFound(n)) if n == "missing"
+        ));
+        assert!(matches!(
+            run_action(&mut ctx, "(get $M \"g\")"),
+            Err(ScriptError::UnknownModule(n)) if n == "M"
+        ));
+    }
+
+    #[test]
+    fn get_without_module() {
+        let mut ctx = Context::new();
+        assert!(matches!(
+            run_action(&mut ctx, "(get \"g\")"),
+            Err(ScriptError::NoModule)
+        ));
+    }
+
+    fn module_error(input: &str) -> Option<ScriptError> {
+        match run_script(input) {
+            Ok(()) => None,
+            Err(ScriptError::Failed { mut errors, .. }) => match errors.pop() {
+                Some(ScriptError::At(_, e)) => Some(*e),
+                e => e,
+            },
+            Err(e) => panic!("unexpected error {e:?}"),
+        }
+    }
+
+    #[test]
+    fn quoted_modules() {
+        assert!(module_error("(module quote \"(type (func))\" \"(func (type 0))\")").is_none());
+        assert!(module_error("(module quote \"(module)\")").is_none());
+        assert!(matches!(
+            module_error("(module quote \"(type (func))\" \"(func (type 0)\")"),
+            Some(ScriptError::Text(_))
+        ));
+        assert!(module_error("(module quote \"(func (local $x i32))\")").is_none());
+        assert!(matches!(
+            module_error(r#"(module quote "(memory 1) (data (i32.const 0) \"a\")")"#),
+            Some(ScriptError::Text(_))
+        ));
+    }
+
+    #[test]
+    fn assert_malformed() {
+        let malformed = |module: &str, msg: &str| {
+            module_error(&format!("(assert_malformed {module} \"{msg}\")"))
         };
+        assert!(malformed("(module binary \"\\00asm\" \"\\01\\00\")", "unexpected end").is_none());
+        assert!(malformed(
+            "(module binary \"\\00asm\\01\\00\\00\\00\\01\\01\")",
+            "unexpected end of section or function"
+        )
+        .is_none());
+        assert!(malformed(
+            "(module quote \"(func (type 0) i32.frob)\")",
+            "unknown operator"
+        )
+        .is_none());
+        assert!(malformed("(module quote \"(func\")", "unexpected token").is_none());
+
+        assert!(matches!(
+            malformed("(module binary \"\\00asm\\01\\00\\00\\00\")", "unexpected end"),
+            Some(ScriptError::NotMalformed(msg)) if msg == "unexpected end"
+        ));
+        assert!(matches!(
+            malformed("(module binary \"\\00asx\\01\\00\\00\\00\")", "unexpected end"),
+            Some(ScriptError::WrongMessage { actual, .. }) if actual == "magic header not detected"
+        ));
+        // text errors keep their kind
+        assert!(matches!(
+            malformed("(module quote \"(func (type 0) i32.frob)\")", "unexpected token"),
+            Some(ScriptError::WrongMessage { actual, .. }) if actual == "unknown operator"
+        ));
+        assert!(matches!(
+            malformed("(module quote \"(func\")", "unknown operator"),
+            Some(ScriptError::WrongMessage { actual, .. }) if actual == "unexpected token"
+        ));
+        assert!(matches!(
+            malformed("(module quote \"(memory 1) (data (i32.const 0))\")", "unexpected token"),
+            Some(ScriptError::WrongMessage { actual, .. })
+                if actual == "module field data is not supported"
+        ));
+    }
+
+    #[test]
+    fn assert_invalid() {
+        let invalid = |load: &str, msg: &str| {
+            module_error(&format!(
+                "(assert_invalid (module (memory 1) (type (func)) \
+                 (func (type 0) (drop ({load} (i32.const 0))))) \"{msg}\")"
+            ))
+        };
+        let msg = "alignment must not be larger than natural";
+        assert!(invalid("i32.load8_u align=2", msg).is_none());
+        assert!(matches!(
+            invalid("i32.load8_u align=1", msg),
+            Some(ScriptError::NotInvalid(_))
+        ));
+        assert!(matches!(
+            invalid("i64.load align=16", "type mismatch"),
+            Some(ScriptError::WrongMessage { .. })
+        ));
+        assert!(matches!(
+            module_error("(module (memory 1) (type (func)) (func (type 0) i32.load align=8))"),
+            Some(ScriptError::Invalid(ValidationError::AlignmentTooLarge))
+        ));
+    }
+
+    #[test]
+    fn assert_invalid_select() {
+        let select = |operands: &str| {
+            module_error(&format!(
+                "(assert_invalid (module (type (func)) \
+                 (func (type 0) (drop (select {operands})))) \"type mismatch\")"
+            ))
+        };
+        assert!(select("(i32.const 1) (i64.const 1) (i32.const 1)").is_none());
+        assert!(select("(ref.null func) (ref.null func) (i32.const 1)").is_none());
+        assert!(select("(i32.const 1) (i32.const 1) (i64.const 1)").is_none());
+        assert!(select("(i32.const 1) (i32.const 1)").is_none());
+        assert!(matches!(
+            select("(i64.const 1) (i64.const 2) (i32.const 1)"),
+            Some(ScriptError::NotInvalid(_))
+        ));
+    }
+
+    #[test]
+    fn assert_exhaustion() {
+        // "f" calls itself forever, "g" returns right away
+        let module = r#"(module binary
+            "\00asm\01\00\00\00"
+            "\01\04\01\60\00\00"
+            "\03\03\02\00\00"
+            "\07\09\02\01f\00\00\01g\00\01"
+            "\0a\09\02\04\00\10\00\0b\02\00\0b")"#;
+        let exhaustion = |call: &str, msg: &str| {
+            module_error(&format!("{module} (assert_exhaustion {call} \"{msg}\")"))
+        };
+        assert!(exhaustion("(invoke \"f\")", "call stack exhausted").is_none());
+        assert!(matches!(
+            exhaustion("(invoke \"g\")", "call stack exhausted"),
+            Some(ScriptError::NotTrapped(_))
+        ));
+    }
+
+    #[test]
+    fn assert_return_on_named_modules() {
+        // a module exporting "f", which returns the given byte
+        let module = |name: &str, byte: u8| {
+            format!(
+                r#"(module {name} binary
+                    "\00asm\01\00\00\00"
+                    "\01\05\01\60\00\01\7f"
+                    "\03\02\01\00"
+                    "\07\05\01\01f\00\00"
+                    "\0a\06\01\04\00\41\{byte:02x}\0b")"#
+            )
+        };
+        let modules = format!("{} {}", module("$A", 1), module("", 2));
+        let assert_return =
+            |assertion: &str| module_error(&format!("{modules} (assert_return {assertion})"));
+        assert!(assert_return("(invoke \"f\") (i32.const 2)").is_none());
+        assert!(assert_return("(invoke $A \"f\") (i32.const 1)").is_none());
+        assert!(matches!(
+            assert_return("(invoke $A \"f\") (i32.const 2)"),
+            Some(ScriptError::WrongResults { .. })
+        ));
+        assert!(matches!(
+            assert_return("(invoke $A \"f\")"),
+            Some(ScriptError::WrongResults { .. })
+        ));
+        assert!(matches!(
+            assert_return("(invoke $B \"f\") (i32.const 1)"),
+            Some(ScriptError::UnknownModule(m)) if m == "B"
+        ));
+    }
+
+    #[test]
+    fn inline_and_binary_modules() {
+        assert!(module_error("(module $M (type (func)) (func (type 0) nop))").is_none());
+        assert!(module_error("(module binary \"\\00asm\" \"\\01\\00\\00\\00\")").is_none());
+        assert!(matches!(
+            module_error("(module binary \"\\00asm\")"),
+            Some(ScriptError::Binary(_))
+        ));
     }
-    Ok(())
 }
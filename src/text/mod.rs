@@ -2,23 +2,115 @@ pub mod parser;
 pub mod token;
 pub mod sexpr;
 
+use core::fmt;
+
 pub use token::tokenize_script;
+pub use token::tokenize_script_spanned;
 pub use token::tokenize_script_without_ws;
 
 use crate::repr::Module;
 
 use parser::ParseError;
-use token::TokenizeError;
+use token::{Position, Token, TokenizeError};
 
+/// Why a text module couldn't be read, and where in the source.
 #[derive(Debug)]
-pub enum InputError {
-    Parsing(ParseError),
-    Tokenizing(TokenizeError),
+pub enum TextError {
+    Tokenizing {
+        error: TokenizeError,
+        pos: Position,
+    },
+    /// `pos` is the token the parser stopped at, or `None` at the end of the
+    /// input and for tokens that don't come from source text.
+    Parsing {
+        error: ParseError,
+        pos: Option<Position>,
+    },
+}
+
+impl TextError {
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            TextError::Tokenizing { pos, .. } => Some(*pos),
+            TextError::Parsing { pos, .. } => *pos,
+        }
+    }
+}
+
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(pos) = self.position() {
+            write!(f, "{pos}: ")?;
+        }
+        match self {
+            TextError::Tokenizing { error, .. } => write!(f, "{error}"),
+            TextError::Parsing { error, .. } => write!(f, "{error}"),
+        }
+    }
 }
 
-pub fn parse_module(input: &str) -> Result<Module, InputError> {
-    let tokens = tokenize_script_without_ws(&input).map_err(InputError::Tokenizing)?;
+impl std::error::Error for TextError {}
+
+impl From<ParseError> for TextError {
+    fn from(error: ParseError) -> Self {
+        TextError::Parsing { error, pos: None }
+    }
+}
+
+/// Tokenizes `input` without whitespace and comments, keeping the position of
+/// every token apart.
+pub(super) fn tokenize(input: &str) -> Result<(Vec<Token>, Vec<Position>), TextError> {
+    let tokens = tokenize_script_spanned(input).map_err(|e| TextError::Tokenizing {
+        error: e.item,
+        pos: e.pos,
+    })?;
+    Ok(tokens.into_iter().map(|t| (t.item, t.pos)).unzip())
+}
+
+pub fn parse_module(input: &str) -> Result<Module, TextError> {
+    let (tokens, positions) = tokenize(input)?;
     let mut parser = parser::Parser { tokens: &tokens };
-    let module = parser.module().map_err(InputError::Parsing)?;
-    Ok(module)
+    parser.module().map_err(|error| {
+        let consumed = tokens.len() - parser.tokens.len();
+        TextError::Parsing {
+            error,
+            pos: positions.get(consumed).copied(),
+        }
+    })
+}
+
+/// Parses a `(module ...)` that was already tokenized, without whitespace.
+pub fn parse_module_tokens(tokens: &[token::Token]) -> Result<Module, ParseError> {
+    let mut parser = parser::Parser { tokens };
+    parser.module()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_module, parser::ParseError, token::Position, TextError};
+
+    #[test]
+    fn errors_point_at_the_source() {
+        let err = parse_module("(module\n  (type (func))\n  (func (type 0) i32.frob))")
+            .err()
+            .unwrap();
+        assert!(matches!(
+            &err,
+            TextError::Parsing {
+                error: ParseError::UnknownInstruction(_),
+                pos: Some(Position {
+                    line: 3,
+                    column: 26
+                }),
+            }
+        ));
+        assert_eq!(err.to_string(), "3:26: unknown instruction i32.frob");
+
+        let err = parse_module("(module (memory 1 #))").err().unwrap();
+        assert!(matches!(err, TextError::Tokenizing { pos, .. } if pos.column == 19));
+
+        let err = parse_module("(module (memory 1)").err().unwrap();
+        assert!(matches!(err, TextError::Parsing { pos: None, .. }));
+        assert_eq!(err.to_string(), "unexpected end of input");
+    }
 }
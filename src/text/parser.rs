@@ -1,4 +1,10 @@
-use crate::repr::{Expr, Func, FuncType, Import, ImportDesc, Inst, Locals, Module, ResultType, TypeIdx, ValType};
+use std::collections::BTreeMap;
+
+use crate::repr::{
+    Export, ExportDesc, Expr, Func, FuncIdx, FuncType, Global, GlobalIdx, GlobalType, Import,
+    ImportDesc, Inst, Limits, LocalIdx, Locals, MemArg, MemIdx, MemType, Module, Reftype,
+    ResultType, TableIdx, TableType, TypeIdx, ValType,
+};
 
 use super::token::{TextToken, Token};
 
@@ -6,15 +12,373 @@ pub struct Parser<'t> {
     pub(super) tokens: &'t [Token],
 }
 
-struct IdentifierContext {}
+/// The type of a func or import: an index, or a type spelled out inline that
+/// is looked up among the module's types once all of them are known.
+enum TypeUse {
+    Idx(TypeIdx),
+    Inline(FuncType),
+}
+
+/// The func or import that spelled out its type inline.
+enum TypeSite {
+    Func(usize),
+    Import(usize),
+}
+
+#[derive(Default)]
+struct IdentifierContext {
+    /// the index of every func with an id, collected before the fields are
+    /// parsed since calls may refer to funcs further down
+    funcs: BTreeMap<String, u32>,
+    inline_types: Vec<(TypeSite, FuncType)>,
+}
 
 impl IdentifierContext {
-    fn register_func(&mut self, name: &str) -> ParseResult<()> {
-        // todo
-        Ok(())
+    fn func(&self, name: &str) -> ParseResult<FuncIdx> {
+        let idx = self.funcs.get(name).copied();
+        idx.map(FuncIdx)
+            .ok_or_else(|| ParseError::UnknownFunction(name.to_string()))
+    }
+
+    /// The index of a type use. An inline type gets a placeholder, which
+    /// [`IdentifierContext::resolve_inline_types`] replaces.
+    fn type_idx(&mut self, typeuse: TypeUse, site: TypeSite) -> TypeIdx {
+        match typeuse {
+            TypeUse::Idx(idx) => idx,
+            TypeUse::Inline(typ) => {
+                self.inline_types.push((site, typ));
+                TypeIdx(0)
+            }
+        }
+    }
+
+    /// Points every inline type use at an equal type of the module, which is
+    /// added after the explicit ones if there is none yet.
+    fn resolve_inline_types(self, module: &mut Module) {
+        for (site, typ) in self.inline_types {
+            let idx = match module.types.iter().position(|t| *t == typ) {
+                Some(idx) => idx,
+                None => {
+                    module.types.push(typ);
+                    module.types.len() - 1
+                }
+            };
+            let idx = TypeIdx(idx as u32);
+            match site {
+                TypeSite::Func(i) => module.funcs[i].typ = idx,
+                TypeSite::Import(i) => module.imports[i].desc = ImportDesc::Func(idx),
+            }
+        }
     }
 }
 
+/// The index of every func with an id among the fields that `tokens` start
+/// with, up to the `)` closing the module. Defined and imported funcs share
+/// an index space, in the order they appear.
+fn func_ids(tokens: &[Token]) -> BTreeMap<String, u32> {
+    let mut ids = BTreeMap::new();
+    let mut next = 0;
+    let mut depth = 0;
+    let mut field = "";
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::LeftParen => depth += 1,
+            Token::RightParen if depth == 0 => break,
+            Token::RightParen => {
+                depth -= 1;
+                continue;
+            }
+            _ => continue,
+        }
+        let Some(Token::Atom(head)) = tokens.get(i + 1) else { continue };
+        if depth == 1 {
+            field = head;
+        }
+        let is_func = head == "func" && (depth == 1 || depth == 2 && field == "import");
+        if !is_func {
+            continue;
+        }
+        if let Some(Token::Name(id)) = tokens.get(i + 2) {
+            ids.insert(id.clone(), next);
+        }
+        next += 1;
+    }
+    ids
+}
+
+/// The `(export "name")*` and `(import "module" "name")?` abbreviations that may
+/// follow the id of a func, memory or global.
+struct InlineDecls {
+    exports: Vec<String>,
+    import: Option<(String, String)>,
+}
+
+impl InlineDecls {
+    /// Adds the exports to the module, all referring to the same item.
+    fn export(self, module: &mut Module, desc: ExportDesc) {
+        for name in self.exports {
+            module.exports.push(Export { name, desc });
+        }
+    }
+}
+
+/// The index the next func, memory or global gets: imports come first in each
+/// index space, so that's the imported items of the kind plus the defined ones.
+fn next_index(module: &Module, defined: usize, is_kind: fn(&ImportDesc) -> bool) -> u32 {
+    let imported = module.imports.iter().filter(|i| is_kind(&i.desc)).count();
+    (imported + defined) as u32
+}
+
+/// The value type named by `atom`.
+fn valtype(atom: &str) -> Option<ValType> {
+    let typ = match atom {
+        "i32" => ValType::I32,
+        "i64" => ValType::I64,
+        "f32" => ValType::F32,
+        "f64" => ValType::F64,
+        "v128" => ValType::V128,
+        "funcref" => ValType::FuncRef,
+        "externref" => ValType::ExternRef,
+        _ => return None,
+    };
+    Some(typ)
+}
+
+/// The reference type named by `atom`, as a table's element type.
+fn reftype(atom: &str) -> Option<Reftype> {
+    let typ = match atom {
+        "funcref" => Reftype::Funcref,
+        "externref" => Reftype::Externref,
+        _ => return None,
+    };
+    Some(typ)
+}
+
+/// The heap type named by `atom`, as in `ref.null func`. These are not value
+/// types on their own.
+fn heaptype(atom: &str) -> Option<Reftype> {
+    let typ = match atom {
+        "func" => Reftype::Funcref,
+        "extern" => Reftype::Externref,
+        _ => return None,
+    };
+    Some(typ)
+}
+
+/// The instruction named by `atom`, for instructions without immediates.
+fn plain_instr(atom: &str) -> Option<Inst> {
+    let inst = match atom {
+        "unreachable" => Inst::Unreachable,
+        "nop" => Inst::Nop,
+        "return" => Inst::Return,
+        "drop" => Inst::Drop,
+        "select" => Inst::Select,
+        "ref.is_null" => Inst::RefIsNull,
+        "i32.eqz" => Inst::I32Eqz,
+        "i32.eq" => Inst::I32Eq,
+        "i32.ne" => Inst::I32Ne,
+        "i32.ge_s" => Inst::I32GeS,
+        "i32.lt_s" => Inst::I32LtS,
+        "i32.lt_u" => Inst::I32LtU,
+        "i32.le_u" => Inst::I32LeU,
+        "i32.gt_s" => Inst::I32GtS,
+        "i32.gt_u" => Inst::I32GtU,
+        "i32.le_s" => Inst::I32LeS,
+        "i32.ge_u" => Inst::I32GeU,
+        "i64.eqz" => Inst::I64Eqz,
+        "i64.eq" => Inst::I64Eq,
+        "i64.ne" => Inst::I64Ne,
+        "i64.lt_s" => Inst::I64LtS,
+        "i64.lt_u" => Inst::I64LtU,
+        "i64.gt_s" => Inst::I64GtS,
+        "i64.gt_u" => Inst::I64GtU,
+        "i64.le_s" => Inst::I64LeS,
+        "i64.le_u" => Inst::I64LeU,
+        "i64.ge_s" => Inst::I64GeS,
+        "i64.ge_u" => Inst::I64GeU,
+        "f32.eq" => Inst::F32Eq,
+        "f32.ne" => Inst::F32Ne,
+        "f32.lt" => Inst::F32Lt,
+        "f32.gt" => Inst::F32Gt,
+        "f32.le" => Inst::F32Le,
+        "f32.ge" => Inst::F32Ge,
+        "f64.eq" => Inst::F64Eq,
+        "f64.ne" => Inst::F64Ne,
+        "f64.le" => Inst::F64Le,
+        "f64.ge" => Inst::F64Ge,
+        "f64.lt" => Inst::F64Lt,
+        "f64.gt" => Inst::F64Gt,
+        "i32.clz" => Inst::I32Clz,
+        "i32.ctz" => Inst::I32Ctz,
+        "i32.add" => Inst::I32Add,
+        "i32.sub" => Inst::I32Sub,
+        "i32.mul" => Inst::I32Mul,
+        "i32.and" => Inst::I32And,
+        "i32.or" => Inst::I32Or,
+        "i32.xor" => Inst::I32Xor,
+        "i32.div_s" => Inst::I32DivS,
+        "i32.div_u" => Inst::I32DivU,
+        "i32.rem_s" => Inst::I32RemS,
+        "i32.rem_u" => Inst::I32RemU,
+        "i32.shr_s" => Inst::I32ShrS,
+        "i32.shr_u" => Inst::I32ShrU,
+        "i32.rotl" => Inst::I32Rotl,
+        "i32.popcnt" => Inst::I32Popcnt,
+        "i32.shl" => Inst::I32Shl,
+        "i32.rotr" => Inst::I32Rotr,
+        "i64.clz" => Inst::I64Clz,
+        "i64.ctz" => Inst::I64Ctz,
+        "i64.popcnt" => Inst::I64Popcnt,
+        "i64.mul" => Inst::I64Mul,
+        "i64.add" => Inst::I64Add,
+        "i64.sub" => Inst::I64Sub,
+        "i64.div_s" => Inst::I64DivS,
+        "i64.div_u" => Inst::I64DivU,
+        "i64.rem_s" => Inst::I64RemS,
+        "i64.rem_u" => Inst::I64RemU,
+        "i64.or" => Inst::I64Or,
+        "i64.shr_s" => Inst::I64ShrS,
+        "i64.shr_u" => Inst::I64ShrU,
+        "i64.rotl" => Inst::I64Rotl,
+        "i64.rotr" => Inst::I64Rotr,
+        "i64.xor" => Inst::I64Xor,
+        "i64.shl" => Inst::I64Shl,
+        "i64.and" => Inst::I64And,
+        "f32.abs" => Inst::F32Abs,
+        "f32.neg" => Inst::F32Neg,
+        "f32.ceil" => Inst::F32Ceil,
+        "f32.floor" => Inst::F32Floor,
+        "f32.trunc" => Inst::F32Trunc,
+        "f32.nearest" => Inst::F32Nearest,
+        "f32.sqrt" => Inst::F32Sqrt,
+        "f32.add" => Inst::F32Add,
+        "f32.sub" => Inst::F32Sub,
+        "f32.mul" => Inst::F32Mul,
+        "f32.div" => Inst::F32Div,
+        "f32.min" => Inst::F32Min,
+        "f32.max" => Inst::F32Max,
+        "f32.copysign" => Inst::F32Copysign,
+        "f64.add" => Inst::F64Add,
+        "f64.sub" => Inst::F64Sub,
+        "f64.mul" => Inst::F64Mul,
+        "f64.abs" => Inst::F64Abs,
+        "f64.neg" => Inst::F64Neg,
+        "f64.div" => Inst::F64Div,
+        "f64.min" => Inst::F64Min,
+        "f64.max" => Inst::F64Max,
+        "f64.ceil" => Inst::F64Ceil,
+        "f64.floor" => Inst::F64Floor,
+        "f64.trunc" => Inst::F64Trunc,
+        "f64.nearest" => Inst::F64Nearest,
+        "f64.sqrt" => Inst::F64Sqrt,
+        "f64.copysign" => Inst::F64Copysign,
+        "i32.wrap_i64" => Inst::I32WrapI64,
+        "i32.trunc_f32_s" => Inst::I32TruncF32S,
+        "i32.trunc_f32_u" => Inst::I32TruncF32U,
+        "i32.trunc_f64_s" => Inst::I32TruncF64S,
+        "i32.trunc_f64_u" => Inst::I32TruncF64U,
+        "i64.extend_i32_s" => Inst::I64ExtendI32S,
+        "i64.extend_i32_u" => Inst::I64ExtendI32U,
+        "i64.trunc_f32_s" => Inst::I64TruncF32S,
+        "i64.trunc_f32_u" => Inst::I64TruncF32U,
+        "i64.trunc_f64_s" => Inst::I64TruncF64S,
+        "i64.trunc_f64_u" => Inst::I64TruncF64U,
+        "f32.convert_i32_s" => Inst::F32ConvertI32S,
+        "f32.convert_i32_u" => Inst::F32ConvertI32U,
+        "f32.convert_i64_s" => Inst::F32ConvertI64S,
+        "f32.convert_i64_u" => Inst::F32ConvertI64U,
+        "f32.demote_f64" => Inst::F32DemoteF64,
+        "f64.convert_i32_s" => Inst::F64ConvertI32S,
+        "f64.convert_i32_u" => Inst::F64ConvertI32U,
+        "f64.convert_i64_s" => Inst::F64ConvertI64S,
+        "f64.convert_i64_u" => Inst::F64ConvertI64U,
+        "f64.promote_f32" => Inst::F64PromoteF32,
+        "i32.reinterpret_f32" => Inst::I32ReinterpretF32,
+        "i64.reinterpret_f64" => Inst::I64ReinterpretF64,
+        "f32.reinterpret_i32" => Inst::F32ReinterpretI32,
+        "f64.reinterpret_i64" => Inst::F64ReinterpretI64,
+        "i32.extend8_s" => Inst::I32Extend8S,
+        "i32.extend16_s" => Inst::I32Extend16S,
+        "i64.extend8_s" => Inst::I64Extend8S,
+        "i64.extend16_s" => Inst::I64Extend16S,
+        "i64.extend32_s" => Inst::I64Extend32S,
+        _ => return None,
+    };
+    Some(inst)
+}
+
+/// Reinterprets an unsigned literal as a `bits` wide integer, so unsigned
+/// literals can use the whole range: `0xffffffff` is `-1` as an i32.
+pub(crate) fn unsigned_immediate(n: usize, bits: u32) -> ParseResult<i64> {
+    let n = n as u64;
+    if bits < 64 && n >> bits != 0 {
+        return Err(ParseError::ConstantOutOfRange);
+    }
+    let unused = 64 - bits;
+    Ok(((n << unused) as i64) >> unused)
+}
+
+/// Checks that a signed literal fits a `bits` wide integer.
+pub(crate) fn signed_immediate(i: isize, bits: u32) -> ParseResult<i64> {
+    let i = i as i64;
+    let unused = 64 - bits;
+    if (i << unused) >> unused != i {
+        return Err(ParseError::ConstantOutOfRange);
+    }
+    Ok(i)
+}
+
+/// Builds a load or store from its memarg.
+type MemoryInstr = fn(MemArg) -> Inst;
+
+/// The memory instruction named by `atom`.
+fn memory_instr(atom: &str) -> Option<MemoryInstr> {
+    let instr: MemoryInstr = match atom {
+        "i32.load" => Inst::I32Load,
+        "i64.load" => Inst::I64Load,
+        "f32.load" => Inst::F32Load,
+        "f64.load" => Inst::F64Load,
+        "i32.load8_s" => Inst::I32Load8S,
+        "i32.load8_u" => Inst::I32Load8U,
+        "i32.load16_s" => Inst::I32Load16S,
+        "i32.load16_u" => Inst::I32Load16U,
+        "i64.load8_s" => Inst::I64Load8S,
+        "i64.load8_u" => Inst::I64Load8U,
+        "i64.load16_s" => Inst::I64Load16S,
+        "i64.load16_u" => Inst::I64Load16U,
+        "i64.load32_s" => Inst::I64Load32S,
+        "i64.load32_u" => Inst::I64Load32U,
+        "i32.store" => Inst::I32Store,
+        "i64.store" => Inst::I64Store,
+        "f32.store" => Inst::F32Store,
+        "f64.store" => Inst::F64Store,
+        "i32.store8" => Inst::I32Store8,
+        "i32.store16" => Inst::I32Store16,
+        "i64.store8" => Inst::I64Store8,
+        "i64.store16" => Inst::I64Store16,
+        "i64.store32" => Inst::I64Store32,
+        _ => return None,
+    };
+    Some(instr)
+}
+
+/// Builds a load or store from its `offset=` and `align=` immediates. Text
+/// gives the alignment in bytes, the instruction keeps its exponent, and
+/// leaving it out means the natural alignment of the access.
+fn memory_inst(instr: MemoryInstr, offset: Option<u64>, align: Option<u64>) -> ParseResult<Inst> {
+    let mut memarg = MemArg {
+        align: 0,
+        offset: u32::try_from(offset.unwrap_or(0)).map_err(|_| ParseError::FailedExpectedToken)?,
+        memory: MemIdx(0),
+    };
+    memarg.align = match align {
+        Some(bytes) if bytes.is_power_of_two() => bytes.trailing_zeros(),
+        Some(bytes) => return Err(ParseError::InvalidAlignment(bytes)),
+        None => instr(memarg).natural_alignment().unwrap_or(0),
+    };
+    Ok(instr(memarg))
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum ParseContext {
     FuncType,
@@ -34,6 +398,15 @@ pub enum ParseError {
     Context(ParseContext, Box<ParseError>),
     InvalidUtf8,
     UnexpectedImport,
+    UnknownInstruction(String),
+    /// an `align=` that isn't a power of two
+    InvalidAlignment(u64),
+    /// an integer literal that doesn't fit its type
+    ConstantOutOfRange,
+    /// a `$id` that no func has
+    UnknownFunction(String),
+    /// a module field the parser can't read yet, like `elem`
+    UnsupportedModulefield(String),
 }
 
 impl ParseError {
@@ -42,6 +415,30 @@ impl ParseError {
     }
 }
 
+impl core::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParseError::FailedExpectedToken => write!(f, "unexpected token"),
+            ParseError::UnexpectedEot => write!(f, "unexpected end of input"),
+            ParseError::InvalidModulefield(field) => write!(f, "unknown module field {field}"),
+            ParseError::ExpectedLparen => write!(f, "expected ("),
+            ParseError::ExpectedRparen => write!(f, "expected )"),
+            ParseError::Context(ctx, inner) => write!(f, "{inner} in {ctx:?}"),
+            ParseError::InvalidUtf8 => write!(f, "malformed UTF-8 encoding"),
+            ParseError::UnexpectedImport => write!(f, "unknown import kind"),
+            ParseError::UnknownInstruction(instr) => write!(f, "unknown instruction {instr}"),
+            ParseError::InvalidAlignment(align) => {
+                write!(f, "alignment {align} is not a power of two")
+            }
+            ParseError::ConstantOutOfRange => write!(f, "constant out of range"),
+            ParseError::UnknownFunction(id) => write!(f, "unknown function ${id}"),
+            ParseError::UnsupportedModulefield(field) => {
+                write!(f, "module field {field} is not supported")
+            }
+        }
+    }
+}
+
 pub type ParseResult<T> = Result<T, ParseError>;
 
 impl<'t> Parser<'t> {
@@ -63,22 +460,19 @@ impl<'t> Parser<'t> {
     }
 
     fn accept_any_decl(&mut self) -> Option<&'t str> {
-        let (prefix, rest) = self.tokens.split_at(2);
-        let [Token::LeftParen, Token::Atom(decl)] = prefix else { return None };
+        let Some(([Token::LeftParen, Token::Atom(decl)], rest)) = self.tokens.split_at_checked(2) else { return None };
         self.tokens = rest;
         Some(decl.as_ref())
     }
 
     fn expect_any_decl(&mut self) -> ParseResult<&'t str> {
-        let (prefix, rest) = self.tokens.split_at(2);
-        let [Token::LeftParen, Token::Atom(decl)] = prefix else { return Err(ParseError::FailedExpectedToken) };
+        let Some(([Token::LeftParen, Token::Atom(decl)], rest)) = self.tokens.split_at_checked(2) else { return Err(ParseError::FailedExpectedToken) };
         self.tokens = rest;
         Ok(decl.as_ref())
     }
 
     fn accept_decl(&mut self, expected: &str) -> Option<()> {
-        let (prefix, rest) = self.tokens.split_at(2);
-        let [Token::LeftParen, Token::Atom(decl)] = prefix else { return None };
+        let Some(([Token::LeftParen, Token::Atom(decl)], rest)) = self.tokens.split_at_checked(2) else { return None };
         if expected != decl {
             return None;
         }
@@ -87,8 +481,7 @@ impl<'t> Parser<'t> {
     }
 
     fn expect_decl(&mut self, expected: &str) -> ParseResult<()> {
-        let (prefix, rest) = self.tokens.split_at(2);
-        let [Token::LeftParen, Token::Atom(decl)] = prefix else { return Err(ParseError::FailedExpectedToken) };
+        let Some(([Token::LeftParen, Token::Atom(decl)], rest)) = self.tokens.split_at_checked(2) else { return Err(ParseError::FailedExpectedToken) };
         if decl != expected {
             return Err(ParseError::FailedExpectedToken);
         }
@@ -99,7 +492,6 @@ impl<'t> Parser<'t> {
     fn accept_next_token(&mut self) -> Option<&'t Token> {
         let (t, rest) = self.tokens.split_first()?;
         self.tokens = rest;
-        println!("token: {:?}", t);
         Some(t)
     }
 
@@ -129,7 +521,6 @@ impl<'t> Parser<'t> {
         let (t, rest) = self.tokens.split_first()?;
         if f(t) {
             self.tokens = rest;
-            println!("token: {:?}", t);
             Some(t)
         } else {
             None
@@ -160,40 +551,24 @@ impl<'t> Parser<'t> {
     }
 
     fn accept_valtype(&mut self) -> Option<ValType> {
-        if self.accept_atom("i32") {
-            return Some(ValType::I32);
-        }
-        if self.accept_atom("i64") {
-            return Some(ValType::I64);
-        }
-        if self.accept_atom("f32") {
-            return Some(ValType::F32);
-        }
-        if self.accept_atom("f64") {
-            return Some(ValType::F64);
-        }
-        if self.accept_atom("v128") {
-            return Some(ValType::V128);
-        }
-        if self.accept_atom("funcref") {
-            return Some(ValType::FuncRef);
-        }
-        if self.accept_atom("externref") {
-            return Some(ValType::ExternRef);
-        }
-        if self.accept_atom("func") {
-            return Some(ValType::FuncRef);
-        }
-        if self.accept_atom("extern") {
-            return Some(ValType::ExternRef);
-        }
-        None
+        let Some(Token::Atom(atom)) = self.peek_token() else { return None };
+        let typ = valtype(atom)?;
+        self.accept_next_token();
+        Some(typ)
     }
 
     fn expect_valtype(&mut self) -> ParseResult<ValType> {
         self.accept_valtype().ok_or(ParseError::FailedExpectedToken)
     }
 
+    /// Parses an atom that `parse` accepts, like a reftype.
+    fn expect_atom_as<T>(&mut self, parse: fn(&str) -> Option<T>) -> ParseResult<T> {
+        let Some(Token::Atom(atom)) = self.peek_token() else { return Err(ParseError::FailedExpectedToken) };
+        let parsed = parse(atom).ok_or(ParseError::FailedExpectedToken)?;
+        self.accept_next_token();
+        Ok(parsed)
+    }
+
     fn expect_lparen(&mut self) -> ParseResult<()> {
         if self.accept_lparen() {
             Ok(())
@@ -277,12 +652,10 @@ impl<'t> Parser<'t> {
         })
     }
 
-    fn expect_type(&mut self, ctx: &mut IdentifierContext) -> ParseResult<FuncType> {
+    fn expect_type(&mut self) -> ParseResult<FuncType> {
         self.expect_lparen()?;
         self.expect_atom("type")?;
-        if let Some(name) = self.accept_name() {
-            ctx.register_func(name)?;
-        }
+        let _id = self.accept_name();
         let ft = self
             .expect_functype()
             .map_err(|e| e.context(ParseContext::FuncType))?;
@@ -317,17 +690,17 @@ impl<'t> Parser<'t> {
     fn expect_params(&mut self) -> ParseResult<Vec<ValType>> {
         self.expect_lparen()?;
         self.expect_atom("param")?;
-        let id = self.accept_name();
-        let valtype = self.expect_valtype()?;
-        let mut params = vec![valtype];
-        if id.is_none() {
+        // a param with an id declares exactly one
+        if self.accept_name().is_some() {
+            let valtype = self.expect_valtype()?;
             self.expect_rparen()?;
-            return Ok(params);
+            return Ok(vec![valtype]);
         }
+        let mut params = vec![];
         while !self.accept_rparen() {
             params.push(self.expect_valtype()?);
         }
-        return Ok(params);
+        Ok(params)
     }
 
     fn expect_results(&mut self) -> ParseResult<Vec<ValType>> {
@@ -340,47 +713,156 @@ impl<'t> Parser<'t> {
         Ok(results)
     }
 
-    fn expect_typeuse(&mut self) -> ParseResult<TypeIdx> {
-        self.expect_decl("type")?;
-        let typidx = self.expect_typeidx()?;
-        self.expect_rparen()?;
-
+    // typeuse = (type idx)? (param ...)* (result ...)*
+    fn expect_typeuse(&mut self) -> ParseResult<TypeUse> {
+        let mut typidx = None;
+        if self.accept_decl("type").is_some() {
+            typidx = Some(self.expect_typeidx()?);
+            self.expect_rparen()?;
+        }
+        let mut from = vec![];
         while let Ok("param") = self.peek_decl() {
-            let params = self.expect_params()?;
+            from.extend(self.expect_params()?);
         }
-
+        let mut to = vec![];
         while let Ok("result") = self.peek_decl() {
-            let results = self.expect_results()?;
+            to.extend(self.expect_results()?);
         }
-        Ok(typidx)
+        // the params and results aren't checked against a named type yet
+        Ok(match typidx {
+            Some(idx) => TypeUse::Idx(idx),
+            None => TypeUse::Inline(FuncType {
+                from: ResultType { types: from },
+                to: ResultType { types: to },
+            }),
+        })
     }
 
-    fn expect_importdesc_func(&mut self, ctx: &mut IdentifierContext) -> ParseResult<ImportDesc> {
+    fn expect_funcidx(&mut self, ctx: &IdentifierContext) -> ParseResult<FuncIdx> {
+        if let Some(id) = self.accept_name() {
+            return ctx.func(id);
+        }
+        Ok(FuncIdx(self.expect_nat()? as u32))
+    }
+
+    fn expect_importdesc_func(
+        &mut self,
+        ctx: &mut IdentifierContext,
+        module: &Module,
+    ) -> ParseResult<ImportDesc> {
         self.expect_lparen()?;
         self.expect_atom("func")?;
-        let id = self.accept_name();
-        let typ = self.expect_typeuse()?;
+        let _id = self.accept_name();
+        let typeuse = self.expect_typeuse()?;
         self.expect_rparen()?;
+        let typ = ctx.type_idx(typeuse, TypeSite::Import(module.imports.len()));
         Ok(ImportDesc::Func(typ))
     }
 
-    fn expect_importdesc(&mut self, ctx: &mut IdentifierContext) -> ParseResult<ImportDesc> {
+    fn expect_nat(&mut self) -> ParseResult<u64> {
+        match self.accept_next_token().ok_or(ParseError::UnexpectedEot)? {
+            Token::Nat(n) => Ok(*n as u64),
+            _ => Err(ParseError::FailedExpectedToken),
+        }
+    }
+
+    /// Parses the immediate of `i32.const` or `i64.const`, which may be
+    /// signed or unsigned.
+    fn expect_int(&mut self, bits: u32) -> ParseResult<i64> {
+        match self.accept_next_token().ok_or(ParseError::UnexpectedEot)? {
+            Token::Nat(n) => unsigned_immediate(*n, bits),
+            Token::Int(i) => signed_immediate(*i, bits),
+            _ => Err(ParseError::FailedExpectedToken),
+        }
+    }
+
+    fn expect_limits(&mut self) -> ParseResult<Limits> {
+        let min = self.expect_nat()?;
+        let max = match self.peek_token() {
+            Some(Token::Nat(_)) => Some(self.expect_nat()?),
+            _ => None,
+        };
+        Ok(Limits {
+            min,
+            max,
+            is_64: false,
+            shared: false,
+        })
+    }
+
+    // tabletype = limits reftype
+    fn expect_tabletype(&mut self) -> ParseResult<TableType> {
+        let limits = self.expect_limits()?;
+        let reftype = self.expect_atom_as(reftype)?;
+        Ok(TableType { reftype, limits })
+    }
+
+    // globaltype = valtype | (mut valtype)
+    fn expect_globaltype(&mut self) -> ParseResult<GlobalType> {
+        if self.accept_decl("mut").is_some() {
+            let valtype = self.expect_valtype()?;
+            self.expect_rparen()?;
+            return Ok(GlobalType {
+                valtype,
+                mutable: true,
+            });
+        }
+        let valtype = self.expect_valtype()?;
+        Ok(GlobalType {
+            valtype,
+            mutable: false,
+        })
+    }
+
+    fn expect_importdesc_memory(&mut self) -> ParseResult<ImportDesc> {
+        self.expect_decl("memory")?;
+        let _id = self.accept_name();
+        let limits = self.expect_limits()?;
+        self.expect_rparen()?;
+        Ok(ImportDesc::Mem(MemType { limits }))
+    }
+
+    fn expect_importdesc_table(&mut self) -> ParseResult<ImportDesc> {
+        self.expect_decl("table")?;
+        let _id = self.accept_name();
+        let typ = self.expect_tabletype()?;
+        self.expect_rparen()?;
+        Ok(ImportDesc::Table(typ))
+    }
+
+    fn expect_importdesc_global(&mut self) -> ParseResult<ImportDesc> {
+        self.expect_decl("global")?;
+        let _id = self.accept_name();
+        let typ = self.expect_globaltype()?;
+        self.expect_rparen()?;
+        Ok(ImportDesc::Global(typ))
+    }
+
+    fn expect_importdesc(
+        &mut self,
+        ctx: &mut IdentifierContext,
+        module: &Module,
+    ) -> ParseResult<ImportDesc> {
         let decl = self.peek_decl()?;
         match decl {
-            "func" => self.expect_importdesc_func(ctx),
-            "table" => todo!("import table"),
-            "memory" => todo!("import memory"),
-            "global" => todo!("import global"),
+            "func" => self.expect_importdesc_func(ctx, module),
+            "table" => self.expect_importdesc_table(),
+            "memory" => self.expect_importdesc_memory(),
+            "global" => self.expect_importdesc_global(),
             _ => return Err(ParseError::UnexpectedImport),
         }
     }
 
-    fn expect_import(&mut self, ctx: &mut IdentifierContext) -> ParseResult<Import> {
+    fn expect_import(
+        &mut self,
+        ctx: &mut IdentifierContext,
+        module: &Module,
+    ) -> ParseResult<Import> {
         self.expect_lparen()?;
         self.expect_atom("import")?;
         let modname = self.expect_name()?;
         let nm = self.expect_name()?;
-        let desc = self.expect_importdesc(ctx)?;
+        let desc = self.expect_importdesc(ctx, module)?;
         self.expect_rparen()?;
         Ok(Import {
             module: modname,
@@ -389,92 +871,527 @@ impl<'t> Parser<'t> {
         })
     }
 
-    fn accept_local(&mut self) -> ParseResult<Option<Locals>> {
+    fn accept_local(&mut self) -> ParseResult<Option<Vec<Locals>>> {
         let Ok("local") = self.peek_decl() else { return Ok(None) };
         self.expect_lparen()?;
         self.expect_atom("local")?;
-        let id = self.accept_name();
-        assert!(id.is_none());
-        let valtype = self.expect_valtype()?;
-        self.expect_rparen()?;
-        Ok(Some(Locals { n: 1, t: valtype }))
+        // like a param, a local with an id declares exactly one
+        if self.accept_name().is_some() {
+            let t = self.expect_valtype()?;
+            self.expect_rparen()?;
+            return Ok(Some(vec![Locals { n: 1, t }]));
+        }
+        let mut locals = vec![];
+        while !self.accept_rparen() {
+            locals.push(Locals {
+                n: 1,
+                t: self.expect_valtype()?,
+            });
+        }
+        Ok(Some(locals))
     }
 
     fn expect_locals(&mut self) -> ParseResult<Vec<Locals>> {
         let mut locals = vec![];
         while let Some(local) = self.accept_local()? {
-            locals.push(local);
+            locals.extend(local);
         }
         Ok(locals)
     }
 
-    fn accept_instr(&mut self) -> ParseResult<Option<Inst>> {
+    /// Parses a `key=nat` immediate, like the `offset=8` of a load.
+    fn accept_keyword_nat(&mut self, key: &str) -> ParseResult<Option<u64>> {
+        let [Token::Atom(atom), Token::Equal, rest @ ..] = self.tokens else { return Ok(None) };
+        if atom != key {
+            return Ok(None);
+        }
+        self.tokens = rest;
+        Ok(Some(self.expect_nat()?))
+    }
+
+    fn accept_instr(&mut self, ctx: &IdentifierContext) -> ParseResult<Option<Inst>> {
         let Some(atom) = self.accept_any_atom() else { return Ok(None) };
+        if let Some(instr) = memory_instr(atom) {
+            let offset = self.accept_keyword_nat("offset")?;
+            let align = self.accept_keyword_nat("align")?;
+            return Ok(Some(memory_inst(instr, offset, align)?));
+        }
+        match atom {
+            "i32.const" => return Ok(Some(Inst::I32Const(self.expect_int(32)? as i32))),
+            "i64.const" => return Ok(Some(Inst::I64Const(self.expect_int(64)?))),
+            _ => {}
+        }
         let inst = match atom {
-            "unreachable" => Inst::Unreachable,
-            "nop" => Inst::Nop,
-            x => todo!("unimplemented instr: {:?}", x),
+            "ref.null" => Inst::RefNull(self.expect_atom_as(heaptype)?),
+            "ref.func" => Inst::RefFunc(self.expect_funcidx(ctx)?),
+            "call" => Inst::Call(self.expect_funcidx(ctx)?),
+            "local.get" => Inst::LocalGet(LocalIdx(self.expect_nat()? as u32)),
+            "local.set" => Inst::LocalSet(LocalIdx(self.expect_nat()? as u32)),
+            "local.tee" => Inst::LocalTee(LocalIdx(self.expect_nat()? as u32)),
+            "global.get" => Inst::GlobalGet(GlobalIdx(self.expect_nat()? as u32)),
+            "global.set" => Inst::GlobalSet(GlobalIdx(self.expect_nat()? as u32)),
+            "memory.size" => Inst::MemorySize(MemIdx(0)),
+            "memory.grow" => Inst::MemoryGrow(MemIdx(0)),
+            _ => {
+                plain_instr(atom).ok_or_else(|| ParseError::UnknownInstruction(atom.to_string()))?
+            }
         };
         Ok(Some(inst))
     }
-    fn expect_expr(&mut self) -> ParseResult<Vec<Inst>> {
+
+    /// Parses a folded instruction like `(i32.add (local.get 0) (i32.const 1))`,
+    /// which comes after the instructions of its operands.
+    fn accept_folded_instr(
+        &mut self,
+        ctx: &IdentifierContext,
+        instrs: &mut Vec<Inst>,
+    ) -> ParseResult<bool> {
+        let [Token::LeftParen, Token::Atom(_), ..] = self.tokens else { return Ok(false) };
+        self.expect_lparen()?;
+        let inst = self
+            .accept_instr(ctx)?
+            .ok_or(ParseError::FailedExpectedToken)?;
+        instrs.extend(self.expect_expr(ctx)?);
+        self.expect_rparen()?;
+        instrs.push(inst);
+        Ok(true)
+    }
+
+    fn expect_expr(&mut self, ctx: &IdentifierContext) -> ParseResult<Vec<Inst>> {
         let mut instrs = vec![];
-        
-        while let Some(inst) = { println!("expecting inst, peeking: {:?}", self.peek_token()); self.accept_instr()? } {
-            instrs.push(inst);
+        loop {
+            if let Some(inst) = self.accept_instr(ctx)? {
+                instrs.push(inst);
+            } else if !self.accept_folded_instr(ctx, &mut instrs)? {
+                return Ok(instrs);
+            }
         }
-        Ok(instrs)
     }
 
-    fn expect_func(&mut self, ctx: &mut IdentifierContext) -> ParseResult<Func> {
+    fn accept_inline_decls(&mut self) -> ParseResult<InlineDecls> {
+        let mut exports = vec![];
+        while self.accept_decl("export").is_some() {
+            exports.push(self.expect_name()?);
+            self.expect_rparen()?;
+        }
+        let mut import = None;
+        if self.accept_decl("import").is_some() {
+            let modname = self.expect_name()?;
+            let nm = self.expect_name()?;
+            self.expect_rparen()?;
+            import = Some((modname, nm));
+        }
+        Ok(InlineDecls { exports, import })
+    }
+
+    /// Parses a func field, which is either a definition or an inline import,
+    /// and adds it and its inline exports to the module.
+    fn expect_func(&mut self, ctx: &mut IdentifierContext, module: &mut Module) -> ParseResult<()> {
         self.expect_lparen()?;
         self.expect_atom("func")?;
         let id = self.accept_name();
         // todo handle name
-        let typ = self.expect_typeuse()?;
-        let locals = self.expect_locals()?;
-        let expr = self.expect_expr()?;
-        self.expect_rparen().map_err(|e| {
-            e.context(ParseContext::Func)
-        })?;
-        Ok(Func { typ, locals, body: expr })
+        let inline = self.accept_inline_decls()?;
+        let idx = next_index(module, module.funcs.len(), |d| {
+            matches!(d, ImportDesc::Func(_))
+        });
+        let typeuse = self.expect_typeuse()?;
+        if let Some((modname, nm)) = inline.import.clone() {
+            self.expect_rparen()?;
+            let typ = ctx.type_idx(typeuse, TypeSite::Import(module.imports.len()));
+            module.imports.push(Import {
+                module: modname,
+                nm,
+                desc: ImportDesc::Func(typ),
+            });
+        } else {
+            let locals = self.expect_locals()?;
+            let expr = self.expect_expr(ctx)?;
+            self.expect_rparen()
+                .map_err(|e| e.context(ParseContext::Func))?;
+            let typ = ctx.type_idx(typeuse, TypeSite::Func(module.funcs.len()));
+            module.funcs.push(Func {
+                typ,
+                locals,
+                body: expr,
+            });
+        }
+        inline.export(module, ExportDesc::Func(FuncIdx(idx)));
+        Ok(())
+    }
+
+    fn expect_table(&mut self, module: &mut Module) -> ParseResult<()> {
+        self.expect_decl("table")?;
+        let _id = self.accept_name();
+        let inline = self.accept_inline_decls()?;
+        let idx = next_index(module, module.tables.len(), |d| {
+            matches!(d, ImportDesc::Table(_))
+        });
+        let table = self.expect_tabletype()?;
+        self.expect_rparen()?;
+        match inline.import.clone() {
+            Some((modname, nm)) => module.imports.push(Import {
+                module: modname,
+                nm,
+                desc: ImportDesc::Table(table),
+            }),
+            None => module.tables.push(table),
+        }
+        inline.export(module, ExportDesc::Table(TableIdx(idx)));
+        Ok(())
+    }
+
+    fn expect_memory(&mut self, module: &mut Module) -> ParseResult<()> {
+        self.expect_decl("memory")?;
+        let _id = self.accept_name();
+        let inline = self.accept_inline_decls()?;
+        let idx = next_index(module, module.mems.len(), |d| {
+            matches!(d, ImportDesc::Mem(_))
+        });
+        let mem = MemType {
+            limits: self.expect_limits()?,
+        };
+        self.expect_rparen()?;
+        match inline.import.clone() {
+            Some((modname, nm)) => module.imports.push(Import {
+                module: modname,
+                nm,
+                desc: ImportDesc::Mem(mem),
+            }),
+            None => module.mems.push(mem),
+        }
+        inline.export(module, ExportDesc::Mem(MemIdx(idx)));
+        Ok(())
+    }
+
+    fn expect_global(&mut self, ctx: &IdentifierContext, module: &mut Module) -> ParseResult<()> {
+        self.expect_decl("global")?;
+        let _id = self.accept_name();
+        let inline = self.accept_inline_decls()?;
+        let idx = next_index(module, module.globals.len(), |d| {
+            matches!(d, ImportDesc::Global(_))
+        });
+        let typ = self.expect_globaltype()?;
+        match inline.import.clone() {
+            Some((modname, nm)) => module.imports.push(Import {
+                module: modname,
+                nm,
+                desc: ImportDesc::Global(typ),
+            }),
+            None => {
+                let init = self.expect_expr(ctx)?;
+                module.globals.push(Global { typ, init });
+            }
+        }
+        self.expect_rparen()?;
+        inline.export(module, ExportDesc::Global(GlobalIdx(idx)));
+        Ok(())
+    }
+
+    fn expect_export(&mut self, ctx: &IdentifierContext, module: &mut Module) -> ParseResult<()> {
+        self.expect_decl("export")?;
+        let name = self.expect_name()?;
+        let desc = match self.expect_any_decl()? {
+            "func" => ExportDesc::Func(self.expect_funcidx(ctx)?),
+            "table" => ExportDesc::Table(TableIdx(self.expect_nat()? as u32)),
+            "memory" => ExportDesc::Mem(MemIdx(self.expect_nat()? as u32)),
+            "global" => ExportDesc::Global(GlobalIdx(self.expect_nat()? as u32)),
+            _ => return Err(ParseError::FailedExpectedToken),
+        };
+        self.expect_rparen()?;
+        self.expect_rparen()?;
+        module.exports.push(Export { name, desc });
+        Ok(())
     }
 
     pub(super) fn module(&mut self) -> ParseResult<Module> {
         self.expect_lparen()?;
         self.expect_atom("module")?;
+        let _id = self.accept_name();
         let mut module = Module::default();
-        let mut ctx = IdentifierContext {};
+        let mut ctx = IdentifierContext {
+            funcs: func_ids(self.tokens),
+            ..IdentifierContext::default()
+        };
         loop {
             if self.accept_rparen() {
+                ctx.resolve_inline_types(&mut module);
+                module.refs = module.collect_refs();
                 return Ok(module);
             }
             let decl = self.peek_decl()?;
-            println!("decl: {}", &decl);
             match decl {
                 "type" => {
                     let typ = self
-                        .expect_type(&mut ctx)
+                        .expect_type()
                         .map_err(|e| e.context(ParseContext::Type))?;
                     module.types.push(typ);
                 }
                 "import" => {
-                    let import = self.expect_import(&mut ctx)?;
+                    let import = self.expect_import(&mut ctx, &module)?;
                     module.imports.push(import);
                 }
-                "func" => {
-                    let func = self.expect_func(&mut ctx)?;
-                    module.funcs.push(func)
-                },
-                "table" => todo!("table"),
-                "mem" => todo!("mem"),
-                "global" => todo!("global"),
-                "export" => todo!("export"),
-                "start" => todo!("start"),
-                "elem" => todo!("elem"),
-                "data" => todo!("data"),
+                "func" => self.expect_func(&mut ctx, &mut module)?,
+                "table" => self.expect_table(&mut module)?,
+                "memory" => self.expect_memory(&mut module)?,
+                "global" => self.expect_global(&ctx, &mut module)?,
+                "export" => self.expect_export(&ctx, &mut module)?,
+                "start" => {
+                    self.expect_decl("start")?;
+                    module.start = Some(self.expect_funcidx(&ctx)?);
+                    self.expect_rparen()?;
+                }
+                "elem" | "data" => {
+                    return Err(ParseError::UnsupportedModulefield(decl.to_string()))
+                }
                 x => return Err(ParseError::InvalidModulefield(x.to_string())),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ParseError;
+    use crate::repr::{ExportDesc, FuncIdx, ImportDesc, Inst, Reftype, ValType};
+    use crate::text::{parse_module, TextError};
+
+    #[test]
+    fn inline_imports_and_exports() {
+        let module = parse_module(
+            r#"(module
+                (type (func))
+                (func $f (export "f") (import "m" "f") (type 0))
+                (func (export "g") (export "h") (type 0) nop)
+                (memory (export "mem") 1 2)
+                (global (import "m" "g") (mut i32))
+                (global $g (export "g2") (import "m" "h") i64))"#,
+        )
+        .unwrap();
+
+        let imports: Vec<_> = module
+            .imports
+            .iter()
+            .map(|i| (i.module.as_str(), i.nm.as_str()))
+            .collect();
+        assert_eq!(imports, [("m", "f"), ("m", "g"), ("m", "h")]);
+        assert!(matches!(module.imports[0].desc, ImportDesc::Func(_)));
+        assert!(matches!(module.imports[1].desc, ImportDesc::Global(g) if g.mutable));
+        assert!(matches!(module.imports[2].desc, ImportDesc::Global(g) if !g.mutable));
+        assert_eq!(module.funcs.len(), 1);
+        assert_eq!(module.mems[0].limits.max, Some(2));
+
+        let exports: Vec<_> = module
+            .exports
+            .iter()
+            .map(|e| match e.desc {
+                ExportDesc::Func(idx) => (e.name.as_str(), "func", idx.0),
+                ExportDesc::Table(idx) => (e.name.as_str(), "table", idx.0),
+                ExportDesc::Mem(idx) => (e.name.as_str(), "memory", idx.0),
+                ExportDesc::Global(idx) => (e.name.as_str(), "global", idx.0),
+            })
+            .collect();
+        assert_eq!(
+            exports,
+            [
+                ("f", "func", 0),
+                ("g", "func", 1),
+                ("h", "func", 1),
+                ("mem", "memory", 0),
+                ("g2", "global", 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn funcs_spell_out_their_types_and_have_ids() {
+        let module = parse_module(
+            r#"(module
+                (import "env" "log" (func $log (param i32)))
+                (func $main (export "main")
+                    (call $twice (i32.const 3))
+                    drop)
+                (type (func (param i32) (result i32)))
+                (func $twice (param $x i32) (result i32)
+                    (i32.add (local.get 0) (local.get 0)))
+                (func)
+                (export "twice" (func $twice))
+                (start $main))"#,
+        )
+        .unwrap();
+        let types: Vec<_> = module
+            .types
+            .iter()
+            .map(|t| (t.from.types.clone(), t.to.types.clone()))
+            .collect();
+        // inline types come after the explicit one, and equal ones are shared
+        assert_eq!(
+            types,
+            [
+                (vec![ValType::I32], vec![ValType::I32]),
+                (vec![ValType::I32], vec![]),
+                (vec![], vec![]),
+            ]
+        );
+        assert!(matches!(module.imports[0].desc, ImportDesc::Func(t) if t.0 == 1));
+        let funcs: Vec<_> = module.funcs.iter().map(|f| f.typ.0).collect();
+        assert_eq!(funcs, [2, 0, 2]);
+        assert!(matches!(
+            module.funcs[0].body[..],
+            [Inst::I32Const(3), Inst::Call(FuncIdx(2)), Inst::Drop]
+        ));
+        assert!(matches!(
+            module.funcs[1].body[..],
+            [Inst::LocalGet(_), Inst::LocalGet(_), Inst::I32Add]
+        ));
+        let exports: Vec<_> = module
+            .exports
+            .iter()
+            .map(|e| (&e.name[..], e.desc))
+            .collect();
+        assert!(matches!(
+            exports[..],
+            [
+                ("main", ExportDesc::Func(FuncIdx(1))),
+                ("twice", ExportDesc::Func(FuncIdx(2)))
+            ]
+        ));
+        assert!(matches!(module.start, Some(FuncIdx(1))));
+
+        let err = parse_module("(module (func call $nowhere))");
+        assert!(matches!(
+            err,
+            Err(TextError::Parsing {
+                error: ParseError::UnknownFunction(id),
+                ..
+            }) if id == "nowhere"
+        ));
+    }
+
+    #[test]
+    fn locals_and_unsupported_fields() {
+        let module =
+            parse_module("(module (func (local $x i32) (local) (local i64 f32) local.get 0))")
+                .unwrap();
+        let locals: Vec<_> = module.funcs[0].locals.iter().map(|l| l.t).collect();
+        assert_eq!(locals, [ValType::I32, ValType::I64, ValType::F32]);
+
+        assert!(parse_module("(module (func (local $x i32 i64)))").is_err());
+        for field in ["data", "elem"] {
+            let err = parse_module(&format!("(module (memory 1) ({field} (i32.const 0)))"));
+            assert!(matches!(
+                err,
+                Err(TextError::Parsing {
+                    error: ParseError::UnsupportedModulefield(f),
+                    ..
+                }) if f == field
+            ));
+        }
+    }
+
+    #[test]
+    fn memory_instructions_take_offset_and_align() {
+        let module = parse_module(
+            "(module (type (func)) (func (type 0)
+                i32.load i64.load offset=8 f32.store align=1 i64.load16_u offset=0x10 align=2))",
+        )
+        .unwrap();
+        let memargs: Vec<_> = module.funcs[0]
+            .body
+            .iter()
+            .map(|inst| match inst {
+                Inst::I32Load(m) | Inst::I64Load(m) | Inst::F32Store(m) | Inst::I64Load16U(m) => {
+                    (m.offset, m.align)
+                }
+                inst => panic!("unexpected {inst:?}"),
+            })
+            .collect();
+        assert_eq!(memargs, [(0, 2), (8, 3), (0, 0), (16, 1)]);
+
+        let err = parse_module("(module (type (func)) (func (type 0) i32.load align=3))");
+        assert!(matches!(
+            err,
+            Err(TextError::Parsing {
+                error: ParseError::InvalidAlignment(3),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn tables_take_reftypes() {
+        let module = parse_module(
+            r#"(module
+                (import "m" "t" (table 1 funcref))
+                (table (export "t2") 0 2 externref)
+                (type (func))
+                (func (type 0) ref.null extern ref.null func))"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            module.imports[0].desc,
+            ImportDesc::Table(t) if matches!(t.reftype, Reftype::Funcref) && t.limits.min == 1
+        ));
+        assert!(matches!(module.tables[0].reftype, Reftype::Externref));
+        assert_eq!(module.tables[0].limits.max, Some(2));
+        assert!(matches!(module.exports[0].desc, ExportDesc::Table(idx) if idx.0 == 1));
+        assert!(matches!(
+            module.funcs[0].body[..],
+            [
+                Inst::RefNull(Reftype::Externref),
+                Inst::RefNull(Reftype::Funcref)
+            ]
+        ));
+
+        // heap types only appear where the grammar asks for one
+        for input in [
+            "(module (table 1 func))",
+            "(module (type (func (param extern))))",
+            "(module (type (func)) (func (type 0) ref.null funcref))",
+        ] {
+            assert!(parse_module(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn integer_immediates_are_reinterpreted() {
+        let consts = |body: &str| {
+            let module = parse_module(&format!("(module (type (func)) (func (type 0) {body}))"))?;
+            Ok(module.funcs[0].body.clone())
+        };
+        let body: Result<_, TextError> = consts(
+            "i32.const 0xFFFFFFFF i32.const -1 i32.const 2147483647 i32.const -2147483648
+             i32.const 0x8000_0000 i64.const 0xFFFFFFFFFFFFFFFF i64.const -0x8000000000000000",
+        );
+        assert!(matches!(
+            body.unwrap()[..],
+            [
+                Inst::I32Const(-1),
+                Inst::I32Const(-1),
+                Inst::I32Const(i32::MAX),
+                Inst::I32Const(i32::MIN),
+                Inst::I32Const(i32::MIN),
+                Inst::I64Const(-1),
+                Inst::I64Const(i64::MIN),
+            ]
+        ));
+
+        for overflow in [
+            "i32.const 0x100000000",
+            "i32.const +2147483648",
+            "i32.const -2147483649",
+            "i32.const +0xFFFFFFFF",
+        ] {
+            assert!(
+                matches!(
+                    consts(overflow),
+                    Err(TextError::Parsing {
+                        error: ParseError::ConstantOutOfRange,
+                        ..
+                    })
+                ),
+                "{overflow}"
+            );
+        }
+    }
+}
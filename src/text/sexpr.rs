@@ -1,7 +1,14 @@
 use core::fmt;
 use std::iter::Peekable;
 
-use super::{token::{TextToken, Token}, tokenize_script_without_ws, InputError};
+use crate::repr::Module;
+
+use super::{
+    parse_module_tokens,
+    parser::ParseError,
+    token::{NanLiteral, TextToken, Token},
+    tokenize, TextError,
+};
 
 #[derive(Clone, PartialEq)]
 pub enum Sexpr {
@@ -11,6 +18,7 @@ pub enum Sexpr {
     Nat(usize),
     Int(isize),
     Float(f64),
+    Nan(NanLiteral),
     Equal,
     List(Vec<Sexpr>)
 }
@@ -24,22 +32,53 @@ impl fmt::Debug for Sexpr {
             Self::Nat(arg0) => write!(f, "{}", arg0),
             Self::Int(arg0) => write!(f, "{}", arg0),
             Self::Float(arg0) => write!(f, "{}", arg0),
+            Self::Nan(arg0) => write!(f, "{:?}", arg0),
             Self::Equal => write!(f, "Equal"),
             Self::List(arg0) => write!(f, "{:#?}", arg0),
         }
     }
 }
 
-pub fn parse_module_to_sexpr(input: &str) -> Result<Sexpr, InputError> {
-    let tokens = tokenize_script_without_ws(&input).map_err(InputError::Tokenizing)?;
+pub fn parse_module_to_sexpr(input: &str) -> Result<Sexpr, TextError> {
+    let (tokens, _) = tokenize(input)?;
     let mut tokens_iter = tokens.into_iter().peekable();
-    let sexpr = tokens_to_sexpr(&mut tokens_iter).unwrap();
+    let sexpr = tokens_to_sexpr(&mut tokens_iter).ok_or(ParseError::UnexpectedEot)?;
     Ok(sexpr)
 }
 
+/// Builds a module from a `(module ...)` tree. The recursive descent parser
+/// is the canonical way to read modules, so the tree is turned back into
+/// tokens for it rather than walked by a second parser.
+pub fn module_from_sexpr(sexpr: &Sexpr) -> Result<Module, ParseError> {
+    let mut tokens = vec![];
+    sexpr_to_tokens(sexpr, &mut tokens);
+    parse_module_tokens(&tokens)
+}
+
+fn sexpr_to_tokens(sexpr: &Sexpr, tokens: &mut Vec<Token>) {
+    let token = match sexpr {
+        Sexpr::Atom(a) => Token::Atom(a.clone()),
+        Sexpr::Name(n) => Token::Name(n.clone()),
+        Sexpr::Text(t) => Token::Text(t.clone()),
+        Sexpr::Nat(n) => Token::Nat(*n),
+        Sexpr::Int(i) => Token::Int(*i),
+        Sexpr::Float(f) => Token::Float(*f),
+        Sexpr::Nan(n) => Token::Nan(*n),
+        Sexpr::Equal => Token::Equal,
+        Sexpr::List(exprs) => {
+            tokens.push(Token::LeftParen);
+            for expr in exprs {
+                sexpr_to_tokens(expr, tokens);
+            }
+            Token::RightParen
+        }
+    };
+    tokens.push(token);
+}
 
-fn tokens_to_sexpr<I>(tokens: &mut Peekable<I>) -> Option<Sexpr>
-where I: Iterator<Item=Token>
+pub(crate) fn tokens_to_sexpr<I>(tokens: &mut Peekable<I>) -> Option<Sexpr>
+where
+    I: Iterator<Item = Token>,
 {
     let t = tokens.next()?;
     let expr = match t {
@@ -63,9 +102,49 @@ where I: Iterator<Item=Token>
         Token::Nat(n) => Sexpr::Nat(n),
         Token::Int(i) => Sexpr::Int(i),
         Token::Float(f) => Sexpr::Float(f),
+        Token::Nan(n) => Sexpr::Nan(n),
         Token::Equal => Sexpr::Equal,
         Token::Comment(_) => return None,
         Token::Whitespace => return None,
     };
     return Some(expr)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{module_from_sexpr, parse_module_to_sexpr, Module, ParseError, Sexpr};
+    use crate::text::parse_module;
+
+    #[test]
+    fn modules_from_trees() {
+        let input = r#"(module
+            (type (func (param i32) (result i32)))
+            (func $f (export "f") (type 0) (local $x i32)
+                (i32.add (local.get 0) (i32.const 1)))
+            (func (export "g") (type 0) (call $f (local.get 0))))"#;
+        let sexpr = parse_module_to_sexpr(input).unwrap();
+        let module = module_from_sexpr(&sexpr).unwrap();
+        let expected = parse_module(input).unwrap();
+        assert_eq!(module.types, expected.types);
+        assert_eq!(
+            format!("{:?}", module.funcs),
+            format!("{:?}", expected.funcs)
+        );
+        let exports = |module: &Module| {
+            let exports = module.exports.iter();
+            exports
+                .map(|e| format!("{} {:?}", e.name, e.desc))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(exports(&module), ["f Func(FuncIdx(0))", "g Func(FuncIdx(1))"]);
+        assert_eq!(exports(&module), exports(&expected));
+
+        let sexpr = parse_module_to_sexpr("(module (func i32.frob))").unwrap();
+        assert!(matches!(
+            module_from_sexpr(&sexpr),
+            Err(ParseError::UnknownInstruction(_))
+        ));
+        let atom = Sexpr::Atom("module".into());
+        assert!(module_from_sexpr(&atom).is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{f64::NAN, str::FromStr, string::FromUtf8Error};
+use std::{str::FromStr, string::FromUtf8Error};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct TextToken(Vec<u8>);
@@ -7,6 +7,10 @@ impl TextToken {
     pub fn try_string(&self) -> Result<String, FromUtf8Error> {
         String::from_utf8(self.0.clone())
     }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -19,17 +23,87 @@ pub enum Token {
     Nat(usize),
     Int(isize),
     Float(f64),
+    Nan(NanLiteral),
     Equal,
     Comment(String),
     Whitespace,
 }
 
+/// A `nan`, `-nan` or `nan:0x...` literal. It isn't a [`Token::Float`]
+/// because where the payload goes depends on the width of the float.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NanLiteral {
+    pub negative: bool,
+    /// the explicit payload, plain `nan` has the canonical one
+    pub payload: Option<u64>,
+}
+
+impl NanLiteral {
+    /// The f32 with this sign and payload, `None` if the payload is zero or
+    /// doesn't fit in 23 bits.
+    pub fn to_f32(&self) -> Option<f32> {
+        let payload = self.payload.unwrap_or(1 << 22);
+        if payload == 0 || payload >= 1 << 23 {
+            return None;
+        }
+        let sign = if self.negative { 1 << 31 } else { 0 };
+        Some(f32::from_bits(sign | 0x7f80_0000 | payload as u32))
+    }
+
+    /// The f64 with this sign and payload, `None` if the payload is zero or
+    /// doesn't fit in 52 bits.
+    pub fn to_f64(&self) -> Option<f64> {
+        let payload = self.payload.unwrap_or(1 << 51);
+        if payload == 0 || payload >= 1 << 52 {
+            return None;
+        }
+        let sign = if self.negative { 1 << 63 } else { 0 };
+        Some(f64::from_bits(sign | 0x7ff0_0000_0000_0000 | payload))
+    }
+}
+
 #[derive(Debug)]
 pub enum Sign {
     Positive,
     Negative,
 }
 
+/// A 1-based line and column in the source text.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    fn start() -> Self {
+        Self { line: 1, column: 1 }
+    }
+
+    fn advance(&mut self, consumed: &str) {
+        for c in consumed.chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+    }
+}
+
+impl core::fmt::Display for Position {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub item: T,
+    pub pos: Position,
+}
+
 #[derive(Debug)]
 pub enum TokenizeError {
     UnknownError,
@@ -38,6 +112,17 @@ pub enum TokenizeError {
     UnexpectedEof,
 }
 
+impl core::fmt::Display for TokenizeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TokenizeError::UnknownError => write!(f, "unknown token"),
+            TokenizeError::FailedExpectedToken => write!(f, "unexpected token"),
+            TokenizeError::UnexpectedNextChar(c) => write!(f, "unexpected character {c:?}"),
+            TokenizeError::UnexpectedEof => write!(f, "unexpected end of input"),
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct Lexer<'s> {
     input: &'s str,
@@ -60,6 +145,42 @@ fn parse_longest<'s, T: core::fmt::Debug>(
     return val;
 }
 
+/// Rounds `mantissa * 2^exp` to the nearest f64, ties to even.
+fn hexfloat_value(mantissa: u64, exp: i64) -> f64 {
+    if mantissa == 0 {
+        return 0.0;
+    }
+    let bits = 64 - mantissa.leading_zeros() as i64;
+    // exponent of the leading bit, and how many bits of precision are left
+    // for it: 53 for normal numbers, fewer for subnormals
+    let top = exp + bits - 1;
+    let precision = if top >= -1022 { 53 } else { 53 - (-1022 - top) };
+    let shift = bits - precision.max(0);
+    let (mut rounded, mut exp) = (mantissa as u128, exp);
+    if shift > 0 {
+        let shift = shift.min(127) as u32;
+        let rest = rounded & ((1 << shift) - 1);
+        let half = 1 << (shift - 1);
+        rounded >>= shift;
+        if rest > half || (rest == half && rounded & 1 == 1) {
+            rounded += 1;
+        }
+        exp += shift as i64;
+    }
+    // `rounded` fits 54 bits, so only the scaling can lose precision, and it
+    // does so exactly when the result is out of range
+    let mut value = rounded as f64;
+    while exp > 1000 {
+        value *= 2f64.powi(1000);
+        exp -= 1000;
+    }
+    while exp < -1000 {
+        value *= 2f64.powi(-1000);
+        exp += 1000;
+    }
+    value * 2f64.powi(exp as i32)
+}
+
 impl<'s> Lexer<'s> {
     fn peek_next_char(&self) -> Option<char> {
         self.input.chars().next()
@@ -282,7 +403,7 @@ impl<'s> Lexer<'s> {
             .ok_or(TokenizeError::FailedExpectedToken)? as usize;
         loop {
             self.accept_char('_');
-            let Some(digit) = self.accept_digit() else { break };
+            let Some(digit) = self.accept_hexdigit() else { break };
             num = num.wrapping_mul(16);
             num = num.wrapping_add(digit as usize);
         }
@@ -325,23 +446,86 @@ impl<'s> Lexer<'s> {
         }
     }
 
+    /// Parses the part of a hex float after `0x`, e.g. `1.921fb54442d18p+1`.
+    fn hexfloat(&mut self) -> LexResult<f64> {
+        // the mantissa keeps at most 61 significant bits, which is enough to
+        // round correctly; any digit dropped beyond that only sets `sticky`
+        let mut mantissa: u64 = 0;
+        let mut exp: i64 = 0;
+        let mut sticky = false;
+        let mut push_digit = |digit: u32, fractional: bool| {
+            if mantissa >> 57 == 0 {
+                mantissa = mantissa * 16 + digit as u64;
+                if fractional {
+                    exp -= 4;
+                }
+            } else {
+                sticky |= digit != 0;
+                if !fractional {
+                    exp += 4;
+                }
+            }
+        };
+
+        let digit = self
+            .accept_hexdigit()
+            .ok_or(TokenizeError::FailedExpectedToken)?;
+        push_digit(digit, false);
+        loop {
+            self.accept_char('_');
+            let Some(digit) = self.accept_hexdigit() else { break };
+            push_digit(digit, false);
+        }
+        let has_point = self.accept_char('.');
+        if has_point {
+            if let Some(digit) = self.accept_hexdigit() {
+                push_digit(digit, true);
+                loop {
+                    self.accept_char('_');
+                    let Some(digit) = self.accept_hexdigit() else { break };
+                    push_digit(digit, true);
+                }
+            }
+        }
+        let has_exp = self.accept_char('p') || self.accept_char('P');
+        if has_exp {
+            let sign = self.accept_sign().unwrap_or(Sign::Positive);
+            // saturate, anything this large is zero or infinity anyway
+            let num = self.num()?.min(1 << 20) as i64;
+            match sign {
+                Sign::Positive => exp += num,
+                Sign::Negative => exp -= num,
+            }
+        }
+        if !has_point && !has_exp {
+            // that's just a hex nat
+            return Err(TokenizeError::FailedExpectedToken);
+        }
+        if sticky {
+            mantissa |= 1;
+        }
+        Ok(hexfloat_value(mantissa, exp))
+    }
+
     fn float(&mut self) -> LexResult<Token> {
         // TODO: exponents
         let sign = self.accept_sign();
         if self.accept_string("0x") {
-            let dec = self.hexnum()?;
-            self.expect_char('.')?;
-            let frac = self.hexnum().ok();
-            let floatstr = format!("{}.{}", dec, frac.unwrap_or(0));
-            let float = f64::from_str(&floatstr).unwrap();
-            Ok(Token::Float(float))
+            let float = self.hexfloat()?;
+            match sign {
+                Some(Sign::Negative) => Ok(Token::Float(-float)),
+                _ => Ok(Token::Float(float)),
+            }
         } else {
             let dec = self.num()?;
             self.expect_char('.')?;
             let frac = self.num().ok();
             let floatstr = format!("{}.{}", dec, frac.unwrap_or(0));
             let float = f64::from_str(&floatstr).unwrap();
-            Ok(Token::Float(float))
+            match sign {
+                Some(Sign::Negative) => Ok(Token::Float(-float)),
+                _ => Ok(Token::Float(float)),
+            }
         }
     }
 
@@ -357,15 +541,20 @@ impl<'s> Lexer<'s> {
     fn float_nan(&mut self) -> LexResult<Token> {
         let sign = self.accept_sign();
         self.expect_string("nan")?;
-        Ok(Token::Float(f64::NAN))
+        Ok(Token::Nan(NanLiteral {
+            negative: matches!(sign, Some(Sign::Negative)),
+            payload: None,
+        }))
     }
 
     fn float_nan_hex(&mut self) -> LexResult<Token> {
         let sign = self.accept_sign();
         self.expect_string("nan:0x")?;
         let num = self.hexnum()?;
-        // TODO: change nan pattern
-        Ok(Token::Float(f64::NAN))
+        Ok(Token::Nan(NanLiteral {
+            negative: matches!(sign, Some(Sign::Negative)),
+            payload: Some(num as u64),
+        }))
     }
 
     fn atom(&mut self) -> LexResult<Token> {
@@ -457,25 +646,42 @@ pub fn tokenize_script(input: &str) -> Result<Vec<Token>, TokenizeError> {
     }
 }
 
-pub fn tokenize_script_without_ws(input: &str) -> Result<Vec<Token>, TokenizeError> {
+/// Like [`tokenize_script_without_ws`], but records where each token starts.
+pub fn tokenize_script_spanned(input: &str) -> Result<Vec<Spanned<Token>>, Spanned<TokenizeError>> {
     let mut tokens = vec![];
     let mut tokenizer = Lexer { input };
+    let mut pos = Position::start();
     loop {
-        let Some(token) = tokenizer.token()? else { return Ok(tokens) };
+        let before = tokenizer.input;
+        let token = match tokenizer.token() {
+            Ok(Some(token)) => token,
+            Ok(None) => return Ok(tokens),
+            Err(item) => return Err(Spanned { item, pos }),
+        };
+        let start = pos;
+        pos.advance(&before[..before.len() - tokenizer.input.len()]);
         match token {
             Token::Comment(_) => continue,
             Token::Whitespace => continue,
             _ => {}
         };
-        tokens.push(token);
+        tokens.push(Spanned {
+            item: token,
+            pos: start,
+        });
     }
 }
 
+pub fn tokenize_script_without_ws(input: &str) -> Result<Vec<Token>, TokenizeError> {
+    let tokens = tokenize_script_spanned(input).map_err(|e| e.item)?;
+    Ok(tokens.into_iter().map(|t| t.item).collect())
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::text::token::Token;
+    use crate::text::token::{NanLiteral, Position, Token};
 
-    use super::tokenize_script;
+    use super::{tokenize_script, tokenize_script_spanned, tokenize_script_without_ws};
 
     #[test]
     fn tokenize_string() {
@@ -484,4 +690,96 @@ mod tests {
         let token = &tokens[0];
         assert!(matches!(token, Token::Text(_)));
     }
+
+    #[test]
+    fn token_positions() {
+        let tokens = tokenize_script_spanned("(module\n  ;; comment\n  (func))").unwrap();
+        let positions: Vec<_> = tokens.iter().map(|t| (t.pos.line, t.pos.column)).collect();
+        assert_eq!(positions, [(1, 1), (1, 2), (3, 3), (3, 4), (3, 8), (3, 9)]);
+    }
+
+    #[test]
+    fn tokenize_error_position() {
+        let err = tokenize_script_spanned("(module\n  \"abc").unwrap_err();
+        assert_eq!(err.pos, Position { line: 2, column: 3 });
+    }
+
+    fn float(input: &str) -> f64 {
+        let tokens = tokenize_script_without_ws(input).unwrap();
+        let [Token::Float(f)] = tokens[..] else { panic!("expected a float, got {tokens:?}") };
+        f
+    }
+
+    #[test]
+    fn hex_floats() {
+        assert_eq!(float("0x1.921fb54442d18p+1"), std::f64::consts::PI);
+        assert_eq!(float("0x1p-2"), 0.25);
+        assert_eq!(float("-0x1.8p1"), -3.0);
+        assert_eq!(float("0x1_0.8"), 16.5);
+        assert_eq!(float("0x0.8"), 0.5);
+        assert_eq!(float("0x1.fffffffffffffp1023"), f64::MAX);
+        assert_eq!(float("0x1p1024"), f64::INFINITY);
+        assert!(float("-0x0.0p0").is_sign_negative());
+    }
+
+    #[test]
+    fn signed_decimal_floats() {
+        assert_eq!(float("-1.5"), -1.5);
+        assert_eq!(float("+1.5"), 1.5);
+        assert!(float("-0.0").is_sign_negative());
+        assert_eq!(float("-inf"), f64::NEG_INFINITY);
+        assert_eq!(float("+inf"), f64::INFINITY);
+    }
+
+    fn nan(input: &str) -> NanLiteral {
+        let tokens = tokenize_script_without_ws(input).unwrap();
+        let [Token::Nan(nan)] = tokens[..] else { panic!("expected a nan, got {tokens:?}") };
+        nan
+    }
+
+    #[test]
+    fn nans_keep_sign_and_payload() {
+        assert_eq!(nan("nan").to_f32().unwrap().to_bits(), 0x7fc0_0000);
+        assert_eq!(nan("-nan").to_f32().unwrap().to_bits(), 0xffc0_0000);
+        assert_eq!(
+            nan("+nan").to_f64().unwrap().to_bits(),
+            0x7ff8_0000_0000_0000
+        );
+        assert_eq!(
+            nan("-nan").to_f64().unwrap().to_bits(),
+            0xfff8_0000_0000_0000
+        );
+        assert_eq!(nan("nan:0x200000").to_f32().unwrap().to_bits(), 0x7fa0_0000);
+        assert_eq!(nan("-nan:0x1").to_f32().unwrap().to_bits(), 0xff80_0001);
+        assert_eq!(
+            nan("nan:0xf_ffff_ffff_ffff").to_f64().unwrap().to_bits(),
+            0x7fff_ffff_ffff_ffff
+        );
+        // the payload has to fit and a zero payload would be an infinity
+        assert_eq!(nan("nan:0x800000").to_f32(), None);
+        assert_eq!(
+            nan("nan:0x800000").to_f64().unwrap().to_bits(),
+            0x7ff0_0000_0080_0000
+        );
+        assert_eq!(nan("nan:0x0").to_f64(), None);
+    }
+
+    #[test]
+    fn hex_floats_round_to_nearest_even() {
+        // smallest subnormal, and halfway below it
+        assert_eq!(float("0x1p-1074").to_bits(), 1);
+        assert_eq!(float("0x1p-1075").to_bits(), 0);
+        assert_eq!(float("0x1.8p-1075").to_bits(), 1);
+        // one bit past the 53 bits of precision: a tie, rounds to even
+        assert_eq!(float("0x1.00000000000008p0"), 1.0);
+        assert_eq!(
+            float("0x1.00000000000018p0").to_bits(),
+            1.0f64.to_bits() + 2
+        );
+        // anything beyond the tie rounds up, even many digits later
+        assert_eq!(
+            float("0x1.000000000000080000000001p0").to_bits(),
+            1.0f64.to_bits() + 1
+        );
+    }
 }
@@ -0,0 +1,470 @@
+//! Checks the rules a module must follow beyond being well-formed.
+//!
+//! Only a few of the validation rules are implemented so far; a module that
+//! passes may still be invalid.
+
+use core::fmt;
+
+use alloc::{vec, vec::Vec};
+
+use crate::{
+    flat::{stack_effect, Signatures},
+    repr::{
+        BlockType, FuncIdx, FuncType, GlobalType, ImportDesc, Inst, Locals, Module, Reftype,
+        TypeIdx, ValType,
+    },
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// A load or store claims a larger alignment than the size of its access.
+    AlignmentTooLarge,
+    /// An instruction refers to a global that doesn't exist.
+    UnknownGlobal(u32),
+    /// `global.set` of a global that isn't mutable.
+    ImmutableGlobal,
+    /// `ref.func` of a function that isn't in [`Module::refs`].
+    UndeclaredFunctionReference(u32),
+    /// An instruction is missing an operand or gets one of the wrong type.
+    /// Only the operands of `select` are checked so far.
+    TypeMismatch,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::AlignmentTooLarge => {
+                write!(f, "alignment must not be larger than natural")
+            }
+            ValidationError::UnknownGlobal(idx) => write!(f, "unknown global {idx}"),
+            ValidationError::ImmutableGlobal => write!(f, "global is immutable"),
+            ValidationError::UndeclaredFunctionReference(_) => {
+                write!(f, "undeclared function reference")
+            }
+            ValidationError::TypeMismatch => write!(f, "type mismatch"),
+        }
+    }
+}
+
+pub fn validate(module: &Module) -> Result<(), ValidationError> {
+    // imported globals come first in the index space
+    let imported = module
+        .imports
+        .iter()
+        .filter_map(|import| match import.desc {
+            ImportDesc::Global(typ) => Some(typ),
+            _ => None,
+        });
+    let globals: Vec<_> = imported
+        .chain(module.globals.iter().map(|g| g.typ))
+        .collect();
+    let funcs: Vec<_> = module
+        .imports
+        .iter()
+        .filter_map(|import| match import.desc {
+            ImportDesc::Func(typ) => Some(typ),
+            _ => None,
+        })
+        .chain(module.funcs.iter().map(|f| f.typ))
+        .collect();
+    for func in &module.funcs {
+        for inst in func.insts() {
+            validate_inst(inst, &globals)?;
+        }
+        if let Some(typ) = module.types.get(func.typ.0 as usize) {
+            let body = Body {
+                module,
+                funcs: &funcs,
+                globals: &globals,
+                params: &typ.from.types,
+                locals: &func.locals,
+            };
+            body.check_operands(&func.body, &[])?;
+        }
+    }
+    if let Some(idx) = module.undeclared_ref() {
+        return Err(ValidationError::UndeclaredFunctionReference(idx.0));
+    }
+    Ok(())
+}
+
+fn validate_inst(inst: &Inst, globals: &[GlobalType]) -> Result<(), ValidationError> {
+    if let (Some(memarg), Some(natural)) = (inst.memarg(), inst.natural_alignment()) {
+        if memarg.align > natural {
+            return Err(ValidationError::AlignmentTooLarge);
+        }
+    }
+    if let Inst::GlobalGet(idx) | Inst::GlobalSet(idx) = inst {
+        let global = globals
+            .get(idx.0 as usize)
+            .ok_or(ValidationError::UnknownGlobal(idx.0))?;
+        if matches!(inst, Inst::GlobalSet(_)) && !global.mutable {
+            return Err(ValidationError::ImmutableGlobal);
+        }
+    }
+    Ok(())
+}
+
+/// The operand stack of a block, as far as validation follows it.
+struct Operands {
+    /// `None` for a value whose type isn't worked out
+    types: Vec<Option<ValType>>,
+    /// Set after an unconditional branch, from where on the stack is
+    /// polymorphic: popping past its bottom gives a value of any type.
+    unreachable: bool,
+}
+
+impl Operands {
+    fn pop(&mut self) -> Result<Option<ValType>, ValidationError> {
+        match self.types.pop() {
+            Some(t) => Ok(t),
+            None if self.unreachable => Ok(None),
+            None => Err(ValidationError::TypeMismatch),
+        }
+    }
+
+    fn pop_expecting(&mut self, expected: ValType) -> Result<(), ValidationError> {
+        match self.pop()? {
+            Some(t) if t != expected => Err(ValidationError::TypeMismatch),
+            _ => Ok(()),
+        }
+    }
+
+    fn pop_params(&mut self, params: &[ValType]) -> Result<(), ValidationError> {
+        for &param in params.iter().rev() {
+            self.pop_expecting(param)?;
+        }
+        Ok(())
+    }
+
+    fn push_results(&mut self, results: &[ValType]) {
+        self.types.extend(results.iter().copied().map(Some));
+    }
+
+    fn set_unreachable(&mut self) {
+        self.types.clear();
+        self.unreachable = true;
+    }
+}
+
+/// A function body with what it can refer to, for following the types of
+/// its operands.
+struct Body<'a> {
+    module: &'a Module,
+    /// the type of every function, imported ones first
+    funcs: &'a [TypeIdx],
+    globals: &'a [GlobalType],
+    params: &'a [ValType],
+    locals: &'a [Locals],
+}
+
+impl Body<'_> {
+    /// Follows the operand types through a block that starts with `params`
+    /// on its stack. It only reports missing operands and the operands of
+    /// `select`, every other instruction takes operands of any type.
+    fn check_operands(&self, body: &[Inst], params: &[ValType]) -> Result<(), ValidationError> {
+        let mut stack = Operands {
+            types: params.iter().copied().map(Some).collect(),
+            unreachable: false,
+        };
+        for inst in body {
+            match inst {
+                Inst::Block(typ, body) | Inst::Loop(typ, body) => {
+                    let Some((params, results)) = self.block_type(*typ) else {
+                        stack.set_unreachable();
+                        continue;
+                    };
+                    stack.pop_params(&params)?;
+                    self.check_operands(body.as_ref(), &params)?;
+                    stack.push_results(&results);
+                }
+                Inst::IfElse(typ, then, els) => {
+                    stack.pop_expecting(ValType::I32)?;
+                    let Some((params, results)) = self.block_type(*typ) else {
+                        stack.set_unreachable();
+                        continue;
+                    };
+                    stack.pop_params(&params)?;
+                    self.check_operands(then.as_ref(), &params)?;
+                    self.check_operands(els.as_ref(), &params)?;
+                    stack.push_results(&results);
+                }
+                Inst::Unreachable | Inst::Break(_) | Inst::Return => stack.set_unreachable(),
+                Inst::BreakTable(..) => {
+                    stack.pop_expecting(ValType::I32)?;
+                    stack.set_unreachable();
+                }
+                Inst::BreakIf(_) => stack.pop_expecting(ValType::I32)?,
+                Inst::Call(idx) => match self.func_type(*idx) {
+                    Some(typ) => {
+                        stack.pop_params(&typ.from.types)?;
+                        stack.push_results(&typ.to.types);
+                    }
+                    None => stack.set_unreachable(),
+                },
+                Inst::CallIndirect(idx, _) => {
+                    stack.pop_expecting(ValType::I32)?;
+                    match self.module.types.get(idx.0 as usize) {
+                        Some(typ) => {
+                            stack.pop_params(&typ.from.types)?;
+                            stack.push_results(&typ.to.types);
+                        }
+                        None => stack.set_unreachable(),
+                    }
+                }
+                Inst::Select => {
+                    stack.pop_expecting(ValType::I32)?;
+                    let second = stack.pop()?;
+                    let first = stack.pop()?;
+                    // without a type annotation both have to be numbers
+                    let is_ref = |t: Option<ValType>| {
+                        matches!(t, Some(ValType::FuncRef | ValType::ExternRef))
+                    };
+                    if is_ref(first) || is_ref(second) {
+                        return Err(ValidationError::TypeMismatch);
+                    }
+                    if let (Some(a), Some(b)) = (first, second) {
+                        if a != b {
+                            return Err(ValidationError::TypeMismatch);
+                        }
+                    }
+                    stack.types.push(first.or(second));
+                }
+                Inst::SelectTyped(typ) => {
+                    stack.pop_expecting(ValType::I32)?;
+                    stack.pop_expecting(*typ)?;
+                    stack.pop_expecting(*typ)?;
+                    stack.types.push(Some(*typ));
+                }
+                inst => {
+                    // calls are handled above, nothing else needs signatures
+                    let Ok((pops, pushes)) = stack_effect(inst, &Signatures::default()) else {
+                        stack.set_unreachable();
+                        continue;
+                    };
+                    for _ in 0..pops {
+                        stack.pop()?;
+                    }
+                    if pushes == 1 {
+                        stack.types.push(self.result_type(inst));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn block_type(&self, typ: BlockType) -> Option<(Vec<ValType>, Vec<ValType>)> {
+        match typ {
+            BlockType::Empty => Some((vec![], vec![])),
+            BlockType::Inline(t) => Some((vec![], vec![t])),
+            BlockType::Type(idx) => {
+                let typ = self.module.types.get(idx.0 as usize)?;
+                Some((typ.from.types.clone(), typ.to.types.clone()))
+            }
+        }
+    }
+
+    fn func_type(&self, idx: FuncIdx) -> Option<&FuncType> {
+        let typ = self.funcs.get(idx.0 as usize)?;
+        self.module.types.get(typ.0 as usize)
+    }
+
+    fn local_type(&self, idx: u32) -> Option<ValType> {
+        let idx = idx as usize;
+        if let Some(&t) = self.params.get(idx) {
+            return Some(t);
+        }
+        let mut idx = idx - self.params.len();
+        for locals in self.locals {
+            if idx < locals.n as usize {
+                return Some(locals.t);
+            }
+            idx -= locals.n as usize;
+        }
+        None
+    }
+
+    /// The type of the value an instruction pushes, for the instructions
+    /// whose `select` operands the checks care about.
+    fn result_type(&self, inst: &Inst) -> Option<ValType> {
+        match inst {
+            Inst::I32Const(_) | Inst::RefIsNull | Inst::MemorySize(_) | Inst::MemoryGrow(_) => {
+                Some(ValType::I32)
+            }
+            Inst::I64Const(_) => Some(ValType::I64),
+            Inst::F32Const(_) => Some(ValType::F32),
+            Inst::F64Const(_) => Some(ValType::F64),
+            Inst::RefNull(Reftype::Funcref) | Inst::RefFunc(_) => Some(ValType::FuncRef),
+            Inst::RefNull(Reftype::Externref) => Some(ValType::ExternRef),
+            Inst::LocalGet(idx) | Inst::LocalTee(idx) => self.local_type(idx.0),
+            Inst::GlobalGet(idx) => self.globals.get(idx.0 as usize).map(|g| g.valtype),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::{validate, ValidationError};
+    use crate::repr::{
+        BlockType, Func, FuncIdx, FuncType, Global, GlobalIdx, GlobalType, Import, ImportDesc,
+        Inst, MemArg, MemIdx, Module, Reftype, ResultType, TypeIdx, ValType,
+    };
+
+    fn with_body(body: Vec<Inst>) -> Module {
+        Module {
+            types: vec![FuncType {
+                from: ResultType { types: vec![] },
+                to: ResultType { types: vec![] },
+            }],
+            funcs: vec![Func {
+                typ: TypeIdx(0),
+                locals: vec![],
+                body,
+            }],
+            ..Module::default()
+        }
+    }
+
+    #[test]
+    fn alignment_up_to_natural() {
+        let memarg = |align| MemArg {
+            align,
+            offset: 0,
+            memory: MemIdx(0),
+        };
+        let load = |load| with_body(vec![Inst::I32Const(0), load, Inst::Drop]);
+        assert!(validate(&load(Inst::I64Load(memarg(3)))).is_ok());
+        let store = vec![
+            Inst::I32Const(0),
+            Inst::I32Const(1),
+            Inst::I32Store16(memarg(0)),
+        ];
+        assert!(validate(&with_body(store)).is_ok());
+        assert_eq!(
+            validate(&load(Inst::I32Load8U(memarg(1)))),
+            Err(ValidationError::AlignmentTooLarge)
+        );
+        // nested in a block
+        let block = Inst::Block(BlockType::Empty, vec![Inst::F32Load(memarg(3))].into());
+        assert_eq!(
+            validate(&with_body(vec![block])),
+            Err(ValidationError::AlignmentTooLarge)
+        );
+    }
+
+    #[test]
+    fn only_mutable_globals_can_be_set() {
+        let global = |mutable| GlobalType {
+            valtype: ValType::I32,
+            mutable,
+        };
+        let with_globals = |body| Module {
+            imports: vec![Import {
+                module: "env".into(),
+                nm: "g".into(),
+                desc: ImportDesc::Global(global(false)),
+            }],
+            globals: vec![Global {
+                typ: global(true),
+                init: vec![Inst::I32Const(0)],
+            }],
+            ..with_body(body)
+        };
+        let set = |idx| vec![Inst::I32Const(1), Inst::GlobalSet(GlobalIdx(idx))];
+        assert!(validate(&with_globals(set(1))).is_ok());
+        assert!(validate(&with_globals(vec![Inst::GlobalGet(GlobalIdx(0))])).is_ok());
+        assert_eq!(
+            validate(&with_globals(set(0))),
+            Err(ValidationError::ImmutableGlobal)
+        );
+        assert_eq!(
+            validate(&with_globals(set(2))),
+            Err(ValidationError::UnknownGlobal(2))
+        );
+    }
+
+    #[test]
+    fn select_operands() {
+        let select = |first, second, select| {
+            validate(&with_body(vec![
+                first,
+                second,
+                Inst::I32Const(1),
+                select,
+                Inst::Drop,
+            ]))
+        };
+        let null = Inst::RefNull;
+        assert!(select(Inst::I64Const(1), Inst::I64Const(2), Inst::Select).is_ok());
+        assert_eq!(
+            select(Inst::I64Const(1), Inst::F64Const(2.0), Inst::Select),
+            Err(ValidationError::TypeMismatch)
+        );
+        // references need the typed form
+        let externref = Inst::SelectTyped(ValType::ExternRef);
+        assert!(select(
+            null(Reftype::Externref),
+            null(Reftype::Externref),
+            externref.clone()
+        )
+        .is_ok());
+        assert_eq!(
+            select(
+                null(Reftype::Externref),
+                null(Reftype::Externref),
+                Inst::Select
+            ),
+            Err(ValidationError::TypeMismatch)
+        );
+        assert_eq!(
+            select(null(Reftype::Funcref), null(Reftype::Externref), externref),
+            Err(ValidationError::TypeMismatch)
+        );
+        // the condition has to be an i32
+        let body = vec![
+            Inst::I32Const(1),
+            Inst::I32Const(2),
+            Inst::I64Const(1),
+            Inst::Select,
+            Inst::Drop,
+        ];
+        assert_eq!(
+            validate(&with_body(body)),
+            Err(ValidationError::TypeMismatch)
+        );
+        // a block can't take operands from outside of it
+        let block = Inst::Block(
+            BlockType::Empty,
+            vec![Inst::I32Const(1), Inst::Select, Inst::Drop].into(),
+        );
+        let body = vec![Inst::I32Const(1), Inst::I32Const(2), block];
+        assert_eq!(
+            validate(&with_body(body)),
+            Err(ValidationError::TypeMismatch)
+        );
+        // but after `unreachable` anything goes
+        let body = vec![Inst::Unreachable, Inst::Select, Inst::Drop];
+        assert!(validate(&with_body(body)).is_ok());
+    }
+
+    #[test]
+    fn referenced_functions_are_declared() {
+        let module = Module {
+            refs: [FuncIdx(0)].into(),
+            ..with_body(vec![Inst::RefFunc(FuncIdx(0)), Inst::Drop])
+        };
+        assert!(validate(&module).is_ok());
+        let module = Module {
+            refs: [FuncIdx(0)].into(),
+            ..with_body(vec![Inst::RefFunc(FuncIdx(1)), Inst::Drop])
+        };
+        assert_eq!(
+            validate(&module),
+            Err(ValidationError::UndeclaredFunctionReference(1))
+        );
+    }
+}